@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use anyhow::*;
+
+use crate::mipmap::MipmapGenerator;
+use crate::sampler::SamplerCache;
+use crate::texture::{Texture, SamplerDesc};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum TextureOrigin {
+    File(PathBuf),
+    // The model file an image was decoded from and its index in there
+    Embedded(PathBuf, usize),
+}
+
+// Paths are canonical, so different spellings of a path share
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct TextureKey {
+    origin: TextureOrigin,
+    srgb: bool,
+    sampler: SamplerDesc,
+}
+
+impl TextureKey {
+    fn file(path: &Path, srgb: bool, sampler: &SamplerDesc) -> Result<Self> {
+        let canonical = path.canonicalize()
+            .with_context(|| format!("Unable to find texture {}", path.display()))?;
+        Ok(Self { origin: TextureOrigin::File(canonical), srgb, sampler: *sampler })
+    }
+
+    fn embedded(file: &Path, index: usize, srgb: bool, sampler: &SamplerDesc) -> Result<Self> {
+        let canonical = file.canonicalize()
+            .with_context(|| format!("Unable to find model {}", file.display()))?;
+        Ok(Self { origin: TextureOrigin::Embedded(canonical, index), srgb, sampler: *sampler })
+    }
+}
+
+// Weak handles by key, the part of the cache that needs no device
+struct Handles<T> {
+    entries: HashMap<TextureKey, Weak<T>>,
+}
+
+impl<T> Handles<T> {
+    fn new() -> Self {
+        Self { entries: HashMap::new() }
+    }
+
+    fn get(&self, key: &TextureKey) -> Option<Rc<T>> {
+        self.entries.get(key).and_then(Weak::upgrade)
+    }
+
+    fn insert(&mut self, key: TextureKey, value: &Rc<T>) {
+        self.entries.insert(key, Rc::downgrade(value));
+    }
+
+    fn purge(&mut self) {
+        self.entries.retain(|_, value| value.strong_count() > 0);
+    }
+
+    fn num_loaded(&self) -> usize {
+        self.entries.values().filter(|value| value.strong_count() > 0).count()
+    }
+}
+
+/// Textures loaded from disk, shared between everything asking for the same
+/// file with the same settings. The cache only holds weak references: a
+/// texture is freed, GPU memory included, once the last handle to it is
+/// dropped, and loaded again the next time it is asked for.
+pub struct TextureCache {
+
+    textures: Handles<Texture>,
+    samplers: SamplerCache,
+    mipmaps: MipmapGenerator,
+
+} impl TextureCache {
+
+    pub fn new() -> Self {
+        Self { textures: Handles::new(), samplers: SamplerCache::new(), mipmaps: MipmapGenerator::new() }
+    }
+
+    /// A colour texture with the default sampler.
+    pub fn load(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, path: &Path) -> Result<Rc<Texture>> {
+        self.load_with(device, queue, path, true, &SamplerDesc::default())
+    }
+
+    /// Missing files and images that fail to decode are errors, and leave
+    /// the cache as it was.
+    pub fn load_with(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: &Path,
+        srgb: bool,
+        sampler: &SamplerDesc,
+    ) -> Result<Rc<Texture>> {
+        self.purge();
+        let key = TextureKey::file(path, srgb, sampler)?;
+        if let Some(texture) = self.textures.get(&key) {
+            return Ok(texture);
+        }
+
+        let img = image::open(path)
+            .with_context(|| format!("Unable to load texture {}", path.display()))?;
+        let texture = Rc::new(self.from_image(device, queue, &img, path.to_str(), srgb, sampler)?);
+        log::debug!("Loaded texture {}", path.display());
+        self.textures.insert(key, &texture);
+        Ok(texture)
+    }
+
+    /// An image decoded from inside the model `file`, shared like a file
+    /// would be. `index` tells it apart from the file's other images.
+    #[allow(clippy::too_many_arguments)]
+    pub fn load_embedded(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        file: &Path,
+        index: usize,
+        img: &image::DynamicImage,
+        srgb: bool,
+        sampler: &SamplerDesc,
+    ) -> Result<Rc<Texture>> {
+        self.purge();
+        let key = TextureKey::embedded(file, index, srgb, sampler)?;
+        if let Some(texture) = self.textures.get(&key) {
+            return Ok(texture);
+        }
+
+        let label = format!("{} image {}", file.display(), index);
+        let texture = Rc::new(self.from_image(device, queue, img, Some(&label), srgb, sampler)?);
+        log::debug!("Loaded {}", label);
+        self.textures.insert(key, &texture);
+        Ok(texture)
+    }
+
+    /// A texture outside the cache, sharing samplers and mipmap pipelines
+    /// with the cached ones.
+    pub fn from_image(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &image::DynamicImage,
+        label: Option<&str>,
+        srgb: bool,
+        sampler: &SamplerDesc,
+    ) -> Result<Texture> {
+        let sampler = self.samplers.get(device, sampler);
+        Texture::from_image(device, queue, img, label, srgb, sampler, &mut self.mipmaps)
+    }
+
+    /// Forgets textures nobody holds anymore, and the samplers they used.
+    pub fn purge(&mut self) {
+        self.textures.purge();
+        self.samplers.purge();
+    }
+
+    /// For textures made some other way to share samplers with the cached
+    /// ones.
+    pub fn samplers(&mut self) -> &mut SamplerCache {
+        &mut self.samplers
+    }
+
+    /// Textures some handle still holds.
+    pub fn num_loaded(&self) -> usize {
+        self.textures.num_loaded()
+    }
+}
+
+impl Default for TextureCache {
+    fn default() -> Self { Self::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMAGE: &str = "res/tests/gltf/nodes.png";
+
+    fn key(path: &str, srgb: bool) -> TextureKey {
+        TextureKey::file(Path::new(path), srgb, &SamplerDesc::default()).unwrap()
+    }
+
+    #[test]
+    fn same_file_and_settings_share() {
+        let mut handles = Handles::new();
+        let srgb = Rc::new("srgb");
+        handles.insert(key(IMAGE, true), &srgb);
+        // Another spelling of the same path
+        let found = handles.get(&key("res/tests/../tests/gltf/./nodes.png", true)).unwrap();
+        assert!(Rc::ptr_eq(&found, &srgb));
+
+        // The same file read as linear data is a texture of its own
+        assert!(handles.get(&key(IMAGE, false)).is_none());
+        let linear = Rc::new("linear");
+        handles.insert(key(IMAGE, false), &linear);
+        assert!(Rc::ptr_eq(&handles.get(&key(IMAGE, false)).unwrap(), &linear));
+        assert!(Rc::ptr_eq(&handles.get(&key(IMAGE, true)).unwrap(), &srgb));
+        // And so is one with another sampler
+        let nearest = SamplerDesc { mag_filter: wgpu::FilterMode::Nearest, ..SamplerDesc::default() };
+        assert!(handles.get(&TextureKey::file(Path::new(IMAGE), true, &nearest).unwrap()).is_none());
+        assert_eq!(handles.num_loaded(), 2);
+    }
+
+    #[test]
+    fn embedded_images_are_told_apart_by_file_and_index() {
+        let sampler = SamplerDesc::default();
+        let embedded = |file: &str, index| TextureKey::embedded(Path::new(file), index, true, &sampler).unwrap();
+        let mut handles = Handles::new();
+        let first = Rc::new("first");
+        handles.insert(embedded("res/tests/gltf/nodes.glb", 0), &first);
+        assert!(Rc::ptr_eq(&handles.get(&embedded("res/tests/gltf/../gltf/nodes.glb", 0)).unwrap(), &first));
+        assert!(handles.get(&embedded("res/tests/gltf/nodes.glb", 1)).is_none());
+        assert!(handles.get(&embedded("res/tests/gltf/nodes.gltf", 0)).is_none());
+        // Not even a file next to it with the same name
+        assert!(handles.get(&key("res/tests/gltf/nodes.glb", true)).is_none());
+    }
+
+    #[test]
+    fn entries_go_with_their_last_handle() {
+        let mut handles = Handles::new();
+        let texture = Rc::new("texture");
+        let other = texture.clone();
+        handles.insert(key(IMAGE, true), &texture);
+        drop(texture);
+        assert_eq!(handles.num_loaded(), 1);
+        assert!(handles.get(&key(IMAGE, true)).is_some());
+
+        drop(other);
+        assert_eq!(handles.num_loaded(), 0);
+        assert!(handles.get(&key(IMAGE, true)).is_none());
+        assert_eq!(handles.entries.len(), 1);
+        handles.purge();
+        assert!(handles.entries.is_empty());
+    }
+
+    #[test]
+    fn missing_files_are_errors() {
+        let error = TextureKey::file(Path::new("res/tests/missing.png"), true, &SamplerDesc::default()).unwrap_err();
+        assert!(format!("{:#}", error).contains("Unable to find texture res/tests/missing.png"), "{:#}", error);
+    }
+}
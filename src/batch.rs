@@ -0,0 +1,156 @@
+use std::cmp::Ordering;
+use std::ops::Range;
+
+use crate::instance::Instance;
+
+/// A run of consecutive instances in the instance buffer drawn with the
+/// same LOD mesh.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub lod: usize,
+    pub instances: Range<u32>,
+}
+
+/// Decides the order instances are uploaded in and how they are split into
+/// draws. Opaque instances come first, grouped by LOD and front to back
+/// inside each group so the depth test rejects as much as possible.
+/// Transparent instances follow, strictly back to front; consecutive ones
+/// sharing a LOD are merged into one draw. Both sorts are stable, so
+/// instances at the same depth keep their creation order.
+pub struct BatchState {
+
+    order: Vec<usize>,
+    opaque: Vec<Batch>,
+    transparent: Vec<Batch>,
+
+} impl BatchState {
+
+    pub fn new() -> Self {
+        Self { order: Vec::new(), opaque: Vec::new(), transparent: Vec::new() }
+    }
+
+    /// `depth` is the view space depth of an instance, growing away from
+    /// the camera.
+    pub fn update<F>(&mut self, instances: &[Instance], levels: &[usize], depth: F)
+    where F: Fn(&Instance) -> f32 {
+        let depths = instances.iter().map(depth).collect::<Vec<f32>>();
+        let (mut transparent, mut opaque): (Vec<usize>, Vec<usize>) = (0..instances.len())
+            .partition(|&i| instances[i].is_transparent());
+
+        opaque.sort_by(|&a, &b| {
+            levels[a].cmp(&levels[b]).then_with(|| compare_depth(depths[a], depths[b]))
+        });
+        transparent.sort_by(|&a, &b| compare_depth(depths[b], depths[a]));
+
+        self.order.clear();
+        self.opaque = Self::push_runs(&mut self.order, &opaque, levels);
+        self.transparent = Self::push_runs(&mut self.order, &transparent, levels);
+    }
+
+    fn push_runs(order: &mut Vec<usize>, sorted: &[usize], levels: &[usize]) -> Vec<Batch> {
+        let mut batches: Vec<Batch> = Vec::new();
+        for &i in sorted {
+            let index = order.len() as u32;
+            order.push(i);
+            match batches.last_mut() {
+                Some(batch) if batch.lod == levels[i] => batch.instances.end = index + 1,
+                _ => batches.push(Batch { lod: levels[i], instances: index..index + 1 }),
+            }
+        }
+        batches
+    }
+
+    /// Instance indices in upload order.
+    pub fn order(&self) -> &[usize] { &self.order }
+    pub fn opaque(&self) -> &[Batch] { &self.opaque }
+    pub fn transparent(&self) -> &[Batch] { &self.transparent }
+}
+
+impl Default for BatchState {
+    fn default() -> Self { Self::new() }
+}
+
+// A total order, so a NaN depth from a broken transform can't upset the
+// sort; NaNs go after every other depth
+fn compare_depth(a: f32, b: f32) -> Ordering {
+    a.total_cmp(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Vector3, Quaternion};
+    use super::*;
+
+    // Depth is the x coordinate
+    fn instance(depth: f32, opacity: f32) -> Instance {
+        Instance::new(Vector3::new(depth, 0.0, 0.0), Quaternion::new(1.0, 0.0, 0.0, 0.0), opacity)
+    }
+
+    fn update(instances: &[Instance], levels: &[usize]) -> BatchState {
+        let mut state = BatchState::new();
+        state.update(instances, levels, |instance| instance.position().x);
+        state
+    }
+
+    #[test]
+    fn opaque_front_to_back_within_a_lod() {
+        let instances = vec![instance(5.0, 1.0), instance(1.0, 1.0), instance(9.0, 1.0), instance(3.0, 1.0)];
+        let state = update(&instances, &[1, 0, 1, 0]);
+        assert_eq!(state.order(), &[1, 3, 0, 2]);
+        assert_eq!(state.opaque(), &[
+            Batch { lod: 0, instances: 0..2 },
+            Batch { lod: 1, instances: 2..4 },
+        ]);
+        assert!(state.transparent().is_empty());
+    }
+
+    #[test]
+    fn transparent_back_to_front_after_opaque() {
+        let instances = vec![
+            instance(2.0, 0.5),
+            instance(4.0, 1.0),
+            instance(8.0, 0.5),
+            instance(6.0, 0.5),
+        ];
+        let state = update(&instances, &[0, 0, 1, 0]);
+        assert_eq!(state.order(), &[1, 2, 3, 0]);
+        assert_eq!(state.opaque(), &[Batch { lod: 0, instances: 0..1 }]);
+        // Depth wins over LOD, so the LOD 0 instances can't be merged
+        // around the LOD 1 one
+        assert_eq!(state.transparent(), &[
+            Batch { lod: 1, instances: 1..2 },
+            Batch { lod: 0, instances: 2..4 },
+        ]);
+    }
+
+    #[test]
+    fn equal_depths_keep_creation_order() {
+        let instances = vec![
+            instance(3.0, 0.5),
+            instance(3.0, 1.0),
+            instance(3.0, 0.5),
+            instance(3.0, 1.0),
+            instance(3.0, 0.5),
+        ];
+        let state = update(&instances, &[0; 5]);
+        assert_eq!(state.order(), &[1, 3, 0, 2, 4]);
+
+        // And stay that way frame after frame
+        let mut state = state;
+        state.update(&instances, &[0; 5], |instance| instance.position().x);
+        assert_eq!(state.order(), &[1, 3, 0, 2, 4]);
+    }
+
+    #[test]
+    fn nan_depths_still_sort() {
+        let instances = (0..40)
+            .map(|i| instance(if i % 3 == 0 { f32::NAN } else { (i * 7 % 11) as f32 }, if i % 2 == 0 { 1.0 } else { 0.5 }))
+            .collect::<Vec<_>>();
+        let state = update(&instances, &[0; 40]);
+        let mut order = state.order().to_vec();
+        order.sort_unstable();
+        assert_eq!(order, (0..40).collect::<Vec<_>>());
+        // Furthest of all, so drawn last among the opaque ones
+        assert!(instances[state.order()[19]].position().x.is_nan());
+    }
+}
@@ -0,0 +1,50 @@
+use std::path::PathBuf;
+use anyhow::*;
+
+use learn_wgpu::{mesh_cache, model::ModelData};
+
+const USAGE: &str = "Usage: mesh-convert <model.obj|.ply|.gltf|.glb> [output.mesh] [--crease-angle <degrees>]";
+
+// Converts a model to the binary mesh cache, with normals, tangents and the
+// optimiser already applied, so loading it skips all of that
+fn main() -> Result<()> {
+    env_logger::init();
+    let mut input = None;
+    let mut output = None;
+    let mut crease_angle = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--crease-angle" => {
+                let degrees = args.next().context(USAGE)?;
+                let degrees = degrees.parse::<f32>()
+                    .with_context(|| format!("Bad crease angle {:?}", degrees))?;
+                crease_angle = Some(cgmath::Deg(degrees));
+            }
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ if output.is_none() => output = Some(PathBuf::from(arg)),
+            _ => bail!(USAGE),
+        }
+    }
+    let input = input.context(USAGE)?;
+    let output = output.unwrap_or_else(|| input.with_extension(mesh_cache::EXTENSION));
+
+    let mut data = ModelData::load(&input)?;
+    if !data.materials.is_empty() {
+        log::warn!("{} has materials, only the geometry is converted", input.display());
+    }
+    // Caches hold a single space, so the nodes are baked in
+    data.flatten();
+    data.prepare(crease_angle);
+    mesh_cache::validate(&data)
+        .with_context(|| format!("Unable to convert {}", input.display()))?;
+    mesh_cache::write(&output, &data)?;
+
+    let vertices = data.meshes.iter().map(|mesh| mesh.vertices.len()).sum::<usize>();
+    let triangles = data.meshes.iter().map(|mesh| mesh.indices.len() / 3).sum::<usize>();
+    println!(
+        "Wrote {}: {} meshes, {} vertices, {} triangles",
+        output.display(), data.meshes.len(), vertices, triangles,
+    );
+    Ok(())
+}
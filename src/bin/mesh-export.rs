@@ -0,0 +1,54 @@
+use std::path::{Path, PathBuf};
+use anyhow::*;
+
+use learn_wgpu::{scene, export::{self, ExportFormat}, model::ModelData};
+
+const USAGE: &str = "Usage: mesh-export <output.obj|.ply> [--scene <scene.ron>] [--binary] [--baked]";
+
+// Exports the mesh a scene draws, as it looks after loading, for inspection
+// in other tools. --baked writes every instance in world space instead of
+// the single model space mesh, --binary picks binary PLY over ASCII.
+fn main() -> Result<()> {
+    env_logger::init();
+    let mut output = None;
+    let mut scene_path = PathBuf::from(scene::SCENE_PATH);
+    let mut binary = false;
+    let mut baked = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scene" => scene_path = PathBuf::from(args.next().context(USAGE)?),
+            "--binary" => binary = true,
+            "--baked" => baked = true,
+            _ if output.is_none() => output = Some(PathBuf::from(arg)),
+            _ => bail!(USAGE),
+        }
+    }
+    let output = output.context(USAGE)?;
+    let format = match (extension(&output), binary) {
+        (Some("obj"), false) => ExportFormat::Obj,
+        (Some("obj"), true) => bail!("OBJ has no binary form"),
+        (Some("ply"), false) => ExportFormat::PlyAscii,
+        (Some("ply"), true) => ExportFormat::PlyBinary,
+        _ => bail!(USAGE),
+    };
+
+    let scene = scene::SceneDesc::load(&scene_path)?;
+    let mut data = ModelData::from_desc(&scene.mesh)?;
+    data.flatten();
+    data.prepare(scene.crease_angle.map(cgmath::Deg));
+    let meshes = if baked {
+        export::bake(&data.meshes, &scene.instances.build())
+    } else {
+        data.meshes
+    };
+    export::export(&output, &meshes, format)?;
+
+    let triangles = meshes.iter().map(|mesh| mesh.indices.len() / 3).sum::<usize>();
+    println!("Wrote {}: {} meshes, {} triangles", output.display(), meshes.len(), triangles);
+    Ok(())
+}
+
+fn extension(path: &Path) -> Option<&str> {
+    path.extension().and_then(|extension| extension.to_str())
+}
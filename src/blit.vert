@@ -0,0 +1,11 @@
+// blit.vert
+#version 450
+
+layout(location=0) out vec2 v_tex_coords;
+
+void main() {
+    // One triangle covering the whole target: (-1, -1), (3, -1), (-1, 3)
+    vec2 position = vec2(float(gl_VertexIndex == 1) * 4.0 - 1.0, float(gl_VertexIndex == 2) * 4.0 - 1.0);
+    v_tex_coords = vec2(position.x + 1.0, 1.0 - position.y) * 0.5;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
@@ -0,0 +1,320 @@
+use cgmath::{Matrix4, Point3, Vector3, Vector4, InnerSpace, MetricSpace, EuclideanSpace, Transform};
+
+/// Axis aligned box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+
+} impl Aabb {
+
+    /// None when there are no points.
+    pub fn from_points(points: &[Point3<f32>]) -> Option<Self> {
+        let first = *points.first()?;
+        Some(points.iter().fold(Self { min: first, max: first }, |aabb, point| Self {
+            min: Point3::new(aabb.min.x.min(point.x), aabb.min.y.min(point.y), aabb.min.z.min(point.z)),
+            max: Point3::new(aabb.max.x.max(point.x), aabb.max.y.max(point.y), aabb.max.z.max(point.z)),
+        }))
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        self.min.midpoint(self.max)
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self::from_points(&[self.min, self.max, other.min, other.max]).unwrap()
+    }
+
+    /// The box around this one after `transform`. It stays axis aligned,
+    /// so it grows when rotated.
+    pub fn transform(&self, transform: &Matrix4<f32>) -> Self {
+        // Arvo's method: each output axis takes the smaller and larger end of
+        // every input axis' contribution
+        let mut min = transform.w.truncate();
+        let mut max = min;
+        for column in 0..3 {
+            for row in 0..3 {
+                let a = transform[column][row] * self.min[column];
+                let b = transform[column][row] * self.max[column];
+                min[row] += a.min(b);
+                max[row] += a.max(b);
+            }
+        }
+        Self { min: Point3::from_vec(min), max: Point3::from_vec(max) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sphere {
+
+    pub center: Point3<f32>,
+    pub radius: f32,
+
+} impl Sphere {
+
+    /// Not the smallest enclosing sphere, but close: the smaller of Ritter's
+    /// sphere and the one centred on the bounding box. None when there are
+    /// no points.
+    pub fn from_points(points: &[Point3<f32>]) -> Option<Self> {
+        let center = Aabb::from_points(points)?.center();
+        let boxed = Self { center, radius: Self::max_distance(center, points) };
+        let ritter = Self::ritter(points);
+        Some(if ritter.radius < boxed.radius { ritter } else { boxed })
+    }
+
+    // Starts from two far apart points and grows to take in any point left
+    // outside
+    fn ritter(points: &[Point3<f32>]) -> Self {
+        let farthest = |from: Point3<f32>| {
+            points.iter()
+                .copied()
+                .fold(from, |best, point| if from.distance2(point) > from.distance2(best) { point } else { best })
+        };
+        let a = farthest(points[0]);
+        let b = farthest(a);
+        let mut sphere = Self { center: a.midpoint(b), radius: a.distance(b) / 2.0 };
+        for &point in points {
+            let distance = sphere.center.distance(point);
+            if distance > sphere.radius {
+                let radius = (sphere.radius + distance) / 2.0;
+                sphere.center += (point - sphere.center) * ((radius - sphere.radius) / distance);
+                sphere.radius = radius;
+            }
+        }
+        // Rounding can leave the last points just outside
+        sphere.radius = sphere.radius.max(Self::max_distance(sphere.center, points));
+        sphere
+    }
+
+    fn max_distance(center: Point3<f32>, points: &[Point3<f32>]) -> f32 {
+        points.iter().map(|&point| center.distance(point)).fold(0.0, f32::max)
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        let offset = other.center - self.center;
+        let distance = offset.magnitude();
+        if distance + other.radius <= self.radius {
+            return *self;
+        }
+        if distance + self.radius <= other.radius {
+            return *other;
+        }
+        let radius = (distance + self.radius + other.radius) / 2.0;
+        Self {
+            center: self.center + offset * ((radius - self.radius) / distance),
+            radius,
+        }
+    }
+
+    /// Scales the radius by the transform's largest axis scale, so the
+    /// sphere still encloses everything under non-uniform scales.
+    pub fn transform(&self, transform: &Matrix4<f32>) -> Self {
+        let scale = transform.x.truncate().magnitude()
+            .max(transform.y.truncate().magnitude())
+            .max(transform.z.truncate().magnitude());
+        Self {
+            center: transform.transform_point(self.center),
+            radius: self.radius * scale,
+        }
+    }
+}
+
+/// Box and sphere around the same geometry; culling and picking use
+/// whichever is the cheaper or tighter test.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+
+    pub aabb: Aabb,
+    pub sphere: Sphere,
+
+} impl Bounds {
+
+    pub fn from_points(points: &[Point3<f32>]) -> Option<Self> {
+        Some(Self { aabb: Aabb::from_points(points)?, sphere: Sphere::from_points(points)? })
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self { aabb: self.aabb.union(&other.aabb), sphere: self.sphere.union(&other.sphere) }
+    }
+
+    pub fn transform(&self, transform: &Matrix4<f32>) -> Self {
+        Self { aabb: self.aabb.transform(transform), sphere: self.sphere.transform(transform) }
+    }
+}
+
+/// The six planes around what a view projection matrix shows, normals
+/// pointing inwards.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frustum {
+
+    // xyz is the unit normal, w the distance along it from the origin
+    planes: [Vector4<f32>; 6],
+
+} impl Frustum {
+
+    /// Gribb and Hartmann's plane extraction, for wgpu's clip space where z
+    /// runs from 0 to 1.
+    pub fn from_matrix(view_proj: &Matrix4<f32>) -> Self {
+        let row = |i: usize| Vector4::new(view_proj.x[i], view_proj.y[i], view_proj.z[i], view_proj.w[i]);
+        let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+        let mut planes = [w + x, w - x, w + y, w - y, z, w - z];
+        for plane in &mut planes {
+            *plane /= plane.truncate().magnitude();
+        }
+        Self { planes }
+    }
+
+    /// False when the bounds are entirely outside. Bounds near a corner of
+    /// the frustum can pass without touching it.
+    pub fn intersects(&self, bounds: &Bounds) -> bool {
+        let Aabb { min, max } = bounds.aabb;
+        self.planes.iter().all(|plane| {
+            let normal = plane.truncate();
+            if normal.dot(bounds.sphere.center.to_vec()) + plane.w < -bounds.sphere.radius {
+                return false;
+            }
+            // The box corner farthest along the normal
+            let corner = Vector3::new(
+                if normal.x >= 0.0 { max.x } else { min.x },
+                if normal.y >= 0.0 { max.y } else { min.y },
+                if normal.z >= 0.0 { max.z } else { min.z },
+            );
+            normal.dot(corner) + plane.w >= 0.0
+        })
+    }
+}
+
+/// Union of every bounds given, None if there are none.
+pub fn union_all<I>(bounds: I) -> Option<Bounds>
+where I: IntoIterator<Item = Bounds> {
+    bounds.into_iter().fold(None, |total: Option<Bounds>, bounds| match total {
+        Some(total) => Some(total.union(&bounds)),
+        None => Some(bounds),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Matrix3, SquareMatrix};
+
+    fn assert_near(a: Point3<f32>, b: Point3<f32>) {
+        assert!(a.distance(b) < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    // Corners of a box, every combination of min and max
+    fn corners(aabb: &Aabb) -> Vec<Point3<f32>> {
+        (0..8)
+            .map(|i| Point3::new(
+                if i & 1 == 0 { aabb.min.x } else { aabb.max.x },
+                if i & 2 == 0 { aabb.min.y } else { aabb.max.y },
+                if i & 4 == 0 { aabb.min.z } else { aabb.max.z },
+            ))
+            .collect()
+    }
+
+    #[test]
+    fn box_under_rotation_and_scale() {
+        let aabb = Aabb { min: Point3::new(-1.0, -2.0, 0.0), max: Point3::new(3.0, 2.0, 1.0) };
+        let transforms = vec![
+            Matrix4::identity(),
+            Matrix4::from_translation(Vector3::new(5.0, -1.0, 2.0)),
+            Matrix4::from_nonuniform_scale(2.0, -1.0, 0.5),
+            Matrix4::from_angle_z(Deg(90.0)),
+            Matrix4::from_translation(Vector3::new(1.0, 2.0, 3.0))
+                * Matrix4::from_angle_y(Deg(30.0))
+                * Matrix4::from_angle_x(Deg(-45.0))
+                * Matrix4::from_nonuniform_scale(1.0, 3.0, 2.0),
+        ];
+        for transform in &transforms {
+            // Arvo's box is exactly the box around the transformed corners
+            let moved = corners(&aabb).iter().map(|&c| transform.transform_point(c)).collect::<Vec<_>>();
+            let expected = Aabb::from_points(&moved).unwrap();
+            let actual = aabb.transform(transform);
+            assert_near(actual.min, expected.min);
+            assert_near(actual.max, expected.max);
+        }
+
+        // A quarter turn about z swaps the x and y extents
+        let turned = aabb.transform(&Matrix4::from_angle_z(Deg(90.0)));
+        assert_near(turned.min, Point3::new(-2.0, -1.0, 0.0));
+        assert_near(turned.max, Point3::new(2.0, 3.0, 1.0));
+    }
+
+    #[test]
+    fn sphere_around_a_point_cloud() {
+        // Points on a sphere of radius 2 about (1, 2, 3), with a cluster
+        // on one side that pulls the box centre but not the sphere
+        let center = Point3::new(1.0, 2.0, 3.0);
+        let mut points = Vec::new();
+        for i in 0..16 {
+            for j in 1..8 {
+                let (theta, phi) = (i as f32 * std::f32::consts::FRAC_PI_8, j as f32 * std::f32::consts::FRAC_PI_8);
+                let direction = Vector3::new(phi.sin() * theta.cos(), phi.cos(), phi.sin() * theta.sin());
+                points.push(center + direction * 2.0);
+            }
+        }
+        points.push(center + Vector3::unit_y() * 2.0);
+        points.push(center - Vector3::unit_y() * 2.0);
+        for i in 0..50 {
+            points.push(center + Vector3::new(1.5, 0.0, i as f32 * 0.01));
+        }
+
+        let sphere = Sphere::from_points(&points).unwrap();
+        assert!(points.iter().all(|&p| sphere.center.distance(p) <= sphere.radius + 1e-5));
+        // Within a few percent of the smallest sphere
+        assert!(sphere.radius >= 2.0 - 1e-4 && sphere.radius < 2.1, "{:?}", sphere);
+
+        let single = Sphere::from_points(&[center]).unwrap();
+        assert_eq!(single, Sphere { center, radius: 0.0 });
+        assert!(Sphere::from_points(&[]).is_none());
+        assert!(Bounds::from_points(&[]).is_none());
+    }
+
+    #[test]
+    fn sphere_transform_takes_the_largest_scale() {
+        let sphere = Sphere { center: Point3::new(1.0, 0.0, 0.0), radius: 1.0 };
+        let transform = Matrix4::from_translation(Vector3::new(0.0, 1.0, 0.0))
+            * Matrix4::from(Matrix3::from_angle_z(Deg(90.0)))
+            * Matrix4::from_nonuniform_scale(1.0, 3.0, 0.5);
+        let moved = sphere.transform(&transform);
+        assert_near(moved.center, Point3::new(0.0, 2.0, 0.0));
+        assert!((moved.radius - 3.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn unions_enclose_every_part() {
+        let parts = vec![
+            Bounds::from_points(&[Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 1.0, 1.0)]).unwrap(),
+            Bounds::from_points(&[Point3::new(4.0, -1.0, 0.0), Point3::new(5.0, 0.0, 2.0)]).unwrap(),
+            Bounds::from_points(&[Point3::new(0.5, 0.5, 0.5)]).unwrap(),
+        ];
+        let total = union_all(parts.iter().copied()).unwrap();
+        assert_eq!(total.aabb, Aabb { min: Point3::new(0.0, -1.0, 0.0), max: Point3::new(5.0, 1.0, 2.0) });
+        for part in &parts {
+            let reach = total.sphere.center.distance(part.sphere.center) + part.sphere.radius;
+            assert!(reach <= total.sphere.radius + 1e-5, "{:?} outside {:?}", part, total);
+        }
+        // A sphere inside the other is absorbed unchanged
+        assert_eq!(parts[0].sphere.union(&parts[2].sphere), parts[0].sphere);
+
+        assert_eq!(union_all(vec![parts[1]]), Some(parts[1]));
+        assert_eq!(union_all(Vec::new()), None);
+    }
+
+    #[test]
+    fn frustum_culls_outside_bounds() {
+        let view_proj = cgmath::perspective(Deg(90.0), 1.0, 0.1, 100.0)
+            * Matrix4::look_at(Point3::new(0.0, 0.0, 0.0), Point3::new(0.0, 0.0, -1.0), Vector3::unit_y());
+        let frustum = Frustum::from_matrix(&(crate::camera::OPENGL_TO_WGPU_MATRIX * view_proj));
+        let cube = |x: f32, y: f32, z: f32| {
+            Bounds::from_points(&[Point3::new(x - 0.5, y - 0.5, z - 0.5), Point3::new(x + 0.5, y + 0.5, z + 0.5)]).unwrap()
+        };
+        assert!(frustum.intersects(&cube(0.0, 0.0, -5.0)));
+        assert!(frustum.intersects(&cube(5.0, 0.0, -5.0)));
+        assert!(!frustum.intersects(&cube(0.0, 0.0, 5.0)));
+        assert!(!frustum.intersects(&cube(8.0, 0.0, -5.0)));
+        assert!(!frustum.intersects(&cube(0.0, 0.0, -200.0)));
+    }
+}
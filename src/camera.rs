@@ -5,6 +5,8 @@ use winit::{
 };
 use wgpu::util::DeviceExt;
 
+use crate::bounds::Frustum;
+
 pub struct CameraState {
 
     camera_desc: Camera,
@@ -79,6 +81,42 @@ pub struct CameraState {
         self.camera_controller.process_events(event)
     }
 
+    /// Moves the camera to a viewpoint that came with a model. The aspect
+    /// ratio keeps following the window.
+    pub fn set_view(&mut self, view: &CameraView) {
+        self.camera_desc.eye = view.eye;
+        self.camera_desc.target = view.target;
+        self.camera_desc.up = view.up;
+        self.camera_desc.fovy = view.fovy;
+        self.camera_desc.znear = view.znear;
+        self.camera_desc.zfar = view.zfar;
+    }
+
+    /// What the camera sees, in world space.
+    pub fn frustum(&self) -> Frustum {
+        Frustum::from_matrix(&self.camera_desc.build_view_projection_matrix())
+    }
+
+    pub fn eye(&self) -> cgmath::Point3<f32> {
+        self.camera_desc.eye
+    }
+
+    /// Distance of `point` in front of the camera, along the view direction.
+    pub fn view_depth(&self, point: cgmath::Point3<f32>) -> f32 {
+        use cgmath::InnerSpace;
+        let forward = (self.camera_desc.target - self.camera_desc.eye).normalize();
+        (point - self.camera_desc.eye).dot(forward)
+    }
+
+    /// World space directions of the screen's right and up, for quads that
+    /// face the camera.
+    pub fn billboard_axes(&self) -> (cgmath::Vector3<f32>, cgmath::Vector3<f32>) {
+        use cgmath::InnerSpace;
+        let forward = (self.camera_desc.target - self.camera_desc.eye).normalize();
+        let right = forward.cross(self.camera_desc.up).normalize();
+        (right, right.cross(forward))
+    }
+
     pub fn bind_group_layout(&self) -> &wgpu::BindGroupLayout { 
         &self.uniform_bind_group_layout 
     }
@@ -88,6 +126,17 @@ pub struct CameraState {
     }
 }
 
+/// A perspective viewpoint, `fovy` in degrees.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraView {
+    pub eye: cgmath::Point3<f32>,
+    pub target: cgmath::Point3<f32>,
+    pub up: cgmath::Vector3<f32>,
+    pub fovy: f32,
+    pub znear: f32,
+    pub zfar: f32,
+}
+
 struct Camera {
 
     eye: cgmath::Point3<f32>,
@@ -243,7 +292,7 @@ struct Uniforms {
     }
 }
 
-const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
+pub(crate) const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     1.0, 0.0, 0.0, 0.0, 
     0.0, 1.0, 0.0, 0.0, 
     0.0, 0.0, 0.5, 0.0, 
@@ -0,0 +1,224 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use anyhow::*;
+
+use crate::model::{MeshData, Vertex};
+use crate::instance::Instance;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Obj,
+    PlyAscii,
+    PlyBinary,
+}
+
+/// Writes positions, normals, texture coordinates and triangles. Tangents
+/// and materials are left out, neither format has a standard place for them.
+/// PLY has a single vertex list, so several meshes are merged into one.
+pub fn export(path: &Path, meshes: &[MeshData], format: ExportFormat) -> Result<()> {
+    let file = File::create(path)
+        .with_context(|| format!("Unable to create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    match format {
+        ExportFormat::Obj => write_obj(&mut writer, meshes),
+        ExportFormat::PlyAscii => write_ply(&mut writer, meshes, false),
+        ExportFormat::PlyBinary => write_ply(&mut writer, meshes, true),
+    }
+    .and_then(|_| writer.flush())
+    .with_context(|| format!("Unable to write {}", path.display()))
+}
+
+/// One copy of the meshes per instance, moved to where the instance draws
+/// it.
+pub fn bake(meshes: &[MeshData], instances: &[Instance]) -> Vec<MeshData> {
+    instances.iter()
+        .enumerate()
+        .flat_map(|(i, instance)| {
+            meshes.iter().map(move |mesh| {
+                let mut baked = MeshData {
+                    name: format!("{}/{}", mesh.name, i),
+                    vertices: posed_vertices(mesh, instance),
+                    indices: mesh.indices.clone(),
+                    material: mesh.material,
+                    // Baked in the bind pose
+                    skin: None,
+                    morph: None,
+                };
+                baked.transform(&instance.model_matrix());
+                baked
+            })
+        })
+        .collect()
+}
+
+// Morph targets applied with the weights the instance draws them with.
+// Skinned meshes are drawn without theirs, so they aren't applied there
+fn posed_vertices(mesh: &MeshData, instance: &Instance) -> Vec<Vertex> {
+    match (&mesh.morph, &mesh.skin) {
+        (Some(morph), None) => morph.apply(&mesh.vertices, &morph.combined_weights(instance.morph_weights())),
+        _ => mesh.vertices.clone(),
+    }
+}
+
+pub fn write_obj<W: Write>(writer: &mut W, meshes: &[MeshData]) -> std::io::Result<()> {
+    writeln!(writer, "# Exported by learn_wgpu")?;
+    // OBJ indices are 1-based and count from the start of the file
+    let mut first = 1;
+    for mesh in meshes {
+        writeln!(writer, "o {}", mesh.name)?;
+        for vertex in &mesh.vertices {
+            let [x, y, z] = vertex.position;
+            writeln!(writer, "v {} {} {}", x, y, z)?;
+        }
+        for vertex in &mesh.vertices {
+            // OBJ puts v = 0 at the bottom of the image, wgpu at the top
+            let [u, v] = vertex.tex_coords;
+            writeln!(writer, "vt {} {}", u, 1.0 - v)?;
+        }
+        for vertex in &mesh.vertices {
+            let [x, y, z] = vertex.normal;
+            writeln!(writer, "vn {} {} {}", x, y, z)?;
+        }
+        for triangle in mesh.indices.chunks_exact(3) {
+            let (a, b, c) = (triangle[0] + first, triangle[1] + first, triangle[2] + first);
+            writeln!(writer, "f {}/{}/{} {}/{}/{} {}/{}/{}", a, a, a, b, b, b, c, c, c)?;
+        }
+        first += mesh.vertices.len() as u32;
+    }
+    Ok(())
+}
+
+// Texture coordinates are written as s and t, the names most tools read
+pub fn write_ply<W: Write>(writer: &mut W, meshes: &[MeshData], binary: bool) -> std::io::Result<()> {
+    let num_vertices = meshes.iter().map(|mesh| mesh.vertices.len()).sum::<usize>();
+    let num_faces = meshes.iter().map(|mesh| mesh.indices.len() / 3).sum::<usize>();
+    writeln!(writer, "ply")?;
+    writeln!(writer, "format {} 1.0", if binary { "binary_little_endian" } else { "ascii" })?;
+    writeln!(writer, "comment Exported by learn_wgpu")?;
+    writeln!(writer, "element vertex {}", num_vertices)?;
+    for property in &["x", "y", "z", "nx", "ny", "nz", "s", "t"] {
+        writeln!(writer, "property float {}", property)?;
+    }
+    writeln!(writer, "element face {}", num_faces)?;
+    writeln!(writer, "property list uchar uint vertex_indices")?;
+    writeln!(writer, "end_header")?;
+
+    for vertex in meshes.iter().flat_map(|mesh| &mesh.vertices) {
+        let [x, y, z] = vertex.position;
+        let [nx, ny, nz] = vertex.normal;
+        let [s, t] = vertex.tex_coords;
+        // PLY's t grows upwards like OBJ's v
+        let values = [x, y, z, nx, ny, nz, s, 1.0 - t];
+        if binary {
+            for value in &values {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        } else {
+            let values = values.iter().map(f32::to_string).collect::<Vec<_>>();
+            writeln!(writer, "{}", values.join(" "))?;
+        }
+    }
+
+    let mut first = 0;
+    for mesh in meshes {
+        for triangle in mesh.indices.chunks_exact(3) {
+            let (a, b, c) = (triangle[0] + first, triangle[1] + first, triangle[2] + first);
+            if binary {
+                writer.write_all(&[3])?;
+                for index in &[a, b, c] {
+                    writer.write_all(&index.to_le_bytes())?;
+                }
+            } else {
+                writeln!(writer, "3 {} {} {}", a, b, c)?;
+            }
+        }
+        first += mesh.vertices.len() as u32;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{obj, ply, primitives};
+
+    // Each triangle as its corners' position, texture coordinates and
+    // normal, rounded so text round trips compare equal, starting from the
+    // smallest corner so the winding is kept
+    fn triangle_set(meshes: &[MeshData]) -> Vec<[[i64; 8]; 3]> {
+        let mut triangles = Vec::new();
+        for mesh in meshes {
+            let corner = |index: u32| {
+                let vertex = &mesh.vertices[index as usize];
+                let mut key = [0; 8];
+                let values = vertex.position.iter().chain(&vertex.tex_coords).chain(&vertex.normal);
+                for (key, value) in key.iter_mut().zip(values) {
+                    *key = (value * 1e5).round() as i64;
+                }
+                key
+            };
+            for triangle in mesh.indices.chunks_exact(3) {
+                let corners = [corner(triangle[0]), corner(triangle[1]), corner(triangle[2])];
+                let first = (0..3).min_by_key(|&i| corners[i]).unwrap();
+                triangles.push([corners[first], corners[(first + 1) % 3], corners[(first + 2) % 3]]);
+            }
+        }
+        triangles.sort_unstable();
+        triangles
+    }
+
+    fn meshes() -> Vec<MeshData> {
+        let mut meshes = vec![primitives::uv_sphere(1.0, 12, 6), primitives::cube(2.0, 2)];
+        // Off-centre and with texture coordinates that aren't symmetric
+        // about v = 0.5, so a missed flip or offset shows
+        for vertex in &mut meshes[1].vertices {
+            vertex.position[0] += 3.0;
+            vertex.tex_coords[1] *= 0.25;
+        }
+        meshes
+    }
+
+    #[test]
+    fn obj_round_trip() {
+        let meshes = meshes();
+        let mut bytes = Vec::new();
+        write_obj(&mut bytes, &meshes).unwrap();
+        let model = obj::parse(std::str::from_utf8(&bytes).unwrap(), Path::new("export.obj")).unwrap();
+
+        let names = model.meshes.iter().map(|mesh| mesh.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, meshes.iter().map(|mesh| mesh.name.as_str()).collect::<Vec<_>>());
+        for (read, written) in model.meshes.iter().zip(&meshes) {
+            assert_eq!(triangle_set(std::slice::from_ref(read)), triangle_set(std::slice::from_ref(written)));
+        }
+    }
+
+    #[test]
+    fn ply_round_trip() {
+        let meshes = meshes();
+        for &binary in &[false, true] {
+            let mut bytes = Vec::new();
+            write_ply(&mut bytes, &meshes, binary).unwrap();
+            let read = ply::parse(&bytes, String::from("export")).unwrap();
+
+            let num_vertices = meshes.iter().map(|mesh| mesh.vertices.len()).sum::<usize>();
+            assert_eq!(read.vertices.len(), num_vertices);
+            assert_eq!(triangle_set(&[read]), triangle_set(&meshes), "binary: {}", binary);
+        }
+    }
+
+    #[test]
+    fn baked_instances_move_their_copies() {
+        let quad = primitives::plane(1.0, 1);
+        let instances = vec![
+            Instance::new(cgmath::Vector3::new(0.0, 0.0, 0.0), cgmath::Quaternion::new(1.0, 0.0, 0.0, 0.0), 1.0),
+            Instance::new(cgmath::Vector3::new(5.0, 0.0, 0.0), cgmath::Quaternion::new(1.0, 0.0, 0.0, 0.0), 1.0),
+        ];
+        let baked = bake(std::slice::from_ref(&quad), &instances);
+        assert_eq!(baked.len(), 2);
+        assert_eq!(baked[1].name, format!("{}/1", quad.name));
+        for (original, moved) in quad.vertices.iter().zip(&baked[1].vertices) {
+            assert_eq!(moved.position, [original.position[0] + 5.0, original.position[1], original.position[2]]);
+        }
+    }
+}
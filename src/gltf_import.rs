@@ -0,0 +1,619 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+use cgmath::{Matrix4, Vector3, Vector4, Point3, Quaternion, Deg, Rad};
+use cgmath::{SquareMatrix, InnerSpace, EuclideanSpace};
+use image::{DynamicImage, ImageBuffer};
+use anyhow::*;
+
+use crate::model::{ModelData, MeshData, NodeData, MaterialData, TextureData, TextureSource, Vertex};
+use crate::texture::SamplerDesc;
+use crate::normals;
+use crate::camera::CameraView;
+use crate::morph::{MorphData, MorphTarget};
+use crate::skin::{SkinData, SkinVertex, Skeleton, Joint, Transform, AnimationClip, Channel, Keyframes, Interpolation};
+
+// glTF allows an infinite far plane, cgmath::perspective doesn't
+const INFINITE_ZFAR: f32 = 1000.0;
+
+/// Loads a `.gltf`, with its buffers and images in external files or data
+/// URIs, or a self-contained `.glb`. Every node of the default scene that
+/// draws a mesh becomes one of the model's nodes, carrying its transform
+/// relative to the scene root; meshes stay in their own space and are
+/// imported once however many nodes share them. The whole scene is one
+/// model, each instance draws a copy of it. The first perspective camera
+/// in the hierarchy becomes the model's viewpoint.
+/// Meshes bound to the first skin keep their bind pose instead, and the
+/// skin's skeleton comes along with every animation of its joints. Morph
+/// targets come with the mesh's default weights; weight animations aren't
+/// imported.
+pub fn load(path: &Path) -> Result<ModelData> {
+    let (document, buffers, images) = gltf::import(path)
+        .with_context(|| format!("Unable to import {}", path.display()))?;
+
+    let images = document.images()
+        .zip(images)
+        .map(|(image, data)| import_image(path, &image, data))
+        .collect::<Result<Vec<_>>>()?;
+    let materials = document.materials()
+        .map(|material| import_material(&material, &images))
+        .collect();
+
+    let scene = document.default_scene()
+        .or_else(|| document.scenes().next())
+        .with_context(|| format!("{} has no scenes", path.display()))?;
+    let skin = document.skins()
+        .next()
+        .map(|skin| import_skin(&document, &skin, &buffers))
+        .transpose()
+        .with_context(|| format!("Unable to import the skin of {}", path.display()))?;
+    let num_joints = skin.as_ref().map(|skin| skin.skeleton.joints().len());
+    let mut importer = Importer { 
+        buffers: &buffers, 
+        num_joints, 
+        meshes: Vec::new(), 
+        imported: HashMap::new(),
+        nodes: Vec::new(), 
+        camera: None,
+    };
+    for node in scene.nodes() {
+        importer.node(&node, Matrix4::identity())
+            .with_context(|| format!("Unable to import {}", path.display()))?;
+    }
+    if importer.meshes.is_empty() {
+        bail!("{} has no triangle meshes", path.display());
+    }
+    Ok(ModelData { meshes: importer.meshes, materials, nodes: importer.nodes, camera: importer.camera, skin, terrain: None })
+}
+
+struct Importer<'a> {
+    buffers: &'a [gltf::buffer::Data],
+    // Joints in the first skin, the only one imported
+    num_joints: Option<usize>,
+    meshes: Vec<MeshData>,
+    // Where the primitives of each glTF mesh went in `meshes`, by mesh
+    // index and whether they were imported skinned
+    imported: HashMap<(usize, bool), Vec<usize>>,
+    nodes: Vec<NodeData>,
+    camera: Option<CameraView>,
+}
+
+impl<'a> Importer<'a> {
+    fn node(&mut self, node: &gltf::Node, parent: Matrix4<f32>) -> Result<()> {
+        let transform = parent * Matrix4::from(node.transform().matrix());
+        if let Some(mesh) = node.mesh() {
+            let skinned = match node.skin() {
+                Some(skin) if skin.index() == 0 => true,
+                Some(_) => {
+                    log::warn!("Only the first skin is imported, {:?} stays in its bind pose", mesh.name().unwrap_or(""));
+                    false
+                }
+                None => false,
+            };
+            let meshes = self.mesh(&mesh, skinned)?;
+            self.nodes.push(NodeData {
+                name: node.name().map(String::from).unwrap_or_else(|| format!("node {}", node.index())),
+                // Skinned meshes ignore their node's transform, the joints
+                // place them
+                transform: if skinned { Matrix4::identity() } else { transform },
+                meshes,
+            });
+        }
+        if let (None, Some(camera)) = (&self.camera, node.camera()) {
+            self.camera = camera_view(&camera, transform);
+        }
+        for child in node.children() {
+            self.node(&child, transform)?;
+        }
+        Ok(())
+    }
+
+    // Indices of the mesh's primitives in `meshes`, importing them the
+    // first time
+    fn mesh(&mut self, mesh: &gltf::Mesh, skinned: bool) -> Result<Vec<usize>> {
+        if let Some(meshes) = self.imported.get(&(mesh.index(), skinned)) {
+            return Ok(meshes.clone());
+        }
+        let mut meshes = Vec::new();
+        for primitive in mesh.primitives() {
+            let name = format!("{}/{}", mesh.name().unwrap_or("mesh"), primitive.index());
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                log::warn!("Skipping {}, only triangle lists are drawn", name);
+                continue;
+            }
+            let data = self.primitive(&primitive, mesh.weights(), skinned)
+                .with_context(|| format!("Mesh {}", name))?;
+            meshes.push(self.meshes.len());
+            self.meshes.push(MeshData { name, ..data });
+        }
+        self.imported.insert((mesh.index(), skinned), meshes.clone());
+        Ok(meshes)
+    }
+
+    fn primitive(
+        &self,
+        primitive: &gltf::Primitive,
+        morph_weights: Option<&[f32]>,
+        skinned: bool,
+    ) -> Result<MeshData> {
+        let buffers = self.buffers;
+        let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]));
+
+        let positions = reader.read_positions()
+            .context("No POSITION attribute")?
+            .collect::<Vec<[f32; 3]>>();
+        let normals = reader.read_normals().map(|normals| normals.collect::<Vec<[f32; 3]>>());
+        let tangents = reader.read_tangents().map(|tangents| tangents.collect::<Vec<[f32; 4]>>());
+        let tex_coords = reader.read_tex_coords(0)
+            .map(|tex_coords| tex_coords.into_f32().collect::<Vec<[f32; 2]>>());
+        let counts = [
+            ("NORMAL", normals.as_ref().map(Vec::len)),
+            ("TANGENT", tangents.as_ref().map(Vec::len)),
+            ("TEXCOORD_0", tex_coords.as_ref().map(Vec::len)),
+        ];
+        for (attribute, count) in counts.iter() {
+            if let Some(count) = count.filter(|&count| count != positions.len()) {
+                bail!("{} vertices in POSITION but {} in {}", positions.len(), count, attribute);
+            }
+        }
+        let indices = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect::<Vec<u32>>(),
+            None => (0..positions.len() as u32).collect(),
+        };
+        if indices.len() % 3 != 0 {
+            bail!("{} indices don't make whole triangles", indices.len());
+        }
+        if let Some(index) = indices.iter().find(|&&index| index as usize >= positions.len()) {
+            bail!("Index {} is out of range, the mesh has {} vertices", index, positions.len());
+        }
+
+        let skin = if skinned {
+            Some(self.skin(&reader, positions.len())?)
+        } else {
+            None
+        };
+        let morph = self.morph(&reader, morph_weights, positions.len())?;
+        let vertices = positions.iter()
+            .enumerate()
+            .map(|(i, &position)| Vertex {
+                position,
+                tex_coords: tex_coords.as_ref().map_or([0.0, 0.0], |tex_coords| tex_coords[i]),
+                normal: normals.as_ref().map_or([0.0; 3], |normals| normals[i]),
+                tangent: tangents.as_ref().map_or([0.0; 4], |tangents| tangents[i]),
+            })
+            .collect();
+        let mut mesh = MeshData {
+            name: String::new(),
+            vertices,
+            indices,
+            material: primitive.material().index(),
+            skin,
+            morph,
+        };
+        // The spec asks for flat normals where the file has none
+        if normals.is_none() {
+            normals::generate_flat_normals(&mut mesh);
+        }
+        Ok(mesh)
+    }
+
+    // Weights the mesh doesn't give default to 0
+    fn morph<'s, F>(
+        &self,
+        reader: &gltf::mesh::Reader<'a, 's, F>,
+        weights: Option<&[f32]>,
+        num_vertices: usize,
+    ) -> Result<Option<MorphData>>
+    where F: Clone + Fn(gltf::Buffer<'a>) -> Option<&'s [u8]> {
+        let targets = reader.read_morph_targets()
+            .enumerate()
+            .map(|(i, (positions, normals, _))| {
+                let deltas = |deltas: Option<gltf::accessor::Iter<'s, [f32; 3]>>| match deltas {
+                    Some(deltas) => deltas.collect(),
+                    None => vec![[0.0; 3]; num_vertices],
+                };
+                let position_deltas: Vec<[f32; 3]> = deltas(positions);
+                let normal_deltas: Vec<[f32; 3]> = deltas(normals);
+                if position_deltas.len() != num_vertices || normal_deltas.len() != num_vertices {
+                    bail!("{} vertices but morph target {} has deltas for {}", num_vertices, i, position_deltas.len().min(normal_deltas.len()));
+                }
+                Ok(MorphTarget { position_deltas, normal_deltas })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if targets.is_empty() {
+            return Ok(None);
+        }
+        let mut weights = weights.map_or_else(Vec::new, <[f32]>::to_vec);
+        weights.resize(targets.len(), 0.0);
+        Ok(Some(MorphData { targets, weights }))
+    }
+
+    fn skin<'s, F>(&self, reader: &gltf::mesh::Reader<'a, 's, F>, num_vertices: usize) -> Result<Vec<SkinVertex>>
+    where F: Clone + Fn(gltf::Buffer<'a>) -> Option<&'s [u8]> {
+        let num_joints = self.num_joints.unwrap_or(0);
+        let joints = reader.read_joints(0)
+            .context("Skinned mesh has no JOINTS_0 attribute")?
+            .into_u16();
+        let weights = reader.read_weights(0)
+            .context("Skinned mesh has no WEIGHTS_0 attribute")?
+            .into_f32();
+        let skin = joints.zip(weights)
+            .map(|(mut joints, weights)| {
+                let sum = weights.iter().sum::<f32>();
+                let weights = if sum > 0.0 {
+                    [weights[0] / sum, weights[1] / sum, weights[2] / sum, weights[3] / sum]
+                } else {
+                    [1.0, 0.0, 0.0, 0.0]
+                };
+                for (joint, &weight) in joints.iter_mut().zip(&weights) {
+                    if weight == 0.0 {
+                        // Unused, but the shader still indexes with it
+                        *joint = 0;
+                    } else if *joint as usize >= num_joints {
+                        bail!("Joint {} is out of range, the skin has {}", joint, num_joints);
+                    }
+                }
+                Ok(SkinVertex { joints, weights })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if skin.len() != num_vertices {
+            bail!("{} vertices but {} joints and weights", num_vertices, skin.len());
+        }
+        Ok(skin)
+    }
+}
+
+fn import_skin(document: &gltf::Document, skin: &gltf::Skin, buffers: &[gltf::buffer::Data]) -> Result<SkinData> {
+    let mut parents = vec![None; document.nodes().len()];
+    for node in document.nodes() {
+        for child in node.children() {
+            parents[child.index()] = Some(node.index());
+        }
+    }
+    let locals = document.nodes()
+        .map(|node| Matrix4::from(node.transform().matrix()))
+        .collect::<Vec<_>>();
+    let joint_nodes = skin.joints().map(|node| node.index()).collect::<Vec<_>>();
+    let joint_of = |node: usize| joint_nodes.iter().position(|&joint| joint == node);
+
+    let reader = skin.reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]));
+    let inverse_bind_matrices = match reader.read_inverse_bind_matrices() {
+        Some(matrices) => matrices.map(Matrix4::from).collect::<Vec<_>>(),
+        None => vec![Matrix4::identity(); joint_nodes.len()],
+    };
+    if inverse_bind_matrices.len() < joint_nodes.len() {
+        bail!("{} joints but {} inverse bind matrices", joint_nodes.len(), inverse_bind_matrices.len());
+    }
+
+    let joints = skin.joints()
+        .zip(inverse_bind_matrices)
+        .map(|(node, inverse_bind_matrix)| {
+            // Nodes up to the parent joint, or to the root, don't animate
+            // and fold into one transform
+            let mut parent = None;
+            let mut parent_transform = Matrix4::identity();
+            let mut ancestor = parents[node.index()];
+            while let Some(index) = ancestor {
+                if let Some(joint) = joint_of(index) {
+                    parent = Some(joint);
+                    break;
+                }
+                parent_transform = locals[index] * parent_transform;
+                ancestor = parents[index];
+            }
+            let (translation, [x, y, z, w], scale) = node.transform().decomposed();
+            Joint {
+                name: node.name().map(String::from).unwrap_or_else(|| format!("node {}", node.index())),
+                parent,
+                parent_transform,
+                rest: Transform {
+                    translation: translation.into(),
+                    rotation: Quaternion::new(w, x, y, z),
+                    scale: scale.into(),
+                },
+                inverse_bind_matrix,
+            }
+        })
+        .collect();
+    let clips = document.animations()
+        .map(|animation| import_animation(&animation, buffers, joint_of))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(SkinData { skeleton: Skeleton::new(joints)?, clips })
+}
+
+// Only channels animating the skin's joints are kept. Keyframe times have
+// to be finite, or sorting and sampling them means nothing
+fn import_animation<J>(animation: &gltf::Animation, buffers: &[gltf::buffer::Data], joint_of: J) -> Result<AnimationClip>
+where J: Fn(usize) -> Option<usize> {
+    use gltf::animation::util::ReadOutputs;
+
+    let name = animation.name()
+        .map(String::from)
+        .unwrap_or_else(|| format!("animation {}", animation.index()));
+    let channels = animation.channels()
+        .filter_map(|channel| {
+            let joint = joint_of(channel.target().node().index())?;
+            let reader = channel.reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]));
+            let times = reader.read_inputs()?.collect::<Vec<f32>>();
+            if let Some(time) = times.iter().find(|time| !time.is_finite()) {
+                return Some(Err(anyhow!("Animation {:?} has a keyframe at time {}", name, time)));
+            }
+            let keyframes = match reader.read_outputs()? {
+                ReadOutputs::Translations(values) => Keyframes::Translation(values.map(Vector3::from).collect()),
+                ReadOutputs::Rotations(values) => Keyframes::Rotation(
+                    values.into_f32().map(|[x, y, z, w]| Quaternion::new(w, x, y, z)).collect()
+                ),
+                ReadOutputs::Scales(values) => Keyframes::Scale(values.map(Vector3::from).collect()),
+                ReadOutputs::MorphTargetWeights(_) => return None,
+            };
+            let interpolation = match channel.sampler().interpolation() {
+                gltf::animation::Interpolation::Step => Interpolation::Step,
+                gltf::animation::Interpolation::Linear => Interpolation::Linear,
+                gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+            };
+            Some(Ok(Channel { joint, times, keyframes, interpolation }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(AnimationClip::new(name, channels))
+}
+
+// Image files next to the model go through the texture cache by path, like
+// any other file; gltf::import decoded them as well, but that copy is
+// dropped. Images in buffers or data URIs are kept decoded.
+fn import_image(path: &Path, image: &gltf::Image, data: gltf::image::Data) -> Result<TextureSource> {
+    if let gltf::image::Source::Uri { uri, .. } = image.source() {
+        let file = if uri.starts_with("file:") {
+            Some(Path::new(uri.trim_start_matches("file:").trim_start_matches("//")).to_path_buf())
+        } else if !uri.contains(':') {
+            Some(path.parent().unwrap_or_else(|| Path::new("")).join(uri))
+        } else {
+            None
+        };
+        if let Some(file) = file {
+            return Ok(TextureSource::Path(file));
+        }
+    }
+    let decoded = to_image(data)
+        .with_context(|| format!("{}: image {}", path.display(), image.index()))?;
+    Ok(TextureSource::Embedded { file: path.to_path_buf(), index: image.index(), image: Rc::new(decoded) })
+}
+
+fn import_material(material: &gltf::Material, images: &[TextureSource]) -> MaterialData {
+    let texture = |texture: gltf::Texture| TextureData {
+        source: images[texture.source().index()].clone(),
+        sampler: import_sampler(&texture.sampler()),
+    };
+    let pbr = material.pbr_metallic_roughness();
+    MaterialData {
+        name: material.name()
+            .map(String::from)
+            .unwrap_or_else(|| format!("material {}", material.index().unwrap_or(0))),
+        base_color_factor: pbr.base_color_factor(),
+        diffuse_texture: pbr.base_color_texture().map(|info| texture(info.texture())),
+        metallic_factor: pbr.metallic_factor(),
+        roughness_factor: pbr.roughness_factor(),
+        metallic_roughness_texture: pbr.metallic_roughness_texture().map(|info| texture(info.texture())),
+        normal_texture: material.normal_texture().map(|info| texture(info.texture())),
+        emissive_factor: material.emissive_factor(),
+        emissive_texture: material.emissive_texture().map(|info| texture(info.texture())),
+    }
+}
+
+fn import_sampler(sampler: &gltf::texture::Sampler) -> SamplerDesc {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+    use wgpu::FilterMode::{Nearest, Linear};
+
+    let address_mode = |mode| match mode {
+        WrappingMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
+        WrappingMode::MirroredRepeat => wgpu::AddressMode::MirrorRepeat,
+        WrappingMode::Repeat => wgpu::AddressMode::Repeat,
+    };
+    let default = SamplerDesc::default();
+    let mag_filter = match sampler.mag_filter() {
+        Some(MagFilter::Nearest) => Nearest,
+        Some(MagFilter::Linear) => Linear,
+        None => default.mag_filter,
+    };
+    // Plain NEAREST and LINEAR don't mipmap, so they stay on the full size
+    // level
+    let (min_filter, mipmap_filter, lod_max_clamp) = match sampler.min_filter() {
+        Some(MinFilter::Nearest) => (Nearest, Nearest, 0.0),
+        Some(MinFilter::Linear) => (Linear, Nearest, 0.0),
+        Some(MinFilter::NearestMipmapNearest) => (Nearest, Nearest, default.lod_max_clamp),
+        Some(MinFilter::LinearMipmapNearest) => (Linear, Nearest, default.lod_max_clamp),
+        Some(MinFilter::NearestMipmapLinear) => (Nearest, Linear, default.lod_max_clamp),
+        Some(MinFilter::LinearMipmapLinear) => (Linear, Linear, default.lod_max_clamp),
+        None => (default.min_filter, default.mipmap_filter, default.lod_max_clamp),
+    };
+    SamplerDesc {
+        address_mode_u: address_mode(sampler.wrap_s()),
+        address_mode_v: address_mode(sampler.wrap_t()),
+        mag_filter,
+        min_filter,
+        mipmap_filter,
+        lod_max_clamp,
+        ..default
+    }
+}
+
+// glTF cameras look down their local -Z with +Y up
+fn camera_view(camera: &gltf::Camera, transform: Matrix4<f32>) -> Option<CameraView> {
+    match camera.projection() {
+        gltf::camera::Projection::Perspective(perspective) => {
+            let eye = Point3::from_vec(transform.w.truncate());
+            let forward = (transform * -Vector4::unit_z()).truncate().normalize();
+            let up = (transform * Vector4::unit_y()).truncate().normalize();
+            Some(CameraView {
+                eye,
+                target: eye + forward,
+                up,
+                fovy: Deg::from(Rad(perspective.yfov())).0,
+                znear: perspective.znear(),
+                zfar: perspective.zfar().unwrap_or(INFINITE_ZFAR),
+            })
+        }
+        gltf::camera::Projection::Orthographic(_) => {
+            log::warn!("Ignoring orthographic camera {:?}", camera.name().unwrap_or(""));
+            None
+        }
+    }
+}
+
+// 16 bit formats come as native endian bytes
+fn to_image(data: gltf::image::Data) -> Result<DynamicImage> {
+    use gltf::image::Format;
+
+    let (width, height, pixels) = (data.width, data.height, data.pixels);
+    let wide = |pixels: Vec<u8>| {
+        pixels.chunks_exact(2)
+            .map(|bytes| u16::from_ne_bytes([bytes[0], bytes[1]]))
+            .collect::<Vec<u16>>()
+    };
+    let image = match data.format {
+        Format::R8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+        Format::R8G8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA8),
+        Format::R8G8B8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+        Format::R8G8B8A8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8),
+        Format::B8G8R8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageBgr8),
+        Format::B8G8R8A8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageBgra8),
+        Format::R16 => ImageBuffer::from_raw(width, height, wide(pixels)).map(DynamicImage::ImageLuma16),
+        Format::R16G16 => ImageBuffer::from_raw(width, height, wide(pixels)).map(DynamicImage::ImageLumaA16),
+        Format::R16G16B16 => ImageBuffer::from_raw(width, height, wide(pixels)).map(DynamicImage::ImageRgb16),
+        Format::R16G16B16A16 => ImageBuffer::from_raw(width, height, wide(pixels)).map(DynamicImage::ImageRgba16),
+    };
+    image.with_context(|| format!("Pixel data doesn't fill a {}x{} image", width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_fixture(name: &str) -> ModelData {
+        load(&Path::new("res/tests/gltf").join(name)).unwrap()
+    }
+
+    fn check_nodes(model: &ModelData) {
+        // Both nodes draw the one quad, which stays in its own space
+        assert_eq!(model.meshes.len(), 1);
+        assert_eq!(model.meshes[0].name, "Quad/0");
+        assert_eq!(model.meshes[0].vertices[2].position, [0.5, 0.5, 0.0]);
+        assert_eq!(model.meshes[0].vertices[2].normal, [0.0, 0.0, 1.0]);
+        assert_eq!(model.meshes[0].vertices[2].tex_coords, [1.0, 0.0]);
+        assert_eq!(model.meshes[0].indices, [0, 1, 2, 0, 2, 3]);
+
+        let names = model.nodes.iter().map(|node| node.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["Parent", "Child"]);
+        assert!(model.nodes.iter().all(|node| node.meshes == [0]));
+        assert_eq!(model.nodes[0].transform, Matrix4::from_translation(Vector3::new(0.0, 1.0, 0.0)));
+        // The child's transform comes after its parent's
+        assert_eq!(
+            model.nodes[1].transform,
+            Matrix4::from_translation(Vector3::new(2.0, 1.0, 0.0)) * Matrix4::from_scale(2.0),
+        );
+    }
+
+    fn check_material(model: &ModelData) {
+        assert_eq!(model.materials.len(), 1);
+        let material = &model.materials[0];
+        assert_eq!(material.name, "Glow");
+        assert_eq!(material.base_color_factor, [1.0, 0.5, 0.25, 1.0]);
+        assert_eq!(material.metallic_factor, 0.25);
+        assert_eq!(material.roughness_factor, 0.75);
+        assert_eq!(material.emissive_factor, [0.1, 0.2, 0.3]);
+        assert!(material.metallic_roughness_texture.is_none());
+        assert!(material.normal_texture.is_none());
+
+        let diffuse = material.diffuse_texture.as_ref().unwrap();
+        assert_eq!(diffuse.sampler.mag_filter, wgpu::FilterMode::Nearest);
+        assert_eq!(diffuse.sampler.address_mode_u, wgpu::AddressMode::Repeat);
+        assert_eq!(diffuse.sampler.address_mode_v, wgpu::AddressMode::ClampToEdge);
+        let emissive = material.emissive_texture.as_ref().unwrap();
+        match (&diffuse.source, &emissive.source) {
+            (TextureSource::Path(diffuse), TextureSource::Path(emissive)) => {
+                assert_eq!(diffuse, Path::new("res/tests/gltf/nodes.png"));
+                assert_eq!(emissive, diffuse);
+            }
+            (
+                TextureSource::Embedded { file, index, image: diffuse },
+                TextureSource::Embedded { index: emissive_index, image: emissive, .. },
+            ) => {
+                assert_eq!(file, Path::new("res/tests/gltf/nodes.glb"));
+                assert_eq!((*index, *emissive_index), (0, 0));
+                // One decoded image for both
+                assert!(Rc::ptr_eq(diffuse, emissive));
+                assert_eq!(diffuse.to_rgba8().get_pixel(1, 0).0, [255, 0, 0, 255]);
+            }
+            _ => panic!("expected both textures to come from the same image"),
+        }
+    }
+
+    #[test]
+    fn gltf_with_external_files() {
+        let model = load_fixture("nodes.gltf");
+        check_nodes(&model);
+        check_material(&model);
+        let diffuse = &model.materials[0].diffuse_texture.as_ref().unwrap().source;
+        assert!(matches!(diffuse, TextureSource::Path(_)), "external images should be loaded by path");
+    }
+
+    #[test]
+    fn glb_with_embedded_buffer_and_image() {
+        let model = load_fixture("nodes.glb");
+        check_nodes(&model);
+        check_material(&model);
+        let diffuse = &model.materials[0].diffuse_texture.as_ref().unwrap().source;
+        assert!(matches!(diffuse, TextureSource::Embedded { .. }), "images in the buffer should stay decoded");
+    }
+
+    #[test]
+    fn min_filters_without_mipmaps_keep_to_the_first_level() {
+        use wgpu::FilterMode::{Nearest, Linear};
+
+        // NEAREST, LINEAR, LINEAR_MIPMAP_NEAREST, LINEAR_MIPMAP_LINEAR and none
+        let json = br#"{
+            "asset": { "version": "2.0" },
+            "samplers": [{ "minFilter": 9728 }, { "minFilter": 9729 }, { "minFilter": 9985 }, { "minFilter": 9987 }, {}]
+        }"#;
+        let gltf = gltf::Gltf::from_slice(json).unwrap();
+        let descs = gltf.samplers().map(|sampler| import_sampler(&sampler)).collect::<Vec<_>>();
+        let filters = descs.iter().map(|desc| (desc.min_filter, desc.mipmap_filter)).collect::<Vec<_>>();
+        let default = SamplerDesc::default();
+        assert_eq!(filters[..4], [(Nearest, Nearest), (Linear, Nearest), (Linear, Nearest), (Linear, Linear)]);
+        assert_eq!(filters[4], (default.min_filter, default.mipmap_filter));
+        let clamps = descs.iter().map(|desc| desc.lod_max_clamp).collect::<Vec<_>>();
+        assert_eq!(clamps, [0.0, 0.0, default.lod_max_clamp, default.lod_max_clamp, default.lod_max_clamp]);
+    }
+
+    #[test]
+    fn nan_keyframe_times_are_rejected() {
+        let error = format!("{:#}", load(Path::new("res/tests/gltf/nan_time.gltf")).err().expect("the file should be rejected"));
+        assert!(error.contains("Animation \"Bend\" has a keyframe at time NaN"), "{}", error);
+    }
+
+    #[test]
+    fn flatten_bakes_one_copy_per_node() {
+        let mut model = load_fixture("nodes.gltf");
+        model.flatten();
+        assert!(model.nodes.is_empty());
+        let names = model.meshes.iter().map(|mesh| mesh.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["Parent/Quad/0", "Child/Quad/0"]);
+        assert_eq!(model.meshes[0].vertices[2].position, [0.5, 1.5, 0.0]);
+        assert_eq!(model.meshes[1].vertices[2].position, [3.0, 2.0, 0.0]);
+        assert_eq!(model.meshes[1].vertices[2].normal, [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn missing_normals_are_flat() {
+        let model = load_fixture("no_normals.gltf");
+        let quad = &model.meshes[0];
+        assert_eq!(quad.vertices.len(), 4);
+        assert!(quad.vertices.iter().all(|vertex| vertex.normal == [0.0, 0.0, 1.0]));
+    }
+
+    #[test]
+    fn attribute_counts_must_match_positions() {
+        let error = load(Path::new("res/tests/gltf/bad_normals.gltf")).err().expect("the fixture should fail to load");
+        let error = format!("{:#}", error);
+        assert!(error.contains("Mesh Quad/0: 4 vertices in POSITION but 3 in NORMAL"), "{}", error);
+    }
+}
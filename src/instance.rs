@@ -8,14 +8,10 @@ use cgmath::{
     Deg,
 };
 use wgpu::util::DeviceExt;
+use vertex_layout_derive::VertexLayout;
 
-const NUM_INSTANCES_PER_ROW: u32 = 10;
-const NUM_INSTANCES: u32 = NUM_INSTANCES_PER_ROW ^ 2;
-const INSTANCE_DISPLACEMENT: Vector3<f32> = Vector3::new(
-    NUM_INSTANCES_PER_ROW as f32 * 0.5,
-    0.0,
-    NUM_INSTANCES_PER_ROW as f32 * 0.5,
-);
+use crate::bounds::{self, Bounds};
+use crate::morph::MAX_MORPH_TARGETS;
 
 pub struct State {
 
@@ -24,93 +20,168 @@ pub struct State {
 
 } impl State {
 
-    pub fn new(device: &wgpu::Device) -> Self {
-        let instances = (0..NUM_INSTANCES_PER_ROW).flat_map( |z| {
-            (0..NUM_INSTANCES_PER_ROW).map(move |x| {
-                let position = 
-                Vector3 { x: x as f32, y: 0.0, z: z as f32 } - INSTANCE_DISPLACEMENT;
-                let rotation = if position.is_zero() {
-                    Quaternion::from_axis_angle(Vector3::unit_z(), Deg(0.0))
-                } else {
-                    Quaternion::from_axis_angle(position.normalize(), Deg(45.0))
-                };
-
-                Instance { position, rotation }
-            })
-        }).collect::<Vec<Instance>>();
+    pub fn new(device: &wgpu::Device, instances: Vec<Instance>) -> Self {
+        let instance_buffer = Self::create_buffer(device, &instances);
+        Self { instances, instance_buffer }
+    }
 
+    fn create_buffer(device: &wgpu::Device, instances: &[Instance]) -> wgpu::Buffer {
         let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<InstanceRaw>>();
-        let instance_buffer = device.create_buffer_init(
+        device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("Instance Buffer"),
                 contents: bytemuck::cast_slice(&instance_data),
-                usage: wgpu::BufferUsage::VERTEX,
+                usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
             }
-        );
+        )
+    }
 
-        Self { instances, instance_buffer }
+    /// Replaces the instances, reusing the buffer when the count is unchanged.
+    pub fn set_instances(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, instances: Vec<Instance>) {
+        if instances.len() == self.instances.len() {
+            let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<InstanceRaw>>();
+            queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instance_data));
+        } else {
+            self.instance_buffer = Self::create_buffer(device, &instances);
+        }
+        self.instances = instances;
+    }
+
+    /// Uploads the instances in the given order, so that draws can address
+    /// groups of them (e.g. LOD buckets) as contiguous ranges.
+    pub fn write_ordered(&self, queue: &wgpu::Queue, order: &[usize]) {
+        let instance_data = order.iter()
+            .map(|&i| self.instances[i].to_raw())
+            .collect::<Vec<InstanceRaw>>();
+        queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instance_data));
+    }
+
+    pub fn instances(&self) -> &[Instance] {
+        &self.instances
+    }
+
+    // Changes show up on the next `write_ordered`
+    pub fn instances_mut(&mut self) -> &mut [Instance] {
+        &mut self.instances
+    }
+
+    /// World bounds of all instances drawing a model with `model_bounds`,
+    /// None when there are no instances.
+    pub fn bounds(&self, model_bounds: &Bounds) -> Option<Bounds> {
+        bounds::union_all(self.instances.iter().map(|instance| instance.world_bounds(model_bounds)))
     }
 
     pub fn buffer<'s>(&'s self) -> &'s wgpu::Buffer {
         &self.instance_buffer
     }
+}
 
-    pub fn num_instances(&self) -> usize {
-        self.instances.len()
-    }
+pub fn grid(per_row: u32) -> Vec<Instance> {
+    let displacement = Vector3::new(per_row as f32 * 0.5, 0.0, per_row as f32 * 0.5);
+    (0..per_row).flat_map( |z| {
+        (0..per_row).map(move |x| {
+            let position = 
+            Vector3 { x: x as f32, y: 0.0, z: z as f32 } - displacement;
+            let rotation = if position.is_zero() {
+                Quaternion::from_axis_angle(Vector3::unit_z(), Deg(0.0))
+            } else {
+                Quaternion::from_axis_angle(position.normalize(), Deg(45.0))
+            };
+
+            Instance { position, rotation, opacity: 1.0, morph_weights: [0.0; MAX_MORPH_TARGETS], label: None }
+        })
+    }).collect::<Vec<Instance>>()
 }
 
-struct Instance {
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instance {
 
     position: Vector3<f32>,
     rotation: Quaternion<f32>,
+    // Multiplies the texture's alpha
+    opacity: f32,
+    // Added to the default weights of every morphed mesh drawn
+    morph_weights: [f32; MAX_MORPH_TARGETS],
+    // Drawn above the instance, facing the camera
+    label: Option<String>,
 
 } impl Instance {
 
-    fn to_raw(&self) -> InstanceRaw {
+    pub fn new(position: Vector3<f32>, rotation: Quaternion<f32>, opacity: f32) -> Self {
+        Self { position, rotation, opacity, morph_weights: [0.0; MAX_MORPH_TARGETS], label: None }
+    }
+
+    /// Transparent instances go through the blended pipeline. Only the
+    /// instance opacity is considered, not the texture's alpha channel.
+    pub fn is_transparent(&self) -> bool {
+        self.opacity < 1.0
+    }
+
+    pub fn position(&self) -> Vector3<f32> {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: Vector3<f32>) {
+        self.position = position;
+    }
+
+    pub fn rotation(&self) -> Quaternion<f32> {
+        self.rotation
+    }
+
+    pub fn set_rotation(&mut self, rotation: Quaternion<f32>) {
+        self.rotation = rotation;
+    }
+
+    pub fn morph_weights(&self) -> &[f32; MAX_MORPH_TARGETS] {
+        &self.morph_weights
+    }
+
+    /// Weights past `MAX_MORPH_TARGETS` are dropped, missing ones are 0.
+    pub fn set_morph_weights(&mut self, weights: &[f32]) {
+        self.morph_weights = [0.0; MAX_MORPH_TARGETS];
+        for (weight, &value) in self.morph_weights.iter_mut().zip(weights) {
+            *weight = value;
+        }
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn set_label(&mut self, label: Option<String>) {
+        self.label = label;
+    }
+
+    pub fn model_matrix(&self) -> Matrix4<f32> {
         let translation_matrix = Matrix4::from_translation(self.position);
         let rotation_matrix = Matrix4::from(self.rotation);
-        let model_matrix = (translation_matrix * rotation_matrix).into();
+        translation_matrix * rotation_matrix
+    }
 
-        InstanceRaw { model_matrix }
+    /// Model space bounds moved to where this instance draws them.
+    pub fn world_bounds(&self, bounds: &Bounds) -> Bounds {
+        bounds.transform(&self.model_matrix())
+    }
+
+    fn to_raw(&self) -> InstanceRaw {
+        let model_matrix = self.model_matrix().into();
+
+        InstanceRaw { model_matrix, opacity: self.opacity, morph_weights: self.morph_weights }
     }
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, VertexLayout)]
+#[layout(step_mode = "Instance")]
 pub struct InstanceRaw {
 
+    #[layout(location = 5)]
     model_matrix: [[f32; 4]; 4],
+    #[layout(location = 9)]
+    opacity: f32,
+    // Only morph.vert reads the weights
+    #[layout(location = 12, format = "Float4", count = 2)]
+    morph_weights: [f32; MAX_MORPH_TARGETS],
 
-} impl InstanceRaw {
-
-    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
-        use std::mem;
-        wgpu::VertexBufferLayout {
-            array_stride: mem::size_of::<InstanceRaw>() as wgpu::BufferAddress,
-            step_mode: wgpu::InputStepMode::Instance,
-            attributes: &[
-                wgpu::VertexAttribute {
-                    offset: 0,
-                    shader_location: 5,
-                    format: wgpu::VertexFormat::Float4,
-                },
-                wgpu::VertexAttribute {
-                    offset: mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
-                    shader_location: 6,
-                    format: wgpu::VertexFormat::Float4,
-                },
-                wgpu::VertexAttribute {
-                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
-                    shader_location: 7,
-                    format: wgpu::VertexFormat::Float4,
-                },
-                wgpu::VertexAttribute {
-                    offset: mem::size_of::<[f32; 12]>() as wgpu::BufferAddress,
-                    shader_location: 8,
-                    format: wgpu::VertexFormat::Float4,
-                },
-            ],
-        }
-    }
-}
\ No newline at end of file
+}
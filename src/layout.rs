@@ -0,0 +1,105 @@
+use crate::model::Vertex;
+use crate::instance::InstanceRaw;
+use crate::skin::SkinVertex;
+
+/// Whether no shader location is used twice across `layouts`, the attribute
+/// lists of the vertex buffers one pipeline binds together. Const, so
+/// `assert_disjoint_locations!` can check it while compiling.
+pub const fn disjoint(layouts: &[&[wgpu::VertexAttribute]]) -> bool {
+    let mut i = 0;
+    while i < layouts.len() {
+        let mut a = 0;
+        while a < layouts[i].len() {
+            let location = layouts[i][a].shader_location;
+            // Everything after this attribute, in this layout and the rest
+            let mut j = i;
+            let mut b = a + 1;
+            while j < layouts.len() {
+                while b < layouts[j].len() {
+                    if layouts[j][b].shader_location == location {
+                        return false;
+                    }
+                    b += 1;
+                }
+                j += 1;
+                b = 0;
+            }
+            a += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Fails to compile when types deriving `VertexLayout` share a shader
+/// location.
+#[macro_export]
+macro_rules! assert_disjoint_locations {
+    ($($layout:ty),+ $(,)?) => {
+        const _: [(); 0] = [(); !$crate::layout::disjoint(&[
+            $(&<$layout>::ATTRIBUTES as &[wgpu::VertexAttribute]),+
+        ]) as usize];
+    };
+}
+
+// The rigid and morph pipelines bind the vertices and the instances
+assert_disjoint_locations!(Vertex, InstanceRaw);
+// The skinned pipeline adds the joints and weights
+assert_disjoint_locations!(Vertex, InstanceRaw, SkinVertex);
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+    use super::*;
+
+    fn attribute(format: wgpu::VertexFormat, offset: usize, shader_location: u32) -> wgpu::VertexAttribute {
+        wgpu::VertexAttribute { format, offset: offset as wgpu::BufferAddress, shader_location }
+    }
+
+    fn check(layout: wgpu::VertexBufferLayout, stride: usize, step_mode: wgpu::InputStepMode, expected: &[wgpu::VertexAttribute]) {
+        assert_eq!(layout.array_stride, stride as wgpu::BufferAddress);
+        assert_eq!(layout.step_mode, step_mode);
+        assert_eq!(layout.attributes, expected);
+    }
+
+    // The layouts as they were written out by hand before the derive
+    #[test]
+    fn derived_layouts_match_the_hand_written_ones() {
+        use wgpu::VertexFormat::*;
+
+        check(Vertex::desc(), size_of::<[f32; 12]>(), wgpu::InputStepMode::Vertex, &[
+            attribute(Float3, 0, 0),
+            attribute(Float2, size_of::<[f32; 3]>(), 1),
+            attribute(Float3, size_of::<[f32; 5]>(), 2),
+            attribute(Float4, size_of::<[f32; 8]>(), 3),
+        ]);
+        check(InstanceRaw::desc(), size_of::<[f32; 25]>(), wgpu::InputStepMode::Instance, &[
+            attribute(Float4, 0, 5),
+            attribute(Float4, size_of::<[f32; 4]>(), 6),
+            attribute(Float4, size_of::<[f32; 8]>(), 7),
+            attribute(Float4, size_of::<[f32; 12]>(), 8),
+            attribute(Float, size_of::<[f32; 16]>(), 9),
+            attribute(Float4, size_of::<[f32; 17]>(), 12),
+            attribute(Float4, size_of::<[f32; 21]>(), 13),
+        ]);
+        check(SkinVertex::desc(), size_of::<[u16; 4]>() + size_of::<[f32; 4]>(), wgpu::InputStepMode::Vertex, &[
+            attribute(Ushort4, 0, 10),
+            attribute(Float4, size_of::<[u16; 4]>(), 11),
+        ]);
+    }
+
+    #[test]
+    fn overlapping_locations_are_caught() {
+        assert!(disjoint(&[&Vertex::ATTRIBUTES, &InstanceRaw::ATTRIBUTES, &SkinVertex::ATTRIBUTES]));
+        assert!(disjoint(&[]));
+
+        // Across buffers, within one, and the last attribute of all
+        let clash = [attribute(wgpu::VertexFormat::Float, 0, 3)];
+        assert!(!disjoint(&[&Vertex::ATTRIBUTES, &clash]));
+        let twice = [attribute(wgpu::VertexFormat::Float, 0, 7), attribute(wgpu::VertexFormat::Float, 4, 7)];
+        assert!(!disjoint(&[&twice]));
+        let last = [attribute(wgpu::VertexFormat::Float, 0, 11)];
+        assert!(!disjoint(&[&Vertex::ATTRIBUTES, &SkinVertex::ATTRIBUTES, &last]));
+        assert!(disjoint(&[&Vertex::ATTRIBUTES, &SkinVertex::ATTRIBUTES, &clash[..0]]));
+    }
+}
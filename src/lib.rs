@@ -0,0 +1,29 @@
+pub mod texture;
+pub mod mipmap;
+pub mod sampler;
+pub mod assets;
+pub mod camera;
+pub mod model;
+pub mod obj;
+pub mod ply;
+pub mod gltf_import;
+pub mod primitives;
+pub mod terrain;
+pub mod volume;
+pub mod normals;
+pub mod optimize;
+pub mod simplify;
+pub mod bounds;
+pub mod mesh_cache;
+pub mod export;
+pub mod skin;
+pub mod morph;
+pub mod instance;
+pub mod layout;
+pub mod scene;
+pub mod lod;
+pub mod batch;
+pub mod physics;
+pub mod text;
+pub mod sprite;
+pub mod particles;
@@ -0,0 +1,228 @@
+use std::fmt;
+use cgmath::{Point3, MetricSpace, EuclideanSpace};
+use serde::Deserialize;
+
+use crate::instance::Instance;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LodDesc {
+
+    // Distance at which each LOD hands over to the next, nearest first
+    pub distances: Vec<f32>,
+    // How far past a switch distance an instance has to move before it
+    // changes level, so instances sitting on the boundary don't pop
+    #[serde(default)]
+    pub hysteresis: f32,
+    // How models without hand made levels get theirs; None only draws the
+    // full detail
+    #[serde(default = "LodDesc::default_simplify")]
+    pub simplify: Option<SimplifyDesc>,
+
+} impl LodDesc {
+
+    fn default_simplify() -> Option<SimplifyDesc> { Some(SimplifyDesc::default()) }
+
+    /// One more level than there are switch distances.
+    pub fn num_levels(&self) -> usize {
+        self.distances.len() + 1
+    }
+
+    /// Picks the level for an instance currently at `current`. Moving to a
+    /// farther level needs `distance` to clear the switch distance plus the
+    /// hysteresis, moving nearer needs it to fall below it minus the
+    /// hysteresis.
+    pub fn select(&self, current: usize, distance: f32, num_levels: usize) -> usize {
+        let max_level = num_levels.saturating_sub(1).min(self.distances.len());
+        let mut level = current.min(max_level);
+        while level < max_level && distance > self.distances[level] + self.hysteresis {
+            level += 1;
+        }
+        while level > 0 && distance < self.distances[level - 1] - self.hysteresis {
+            level -= 1;
+        }
+        level
+    }
+}
+
+impl Default for LodDesc {
+    fn default() -> Self {
+        Self { distances: vec![6.0, 12.0], hysteresis: 0.5, simplify: Self::default_simplify() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct SimplifyDesc {
+
+    // Fraction of the triangles each level keeps of the one before
+    #[serde(default = "SimplifyDesc::default_ratio")]
+    pub ratio: f32,
+    // Furthest a level may stray from the full model, relative to the
+    // model's bounding sphere radius
+    #[serde(default = "SimplifyDesc::default_max_error")]
+    pub max_error: f32,
+
+} impl SimplifyDesc {
+
+    fn default_ratio() -> f32 { 0.5 }
+    fn default_max_error() -> f32 { 0.05 }
+}
+
+impl Default for SimplifyDesc {
+    fn default() -> Self {
+        Self { ratio: Self::default_ratio(), max_error: Self::default_max_error() }
+    }
+}
+
+/// Per-frame LOD selection, one level per instance.
+pub struct LodState {
+
+    desc: LodDesc,
+    levels: Vec<usize>,
+    stats: LodStats,
+
+} impl LodState {
+
+    pub fn new(desc: LodDesc) -> Self {
+        Self { desc, levels: Vec::new(), stats: LodStats::default() }
+    }
+
+    pub fn set_desc(&mut self, desc: LodDesc) {
+        self.desc = desc;
+    }
+
+    /// Forgets the levels, for when the instances are replaced: the next
+    /// `update` picks each one afresh, even if the count is the same.
+    pub fn reset(&mut self) {
+        self.levels.clear();
+    }
+
+    pub fn update(&mut self, eye: Point3<f32>, instances: &[Instance], num_levels: usize) {
+        let num_levels = num_levels.max(1);
+        if self.levels.len() != instances.len() {
+            // The instance set was replaced, so there is no previous level
+            // to hold on to
+            let desc = &self.desc;
+            self.levels = instances.iter()
+                .map(|instance| desc.select(0, Self::distance(eye, instance), num_levels))
+                .collect();
+        } else {
+            for (level, instance) in self.levels.iter_mut().zip(instances) {
+                let distance = Self::distance(eye, instance);
+                *level = self.desc.select(*level, distance, num_levels);
+            }
+        }
+        self.stats.instances_per_level = vec![0; num_levels];
+        for &level in &self.levels {
+            self.stats.instances_per_level[level] += 1;
+        }
+    }
+
+    fn distance(eye: Point3<f32>, instance: &Instance) -> f32 {
+        eye.distance(Point3::from_vec(instance.position()))
+    }
+
+    /// Level of each instance, in the same order as the instances.
+    pub fn levels(&self) -> &[usize] { &self.levels }
+
+    /// As of the last `update`.
+    pub fn stats(&self) -> &LodStats { &self.stats }
+}
+
+/// What the last LOD selection did, for the stats display.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LodStats {
+    // Index is the level, 0 being the full detail
+    pub instances_per_level: Vec<u32>,
+}
+
+impl fmt::Display for LodStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Instances per LOD:")?;
+        for (level, count) in self.instances_per_level.iter().enumerate() {
+            write!(f, " {}: {}", level, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Vector3, Quaternion};
+    use super::*;
+
+    fn desc() -> LodDesc {
+        LodDesc { distances: vec![10.0, 20.0], hysteresis: 1.0, simplify: None }
+    }
+
+    fn instance_at(x: f32) -> Instance {
+        Instance::new(Vector3::new(x, 0.0, 0.0), Quaternion::new(1.0, 0.0, 0.0, 0.0), 1.0)
+    }
+
+    #[test]
+    fn select_buckets_by_distance() {
+        let desc = LodDesc { hysteresis: 0.0, ..desc() };
+        assert_eq!(desc.select(0, 5.0, 3), 0);
+        assert_eq!(desc.select(0, 15.0, 3), 1);
+        assert_eq!(desc.select(0, 25.0, 3), 2);
+        assert_eq!(desc.select(2, 5.0, 3), 0);
+        assert_eq!(desc.select(0, 1000.0, 3), 2);
+    }
+
+    #[test]
+    fn select_holds_level_inside_hysteresis() {
+        let desc = desc();
+        // Just past the switch distance, but not past the hysteresis
+        assert_eq!(desc.select(0, 10.5, 3), 0);
+        assert_eq!(desc.select(0, 11.5, 3), 1);
+        // Coming back, the farther level holds until below 10 - 1
+        assert_eq!(desc.select(1, 9.5, 3), 1);
+        assert_eq!(desc.select(1, 8.5, 3), 0);
+    }
+
+    #[test]
+    fn select_clamps_to_available_levels() {
+        let desc = desc();
+        assert_eq!(desc.select(0, 1000.0, 2), 1);
+        assert_eq!(desc.select(0, 1000.0, 1), 0);
+        assert_eq!(desc.select(5, 5.0, 3), 0);
+    }
+
+    #[test]
+    fn state_keeps_levels_between_updates() {
+        let mut state = LodState::new(desc());
+        let eye = Point3::new(0.0, 0.0, 0.0);
+        let mut instances = vec![instance_at(5.0), instance_at(15.0), instance_at(25.0)];
+        state.update(eye, &instances, 3);
+        assert_eq!(state.levels(), &[0, 1, 2]);
+        assert_eq!(state.stats().instances_per_level, vec![1, 1, 1]);
+
+        // Inside the hysteresis band on both sides, nothing changes
+        instances[0] = instance_at(10.5);
+        instances[1] = instance_at(9.5);
+        state.update(eye, &instances, 3);
+        assert_eq!(state.levels(), &[0, 1, 2]);
+
+        instances[0] = instance_at(12.0);
+        instances[1] = instance_at(8.0);
+        state.update(eye, &instances, 3);
+        assert_eq!(state.levels(), &[1, 0, 2]);
+    }
+
+    #[test]
+    fn state_reselects_when_instances_are_replaced() {
+        let mut state = LodState::new(desc());
+        let eye = Point3::new(0.0, 0.0, 0.0);
+        state.update(eye, &[instance_at(25.0)], 3);
+        state.update(eye, &[instance_at(10.5), instance_at(25.0)], 3);
+        // A fresh start picks from level 0, so 10.5 stays inside it
+        assert_eq!(state.levels(), &[0, 2]);
+        assert_eq!(state.stats().instances_per_level, vec![1, 0, 1]);
+        assert_eq!(state.stats().to_string(), "Instances per LOD: 0: 1 1: 0 2: 1");
+
+        // Same count, different instances: without the reset the second
+        // would hold on to level 2 from the instance it replaced
+        state.reset();
+        state.update(eye, &[instance_at(10.5), instance_at(19.5)], 3);
+        assert_eq!(state.levels(), &[0, 1]);
+    }
+}
@@ -4,11 +4,11 @@ use winit::{
     window::{WindowBuilder, Window},
 };
 use futures::executor::block_on;
+use anyhow::Result;
+use std::rc::Rc;
+use cgmath::EuclideanSpace;
 
-mod texture;
-mod camera;
-mod model;
-mod instance;
+use learn_wgpu::{texture, assets, camera, model, bounds, instance, scene, lod, batch, physics, skin, morph, text, sprite, particles, terrain};
 
 fn main() {
     env_logger::init();
@@ -78,11 +78,26 @@ struct State {
     swap_chain: wgpu::SwapChain,
     size: winit::dpi::PhysicalSize<u32>,
     clear_color: wgpu::Color,
-    render_pipeline: wgpu::RenderPipeline,
+    opaque_pipelines: Pipelines,
+    transparent_pipelines: Pipelines,
+    depth_texture: texture::Texture,
     model_state: model::ModelState,
     diffuse_state: texture::TextureState,
+    texture_cache: assets::TextureCache,
     camera_state: camera::CameraState,
     instance_state: instance::State,
+    scene_watcher: scene::SceneWatcher,
+    lod_state: lod::LodState,
+    batch_state: batch::BatchState,
+    physics_state: physics::PhysicsState,
+    skin_state: skin::SkinState,
+    material_layout: wgpu::BindGroupLayout,
+    morph_layout: wgpu::BindGroupLayout,
+    // None when the scene's font couldn't be loaded
+    text_state: Option<text::TextState>,
+    sprite_state: sprite::SpriteState,
+    sprites: Vec<sprite::Sprite>,
+    particle_state: particles::ParticleState,
 
 } impl State {
     // Creating some of the wgpu types requires async code
@@ -101,7 +116,8 @@ struct State {
         //SET DEVICE
         let (device, queue) = adapter.request_device(
             &wgpu::DeviceDescriptor {
-                features: wgpu::Features::empty(),
+                // Sampler borders where the adapter has them
+                features: adapter.features() & wgpu::Features::ADDRESS_MODE_CLAMP_TO_BORDER,
                 limits: wgpu::Limits::default(),
                 label: None,
             },
@@ -116,21 +132,82 @@ struct State {
             present_mode: wgpu::PresentMode::Fifo,
         };
         let swap_chain = device.create_swap_chain(&surface, &sc_desc);
-        let diffuse_state = texture::TextureState::new(&device, &queue);
-        let camera_state = camera::CameraState::new(&device, sc_desc.width, sc_desc.height);
-        let instance_state = instance::State::new(&device);
+        let scene_watcher = scene::SceneWatcher::new(scene::SCENE_PATH);
+        let scene = scene_watcher.scene();
+        let mut texture_cache = assets::TextureCache::new();
+        let diffuse_texture = Self::load_texture(&device, &queue, &mut texture_cache, scene)
+            .unwrap_or_else(|e| {
+                log::error!("{:?}; drawing with a placeholder", e);
+                Rc::new(texture::Texture::placeholder(&device, &queue))
+            });
+        let diffuse_state = texture::TextureState::new(&device, diffuse_texture);
+        let mut camera_state = camera::CameraState::new(&device, sc_desc.width, sc_desc.height);
         let clear_color = wgpu::Color {  //SET CLEAR COLOR
             r: 0.1,
             g: 0.2,
             b: 0.3,
             a: 1.0,
         };
-        let render_pipeline = Self::new_render_pipeline(
-            &device,
-            &[diffuse_state.bind_group_layout(), camera_state.bind_group_layout()],
-            &sc_desc,
+        let skin_state = skin::SkinState::new(&device);
+        let material_layout = model::Material::create_bind_group_layout(&device);
+        let morph_layout = morph::MorphBuffers::create_bind_group_layout(&device);
+        let bind_group_layouts = [
+            diffuse_state.bind_group_layout(), 
+            camera_state.bind_group_layout(), 
+            &material_layout,
+            skin_state.bind_group_layout(),
+            &morph_layout,
+        ];
+        let opaque_pipelines = Pipelines::new(&device, &bind_group_layouts, &sc_desc, false);
+        let transparent_pipelines = Pipelines::new(&device, &bind_group_layouts, &sc_desc, true);
+        let depth_texture = texture::Texture::create_depth_texture(&device, &sc_desc, "Depth Texture");
+        let new_model = |texture_cache: &mut assets::TextureCache, mesh: &scene::MeshDesc| model::ModelState::new(
+            &device, 
+            &queue, 
+            diffuse_state.bind_group_layout(), 
+            &material_layout, 
+            &morph_layout, 
+            texture_cache,
+            mesh, 
+            scene.crease_angle.map(cgmath::Deg),
+            &scene.lod,
         );
-        let model_state = model::ModelState::new(&device);
+        let model_state = new_model(&mut texture_cache, &scene.mesh).unwrap_or_else(|e| {
+            log::error!("{:?}; drawing the pentagon instead", e);
+            new_model(&mut texture_cache, &scene::MeshDesc::Pentagon).expect("The pentagon is built in")
+        });
+        if let Some(view) = model_state.camera() {
+            camera_state.set_view(view);
+        }
+        let instances = Self::build_instances(scene, model_state.terrain());
+        let bodies = scene.instances.bodies(&instances).unwrap_or_else(|e| {
+            log::error!("{:?}; leaving physics out", e);
+            Vec::new()
+        });
+        let physics_state = physics::PhysicsState::new(scene.physics.clone(), bodies);
+        let instance_state = instance::State::new(&device, instances);
+        let lod_state = lod::LodState::new(scene.lod.clone());
+        let batch_state = batch::BatchState::new();
+        let text_state = match text::TextState::new(&device, camera_state.bind_group_layout(), sc_desc.format, &scene.text) {
+            Ok(text_state) => Some(text_state),
+            Err(e) => {
+                log::error!("{:?}; drawing no text", e);
+                None
+            }
+        };
+        let mut sprite_state = sprite::SpriteState::new(&device, diffuse_state.bind_group_layout(), sc_desc.format);
+        let (sprite_textures, sprites) = sprite::load_sprites(&device, &queue, &mut texture_cache, &scene.sprites)
+            .unwrap_or_else(|e| {
+                log::error!("{:?}; leaving the sprites out", e);
+                (Vec::new(), Vec::new())
+            });
+        sprite_state.set_textures(&device, diffuse_state.bind_group_layout(), sprite_textures);
+        let mut particle_state = particles::ParticleState::new(&device, camera_state.bind_group_layout(), sc_desc.format);
+        let particle_systems = particle_state.create_systems(&device, &scene.particles).unwrap_or_else(|e| {
+            log::error!("{:?}; leaving particles out", e);
+            Vec::new()
+        });
+        particle_state.set_systems(particle_systems);
 
         Self {
             surface,
@@ -140,18 +217,164 @@ struct State {
             swap_chain,
             size,
             clear_color,
-            render_pipeline,
+            opaque_pipelines,
+            transparent_pipelines,
+            depth_texture,
             model_state,
             diffuse_state,
+            texture_cache,
             camera_state,
             instance_state,
+            scene_watcher,
+            lod_state,
+            batch_state,
+            physics_state,
+            skin_state,
+            material_layout,
+            morph_layout,
+            text_state,
+            sprite_state,
+            sprites,
+            particle_state,
+        }
+    }
+
+    fn load_texture(
+        device: &wgpu::Device, 
+        queue: &wgpu::Queue, 
+        texture_cache: &mut assets::TextureCache,
+        scene: &scene::SceneDesc,
+    ) -> Result<Rc<texture::Texture>> {
+        let path = scene.texture.as_deref().unwrap_or_else(|| scene::DEFAULT_TEXTURE_PATH.as_ref());
+        texture_cache.load_with(device, queue, path, true, &scene.sampler.desc())
+    }
+
+    fn reload_scene(&mut self) {
+        if let Some(scene) = self.scene_watcher.poll() {
+            match self.apply_scene(&scene) {
+                Ok(()) => {
+                    log::info!("Reloaded scene {}", self.scene_watcher.path().display());
+                    log::debug!("Scene bounds: {:?}", self.scene_bounds());
+                    self.scene_watcher.accept(scene);
+                }
+                Err(e) => log::error!("{:?}; keeping the previous scene", e),
+            }
         }
     }
 
+    // The scene's instances, raised onto the model's terrain if it is one
+    fn build_instances(scene: &scene::SceneDesc, terrain: Option<&terrain::Terrain>) -> Vec<instance::Instance> {
+        let mut instances = scene.instances.build();
+        if let Some(terrain) = terrain {
+            terrain.place(&mut instances);
+        }
+        instances
+    }
+
+    // Everything fallible runs before the running state is touched, so an
+    // error leaves the previous scene in place.
+    fn apply_scene(&mut self, scene: &scene::SceneDesc) -> Result<()> {
+        let diff = self.scene_watcher.scene().diff(scene);
+        let diffuse_texture = if diff.texture {
+            Some(Self::load_texture(&self.device, &self.queue, &mut self.texture_cache, scene)?)
+        } else {
+            None
+        };
+        let model_state = if diff.mesh {
+            Some(model::ModelState::new(
+                &self.device, 
+                &self.queue, 
+                self.diffuse_state.bind_group_layout(), 
+                &self.material_layout, 
+                &self.morph_layout, 
+                &mut self.texture_cache,
+                &scene.mesh,
+                scene.crease_angle.map(cgmath::Deg),
+                &scene.lod,
+            )?)
+        } else {
+            None
+        };
+        let text_state = if diff.text {
+            Some(text::TextState::new(
+                &self.device, 
+                self.camera_state.bind_group_layout(), 
+                self.sc_desc.format, 
+                &scene.text,
+            )?)
+        } else {
+            None
+        };
+        let sprites = if diff.sprites {
+            Some(sprite::load_sprites(&self.device, &self.queue, &mut self.texture_cache, &scene.sprites)?)
+        } else {
+            None
+        };
+        // Instances stand on the terrain, so they move with it
+        let terrain = model_state.as_ref().unwrap_or(&self.model_state).terrain();
+        let terrain_changed = diff.mesh && (terrain.is_some() || self.model_state.terrain().is_some());
+        let instances = if diff.instances || terrain_changed {
+            let instances = Self::build_instances(scene, terrain);
+            let bodies = scene.instances.bodies(&instances)?;
+            Some((instances, bodies))
+        } else {
+            None
+        };
+        let particle_systems = if diff.particles {
+            Some(self.particle_state.create_systems(&self.device, &scene.particles)?)
+        } else {
+            None
+        };
+
+        if let Some(texture) = diffuse_texture {
+            self.diffuse_state.set_texture(&self.device, texture);
+        }
+        if let Some(model_state) = model_state {
+            if let Some(view) = model_state.camera() {
+                self.camera_state.set_view(view);
+            }
+            self.model_state = model_state;
+        }
+        if let Some((instances, bodies)) = instances {
+            self.physics_state.set_bodies(bodies);
+            self.instance_state.set_instances(&self.device, &self.queue, instances);
+            self.lod_state.reset();
+        }
+        if diff.physics {
+            self.physics_state.set_desc(scene.physics.clone());
+        }
+        if diff.lod {
+            self.lod_state.set_desc(scene.lod.clone());
+        }
+        if text_state.is_some() {
+            self.text_state = text_state;
+        }
+        if let Some((textures, sprites)) = sprites {
+            self.sprite_state.set_textures(&self.device, self.diffuse_state.bind_group_layout(), textures);
+            self.sprites = sprites;
+        }
+        if let Some(particle_systems) = particle_systems {
+            self.particle_state.set_systems(particle_systems);
+        }
+        Ok(())
+    }
+
+    /// World bounds of everything drawn, None when nothing is.
+    fn scene_bounds(&self) -> Option<bounds::Bounds> {
+        self.instance_state.bounds(&self.model_state.bounds()?)
+    }
+
+    // The transparent variant blends over what is already drawn and tests
+    // against the depth buffer without writing to it. The skinned variant
+    // takes joints and weights from a third vertex buffer and the joint
+    // matrices from bind group 3, the morphed one the mesh's deltas from
+    // bind group 3.
     fn new_render_pipeline( 
         device: &wgpu::Device, 
         bind_group_layouts: &[&wgpu::BindGroupLayout],
         sc_desc: &wgpu::SwapChainDescriptor,
+        transparent: bool,
+        deformation: Deformation,
     ) -> wgpu::RenderPipeline {
 
         let layout = device.create_pipeline_layout(
@@ -161,24 +384,57 @@ struct State {
                 push_constant_ranges: &[],
             }
         );
-        let vs_module = device.create_shader_module(&wgpu::include_spirv!("shader.vert.spv"));
+        let vs_module = match deformation {
+            Deformation::Rigid => device.create_shader_module(&wgpu::include_spirv!("shader.vert.spv")),
+            Deformation::Skinned => device.create_shader_module(&wgpu::include_spirv!("skinned.vert.spv")),
+            Deformation::Morphed => device.create_shader_module(&wgpu::include_spirv!("morph.vert.spv")),
+        };
         let fs_module = device.create_shader_module(&wgpu::include_spirv!("shader.frag.spv"));
 
+        // Only the skinned pipeline reads the last one
+        let vertex_buffers = [
+            model::Vertex::desc(), 
+            instance::InstanceRaw::desc(), 
+            skin::SkinVertex::desc(),
+        ];
+
+        let (label, color_blend, alpha_blend) = if transparent {
+            (
+                format!("{}Transparent Render Pipeline", deformation.label_prefix()),
+                wgpu::BlendState {
+                    src_factor: wgpu::BlendFactor::SrcAlpha,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                wgpu::BlendState {
+                    src_factor: wgpu::BlendFactor::One,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+            )
+        } else {
+            (
+                format!("{}Render Pipeline", deformation.label_prefix()),
+                wgpu::BlendState::REPLACE, 
+                wgpu::BlendState::REPLACE,
+            )
+        };
+
         return device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
+            label: Some(&label),
             layout: Some(&layout),
             vertex: wgpu::VertexState {
                 module: &vs_module,
                 entry_point: "main",
-                buffers: &[ model::Vertex::desc(), instance::InstanceRaw::desc() ],
+                buffers: if deformation == Deformation::Skinned { &vertex_buffers } else { &vertex_buffers[..2] },
             },
             fragment: Some(wgpu::FragmentState {
                 module: &fs_module,
                 entry_point: "main",
                 targets: &[wgpu::ColorTargetState {
                     format: sc_desc.format,
-                    alpha_blend: wgpu::BlendState::REPLACE,
-                    color_blend: wgpu::BlendState::REPLACE,
+                    alpha_blend,
+                    color_blend,
                     write_mask: wgpu::ColorWrite::ALL,
                 }]
             }),
@@ -189,7 +445,14 @@ struct State {
                 cull_mode: wgpu::CullMode::None,
                 polygon_mode: wgpu::PolygonMode::Fill,
             },
-            depth_stencil: None,
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: texture::Texture::DEPTH_FORMAT,
+                depth_write_enabled: !transparent,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+                clamp_depth: false,
+            }),
             multisample: wgpu::MultisampleState {
                 count: 1,
                 mask: !0,
@@ -203,6 +466,7 @@ struct State {
         self.sc_desc.width = new_size.width;
         self.sc_desc.height = new_size.height;
         self.swap_chain = self.device.create_swap_chain(&self.surface, &self.sc_desc);
+        self.depth_texture = texture::Texture::create_depth_texture(&self.device, &self.sc_desc, "Depth Texture");
         self.camera_state.on_resize(&self.queue, new_size.width, new_size.height);
     }
 
@@ -210,7 +474,64 @@ struct State {
         self.camera_state.on_input(event)
     }
 
-    fn update(&mut self) { self.camera_state.on_update(&self.queue) }
+    fn update(&mut self) { 
+        self.reload_scene();
+        self.camera_state.on_update(&self.queue);
+        self.physics_state.update(self.instance_state.instances_mut());
+        self.skin_state.update(&self.queue, self.model_state.skin());
+        self.update_instances();
+        let (right, up) = self.camera_state.billboard_axes();
+        self.particle_state.update(&self.queue, right, up);
+        self.update_text();
+        for sprite in &self.sprites {
+            self.sprite_state.queue(*sprite);
+        }
+        self.sprite_state.prepare(&self.device, &self.queue, self.sc_desc.width, self.sc_desc.height);
+    }
+
+    fn update_instances(&mut self) {
+        let previous_stats = self.lod_state.stats().clone();
+        self.lod_state.update(
+            self.camera_state.eye(), 
+            self.instance_state.instances(), 
+            self.model_state.num_lods(),
+        );
+        let camera_state = &self.camera_state;
+        self.batch_state.update(
+            self.instance_state.instances(),
+            self.lod_state.levels(),
+            |instance| camera_state.view_depth(cgmath::Point3::from_vec(instance.position())),
+        );
+        self.instance_state.write_ordered(&self.queue, self.batch_state.order());
+
+        if *self.lod_state.stats() != previous_stats {
+            log::debug!("{}", self.lod_state.stats());
+        }
+    }
+
+    // Labels hang over the top of each labelled instance's bounds
+    fn update_text(&mut self) {
+        let text_state = match &mut self.text_state {
+            Some(text_state) => text_state,
+            None => return,
+        };
+        let style = text::TextStyle::default();
+        if self.scene_watcher.scene().text.hud {
+            let stats = self.lod_state.stats().to_string();
+            text_state.queue_screen(&stats, [8.0, 8.0], &style);
+        }
+        if let Some(model_bounds) = self.model_state.bounds() {
+            for instance in self.instance_state.instances() {
+                if let Some(label) = instance.label() {
+                    let aabb = instance.world_bounds(&model_bounds).aabb;
+                    let center = aabb.center();
+                    let anchor = cgmath::Point3::new(center.x, aabb.max.y, center.z);
+                    text_state.queue_label(label, anchor, &style);
+                }
+            }
+        }
+        text_state.prepare(&self.device, &self.queue, self.sc_desc.width, self.sc_desc.height);
+    }
 
     fn render(&mut self) -> Result<(), wgpu::SwapChainError> {
 
@@ -218,6 +539,7 @@ struct State {
         let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("Render Encoder"),
         });
+        self.particle_state.compute(&mut encoder);
         {   //Scoped so render_pass drops
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
@@ -231,25 +553,140 @@ struct State {
                         }
                     }
                 ],
-                depth_stencil_attachment: None,
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                    attachment: self.depth_texture.view(),
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
             });
-            render_pass.set_pipeline(&self.render_pipeline); 
-            render_pass.set_bind_group(0, self.diffuse_state.bind_group(), &[]); 
             render_pass.set_bind_group(1, self.camera_state.bind_group(), &[]);
-            render_pass.set_vertex_buffer(0, self.model_state.vertex_buffer().slice(..));
             render_pass.set_vertex_buffer(1, self.instance_state.buffer().slice(..));
-            render_pass.set_index_buffer(
-                self.model_state.index_buffer().slice(..), 
-                wgpu::IndexFormat::Uint16
+
+            let model_state = &self.model_state;
+            let diffuse = self.diffuse_state.bind_group();
+            let joints = self.skin_state.bind_group();
+            let frustum = self.camera_state.frustum();
+            let instances = self.instance_state.instances();
+            let order = self.batch_state.order();
+            let visible = |mesh: &model::Mesh, node: &model::NodeData, batch: &batch::Batch| match mesh.bounds() {
+                Some(bounds) => {
+                    let bounds = bounds.transform(&node.transform);
+                    batch.instances.clone().any(|i| {
+                        frustum.intersects(&instances[order[i as usize]].world_bounds(&bounds))
+                    })
+                }
+                None => false,
+            };
+            Self::draw_batches(
+                &mut render_pass, 
+                &self.opaque_pipelines, 
+                model_state, 
+                (diffuse, joints), 
+                self.batch_state.opaque(),
+                &visible,
             );
-            render_pass.draw_indexed(
-                0..self.model_state.num_indices(), 
-                0, 
-                0..self.instance_state.num_instances() as u32
+            Self::draw_batches(
+                &mut render_pass, 
+                &self.transparent_pipelines, 
+                model_state, 
+                (diffuse, joints), 
+                self.batch_state.transparent(),
+                &visible,
             );
+            self.particle_state.draw(&mut render_pass, self.camera_state.bind_group());
+            self.sprite_state.draw(&mut render_pass);
+            if let Some(text_state) = &self.text_state {
+                text_state.draw(&mut render_pass, self.camera_state.bind_group());
+            }
         }
         // submit will accept anything that implements IntoIter
         self.queue.submit(std::iter::once(encoder.finish()));
         Ok(())
     }
+
+    // One draw per mesh of each node at the batch's LOD, each over the
+    // batch's range of the instance buffer, skipping meshes no instance of
+    // the batch shows on screen. Meshes without a textured material use the
+    // scene texture; group 2 holds the material with the node's transform
+    // at its offset, group 3 the joints for skinned meshes and the mesh's
+    // own deltas for morphed ones.
+    fn draw_batches<'a>(
+        render_pass: &mut wgpu::RenderPass<'a>, 
+        pipelines: &'a Pipelines,
+        model_state: &'a model::ModelState, 
+        (diffuse, joints): (&'a wgpu::BindGroup, &'a wgpu::BindGroup),
+        batches: &[batch::Batch],
+        visible: &dyn Fn(&model::Mesh, &model::NodeData, &batch::Batch) -> bool,
+    ) {
+        for batch in batches {
+            let meshes = &model_state.lods()[batch.lod];
+            for (i, node) in model_state.nodes().iter().enumerate() {
+                for mesh in node.meshes.iter().map(|&mesh| &meshes[mesh]).filter(|mesh| visible(mesh, node, batch)) {
+                    let material = model_state.material(mesh);
+                    match (mesh.skin_buffer(), mesh.morph_bind_group()) {
+                        (Some(skin_buffer), _) => {
+                            render_pass.set_pipeline(&pipelines.skinned);
+                            render_pass.set_bind_group(3, joints, &[]);
+                            render_pass.set_vertex_buffer(2, skin_buffer.slice(..));
+                        }
+                        (None, Some(morph)) => {
+                            render_pass.set_pipeline(&pipelines.morphed);
+                            render_pass.set_bind_group(3, morph, &[]);
+                        }
+                        (None, None) => render_pass.set_pipeline(&pipelines.rigid),
+                    }
+                    render_pass.set_bind_group(0, material.diffuse_bind_group().unwrap_or(diffuse), &[]);
+                    render_pass.set_bind_group(2, material.bind_group(), &[model::node_offset(i)]);
+                    render_pass.set_vertex_buffer(0, mesh.vertex_buffer().slice(..));
+                    render_pass.set_index_buffer(mesh.index_buffer().slice(..), mesh.index_format());
+                    render_pass.draw_indexed(0..mesh.num_indices(), 0, batch.instances.clone());
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Deformation {
+    Rigid,
+    Skinned,
+    Morphed,
+} impl Deformation {
+
+    fn label_prefix(self) -> &'static str {
+        match self {
+            Deformation::Rigid => "",
+            Deformation::Skinned => "Skinned ",
+            Deformation::Morphed => "Morphed ",
+        }
+    }
+}
+
+/// One pipeline per vertex shader, all blending the same way.
+struct Pipelines {
+
+    rigid: wgpu::RenderPipeline,
+    skinned: wgpu::RenderPipeline,
+    morphed: wgpu::RenderPipeline,
+
+} impl Pipelines {
+
+    // `bind_group_layouts` holds the texture, camera, material, joint and
+    // morph layouts, each pipeline takes the ones its shader reads
+    fn new(
+        device: &wgpu::Device, 
+        bind_group_layouts: &[&wgpu::BindGroupLayout; 5],
+        sc_desc: &wgpu::SwapChainDescriptor,
+        transparent: bool,
+    ) -> Self {
+        let [texture, camera, material, joints, morph] = *bind_group_layouts;
+        Self {
+            rigid: State::new_render_pipeline(device, &[texture, camera, material], sc_desc, transparent, Deformation::Rigid),
+            skinned: State::new_render_pipeline(device, &[texture, camera, material, joints], sc_desc, transparent, Deformation::Skinned),
+            morphed: State::new_render_pipeline(device, &[texture, camera, material, morph], sc_desc, transparent, Deformation::Morphed),
+        }
+    }
 }
@@ -0,0 +1,430 @@
+use std::path::Path;
+use std::mem::size_of;
+use cgmath::Point3;
+use anyhow::*;
+
+use crate::model::{self, Mesh, ModelData, Vertex};
+use crate::bounds::{Aabb, Bounds, Sphere};
+
+// Layout, every number a little endian u32 or f32 and every section padded
+// to 4 bytes:
+//   MAGIC, VERSION, byte order of the data blobs, mesh count
+//   per mesh:
+//     name length, name
+//     vertex stride, attribute count, (location, format code, offset) per
+//     attribute
+//     vertex count, index size (2 or 4), index count
+//     has bounds, aabb min, aabb max, sphere center, sphere radius
+//     vertex data, index data
+// Vertex and index data are stored exactly as the buffers hold them, in the
+// writer's native byte order, so loading hands them to create_buffer_init
+// without converting anything. A file written on a machine of the other
+// byte order is rejected.
+const MAGIC: &[u8; 8] = b"LWGPUMSH";
+// Bump whenever the layout above or Vertex changes
+pub const VERSION: u32 = 2;
+const LITTLE_ENDIAN: u32 = 0;
+const BIG_ENDIAN: u32 = 1;
+pub const EXTENSION: &str = "mesh";
+
+/// Checks that `write` can store the model and that `load` will take the
+/// file back: skinned or morphed models are refused since their skeleton
+/// and morph targets wouldn't be stored, and every mesh has to be whole
+/// triangles of existing vertices.
+pub fn validate(data: &ModelData) -> Result<()> {
+    if data.skin.is_some() {
+        bail!("Skinned models can't be cached, load the source model instead");
+    }
+    if let Some(mesh) = data.meshes.iter().find(|mesh| mesh.morph.is_some()) {
+        bail!("Mesh {:?} has morph targets and can't be cached, load the source model instead", mesh.name);
+    }
+    for mesh in &data.meshes {
+        mesh.validate()?;
+    }
+    Ok(())
+}
+
+/// Writes the meshes of a prepared model, once `validate` passes.
+/// Materials and cameras aren't stored.
+pub fn write(path: &Path, data: &ModelData) -> Result<()> {
+    validate(data)?;
+    let mut writer = Writer { bytes: Vec::new() };
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.u32(VERSION);
+    writer.u32(native_byte_order());
+    writer.u32(data.meshes.len() as u32);
+    for mesh in &data.meshes {
+        writer.u32(mesh.name.len() as u32);
+        writer.blob(mesh.name.as_bytes());
+
+        let layout = Vertex::desc();
+        writer.u32(layout.array_stride as u32);
+        writer.u32(layout.attributes.len() as u32);
+        for attribute in layout.attributes {
+            writer.u32(attribute.shader_location);
+            writer.u32(format_code(attribute.format));
+            writer.u32(attribute.offset as u32);
+        }
+
+        let index_format = model::index_format(mesh.vertices.len());
+        writer.u32(mesh.vertices.len() as u32);
+        writer.u32(index_size(index_format));
+        writer.u32(mesh.indices.len() as u32);
+
+        let bounds = model::vertex_bounds(&mesh.vertices);
+        writer.u32(bounds.is_some() as u32);
+        let bounds = bounds.unwrap_or(Bounds {
+            aabb: Aabb { min: Point3::new(0.0, 0.0, 0.0), max: Point3::new(0.0, 0.0, 0.0) },
+            sphere: Sphere { center: Point3::new(0.0, 0.0, 0.0), radius: 0.0 },
+        });
+        for &point in &[bounds.aabb.min, bounds.aabb.max, bounds.sphere.center] {
+            writer.f32(point.x);
+            writer.f32(point.y);
+            writer.f32(point.z);
+        }
+        writer.f32(bounds.sphere.radius);
+
+        writer.blob(bytemuck::cast_slice(&mesh.vertices));
+        writer.blob(&model::index_bytes(&mesh.indices, index_format));
+    }
+    std::fs::write(path, &writer.bytes)
+        .with_context(|| format!("Unable to write {}", path.display()))
+}
+
+/// Reads a file written by `write` straight into GPU buffers. Files from
+/// another version, of the other byte order, with a vertex layout that
+/// doesn't match `Vertex` or with indices past the vertices are rejected.
+pub fn load(device: &wgpu::Device, path: &Path) -> Result<Vec<Mesh>> {
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("Unable to read {}", path.display()))?;
+    let meshes = parse(&bytes)
+        .with_context(|| format!("Unable to load mesh cache {}", path.display()))?;
+    Ok(meshes.into_iter()
+        .map(|mesh| {
+            Mesh::from_raw(device, mesh.vertex_data, mesh.index_data, mesh.index_format, mesh.num_indices, mesh.bounds)
+        })
+        .collect())
+}
+
+/// One mesh of a cache file, borrowing its data from the file's bytes.
+pub struct RawMesh<'a> {
+    pub name: String,
+    pub vertex_data: &'a [u8],
+    pub index_data: &'a [u8],
+    pub index_format: wgpu::IndexFormat,
+    pub num_indices: u32,
+    pub bounds: Option<Bounds>,
+}
+
+pub fn parse(bytes: &[u8]) -> Result<Vec<RawMesh>> {
+    let mut reader = Reader { bytes, position: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        bail!("Not a mesh cache");
+    }
+    let version = reader.u32()?;
+    if version != VERSION {
+        bail!(
+            "Written as version {}, this build reads version {}; convert the source model again",
+            version, VERSION,
+        );
+    }
+
+    let byte_order = reader.u32()?;
+    if byte_order != native_byte_order() {
+        bail!("Written on a machine of the other byte order; convert the source model again");
+    }
+
+    let num_meshes = reader.u32()?;
+    let mut meshes = Vec::new();
+    for _ in 0..num_meshes {
+        let name_length = reader.u32()? as usize;
+        let name = String::from_utf8_lossy(reader.blob(name_length)?).into_owned();
+        check_layout(&mut reader).with_context(|| format!("Mesh {:?}", name))?;
+
+        let num_vertices = reader.u32()? as usize;
+        let index_format = match reader.u32()? {
+            2 => wgpu::IndexFormat::Uint16,
+            4 => wgpu::IndexFormat::Uint32,
+            size => bail!("Mesh {:?} has {} byte indices", name, size),
+        };
+        let num_indices = reader.u32()?;
+
+        let has_bounds = reader.u32()? != 0;
+        let mut point = || -> Result<Point3<f32>> {
+            Ok(Point3::new(reader.f32()?, reader.f32()?, reader.f32()?))
+        };
+        let (min, max, center) = (point()?, point()?, point()?);
+        let radius = reader.f32()?;
+        let bounds = if has_bounds {
+            Some(Bounds { aabb: Aabb { min, max }, sphere: Sphere { center, radius } })
+        } else {
+            None
+        };
+
+        let vertex_data = reader.blob(num_vertices * size_of::<Vertex>())?;
+        let index_data = reader.blob(num_indices as usize * index_size(index_format) as usize)?;
+        check_indices(index_data, index_format, num_vertices).with_context(|| format!("Mesh {:?}", name))?;
+        meshes.push(RawMesh { name, vertex_data, index_data, index_format, num_indices, bounds });
+    }
+    Ok(meshes)
+}
+
+fn check_layout(reader: &mut Reader) -> Result<()> {
+    let layout = Vertex::desc();
+    let stride = reader.u32()?;
+    let num_attributes = reader.u32()? as usize;
+    let mut attributes = Vec::with_capacity(num_attributes);
+    for _ in 0..num_attributes {
+        attributes.push((reader.u32()?, reader.u32()?, reader.u32()?));
+    }
+    let expected = layout.attributes.iter()
+        .map(|attribute| (attribute.shader_location, format_code(attribute.format), attribute.offset as u32))
+        .collect::<Vec<_>>();
+    if stride as u64 != layout.array_stride || attributes != expected {
+        bail!("Vertex layout doesn't match this build; convert the source model again");
+    }
+    Ok(())
+}
+
+fn check_indices(index_data: &[u8], format: wgpu::IndexFormat, num_vertices: usize) -> Result<()> {
+    let size = index_size(format) as usize;
+    if index_data.len() % (size * 3) != 0 {
+        bail!("{} indices don't make whole triangles", index_data.len() / size);
+    }
+    let out_of_range = index_data.chunks_exact(size)
+        .map(|bytes| match format {
+            wgpu::IndexFormat::Uint16 => u16::from_ne_bytes([bytes[0], bytes[1]]) as usize,
+            wgpu::IndexFormat::Uint32 => u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize,
+        })
+        .find(|&index| index >= num_vertices);
+    if let Some(index) = out_of_range {
+        bail!("Index {} is out of range for {} vertices", index, num_vertices);
+    }
+    Ok(())
+}
+
+fn native_byte_order() -> u32 {
+    if cfg!(target_endian = "little") { LITTLE_ENDIAN } else { BIG_ENDIAN }
+}
+
+// The file's own codes, so a wgpu upgrade renumbering VertexFormat can't
+// silently change what an old file means
+fn format_code(format: wgpu::VertexFormat) -> u32 {
+    use wgpu::VertexFormat::*;
+    match format {
+        Uchar2 => 0,
+        Uchar4 => 1,
+        Char2 => 2,
+        Char4 => 3,
+        Uchar2Norm => 4,
+        Uchar4Norm => 5,
+        Char2Norm => 6,
+        Char4Norm => 7,
+        Ushort2 => 8,
+        Ushort4 => 9,
+        Short2 => 10,
+        Short4 => 11,
+        Ushort2Norm => 12,
+        Ushort4Norm => 13,
+        Short2Norm => 14,
+        Short4Norm => 15,
+        Half2 => 16,
+        Half4 => 17,
+        Float => 18,
+        Float2 => 19,
+        Float3 => 20,
+        Float4 => 21,
+        Uint => 22,
+        Uint2 => 23,
+        Uint3 => 24,
+        Uint4 => 25,
+        Int => 26,
+        Int2 => 27,
+        Int3 => 28,
+        Int4 => 29,
+        Double => 30,
+        Double2 => 31,
+        Double3 => 32,
+        Double4 => 33,
+    }
+}
+
+fn index_size(format: wgpu::IndexFormat) -> u32 {
+    match format {
+        wgpu::IndexFormat::Uint16 => 2,
+        wgpu::IndexFormat::Uint32 => 4,
+    }
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn blob(&mut self, blob: &[u8]) {
+        self.bytes.extend_from_slice(blob);
+        self.bytes.resize(padded(self.bytes.len()), 0);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        let end = self.position.checked_add(length)
+            .filter(|&end| end <= self.bytes.len())
+            .context("File is truncated")?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        self.u32().map(f32::from_bits)
+    }
+
+    // Skips the padding after the blob as well
+    fn blob(&mut self, length: usize) -> Result<&'a [u8]> {
+        let blob = self.take(length)?;
+        self.take(padded(length) - length)?;
+        Ok(blob)
+    }
+}
+
+fn padded(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::MeshData;
+    use crate::primitives;
+
+    fn written(name: &str, data: &ModelData) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("learn_wgpu_{}_{}.mesh", name, std::process::id()));
+        write(&path, data).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        bytes
+    }
+
+    fn model(meshes: Vec<MeshData>) -> ModelData {
+        ModelData { meshes, ..Default::default() }
+    }
+
+    fn error(bytes: &[u8]) -> String {
+        format!("{:#}", parse(bytes).err().expect("the cache should be rejected"))
+    }
+
+    #[test]
+    fn meshes_round_trip() {
+        let cube = primitives::cube(2.0, 1);
+        let sphere = primitives::uv_sphere(1.0, 300, 300);
+        assert_eq!(model::index_format(sphere.vertices.len()), wgpu::IndexFormat::Uint32);
+        let bytes = written("round_trip", &model(vec![cube.clone(), sphere.clone()]));
+
+        let meshes = parse(&bytes).unwrap();
+        assert_eq!(meshes.len(), 2);
+        for (raw, mesh) in meshes.iter().zip(&[cube, sphere]) {
+            let format = model::index_format(mesh.vertices.len());
+            assert_eq!(raw.name, mesh.name);
+            assert_eq!(raw.vertex_data, bytemuck::cast_slice::<Vertex, u8>(&mesh.vertices));
+            assert_eq!(raw.index_data, &model::index_bytes(&mesh.indices, format)[..]);
+            assert_eq!(raw.index_format, format);
+            assert_eq!(raw.num_indices as usize, mesh.indices.len());
+            assert_eq!(raw.bounds, model::vertex_bounds(&mesh.vertices));
+        }
+    }
+
+    #[test]
+    fn shipped_cache_is_current() {
+        let bytes = std::fs::read("res/models/cube.mesh").unwrap();
+        let meshes = parse(&bytes).unwrap();
+        assert_eq!(meshes.iter().map(|mesh| mesh.num_indices).sum::<u32>(), 36);
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut bytes = written("version", &model(vec![primitives::plane(1.0, 1)]));
+        // The version follows the magic
+        bytes[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(error(&bytes).contains(&format!("Written as version {}, this build reads version {}", VERSION + 1, VERSION)));
+
+        bytes[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        bytes[12..16].copy_from_slice(&(1 - native_byte_order()).to_le_bytes());
+        assert!(error(&bytes).contains("other byte order"));
+
+        assert!(error(b"NOTAMESH").contains("Not a mesh cache"));
+    }
+
+    #[test]
+    fn damaged_files_are_rejected() {
+        let plane = primitives::plane(1.0, 1);
+        let bytes = written("damaged", &model(vec![plane.clone()]));
+        assert!(error(&bytes[..bytes.len() - 4]).contains("File is truncated"));
+
+        // The first attribute's format code, after the magic, version, byte
+        // order, mesh count, name, stride, attribute count and location
+        let name = padded(plane.name.len());
+        let format = 8 + 4 * 3 + 4 + name + 4 * 3;
+        let mut changed = bytes.clone();
+        changed[format..format + 4].copy_from_slice(&format_code(wgpu::VertexFormat::Half4).to_le_bytes());
+        assert!(error(&changed).contains("Vertex layout doesn't match"));
+
+        // The last index, a u16 ending the file, points past the vertices.
+        // `write` refuses such a mesh, so the file is changed after
+        assert_eq!(plane.indices.len() * 2 % 4, 0);
+        let mut out_of_range = bytes.clone();
+        let end = out_of_range.len();
+        out_of_range[end - 2..].copy_from_slice(&(plane.vertices.len() as u16).to_ne_bytes());
+        let message = error(&out_of_range);
+        assert!(message.contains(&format!("Index {} is out of range for {} vertices", plane.vertices.len(), plane.vertices.len())), "{}", message);
+    }
+
+    #[test]
+    fn unloadable_models_are_not_written() {
+        let path = std::env::temp_dir().join(format!("learn_wgpu_unloadable_{}.mesh", std::process::id()));
+        let refused = |data: &ModelData| {
+            let message = format!("{:#}", write(&path, data).expect_err("the model should be refused"));
+            assert!(!path.exists());
+            message
+        };
+
+        let mut partial = primitives::plane(1.0, 1);
+        partial.indices.pop();
+        assert!(refused(&model(vec![partial])).contains("not a whole number of triangles"));
+
+        let mut out_of_range = primitives::plane(1.0, 1);
+        out_of_range.indices[0] = out_of_range.vertices.len() as u32;
+        assert!(refused(&model(vec![out_of_range])).contains("uses vertex 4 but only has 4"));
+
+        let mut morphed = primitives::plane(1.0, 1);
+        morphed.morph = Some(crate::morph::MorphData { targets: Vec::new(), weights: Vec::new() });
+        assert!(refused(&model(vec![morphed])).contains("has morph targets"));
+
+        assert!(validate(&model(vec![primitives::plane(1.0, 1)])).is_ok());
+    }
+
+    #[test]
+    fn vertex_uses_the_files_format_codes() {
+        let codes = Vertex::desc().attributes.iter().map(|a| format_code(a.format)).collect::<Vec<_>>();
+        assert_eq!(codes, vec![20, 19, 20, 21]);
+    }
+}
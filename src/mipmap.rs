@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+
+/// How `Texture::from_image_with_mipmaps` fills the levels below the first.
+pub enum Mipmaps<'a> {
+    // Only the full size image
+    None,
+    // Render passes blitting each level into the next
+    Gpu(&'a mut MipmapGenerator),
+    // `generate_mipmaps_cpu`, uploaded level by level
+    Cpu,
+}
+
+/// Levels down to 1x1 for a `width` by `height` texture.
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+/// Size of `level` for a `width` by `height` texture.
+pub fn mip_size(width: u32, height: u32, level: u32) -> (u32, u32) {
+    ((width >> level).max(1), (height >> level).max(1))
+}
+
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Every level below `img`, each bilinearly sampled from the one above at
+/// its texel centres, as the GPU blit does; for even sizes that is the
+/// average of each 2x2 block. With `srgb` the colour channels are averaged
+/// as linear light, not as their encoded values; alpha always is linear.
+pub fn generate_mipmaps_cpu(img: &image::RgbaImage, srgb: bool) -> Vec<image::RgbaImage> {
+    let (width, height) = img.dimensions();
+    let mut levels: Vec<image::RgbaImage> = Vec::new();
+    for level in 1..mip_level_count(width, height) {
+        let source = levels.last().unwrap_or(img);
+        let (level_width, level_height) = mip_size(width, height, level);
+        levels.push(downsample(source, level_width, level_height, srgb));
+    }
+    levels
+}
+
+fn downsample(source: &image::RgbaImage, width: u32, height: u32, srgb: bool) -> image::RgbaImage {
+    let (source_width, source_height) = source.dimensions();
+    let decode = |value: u8, channel: usize| {
+        let value = value as f32 / 255.0;
+        if srgb && channel < 3 { srgb_to_linear(value) } else { value }
+    };
+    let encode = |value: f32, channel: usize| {
+        let value = if srgb && channel < 3 { linear_to_srgb(value) } else { value };
+        (value.clamp(0.0, 1.0) * 255.0).round() as u8
+    };
+    let texel = |x: i64, y: i64, channel: usize| {
+        let x = x.max(0).min(source_width as i64 - 1) as u32;
+        let y = y.max(0).min(source_height as i64 - 1) as u32;
+        decode(source.get_pixel(x, y)[channel], channel)
+    };
+    image::RgbaImage::from_fn(width, height, |x, y| {
+        // Where the centre of the new texel falls in the old texels,
+        // clamped to the edge like the blit's sampler
+        let u = (x as f32 + 0.5) / width as f32 * source_width as f32 - 0.5;
+        let v = (y as f32 + 0.5) / height as f32 * source_height as f32 - 0.5;
+        let (x0, y0) = (u.floor(), v.floor());
+        let (fx, fy) = (u - x0, v - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let mut pixel = [0; 4];
+        for (channel, value) in pixel.iter_mut().enumerate() {
+            let top = texel(x0, y0, channel) * (1.0 - fx) + texel(x0 + 1, y0, channel) * fx;
+            let bottom = texel(x0, y0 + 1, channel) * (1.0 - fx) + texel(x0 + 1, y0 + 1, channel) * fx;
+            *value = encode(top * (1.0 - fy) + bottom * fy, channel);
+        }
+        image::Rgba(pixel)
+    })
+}
+
+/// Uploads `levels` from `generate_mipmaps_cpu` into levels 1 and on of
+/// `texture`.
+pub fn write_mipmaps(queue: &wgpu::Queue, texture: &wgpu::Texture, levels: &[image::RgbaImage]) {
+    for (level, img) in levels.iter().enumerate() {
+        let (width, height) = img.dimensions();
+        queue.write_texture(
+            wgpu::TextureCopyView {
+                texture,
+                mip_level: level as u32 + 1,
+                origin: wgpu::Origin3d::ZERO,
+            },
+
+            img.as_raw(),
+
+            wgpu::TextureDataLayout {
+                offset: 0,
+                bytes_per_row: 4 * width,
+                rows_per_image: height,
+            },
+            wgpu::Extent3d { width, height, depth: 1 },
+        );
+    }
+}
+
+/// What the blit render passes need, made on first use and then shared by
+/// every texture, with a pipeline per format rendered to.
+pub struct MipmapGenerator {
+
+    shared: Option<BlitResources>,
+    pipelines: HashMap<wgpu::TextureFormat, wgpu::RenderPipeline>,
+
+} impl MipmapGenerator {
+
+    pub fn new() -> Self {
+        Self { shared: None, pipelines: HashMap::new() }
+    }
+
+    /// Fills levels 1 to `mip_level_count` - 1 of `texture` from level 0,
+    /// one render pass per level sampling the level above. The texture needs
+    /// `RENDER_ATTACHMENT` and `SAMPLED` usage; for sRGB formats the sampler
+    /// decodes and the target encodes, so the filtering happens in linear
+    /// light.
+    pub fn generate(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+        format: wgpu::TextureFormat,
+        mip_level_count: u32,
+    ) {
+        if mip_level_count < 2 {
+            return;
+        }
+        let shared = self.shared.get_or_insert_with(|| BlitResources::new(device));
+        let pipeline = self.pipelines.entry(format)
+            .or_insert_with(|| create_blit_pipeline(device, shared, format));
+        let views = (0..mip_level_count)
+            .map(|level| texture.create_view(&wgpu::TextureViewDescriptor {
+                label: Some("Mipmap Level"),
+                base_mip_level: level,
+                level_count: NonZeroU32::new(1),
+                ..Default::default()
+            }))
+            .collect::<Vec<_>>();
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Mipmap Encoder"),
+        });
+        for level in 1..mip_level_count as usize {
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &shared.bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&views[level - 1]),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&shared.sampler),
+                    },
+                ],
+                label: Some("Mipmap Bind Group"),
+            });
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Mipmap Pass"),
+                color_attachments: &[
+                    wgpu::RenderPassColorAttachmentDescriptor {
+                        attachment: &views[level],
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                            store: true,
+                        }
+                    }
+                ],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, &bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    /// Formats a pipeline has been made for.
+    pub fn num_pipelines(&self) -> usize {
+        self.pipelines.len()
+    }
+}
+
+impl Default for MipmapGenerator {
+    fn default() -> Self { Self::new() }
+}
+
+// The same for every format
+struct BlitResources {
+
+    bind_group_layout: wgpu::BindGroupLayout,
+    pipeline_layout: wgpu::PipelineLayout,
+    vs_module: wgpu::ShaderModule,
+    fs_module: wgpu::ShaderModule,
+    sampler: wgpu::Sampler,
+
+} impl BlitResources {
+
+    fn new(device: &wgpu::Device) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: false,
+                        filtering: true,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("Mipmap Bind Group Layout"),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Mipmap Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let vs_module = device.create_shader_module(&wgpu::include_spirv!("blit.vert.spv"));
+        let fs_module = device.create_shader_module(&wgpu::include_spirv!("blit.frag.spv"));
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Mipmap Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        Self { bind_group_layout, pipeline_layout, vs_module, fs_module, sampler }
+    }
+}
+
+// One triangle covering the target, no blending or depth
+fn create_blit_pipeline(
+    device: &wgpu::Device,
+    shared: &BlitResources,
+    format: wgpu::TextureFormat,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Mipmap Pipeline"),
+        layout: Some(&shared.pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: &shared.vs_module,
+            entry_point: "main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shared.fs_module,
+            entry_point: "main",
+            targets: &[format.into()],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: wgpu::CullMode::None,
+            polygon_mode: wgpu::PolygonMode::Fill,
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_counts_and_sizes() {
+        assert_eq!(mip_level_count(1, 1), 1);
+        assert_eq!(mip_level_count(4, 4), 3);
+        assert_eq!(mip_level_count(5, 3), 3);
+        assert_eq!(mip_level_count(256, 1), 9);
+        assert_eq!(mip_size(8, 2, 1), (4, 1));
+        assert_eq!(mip_size(8, 2, 3), (1, 1));
+
+        let levels = generate_mipmaps_cpu(&image::RgbaImage::new(8, 2), false);
+        let sizes = levels.iter().map(image::RgbaImage::dimensions).collect::<Vec<_>>();
+        assert_eq!(sizes, [(4, 1), (2, 1), (1, 1)]);
+        assert!(generate_mipmaps_cpu(&image::RgbaImage::new(1, 1), false).is_empty());
+    }
+
+    #[test]
+    fn even_levels_average_each_block() {
+        let img = image::RgbaImage::from_fn(4, 4, |x, y| {
+            let value = (x * 40 + y * 13) as u8;
+            image::Rgba([value, 255 - value, value / 2, 200 - value])
+        });
+        let levels = generate_mipmaps_cpu(&img, false);
+        for (level, source) in levels.iter().zip(std::iter::once(&img).chain(&levels)) {
+            for (x, y, pixel) in level.enumerate_pixels() {
+                for channel in 0..4 {
+                    let sum = (0..4)
+                        .map(|corner| source.get_pixel(2 * x + corner % 2, 2 * y + corner / 2)[channel] as f32)
+                        .sum::<f32>();
+                    let expected = (sum / 4.0).round() as i32;
+                    assert!((pixel[channel] as i32 - expected).abs() <= 1, "{:?} at {}, {}", pixel, x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn srgb_levels_average_light() {
+        // Black and white checks average to half the light, which sRGB
+        // encodes as 188, not 128; alpha is averaged as it is
+        let img = image::RgbaImage::from_fn(2, 2, |x, y| {
+            if (x + y) % 2 == 0 { image::Rgba([255, 255, 255, 255]) } else { image::Rgba([0, 0, 0, 0]) }
+        });
+        let linear = generate_mipmaps_cpu(&img, false)[0].get_pixel(0, 0).0;
+        let srgb = generate_mipmaps_cpu(&img, true)[0].get_pixel(0, 0).0;
+        assert_eq!(linear, [128, 128, 128, 128]);
+        assert_eq!(srgb, [188, 188, 188, 128]);
+
+        for &value in &[0.0, 0.02, 0.5, 1.0] {
+            assert!((linear_to_srgb(srgb_to_linear(value)) - value).abs() < 1e-5);
+        }
+    }
+}
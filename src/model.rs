@@ -1,75 +1,844 @@
-
 use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use wgpu::util::DeviceExt;
+use vertex_layout_derive::VertexLayout;
+use cgmath::{Deg, Point3, Vector3, Matrix3, Matrix4};
+use cgmath::{SquareMatrix, Matrix, InnerSpace};
+use anyhow::*;
+
+use crate::scene::MeshDesc;
+use crate::assets::TextureCache;
+use crate::lod::LodDesc;
+use crate::texture::{Texture, TextureState, SamplerDesc};
+use crate::mipmap::Mipmaps;
+use crate::camera::CameraView;
+use crate::bounds::{self, Bounds};
+use crate::skin::{SkinData, SkinVertex};
+use crate::morph::{MorphData, MorphBuffers, MAX_MORPH_TARGETS};
+use crate::terrain::Terrain;
+use crate::{obj, ply, gltf_import, normals, optimize, simplify, mesh_cache};
 
 pub struct ModelState {
 
+    // Level 0 is the full detail model, every level can hold several meshes
+    lods: Vec<Vec<Mesh>>,
+    // Every mesh is drawn once for each node listing it, at every level
+    nodes: Vec<NodeData>,
+    // Node transforms, `NODE_STRIDE` apart
+    node_buffer: wgpu::Buffer,
+    materials: Vec<Material>,
+    // For meshes without a material
+    default_material: Material,
+    // Viewpoint stored in the model file, if any
+    camera: Option<CameraView>,
+    skin: Option<SkinData>,
+    // For standing instances on the ground
+    terrain: Option<Terrain>,
+
+} impl ModelState {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &wgpu::Device, 
+        queue: &wgpu::Queue, 
+        texture_layout: &wgpu::BindGroupLayout, 
+        material_layout: &wgpu::BindGroupLayout, 
+        morph_layout: &wgpu::BindGroupLayout, 
+        textures: &mut TextureCache,
+        mesh: &MeshDesc,
+        crease_angle: Option<Deg<f32>>,
+        lod: &LodDesc,
+    ) -> Result<Self> {
+        let layouts = (texture_layout, material_layout, morph_layout);
+        let material_layouts = (texture_layout, material_layout);
+        match mesh {
+            MeshDesc::Pentagon => {
+                let lods = PENTAGON_LODS.iter()
+                    .map(|indices| vec![Mesh::new(device, VERTICES, indices, None)])
+                    .collect();
+                Self::assemble(device, queue, material_layouts, textures, lods, ModelData::default())
+            }
+            // Hand made levels, or already prepared by mesh-convert
+            MeshDesc::Cache(path) => {
+                let meshes = mesh_cache::load(device, path)?;
+                Self::assemble(device, queue, material_layouts, textures, vec![meshes], ModelData::default())
+            }
+            // Chunks simplified one by one would open cracks along their
+            // borders
+            MeshDesc::Terrain(_) => {
+                let lod = LodDesc { simplify: None, ..lod.clone() };
+                Self::from_data(device, queue, layouts, textures, ModelData::from_desc(mesh)?, crease_angle, &lod)
+            }
+            _ => Self::from_data(device, queue, layouts, textures, ModelData::from_desc(mesh)?, crease_angle, lod),
+        }
+    }
+
+    // `layouts` holds the texture, material and morph layouts
+    fn from_data(
+        device: &wgpu::Device, 
+        queue: &wgpu::Queue, 
+        layouts: (&wgpu::BindGroupLayout, &wgpu::BindGroupLayout, &wgpu::BindGroupLayout),
+        textures: &mut TextureCache,
+        mut data: ModelData,
+        crease_angle: Option<Deg<f32>>,
+        lod: &LodDesc,
+    ) -> Result<Self> {
+        // The generators and optimiser expect sound meshes
+        for mesh in &data.meshes {
+            mesh.validate()?;
+        }
+        data.prepare(crease_angle);
+        let chains = data.meshes.iter()
+            .map(|mesh| match &lod.simplify {
+                Some(desc) => simplify::lod_chain(mesh, lod.num_levels(), desc.ratio, desc.max_error),
+                None => vec![mesh.clone()],
+            })
+            .collect::<Vec<_>>();
+        // Every level draws every mesh, so a chain that stopped early keeps
+        // drawing its last level in the levels past its end
+        let num_levels = chains.iter().map(Vec::len).max().unwrap_or(1);
+        let lods = (0..num_levels)
+            .map(|level| {
+                let meshes = chains.iter().map(|chain| &chain[level.min(chain.len() - 1)]).collect::<Vec<_>>();
+                log::debug!(
+                    "LOD {}: {:?} triangles", 
+                    level, meshes.iter().map(|mesh| mesh.indices.len() / 3).collect::<Vec<_>>(),
+                );
+                meshes.into_iter()
+                    .map(|mesh| Mesh::from_data(device, layouts.2, mesh, data.skin.as_ref()))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::assemble(device, queue, (layouts.0, layouts.1), textures, lods, data)
+    }
+
+    // Everything but the meshes comes from `data`. A model without nodes
+    // draws each mesh once, where it is.
+    fn assemble(
+        device: &wgpu::Device, 
+        queue: &wgpu::Queue, 
+        (texture_layout, material_layout): (&wgpu::BindGroupLayout, &wgpu::BindGroupLayout),
+        textures: &mut TextureCache,
+        lods: Vec<Vec<Mesh>>,
+        data: ModelData,
+    ) -> Result<Self> {
+        let num_meshes = lods.first().map_or(0, Vec::len);
+        let nodes = if data.nodes.is_empty() {
+            vec![NodeData::root(num_meshes)]
+        } else {
+            data.nodes
+        };
+        if let Some(node) = nodes.iter().find(|node| node.meshes.iter().any(|&mesh| mesh >= num_meshes)) {
+            bail!("Node {:?} draws a mesh the model doesn't have, it has {}", node.name, num_meshes);
+        }
+        let mut node_data = vec![0u8; nodes.len().max(1) * NODE_STRIDE as usize];
+        for (node, data) in nodes.iter().zip(node_data.chunks_exact_mut(NODE_STRIDE as usize)) {
+            let transform: [[f32; 4]; 4] = node.transform.into();
+            data[..size_of::<[[f32; 4]; 4]>()].copy_from_slice(bytemuck::cast_slice(&transform));
+        }
+        let node_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Node Buffer"),
+                contents: &node_data,
+                usage: wgpu::BufferUsage::UNIFORM,
+            }
+        );
+
+        let white = Rc::new(Texture::from_image_with_mipmaps(
+            device, 
+            queue, 
+            &image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4]))),
+            Some("White"),
+            false,
+            textures.samplers().get(device, &SamplerDesc::default()),
+            Mipmaps::None,
+        )?);
+        let mut new_material = |material: &MaterialData| {
+            Material::new(device, queue, (texture_layout, material_layout), textures, &white, &node_buffer, material)
+        };
+        let materials = data.materials.iter()
+            .map(&mut new_material)
+            .collect::<Result<Vec<_>>>()?;
+        let default_material = new_material(&MaterialData::default())?;
+        Ok(Self { 
+            lods, 
+            nodes, 
+            node_buffer, 
+            materials, 
+            default_material, 
+            camera: data.camera, 
+            skin: data.skin,
+            terrain: data.terrain,
+        })
+    }
+
+    pub fn lods(&self) -> &[Vec<Mesh>] { &self.lods }
+    pub fn num_lods(&self) -> usize { self.lods.len() }
+    pub fn nodes(&self) -> &[NodeData] { &self.nodes }
+    pub fn node_buffer(&self) -> &wgpu::Buffer { &self.node_buffer }
+    pub fn material(&self, mesh: &Mesh) -> &Material {
+        mesh.material.map_or(&self.default_material, |i| &self.materials[i])
+    }
+    pub fn camera(&self) -> Option<&CameraView> { self.camera.as_ref() }
+    pub fn skin(&self) -> Option<&SkinData> { self.skin.as_ref() }
+    pub fn terrain(&self) -> Option<&Terrain> { self.terrain.as_ref() }
+
+    /// Bounds of the full detail level in model space, every node
+    /// included, None if it has no vertices.
+    pub fn bounds(&self) -> Option<Bounds> {
+        bounds::union_all(self.nodes.iter().flat_map(|node| {
+            node.meshes.iter()
+                .filter_map(move |&mesh| self.lods[0][mesh].bounds())
+                .map(move |bounds| bounds.transform(&node.transform))
+        }))
+    }
+}
+
+// Dynamic offsets into a uniform buffer have to be aligned this much
+const NODE_STRIDE: wgpu::BufferAddress = wgpu::BIND_BUFFER_ALIGNMENT;
+
+/// Where node `index`'s transform sits in the node buffer, the dynamic
+/// offset to bind a material with when drawing that node.
+pub fn node_offset(index: usize) -> wgpu::DynamicOffset {
+    (index as wgpu::BufferAddress * NODE_STRIDE) as wgpu::DynamicOffset
+}
+
+pub struct Mesh {
+
     vertex_buffer: wgpu::Buffer,
     index_buffer: wgpu::Buffer,
+    index_format: wgpu::IndexFormat,
     num_indices: u32,
+    // Index into the model's materials
+    material: Option<usize>,
+    // Model space, None for a mesh without vertices
+    bounds: Option<Bounds>,
+    // SkinVertex per vertex, for meshes drawn with the skinned pipeline
+    skin_buffer: Option<wgpu::Buffer>,
+    // Deltas and default weights, for meshes drawn with the morph pipeline
+    morph: Option<MorphBuffers>,
 
-} impl ModelState {
-    pub fn new(device: &wgpu::Device) -> Self {
+} impl Mesh {
+    pub fn new(
+        device: &wgpu::Device, 
+        vertices: &[Vertex], 
+        indices: &[u32], 
+        material: Option<usize>,
+    ) -> Self {
+        let index_format = index_format(vertices.len());
+        let mut mesh = Self::from_raw(
+            device,
+            bytemuck::cast_slice(vertices),
+            &index_bytes(indices, index_format),
+            index_format,
+            indices.len() as u32,
+            vertex_bounds(vertices),
+        );
+        mesh.material = material;
+        mesh
+    }
+
+    /// Uploads vertex and index data that is already laid out the way the
+    /// GPU reads it, as stored in a mesh cache.
+    pub fn from_raw(
+        device: &wgpu::Device, 
+        vertex_data: &[u8], 
+        index_data: &[u8], 
+        index_format: wgpu::IndexFormat,
+        num_indices: u32,
+        bounds: Option<Bounds>,
+    ) -> Self {
         let vertex_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("Vertex Buffer"),
-                contents: bytemuck::cast_slice(VERTICES),
+                contents: vertex_data,
                 usage: wgpu::BufferUsage::VERTEX,
             }
         );
         let index_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("Index Buffer"),
-                contents: bytemuck::cast_slice(INDICES),
+                contents: index_data,
                 usage: wgpu::BufferUsage::INDEX,
             }
         );
-        let num_indices = INDICES.len() as u32;
-        Self { vertex_buffer, index_buffer, num_indices }
+        Self { vertex_buffer, index_buffer, index_format, num_indices, material: None, bounds, skin_buffer: None, morph: None }
+    }
+
+    /// Skinned meshes are bounded over the clips of `model_skin`, the
+    /// model's skin.
+    pub fn from_data(
+        device: &wgpu::Device, 
+        morph_layout: &wgpu::BindGroupLayout, 
+        data: &MeshData, 
+        model_skin: Option<&SkinData>,
+    ) -> Result<Self> {
+        data.validate()?;
+        let mut mesh = Self::new(device, &data.vertices, &data.indices, data.material);
+        if let Some(skin) = &data.skin {
+            if let Some(model_skin) = model_skin {
+                mesh.bounds = model_skin.bounds(&data.vertices, skin);
+            }
+            mesh.skin_buffer = Some(device.create_buffer_init(
+                &wgpu::util::BufferInitDescriptor {
+                    label: Some("Skin Buffer"),
+                    contents: bytemuck::cast_slice(skin),
+                    usage: wgpu::BufferUsage::VERTEX,
+                }
+            ));
+        }
+        if let Some(morph) = &data.morph {
+            let num_vertices = data.vertices.len();
+            if data.skin.is_some() {
+                log::warn!("Mesh {:?} is skinned, its morph targets are ignored", data.name);
+            } else {
+                if morph.targets.len() > MAX_MORPH_TARGETS {
+                    log::warn!(
+                        "Mesh {:?} has {} morph targets, only the first {} are drawn", 
+                        data.name, morph.targets.len(), MAX_MORPH_TARGETS,
+                    );
+                }
+                mesh.bounds = morph.bounds(&data.vertices);
+                mesh.morph = Some(MorphBuffers::new(device, morph_layout, morph, num_vertices));
+            }
+        }
+        Ok(mesh)
     }
+
     pub fn vertex_buffer(&self) -> &wgpu::Buffer { &self.vertex_buffer }
     pub fn index_buffer(&self) -> &wgpu::Buffer { &self.index_buffer }
+    pub fn index_format(&self) -> wgpu::IndexFormat { self.index_format }
     pub fn num_indices(&self) -> u32 { self.num_indices }
+    pub fn bounds(&self) -> Option<Bounds> { self.bounds }
+    pub fn skin_buffer(&self) -> Option<&wgpu::Buffer> { self.skin_buffer.as_ref() }
+    pub fn morph_bind_group(&self) -> Option<&wgpu::BindGroup> {
+        self.morph.as_ref().map(MorphBuffers::bind_group)
+    }
 }
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-pub struct Vertex {
+/// 16 bit indices when every vertex fits, 32 bit past that. 0xFFFF is left
+/// out of the 16 bit range since some backends read it as a strip restart.
+pub fn index_format(num_vertices: usize) -> wgpu::IndexFormat {
+    if num_vertices <= u16::MAX as usize {
+        wgpu::IndexFormat::Uint16
+    } else {
+        wgpu::IndexFormat::Uint32
+    }
+}
 
-    position: [f32; 3],
-    tex_coords: [f32; 2]
-    
-} impl Vertex {
-    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
-        return wgpu::VertexBufferLayout {
-            array_stride: size_of::<Self>() as wgpu::BufferAddress,
-            step_mode: wgpu::InputStepMode::Vertex,
-            attributes: &[
-                wgpu::VertexAttribute {
-                    offset: 0,
-                    shader_location: 0,
-                    format: wgpu::VertexFormat::Float3,
+/// Indices as the bytes of an index buffer in `format`.
+pub fn index_bytes(indices: &[u32], format: wgpu::IndexFormat) -> Vec<u8> {
+    match format {
+        wgpu::IndexFormat::Uint16 => {
+            let narrow = indices.iter().map(|&i| i as u16).collect::<Vec<u16>>();
+            bytemuck::cast_slice(&narrow).to_vec()
+        }
+        wgpu::IndexFormat::Uint32 => bytemuck::cast_slice(indices).to_vec(),
+    }
+}
+
+pub fn vertex_bounds(vertices: &[Vertex]) -> Option<Bounds> {
+    let positions = vertices.iter().map(|vertex| Point3::from(vertex.position)).collect::<Vec<_>>();
+    Bounds::from_points(&positions)
+}
+
+pub struct Material {
+
+    // None draws with the scene texture
+    diffuse: Option<(Rc<Texture>, wgpu::BindGroup)>,
+    // White when the material has none, so the factor alone shows
+    emissive: Rc<Texture>,
+    factor_buffer: wgpu::Buffer,
+    // Factors and emissive texture, along with the model's node transforms
+    bind_group: wgpu::BindGroup,
+
+} impl Material {
+    // `layouts` holds the texture and material layouts. Nothing is lit, so
+    // the metallic-roughness and normal textures aren't uploaded.
+    pub fn new(
+        device: &wgpu::Device, 
+        queue: &wgpu::Queue, 
+        (texture_layout, material_layout): (&wgpu::BindGroupLayout, &wgpu::BindGroupLayout),
+        textures: &mut TextureCache,
+        white: &Rc<Texture>,
+        node_buffer: &wgpu::Buffer,
+        data: &MaterialData,
+    ) -> Result<Self> {
+        let mut load = |texture: &Option<TextureData>, srgb: bool| -> Result<Option<Rc<Texture>>> {
+            texture.as_ref()
+                .map(|texture| texture.load(device, queue, textures, srgb))
+                .transpose()
+                .with_context(|| format!("Unable to load material {:?}", data.name))
+        };
+        let diffuse = load(&data.diffuse_texture, true)?.map(|texture| {
+            let bind_group = TextureState::create_bind_group(device, texture_layout, &texture);
+            (texture, bind_group)
+        });
+        let emissive = load(&data.emissive_texture, true)?.unwrap_or_else(|| white.clone());
+
+        let [r, g, b] = data.emissive_factor;
+        let factors = MaterialRaw {
+            base_color_factor: data.base_color_factor,
+            emissive_factor: [r, g, b, 0.0],
+        };
+        let factor_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Material Buffer"),
+                contents: bytemuck::bytes_of(&factors),
+                usage: wgpu::BufferUsage::UNIFORM,
+            }
+        );
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: material_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer {
+                        buffer: node_buffer,
+                        offset: 0,
+                        size: wgpu::BufferSize::new(size_of::<[[f32; 4]; 4]>() as u64),
+                    },
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: factor_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(emissive.view()),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(emissive.sampler()),
                 },
-                wgpu::VertexAttribute {
-                    offset: size_of::<[f32; 3]>() as wgpu::BufferAddress,
-                    shader_location: 1,
-                    format: wgpu::VertexFormat::Float2,
+            ],
+            label: Some("Material Bind Group"),
+        });
+        Ok(Self { diffuse, emissive, factor_buffer, bind_group })
+    }
+
+    /// Node transforms for the vertex shader, at a dynamic offset, then
+    /// the factors and emissive texture for the fragment shader.
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<[[f32; 4]; 4]>() as u64),
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<MaterialRaw>() as u64),
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: false,
+                        filtering: true,
+                    },
+                    count: None,
                 },
             ],
+            label: Some("Material Bind Group Layout"),
+        })
+    }
+
+    pub fn diffuse_bind_group(&self) -> Option<&wgpu::BindGroup> {
+        self.diffuse.as_ref().map(|(_, bind_group)| bind_group)
+    }
+    pub fn emissive(&self) -> &Texture { &self.emissive }
+    pub fn factor_buffer(&self) -> &wgpu::Buffer { &self.factor_buffer }
+    pub fn bind_group(&self) -> &wgpu::BindGroup { &self.bind_group }
+}
+
+// Laid out like the Material uniform block in shader.frag
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct MaterialRaw {
+    base_color_factor: [f32; 4],
+    // w is padding
+    emissive_factor: [f32; 4],
+}
+
+/// A whole model file on the CPU side, before it is uploaded.
+#[derive(Default)]
+pub struct ModelData {
+    pub meshes: Vec<MeshData>,
+    pub materials: Vec<MaterialData>,
+    // Where the meshes are drawn. Empty draws each mesh once, where it is
+    pub nodes: Vec<NodeData>,
+    pub camera: Option<CameraView>,
+    pub skin: Option<SkinData>,
+    // The height field terrain meshes were built from
+    pub terrain: Option<Terrain>,
+}
+
+impl ModelData {
+    /// The CPU side of a scene's mesh, at full detail. Mesh caches only
+    /// exist on the GPU side, so they can't be loaded this way.
+    pub fn from_desc(mesh: &MeshDesc) -> Result<Self> {
+        let meshes = match mesh {
+            MeshDesc::Pentagon => vec![MeshData {
+                name: String::from("Pentagon"),
+                vertices: VERTICES.to_vec(),
+                indices: INDICES.to_vec(),
+                material: None,
+                skin: None,
+                morph: None,
+            }],
+            MeshDesc::Obj(path) => return obj::load(path),
+            MeshDesc::Gltf(path) => return gltf_import::load(path),
+            MeshDesc::Ply(path) => return ply::load(path),
+            MeshDesc::Primitive(primitive) => vec![primitive.build()],
+            MeshDesc::Terrain(desc) => {
+                let terrain = Terrain::load(desc)?;
+                let meshes = terrain.chunks(desc.chunk_size);
+                return Ok(Self { meshes, terrain: Some(terrain), ..Self::default() });
+            }
+            MeshDesc::Cache(path) => bail!("{} is a mesh cache, use the model it was converted from", path.display()),
         };
+        Ok(Self { meshes, ..Self::default() })
     }
+
+    /// Picks the loader from the extension: `.obj`, `.ply`, `.gltf` or `.glb`.
+    pub fn load(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("obj") => obj::load(path),
+            Some("ply") => ply::load(path),
+            Some("gltf") | Some("glb") => gltf_import::load(path),
+            _ => bail!("Don't know how to load {}", path.display()),
+        }
+    }
+
+    /// Bakes every node's transform into its own copy of the meshes it
+    /// draws, for consumers that want the whole model in model space.
+    /// Meshes no node draws are dropped.
+    pub fn flatten(&mut self) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut draws = vec![0; self.meshes.len()];
+        for &mesh in self.nodes.iter().flat_map(|node| &node.meshes) {
+            draws[mesh] += 1;
+        }
+        let meshes = self.nodes.iter()
+            .flat_map(|node| node.meshes.iter().map(move |&mesh| (node, mesh)))
+            .map(|(node, mesh)| {
+                let mut copy = self.meshes[mesh].clone();
+                if draws[mesh] > 1 {
+                    copy.name = format!("{}/{}", node.name, copy.name);
+                }
+                copy.transform(&node.transform);
+                copy
+            })
+            .collect();
+        self.meshes = meshes;
+        self.nodes.clear();
+    }
+
+    /// Generates the normals and tangents the file didn't provide and runs
+    /// every mesh through the optimiser.
+    pub fn prepare(&mut self, crease_angle: Option<Deg<f32>>) {
+        for mesh in &mut self.meshes {
+            normals::generate_normals(mesh, crease_angle);
+            normals::generate_tangents(mesh);
+            let stats = optimize::optimize(mesh);
+            log::debug!(
+                "Mesh {:?}: {} -> {} vertices, ACMR {:.3} -> {:.3}",
+                mesh.name, stats.vertices_before, stats.vertices_after, stats.acmr_before, stats.acmr_after,
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MeshData {
+    pub name: String,
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+    pub material: Option<usize>,
+    // Joints and weights of each vertex, for meshes bound to the model's
+    // skeleton
+    pub skin: Option<Vec<SkinVertex>>,
+    pub morph: Option<MorphData>,
+}
+
+impl MeshData {
+    /// Checks that the indices make whole triangles of existing vertices
+    /// and that the skin and morph data cover every vertex.
+    pub fn validate(&self) -> Result<()> {
+        let num_vertices = self.vertices.len();
+        if self.indices.len() % 3 != 0 {
+            bail!("Mesh {:?} has {} indices, not a whole number of triangles", self.name, self.indices.len());
+        }
+        if let Some(index) = self.indices.iter().find(|&&i| i as usize >= num_vertices) {
+            bail!("Mesh {:?} uses vertex {} but only has {}", self.name, index, num_vertices);
+        }
+        if let Some(skin) = &self.skin {
+            if skin.len() != num_vertices {
+                bail!("Mesh {:?} has {} vertices but skin data for {}", self.name, num_vertices, skin.len());
+            }
+        }
+        if let Some(morph) = &self.morph {
+            if let Some(target) = morph.targets.iter().find(|target| {
+                target.position_deltas.len() != num_vertices || target.normal_deltas.len() != num_vertices
+            }) {
+                bail!(
+                    "Mesh {:?} has {} vertices but morph deltas for {}", 
+                    self.name, num_vertices, target.position_deltas.len().min(target.normal_deltas.len()),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the mesh by `transform`. Normals go through the inverse
+    /// transpose so non-uniform scales keep them perpendicular to the
+    /// surface, and a mirroring transform flips the winding so triangles
+    /// keep facing out.
+    pub fn transform(&mut self, transform: &Matrix4<f32>) {
+        let linear = Matrix3::from_cols(
+            transform.x.truncate(),
+            transform.y.truncate(),
+            transform.z.truncate(),
+        );
+        let normal_matrix = linear.invert().map_or(linear, |inverse| inverse.transpose());
+        let handedness = linear.determinant().signum();
+        let unit = |v: Vector3<f32>| if v.magnitude2() > 0.0 { v.normalize() } else { v };
+        for vertex in &mut self.vertices {
+            vertex.position = (transform * Vector3::from(vertex.position).extend(1.0)).truncate().into();
+            vertex.normal = unit(normal_matrix * Vector3::from(vertex.normal)).into();
+            // Tangents follow the surface like positions do
+            let [x, y, z, w] = vertex.tangent;
+            let tangent = unit(linear * Vector3::new(x, y, z));
+            vertex.tangent = [tangent.x, tangent.y, tangent.z, w * handedness];
+        }
+        // Deltas are directions, so they take the transform without its
+        // translation
+        if let Some(morph) = &mut self.morph {
+            for target in &mut morph.targets {
+                for delta in &mut target.position_deltas {
+                    *delta = (linear * Vector3::from(*delta)).into();
+                }
+                for delta in &mut target.normal_deltas {
+                    *delta = (normal_matrix * Vector3::from(*delta)).into();
+                }
+            }
+        }
+        if handedness < 0.0 {
+            for triangle in self.indices.chunks_exact_mut(3) {
+                triangle.swap(1, 2);
+            }
+        }
+    }
+
+    /// Rebuilds the per-vertex data kept next to `vertices` after the
+    /// vertices were rebuilt, new vertex `i` being a copy of old vertex
+    /// `sources[i]`.
+    pub fn remap_vertex_data(&mut self, sources: &[u32]) {
+        if let Some(skin) = &mut self.skin {
+            *skin = sources.iter().map(|&source| skin[source as usize]).collect();
+        }
+        if let Some(morph) = &mut self.morph {
+            morph.remap(sources);
+        }
+    }
+
+    /// Everything kept next to vertex `i` outside `vertices`, as bytes.
+    /// Vertices only count as the same when these match too.
+    pub fn vertex_data_bytes(&self, i: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        if let Some(skin) = &self.skin {
+            bytes.extend_from_slice(bytemuck::bytes_of(&skin[i]));
+        }
+        if let Some(morph) = &self.morph {
+            for target in &morph.targets {
+                bytes.extend_from_slice(bytemuck::bytes_of(&target.position_deltas[i]));
+                bytes.extend_from_slice(bytemuck::bytes_of(&target.normal_deltas[i]));
+            }
+        }
+        bytes
+    }
+}
+
+/// A place the model draws some of its meshes.
+#[derive(Debug, Clone)]
+pub struct NodeData {
+    pub name: String,
+    // Model space
+    pub transform: Matrix4<f32>,
+    // Indices into the model's meshes
+    pub meshes: Vec<usize>,
+}
+
+impl NodeData {
+    /// Draws all `num_meshes` meshes where they are.
+    pub fn root(num_meshes: usize) -> Self {
+        Self { name: String::from("Root"), transform: Matrix4::identity(), meshes: (0..num_meshes).collect() }
+    }
+}
+
+#[derive(Clone)]
+pub struct MaterialData {
+    pub name: String,
+    // Multiplies the diffuse texture, or the scene texture without one
+    pub base_color_factor: [f32; 4],
+    pub diffuse_texture: Option<TextureData>,
+    // Kept with the material but not drawn, nothing is lit yet
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    pub metallic_roughness_texture: Option<TextureData>,
+    pub normal_texture: Option<TextureData>,
+    // Added to the colour, times the emissive texture if there is one
+    pub emissive_factor: [f32; 3],
+    pub emissive_texture: Option<TextureData>,
+}
+
+// The glTF defaults
+impl Default for MaterialData {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            base_color_factor: [1.0; 4],
+            diffuse_texture: None,
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            emissive_factor: [0.0; 3],
+            emissive_texture: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TextureData {
+    pub source: TextureSource,
+    pub sampler: SamplerDesc,
+}
+
+#[derive(Clone)]
+pub enum TextureSource {
+    Path(PathBuf),
+    // Already decoded from inside the model `file`, e.g. from a buffer in a
+    // .glb, and shared between the materials that use it. `index` is the
+    // image's place among the file's images.
+    Embedded { file: PathBuf, index: usize, image: Rc<image::DynamicImage> },
+}
+
+impl TextureData {
+    /// Everything goes through `textures`, so every material naming the same
+    /// image with the same settings shares one texture: files by path,
+    /// images inside a model file by the file and their index in it.
+    pub fn load(
+        &self, 
+        device: &wgpu::Device, 
+        queue: &wgpu::Queue, 
+        textures: &mut TextureCache, 
+        srgb: bool,
+    ) -> Result<Rc<Texture>> {
+        match &self.source {
+            TextureSource::Path(path) => textures.load_with(device, queue, path, srgb, &self.sampler),
+            TextureSource::Embedded { file, index, image } => {
+                textures.load_embedded(device, queue, file, *index, image, srgb, &self.sampler)
+            }
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, VertexLayout)]
+pub struct Vertex {
+
+    #[layout(location = 0)]
+    pub position: [f32; 3],
+    #[layout(location = 1)]
+    pub tex_coords: [f32; 2],
+    #[layout(location = 2)]
+    pub normal: [f32; 3],
+    // xyz along increasing u. The bitangent, cross(normal, tangent) * w,
+    // points towards decreasing v, up in the image
+    #[layout(location = 3)]
+    pub tangent: [f32; 4],
+
 }
 
 const VERTICES: &[Vertex] = &[
-    Vertex { position: [-0.0868241, 0.49240386, 0.0], tex_coords: [0.4131759, 0.00759614], },
-    Vertex { position: [-0.49513406, 0.06958647, 0.0], tex_coords: [0.0048659444, 0.43041354], },
-    Vertex { position: [-0.21918549, -0.44939706, 0.0], tex_coords: [0.28081453, 0.949397057], },
-    Vertex { position: [0.35966998, -0.3473291, 0.0], tex_coords: [0.85967, 0.84732911], },
-    Vertex { position: [0.44147372, 0.2347359, 0.0], tex_coords: [0.9414737, 0.2652641], },
+    Vertex { position: [-0.0868241, 0.49240386, 0.0], tex_coords: [0.4131759, 0.00759614], normal: [0.0, 0.0, 1.0], tangent: [1.0, 0.0, 0.0, 1.0], },
+    Vertex { position: [-0.49513406, 0.06958647, 0.0], tex_coords: [0.0048659444, 0.43041354], normal: [0.0, 0.0, 1.0], tangent: [1.0, 0.0, 0.0, 1.0], },
+    Vertex { position: [-0.21918549, -0.44939706, 0.0], tex_coords: [0.28081453, 0.949397057], normal: [0.0, 0.0, 1.0], tangent: [1.0, 0.0, 0.0, 1.0], },
+    Vertex { position: [0.35966998, -0.3473291, 0.0], tex_coords: [0.85967, 0.84732911], normal: [0.0, 0.0, 1.0], tangent: [1.0, 0.0, 0.0, 1.0], },
+    Vertex { position: [0.44147372, 0.2347359, 0.0], tex_coords: [0.9414737, 0.2652641], normal: [0.0, 0.0, 1.0], tangent: [1.0, 0.0, 0.0, 1.0], },
 ];
 
-const INDICES: &[u16] = &[
+const INDICES: &[u32] = &[
     0, 1, 4,
     1, 2, 4,
     2, 3, 4,
 ];
+
+// Coarser versions of the pentagon that drop one vertex at a time
+const PENTAGON_LODS: &[&[u32]] = &[
+    INDICES,
+    &[
+        0, 1, 4,
+        1, 2, 4,
+    ],
+    &[
+        0, 2, 4,
+    ],
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_format_boundary() {
+        // The largest 16 bit index, 65534, stays clear of 0xFFFF
+        assert_eq!(index_format(0), wgpu::IndexFormat::Uint16);
+        assert_eq!(index_format(65_535), wgpu::IndexFormat::Uint16);
+        assert_eq!(index_format(65_536), wgpu::IndexFormat::Uint32);
+        assert_eq!(index_format(1 << 20), wgpu::IndexFormat::Uint32);
+    }
+
+    #[test]
+    fn index_bytes_match_the_format() {
+        let indices = [0, 1, 65_534];
+        let format = index_format(65_535);
+        let narrow = [0u16, 1, 65_534];
+        assert_eq!(index_bytes(&indices, format), bytemuck::cast_slice::<u16, u8>(&narrow));
+
+        let indices = [0, 65_535, 65_536];
+        let format = index_format(65_537);
+        let bytes = index_bytes(&indices, format);
+        let wide = bytes.chunks_exact(4)
+            .map(|bytes| u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect::<Vec<_>>();
+        assert_eq!(bytes.len(), 12);
+        assert_eq!(wide, indices);
+    }
+}
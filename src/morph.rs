@@ -0,0 +1,273 @@
+use std::mem::size_of;
+use cgmath::{Point3, Vector3, InnerSpace};
+use wgpu::util::DeviceExt;
+
+use crate::model::Vertex;
+use crate::bounds::Bounds;
+
+// Size of the weight arrays in morph.vert and InstanceRaw
+pub const MAX_MORPH_TARGETS: usize = 8;
+
+/// Offsets a morph target adds to every vertex of its mesh at weight 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MorphTarget {
+    pub position_deltas: Vec<[f32; 3]>,
+    // Zero for targets that leave the normals alone
+    pub normal_deltas: Vec<[f32; 3]>,
+}
+
+/// The morph targets of a mesh and the weights it is drawn with when an
+/// instance doesn't add its own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MorphData {
+    pub targets: Vec<MorphTarget>,
+    // One per target
+    pub weights: Vec<f32>,
+}
+
+impl MorphData {
+    pub fn remap(&mut self, sources: &[u32]) {
+        let remap = |deltas: &[[f32; 3]]| sources.iter().map(|&source| deltas[source as usize]).collect();
+        for target in &mut self.targets {
+            target.position_deltas = remap(&target.position_deltas);
+            target.normal_deltas = remap(&target.normal_deltas);
+        }
+    }
+
+    /// Weights morph.vert ends up with for an instance adding
+    /// `instance_weights` to the mesh's own.
+    pub fn combined_weights(&self, instance_weights: &[f32; MAX_MORPH_TARGETS]) -> [f32; MAX_MORPH_TARGETS] {
+        let mut weights = *instance_weights;
+        for (weight, default) in weights.iter_mut().zip(&self.weights) {
+            *weight += default;
+        }
+        weights
+    }
+
+    /// Every vertex moved by `weights`, the same way morph.vert moves the
+    /// positions. Normals, which the shader leaves alone since nothing is
+    /// lit, get their deltas too and are renormalised.
+    pub fn apply(&self, vertices: &[Vertex], weights: &[f32]) -> Vec<Vertex> {
+        vertices.iter()
+            .enumerate()
+            .map(|(i, vertex)| {
+                let mut position = Vector3::from(vertex.position);
+                let mut normal = Vector3::from(vertex.normal);
+                for (target, &weight) in self.targets.iter().zip(weights) {
+                    position += Vector3::from(target.position_deltas[i]) * weight;
+                    normal += Vector3::from(target.normal_deltas[i]) * weight;
+                }
+                let normal = if normal.magnitude2() > 0.0 { normal.normalize() } else { normal };
+                Vertex { position: position.into(), normal: normal.into(), ..*vertex }
+            })
+            .collect()
+    }
+
+    /// Bounds holding the mesh for any weights between 0 and 1: each vertex
+    /// contributes the corners reached by adding all its negative or all
+    /// its positive deltas.
+    pub fn bounds(&self, vertices: &[Vertex]) -> Option<Bounds> {
+        let points = vertices.iter()
+            .enumerate()
+            .flat_map(|(i, vertex)| {
+                let mut min = Vector3::from(vertex.position);
+                let mut max = min;
+                for target in &self.targets {
+                    let delta = Vector3::from(target.position_deltas[i]);
+                    for axis in 0..3 {
+                        min[axis] += delta[axis].min(0.0);
+                        max[axis] += delta[axis].max(0.0);
+                    }
+                }
+                vec![Point3::new(min.x, min.y, min.z), Point3::new(max.x, max.y, max.z)]
+            })
+            .collect::<Vec<_>>();
+        Bounds::from_points(&points)
+    }
+}
+
+/// A morphed mesh's deltas and default weights on the GPU, bound as group
+/// 2 of the morph pipelines.
+pub struct MorphBuffers {
+
+    info_buffer: wgpu::Buffer,
+    delta_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+
+} impl MorphBuffers {
+
+    pub fn new(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, data: &MorphData, num_vertices: usize) -> Self {
+        let mut default_weights = [0.0; MAX_MORPH_TARGETS];
+        for (weight, &default) in default_weights.iter_mut().zip(&data.weights) {
+            *weight = default;
+        }
+        let info = MorphInfoRaw {
+            default_weights,
+            num_vertices: num_vertices as u32,
+            num_targets: data.targets.len().min(MAX_MORPH_TARGETS) as u32,
+            _padding: [0; 2],
+        };
+        let info_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Morph Info Buffer"),
+                contents: bytemuck::bytes_of(&info),
+                usage: wgpu::BufferUsage::UNIFORM,
+            }
+        );
+        // Position delta of every vertex, one target after the other.
+        // Nothing is lit, so the normal deltas stay on the CPU
+        let deltas = data.targets.iter()
+            .take(MAX_MORPH_TARGETS)
+            .flat_map(|target| &target.position_deltas)
+            .map(|&[x, y, z]| [x, y, z, 0.0])
+            .collect::<Vec<[f32; 4]>>();
+        let delta_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Morph Delta Buffer"),
+                contents: bytemuck::cast_slice(&deltas),
+                usage: wgpu::BufferUsage::STORAGE,
+            }
+        );
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: info_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: delta_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("Morph Bind Group"),
+        });
+        Self { info_buffer, delta_buffer, bind_group }
+    }
+
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<MorphInfoRaw>() as u64),
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("Morph Bind Group Layout"),
+        })
+    }
+
+    pub fn info_buffer(&self) -> &wgpu::Buffer { &self.info_buffer }
+    pub fn delta_buffer(&self) -> &wgpu::Buffer { &self.delta_buffer }
+    pub fn bind_group(&self) -> &wgpu::BindGroup { &self.bind_group }
+}
+
+// Laid out like the Morph uniform block in morph.vert
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct MorphInfoRaw {
+    default_weights: [f32; MAX_MORPH_TARGETS],
+    num_vertices: u32,
+    num_targets: u32,
+    _padding: [u32; 2],
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex(position: [f32; 3]) -> Vertex {
+        Vertex { position, tex_coords: [0.5, 0.5], normal: [0.0, 0.0, 1.0], tangent: [1.0, 0.0, 0.0, 1.0] }
+    }
+
+    // Two vertices; the first target lifts the second vertex and tips its
+    // normal, the second pushes both along x
+    fn morph() -> MorphData {
+        MorphData {
+            targets: vec![
+                MorphTarget {
+                    position_deltas: vec![[0.0, 0.0, 0.0], [0.0, 2.0, 0.0]],
+                    normal_deltas: vec![[0.0, 0.0, 0.0], [1.0, 0.0, -1.0]],
+                },
+                MorphTarget {
+                    position_deltas: vec![[1.0, 0.0, 0.0], [-1.0, 0.0, 0.0]],
+                    normal_deltas: vec![[0.0; 3], [0.0; 3]],
+                },
+            ],
+            weights: vec![0.25, 0.0],
+        }
+    }
+
+    #[test]
+    fn apply_adds_weighted_deltas() {
+        let morph = morph();
+        let vertices = vec![vertex([0.0, 0.0, 0.0]), vertex([1.0, 1.0, 0.0])];
+
+        assert_eq!(morph.apply(&vertices, &[0.0, 0.0])[1].position, vertices[1].position);
+
+        let moved = morph.apply(&vertices, &[0.5, 2.0]);
+        assert_eq!(moved[0].position, [2.0, 0.0, 0.0]);
+        assert_eq!(moved[1].position, [-1.0, 2.0, 0.0]);
+        // (0.5, 0, 0.5) renormalised; the rest of the vertex is untouched
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        assert!((Vector3::from(moved[1].normal) - Vector3::new(half, 0.0, half)).magnitude() < 1e-6);
+        assert_eq!(moved[0].normal, [0.0, 0.0, 1.0]);
+        assert_eq!(moved[1].tex_coords, vertices[1].tex_coords);
+        assert_eq!(moved[1].tangent, vertices[1].tangent);
+
+        // A normal cancelled out entirely is left at zero, not NaN
+        let flat = morph.apply(&vertices, &[1.0, 0.0]);
+        assert_eq!(flat[1].normal, [1.0, 0.0, 0.0]);
+        let gone = MorphData {
+            targets: vec![MorphTarget { position_deltas: vec![[0.0; 3]], normal_deltas: vec![[0.0, 0.0, -1.0]] }],
+            weights: vec![0.0],
+        };
+        assert_eq!(gone.apply(&vertices[..1], &[1.0])[0].normal, [0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn weights_and_bounds() {
+        let morph = morph();
+        let mut instance_weights = [0.0; MAX_MORPH_TARGETS];
+        instance_weights[1] = 0.5;
+        let weights = morph.combined_weights(&instance_weights);
+        assert_eq!(weights[..3], [0.25, 0.5, 0.0]);
+
+        // Every weight between 0 and 1 stays inside
+        let vertices = vec![vertex([0.0, 0.0, 0.0]), vertex([1.0, 1.0, 0.0])];
+        let bounds = morph.bounds(&vertices).unwrap();
+        assert_eq!(bounds.aabb.min, Point3::new(0.0, 0.0, 0.0));
+        assert_eq!(bounds.aabb.max, Point3::new(1.0, 3.0, 0.0));
+        for &weights in &[[0.0, 0.0], [1.0, 0.0], [0.0, 1.0], [1.0, 1.0], [0.3, 0.7]] {
+            for vertex in morph.apply(&vertices, &weights) {
+                let [x, y, z] = vertex.position;
+                assert!((0.0..=1.0).contains(&x) && (0.0..=3.0).contains(&y) && z == 0.0, "{:?}", vertex.position);
+            }
+        }
+    }
+
+    #[test]
+    fn remap_follows_the_vertices() {
+        let mut morph = morph();
+        morph.remap(&[1, 1, 0]);
+        assert_eq!(morph.targets[0].position_deltas, [[0.0, 2.0, 0.0], [0.0, 2.0, 0.0], [0.0, 0.0, 0.0]]);
+        assert_eq!(morph.targets[0].normal_deltas[2], [0.0, 0.0, 0.0]);
+        assert_eq!(morph.targets[1].position_deltas[0], [-1.0, 0.0, 0.0]);
+    }
+}
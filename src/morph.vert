@@ -0,0 +1,60 @@
+// morph.vert
+#version 450
+
+// Must match morph::MAX_MORPH_TARGETS
+const uint MAX_MORPH_TARGETS = 8;
+
+layout(location=0) in vec3 a_position;
+layout(location=1) in vec2 a_tex_coords;
+
+layout(location=5) in vec4 model_matrix_0;
+layout(location=6) in vec4 model_matrix_1;
+layout(location=7) in vec4 model_matrix_2;
+layout(location=8) in vec4 model_matrix_3;
+layout(location=9) in float a_opacity;
+layout(location=12) in vec4 a_morph_weights_0;
+layout(location=13) in vec4 a_morph_weights_1;
+
+layout(location=0) out vec2 v_tex_coords;
+layout(location=1) out float v_opacity;
+
+layout(set=1, binding=0)
+uniform Camera {
+    mat4 u_view_proj;
+};
+
+layout(set=2, binding=0)
+uniform Node {
+    mat4 u_node;
+};
+
+layout(set=3, binding=0)
+uniform Morph {
+    vec4 u_default_weights[MAX_MORPH_TARGETS / 4];
+    uint u_num_vertices;
+    uint u_num_targets;
+};
+
+// Position delta of every vertex, one target after the other
+layout(set=3, binding=1)
+readonly buffer MorphDeltas {
+    vec4 deltas[];
+};
+
+void main() {
+    vec4 weights_0 = u_default_weights[0] + a_morph_weights_0;
+    vec4 weights_1 = u_default_weights[1] + a_morph_weights_1;
+    float weights[MAX_MORPH_TARGETS] = float[](
+        weights_0.x, weights_0.y, weights_0.z, weights_0.w,
+        weights_1.x, weights_1.y, weights_1.z, weights_1.w
+    );
+    vec3 position = a_position;
+    for (uint i = 0; i < u_num_targets; i++) {
+        position += weights[i] * deltas[i * u_num_vertices + uint(gl_VertexIndex)].xyz;
+    }
+
+    mat4 model_matrix = mat4(model_matrix_0, model_matrix_1, model_matrix_2, model_matrix_3);
+    v_tex_coords = a_tex_coords;
+    v_opacity = a_opacity;
+    gl_Position = u_view_proj * model_matrix * u_node * vec4(position, 1.0);
+}
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use cgmath::{Vector3, InnerSpace, Deg, Rad, Zero};
+
+use crate::model::{MeshData, Vertex};
+
+/// Fills in the normal of every vertex that has none (all zeros) with the
+/// angle weighted average of the faces around its position, so UV seams
+/// don't show. With a crease angle, faces meeting at a sharper angle than
+/// it aren't averaged and their shared vertices are split into a hard edge.
+pub fn generate_normals(mesh: &mut MeshData, crease_angle: Option<Deg<f32>>) {
+    if !mesh.vertices.iter().any(|vertex| is_zero(&vertex.normal)) {
+        return;
+    }
+    let faces = faces(mesh);
+    let welded = weld_positions(&mesh.vertices);
+    let corner_positions = mesh.indices.iter().map(|&index| welded[index as usize]).collect::<Vec<_>>();
+    let mut corners_at: Vec<Vec<usize>> = vec![Vec::new(); mesh.vertices.len()];
+    for (corner, &position) in corner_positions.iter().enumerate() {
+        corners_at[position].push(corner);
+    }
+    let min_cos = crease_angle.map(|angle| Rad::from(angle).0.cos());
+
+    set_corner_normals(mesh, |corner, vertex| {
+        if !is_zero(&vertex.normal) {
+            return vertex.normal;
+        }
+        let face_normal = faces[corner / 3].normal;
+        let mut normal = Vector3::zero();
+        for &other in &corners_at[corner_positions[corner]] {
+            let face = &faces[other / 3];
+            let smooth = match min_cos {
+                Some(min_cos) => face.normal.dot(face_normal) >= min_cos,
+                None => true,
+            };
+            if smooth {
+                normal += face.normal * face.angles[other % 3];
+            }
+        }
+        if normal.magnitude2() > 0.0 {
+            normal.normalize().into()
+        } else {
+            face_normal.into()
+        }
+    });
+}
+
+/// Gives every corner the normal of its face, replacing any the mesh had.
+/// glTF asks for this where a mesh comes without normals. Corners of faces
+/// in the same plane keep sharing their vertex.
+pub fn generate_flat_normals(mesh: &mut MeshData) {
+    let faces = faces(mesh);
+    set_corner_normals(mesh, |corner, _| faces[corner / 3].normal.into());
+}
+
+// Rebuilds the vertices corner by corner, each corner's vertex taking the
+// normal `normal(corner, vertex)` gives it. Corners that end up with the
+// same normal keep sharing a vertex.
+fn set_corner_normals<F>(mesh: &mut MeshData, mut normal: F)
+where F: FnMut(usize, &Vertex) -> [f32; 3] {
+    let mut split: HashMap<(u32, [u32; 3]), u32> = HashMap::new();
+    let mut vertices = Vec::with_capacity(mesh.vertices.len());
+    let mut sources = Vec::with_capacity(mesh.vertices.len());
+    for corner in 0..mesh.indices.len() {
+        let index = mesh.indices[corner];
+        let mut vertex = mesh.vertices[index as usize];
+        vertex.normal = normal(corner, &vertex);
+        let key = (index, bits(&vertex.normal));
+        mesh.indices[corner] = *split.entry(key).or_insert_with(|| {
+            vertices.push(vertex);
+            sources.push(index);
+            vertices.len() as u32 - 1
+        });
+    }
+    mesh.vertices = vertices;
+    mesh.remap_vertex_data(&sources);
+}
+
+/// Fills in the tangent of every vertex that has none (all zeros) from the
+/// direction u grows in across the faces around it, made perpendicular to
+/// the normal. w is -1 where the UVs are mirrored, so the bitangent,
+/// cross(normal, tangent) * w, always points towards decreasing v. Run it
+/// after the normals are complete.
+pub fn generate_tangents(mesh: &mut MeshData) {
+    if !mesh.vertices.iter().any(|vertex| is_zero(&vertex.tangent)) {
+        return;
+    }
+    let faces = faces(mesh);
+    let mut tangents = vec![Vector3::zero(); mesh.vertices.len()];
+    let mut bitangents = vec![Vector3::zero(); mesh.vertices.len()];
+    for (triangle, face) in mesh.indices.chunks_exact(3).zip(&faces) {
+        let vertex = |i: usize| &mesh.vertices[triangle[i] as usize];
+        let position = |i: usize| Vector3::from(vertex(i).position);
+        let (edge1, edge2) = (position(1) - position(0), position(2) - position(0));
+        let (uv0, uv1, uv2) = (vertex(0).tex_coords, vertex(1).tex_coords, vertex(2).tex_coords);
+        let (du1, dv1) = (uv1[0] - uv0[0], uv1[1] - uv0[1]);
+        let (du2, dv2) = (uv2[0] - uv0[0], uv2[1] - uv0[1]);
+        let det = du1 * dv2 - du2 * dv1;
+        if det.abs() <= f32::EPSILON {
+            continue;
+        }
+        // Position derivatives along u and v
+        let tangent = (edge1 * dv2 - edge2 * dv1) / det;
+        let bitangent = (edge2 * du1 - edge1 * du2) / det;
+        for (i, &index) in triangle.iter().enumerate() {
+            tangents[index as usize] += tangent * face.angles[i];
+            bitangents[index as usize] += bitangent * face.angles[i];
+        }
+    }
+
+    for ((vertex, tangent), bitangent) in mesh.vertices.iter_mut().zip(tangents).zip(bitangents) {
+        if !is_zero(&vertex.tangent) {
+            continue;
+        }
+        let normal = Vector3::from(vertex.normal);
+        // Gram-Schmidt, with any perpendicular standing in where the UVs
+        // don't give a direction
+        let mut tangent = tangent - normal * normal.dot(tangent);
+        if tangent.magnitude2() <= f32::EPSILON {
+            tangent = any_perpendicular(normal);
+        }
+        let tangent = tangent.normalize();
+        let w = if normal.cross(tangent).dot(bitangent) > 0.0 { -1.0 } else { 1.0 };
+        vertex.tangent = [tangent.x, tangent.y, tangent.z, w];
+    }
+}
+
+struct Face {
+    normal: Vector3<f32>,
+    // Interior angle at each corner, in radians
+    angles: [f32; 3],
+}
+
+// Degenerate triangles get a zero normal and angles, so they add nothing
+fn faces(mesh: &MeshData) -> Vec<Face> {
+    mesh.indices.chunks_exact(3)
+        .map(|triangle| {
+            let position = |i: usize| Vector3::from(mesh.vertices[triangle[i] as usize].position);
+            let (a, b, c) = (position(0), position(1), position(2));
+            let normal = (b - a).cross(c - a);
+            if normal.magnitude2() <= 0.0 {
+                return Face { normal, angles: [0.0; 3] };
+            }
+            let angle = |from: Vector3<f32>, to1: Vector3<f32>, to2: Vector3<f32>| {
+                (to1 - from).angle(to2 - from).0
+            };
+            Face {
+                normal: normal.normalize(),
+                angles: [angle(a, b, c), angle(b, c, a), angle(c, a, b)],
+            }
+        })
+        .collect()
+}
+
+// Maps each vertex to an id shared by all vertices at the same position
+fn weld_positions(vertices: &[Vertex]) -> Vec<usize> {
+    let mut ids: HashMap<[u32; 3], usize> = HashMap::new();
+    vertices.iter()
+        .map(|vertex| {
+            let next = ids.len();
+            *ids.entry(bits(&vertex.position)).or_insert(next)
+        })
+        .collect()
+}
+
+// Adding 0.0 turns -0.0 into 0.0 so both hash the same
+fn bits(v: &[f32; 3]) -> [u32; 3] {
+    [(v[0] + 0.0).to_bits(), (v[1] + 0.0).to_bits(), (v[2] + 0.0).to_bits()]
+}
+
+fn is_zero(v: &[f32]) -> bool {
+    v.iter().all(|&x| x == 0.0)
+}
+
+fn any_perpendicular(normal: Vector3<f32>) -> Vector3<f32> {
+    let axis = if normal.x.abs() < 0.9 { Vector3::unit_x() } else { Vector3::unit_y() };
+    normal.cross(axis).cross(normal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives;
+
+    fn mesh(positions: &[[f32; 3]], tex_coords: &[[f32; 2]], indices: &[u32]) -> MeshData {
+        let vertices = positions.iter()
+            .zip(tex_coords)
+            .map(|(&position, &tex_coords)| Vertex { position, tex_coords, normal: [0.0; 3], tangent: [0.0; 4] })
+            .collect();
+        MeshData { name: String::from("test"), vertices, indices: indices.to_vec(), material: None, skin: None, morph: None }
+    }
+
+    // Eight shared corners, faces wound outwards
+    fn welded_cube() -> MeshData {
+        let positions = (0..8)
+            .map(|i| [(i & 1) as f32 - 0.5, (i >> 1 & 1) as f32 - 0.5, (i >> 2 & 1) as f32 - 0.5])
+            .collect::<Vec<_>>();
+        let indices = [
+            0, 4, 6, 0, 6, 2, // -x
+            1, 3, 7, 1, 7, 5, // +x
+            0, 1, 5, 0, 5, 4, // -y
+            2, 6, 7, 2, 7, 3, // +y
+            0, 2, 3, 0, 3, 1, // -z
+            4, 5, 7, 4, 7, 6, // +z
+        ];
+        mesh(&positions, &[[0.0; 2]; 8], &indices)
+    }
+
+    fn face_normal(mesh: &MeshData, triangle: &[u32]) -> Vector3<f32> {
+        let position = |i: usize| Vector3::from(mesh.vertices[triangle[i] as usize].position);
+        (position(1) - position(0)).cross(position(2) - position(0)).normalize()
+    }
+
+    #[test]
+    fn creased_cube_splits_into_faces() {
+        let mut cube = welded_cube();
+        generate_normals(&mut cube, Some(Deg(45.0)));
+        assert_eq!(cube.vertices.len(), 24);
+        for triangle in cube.indices.chunks_exact(3) {
+            let expected = face_normal(&cube, triangle);
+            for &i in triangle {
+                assert_eq!(Vector3::from(cube.vertices[i as usize].normal), expected);
+            }
+        }
+        // Each an axis
+        for vertex in &cube.vertices {
+            let mut components = vertex.normal.iter().map(|x| x.abs()).collect::<Vec<_>>();
+            components.sort_by(f32::total_cmp);
+            assert_eq!(components, [0.0, 0.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn smooth_cube_keeps_its_corners() {
+        let mut cube = welded_cube();
+        generate_normals(&mut cube, None);
+        assert_eq!(cube.vertices.len(), 8);
+        for vertex in &cube.vertices {
+            // Three faces at right angles weigh the same, so the normal
+            // points along the diagonal
+            let normal = Vector3::from(vertex.normal);
+            let diagonal = Vector3::from(vertex.position).normalize();
+            assert!((normal - diagonal).magnitude() < 1e-5, "{:?}", vertex);
+        }
+    }
+
+    #[test]
+    fn sphere_normals_are_radial() {
+        let mut sphere = primitives::uv_sphere(2.0, 16, 8);
+        for vertex in &mut sphere.vertices {
+            vertex.normal = [0.0; 3];
+        }
+        generate_normals(&mut sphere, Some(Deg(60.0)));
+        for vertex in &sphere.vertices {
+            let radial = Vector3::from(vertex.position).normalize();
+            let normal = Vector3::from(vertex.normal);
+            assert!((normal.magnitude() - 1.0).abs() < 1e-5);
+            // Faces a ring apart are 22.5 degrees apart, the average is
+            // much closer
+            assert!(normal.dot(radial) > 0.99, "{:?}", vertex);
+        }
+        // Copies along the UV seam get the same normal
+        let seam = sphere.vertices.iter().filter(|vertex| vertex.tex_coords[0] == 0.0 || vertex.tex_coords[0] == 1.0);
+        for vertex in seam {
+            let twin = sphere.vertices.iter()
+                .find(|other| other.position == vertex.position && other.tex_coords[0] != vertex.tex_coords[0]);
+            if let Some(twin) = twin {
+                assert_eq!(twin.normal, vertex.normal);
+            }
+        }
+    }
+
+    #[test]
+    fn tangent_handedness() {
+        // Facing +z with v growing down the quad, as images are stored
+        let positions = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]];
+        let indices = [0, 1, 2, 0, 2, 3];
+        let upright = [[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]];
+        let mirrored = [[1.0, 1.0], [0.0, 1.0], [0.0, 0.0], [1.0, 0.0]];
+        for (tex_coords, tangent) in [(upright, [1.0, 0.0, 0.0, 1.0]), (mirrored, [-1.0, 0.0, 0.0, -1.0])].iter() {
+            let mut quad = mesh(&positions, tex_coords, &indices);
+            generate_normals(&mut quad, None);
+            generate_tangents(&mut quad);
+            for vertex in &quad.vertices {
+                assert_eq!(vertex.normal, [0.0, 0.0, 1.0]);
+                assert_eq!(vertex.tangent, *tangent);
+                // The bitangent points towards decreasing v, up the quad
+                let [x, y, z, w] = vertex.tangent;
+                let bitangent = Vector3::from(vertex.normal).cross(Vector3::new(x, y, z)) * w;
+                assert_eq!(bitangent, Vector3::unit_y());
+            }
+        }
+    }
+
+    #[test]
+    fn flat_normals_replace_smooth_ones() {
+        // Two triangles folded along the x axis, and a coplanar neighbour
+        let positions = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [1.0, 1.0, 0.0]];
+        let indices = [0, 1, 2, 1, 0, 3, 1, 4, 2];
+        let mut folded = mesh(&positions, &[[0.0; 2]; 5], &indices);
+        generate_flat_normals(&mut folded);
+        // The fold edge's two ends are split, the coplanar triangles share
+        assert_eq!(folded.vertices.len(), 7);
+        for triangle in folded.indices.chunks_exact(3) {
+            let expected = face_normal(&folded, triangle);
+            for &i in triangle {
+                assert_eq!(Vector3::from(folded.vertices[i as usize].normal), expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::SplitWhitespace;
+use anyhow::*;
+
+use crate::model::{ModelData, MeshData, MaterialData, TextureData, TextureSource, Vertex};
+use crate::texture::SamplerDesc;
+
+/// Loads a `.obj` file and the `.mtl` libraries it references.
+pub fn load(path: &Path) -> Result<ModelData> {
+    let src = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read model {}", path.display()))?;
+    parse(&src, path)
+}
+
+/// Parses OBJ source. `path` names the file in errors and is where `mtllib`
+/// and texture paths are resolved from.
+pub fn parse(src: &str, path: &Path) -> Result<ModelData> {
+    let mut parser = ObjParser::new(path);
+    for (number, line) in src.lines().enumerate() {
+        parser.line(line, number + 1)
+            .with_context(|| format!("{}:{}: {}", path.display(), number + 1, line.trim()))?;
+    }
+    parser.finish()
+}
+
+// (position, tex_coords, normal), zero-based
+type VertexKey = (usize, Option<usize>, Option<usize>);
+
+struct MeshBuilder {
+    name: String,
+    material: Option<usize>,
+    vertices: Vec<Vertex>,
+    indices: Vec<u32>,
+    lookup: HashMap<VertexKey, u32>,
+}
+
+impl MeshBuilder {
+    fn new(name: String, material: Option<usize>) -> Self {
+        Self {
+            name,
+            material,
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            lookup: HashMap::new(),
+        }
+    }
+
+    fn finish(self) -> MeshData {
+        MeshData {
+            name: self.name,
+            vertices: self.vertices,
+            indices: self.indices,
+            material: self.material,
+            skin: None,
+            morph: None,
+        }
+    }
+}
+
+struct ObjParser<'a> {
+    path: &'a Path,
+    positions: Vec<[f32; 3]>,
+    tex_coords: Vec<[f32; 2]>,
+    normals: Vec<[f32; 3]>,
+    materials: Vec<MaterialData>,
+    material_names: HashMap<String, usize>,
+    group: String,
+    material: Option<usize>,
+    current: Option<MeshBuilder>,
+    meshes: Vec<MeshData>,
+}
+
+impl<'a> ObjParser<'a> {
+    fn new(path: &'a Path) -> Self {
+        Self {
+            path,
+            positions: Vec::new(),
+            tex_coords: Vec::new(),
+            normals: Vec::new(),
+            materials: Vec::new(),
+            material_names: HashMap::new(),
+            group: String::from("default"),
+            material: None,
+            current: None,
+            meshes: Vec::new(),
+        }
+    }
+
+    fn line(&mut self, line: &str, number: usize) -> Result<()> {
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(keyword) => keyword,
+            None => return Ok(()),
+        };
+        match keyword {
+            "v" => {
+                let mut position = [0.0; 3];
+                parse_floats(&mut words, &mut position, 3, "vertex position")?;
+                self.positions.push(position);
+            }
+            "vt" => {
+                let mut uv = [0.0; 2];
+                parse_floats(&mut words, &mut uv, 1, "texture coordinate")?;
+                // OBJ puts v = 0 at the bottom of the image, wgpu at the top
+                self.tex_coords.push([uv[0], 1.0 - uv[1]]);
+            }
+            "vn" => {
+                let mut normal = [0.0; 3];
+                parse_floats(&mut words, &mut normal, 3, "vertex normal")?;
+                self.normals.push(normal);
+            }
+            "f" => self.face(words)?,
+            "o" | "g" => {
+                let name = words.collect::<Vec<_>>().join(" ");
+                self.group = if name.is_empty() { String::from("default") } else { name };
+                self.flush();
+            }
+            "usemtl" => {
+                let name = words.next().context("usemtl needs a material name")?;
+                let material = *self.material_names.get(name)
+                    .with_context(|| format!("Unknown material {:?}", name))?;
+                if self.material != Some(material) {
+                    self.material = Some(material);
+                    self.flush();
+                }
+            }
+            "mtllib" => {
+                let names = words.collect::<Vec<_>>();
+                if names.is_empty() {
+                    bail!("mtllib needs a file name");
+                }
+                for name in names {
+                    self.material_library(name)?;
+                }
+            }
+            // Smoothing groups, lines, points and free-form geometry aren't
+            // drawn
+            "s" | "l" | "p" | "cstype" | "deg" | "curv" | "curv2" | "surf" | "parm" | "end" => {}
+            _ => log::warn!("{}:{}: ignoring unknown statement {:?}", self.path.display(), number, keyword),
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        if let Some(builder) = self.current.take() {
+            if !builder.indices.is_empty() {
+                self.meshes.push(builder.finish());
+            }
+        }
+    }
+
+    fn face(&mut self, words: SplitWhitespace) -> Result<()> {
+        let keys = words
+            .map(|word| self.parse_face_vertex(word))
+            .collect::<Result<Vec<VertexKey>>>()?;
+        if keys.len() < 3 {
+            bail!("A face needs at least 3 vertices, found {}", keys.len());
+        }
+
+        if self.current.is_none() {
+            // Faces outside any group are named after their material
+            let name = match self.material {
+                Some(material) if self.group == "default" => self.materials[material].name.clone(),
+                _ => self.group.clone(),
+            };
+            self.current = Some(MeshBuilder::new(name, self.material));
+        }
+        let builder = self.current.as_mut().unwrap();
+        let (tex_coords, normals) = (&self.tex_coords, &self.normals);
+
+        let mut indices = Vec::with_capacity(keys.len());
+        for key in &keys {
+            let index = match builder.lookup.get(key) {
+                Some(index) => *index,
+                None => {
+                    let index = builder.vertices.len() as u32;
+                    builder.vertices.push(Vertex {
+                        position: self.positions[key.0],
+                        tex_coords: key.1.map_or([0.0, 0.0], |i| tex_coords[i]),
+                        normal: key.2.map_or([0.0, 0.0, 0.0], |i| normals[i]),
+                        tangent: [0.0; 4],
+                    });
+                    builder.lookup.insert(*key, index);
+                    index
+                }
+            };
+            indices.push(index);
+        }
+
+        // Polygons are split into a fan around their first vertex
+        for i in 1..indices.len() - 1 {
+            builder.indices.extend_from_slice(&[indices[0], indices[i], indices[i + 1]]);
+        }
+        Ok(())
+    }
+
+    // One of v, v/vt, v//vn or v/vt/vn
+    fn parse_face_vertex(&self, word: &str) -> Result<VertexKey> {
+        let mut parts = word.split('/');
+        let position = parts.next().unwrap_or("");
+        let tex_coords = parts.next().filter(|part| !part.is_empty());
+        let normal = parts.next().filter(|part| !part.is_empty());
+        if parts.next().is_some() {
+            bail!("Face vertex {:?} has more than 3 indices", word);
+        }
+        Ok((
+            resolve_index(position, self.positions.len(), "position")?,
+            tex_coords.map(|i| resolve_index(i, self.tex_coords.len(), "texture coordinate")).transpose()?,
+            normal.map(|i| resolve_index(i, self.normals.len(), "normal")).transpose()?,
+        ))
+    }
+
+    fn material_library(&mut self, name: &str) -> Result<()> {
+        let path = resolve_path(self.path, name);
+        let src = std::fs::read_to_string(&path)
+            .with_context(|| format!("Unable to read material library {}", path.display()))?;
+        let materials = parse_mtl(&src, &path)?;
+        for material in materials {
+            self.material_names.insert(material.name.clone(), self.materials.len());
+            self.materials.push(material);
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<ModelData> {
+        self.flush();
+        if self.meshes.is_empty() {
+            bail!("{} has no faces", self.path.display());
+        }
+        Ok(ModelData { meshes: self.meshes, materials: self.materials, ..ModelData::default() })
+    }
+}
+
+/// Parses an MTL library. Only the diffuse texture is used; the other
+/// statements are accepted and ignored.
+pub fn parse_mtl(src: &str, path: &Path) -> Result<Vec<MaterialData>> {
+    let mut materials: Vec<MaterialData> = Vec::new();
+    for (number, line) in src.lines().enumerate() {
+        let context = || format!("{}:{}: {}", path.display(), number + 1, line.trim());
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("newmtl") => {
+                let name = words.next().context("newmtl needs a material name").with_context(context)?;
+                materials.push(MaterialData { name: name.to_string(), ..Default::default() });
+            }
+            Some("map_Kd") => {
+                let file = texture_file(words).context("map_Kd needs a file name").with_context(context)?;
+                let material = materials.last_mut()
+                    .context("map_Kd comes before any newmtl")
+                    .with_context(context)?;
+                material.diffuse_texture = Some(TextureData {
+                    source: TextureSource::Path(resolve_path(path, &file)),
+                    // OBJ has no sampler settings, and UVs outside 0..1 are
+                    // meant to tile
+                    sampler: SamplerDesc {
+                        address_mode_u: wgpu::AddressMode::Repeat,
+                        address_mode_v: wgpu::AddressMode::Repeat,
+                        ..Default::default()
+                    },
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(materials)
+}
+
+// Skips the options before a texture's file name, such as -s 2 2 or
+// -clamp on, and joins the rest back up, since file names may hold spaces.
+// Numeric options take up to their count of numbers, ending at the first
+// word that isn't one
+fn texture_file(mut words: SplitWhitespace) -> Option<String> {
+    let mut name = Vec::new();
+    while let Some(word) = words.next() {
+        if !name.is_empty() {
+            name.push(word);
+            continue;
+        }
+        let max_numbers = match word {
+            "-blendu" | "-blendv" | "-cc" | "-clamp" | "-imfchan" | "-type" => {
+                words.next();
+                continue;
+            }
+            "-boost" | "-texres" | "-bm" => 1,
+            "-mm" => 2,
+            "-o" | "-s" | "-t" => 3,
+            _ => {
+                name.push(word);
+                continue;
+            }
+        };
+        for _ in 0..max_numbers {
+            match words.clone().next() {
+                Some(arg) if arg.parse::<f32>().is_ok() => { words.next(); }
+                _ => break,
+            }
+        }
+    }
+    if name.is_empty() { None } else { Some(name.join(" ")) }
+}
+
+// Reads up to `values.len()` numbers, of which the first `required` must be
+// present
+fn parse_floats(words: &mut SplitWhitespace, values: &mut [f32], required: usize, what: &str) -> Result<()> {
+    for (i, value) in values.iter_mut().enumerate() {
+        match words.next() {
+            Some(word) => {
+                *value = word.parse()
+                    .with_context(|| format!("Invalid {} component {:?}", what, word))?;
+            }
+            None if i < required => bail!("A {} needs {} components, found {}", what, required, i),
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+// OBJ indices start at 1, negative ones count back from the latest element
+fn resolve_index(word: &str, len: usize, what: &str) -> Result<usize> {
+    let index: i64 = word.parse()
+        .with_context(|| format!("Invalid {} index {:?}", what, word))?;
+    let resolved = if index > 0 { index - 1 } else { len as i64 + index };
+    if index == 0 || resolved < 0 || resolved >= len as i64 {
+        bail!("{} index {} is out of range, {} defined so far", what, index, len);
+    }
+    Ok(resolved as usize)
+}
+
+fn resolve_path(base: &Path, name: &str) -> PathBuf {
+    base.parent().map_or_else(|| PathBuf::from(name), |dir| dir.join(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_error(name: &str) -> String {
+        let path = Path::new("res/tests/obj").join(name);
+        format!("{:#}", load(&path).err().expect("the fixture should fail to load"))
+    }
+
+    #[test]
+    fn loads_groups_materials_and_textures() {
+        let model = load(Path::new("res/tests/obj/textured.obj")).unwrap();
+        let names = model.meshes.iter().map(|mesh| mesh.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["Wall", "Roof"]);
+
+        // The quad is split in two, sharing its corners
+        let wall = &model.meshes[0];
+        assert_eq!(wall.vertices.len(), 4);
+        assert_eq!(wall.indices, [0, 1, 2, 0, 2, 3]);
+        assert_eq!(wall.vertices[2].position, [1.0, 1.0, 0.0]);
+        assert_eq!(wall.vertices[2].tex_coords, [1.0, 0.0]);
+        assert_eq!(wall.vertices[2].normal, [0.0, 0.0, 1.0]);
+
+        let roof = &model.meshes[1];
+        let positions = roof.vertices.iter().map(|vertex| vertex.position).collect::<Vec<_>>();
+        assert_eq!(positions, [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]]);
+        assert_eq!(roof.material, Some(0));
+
+        assert_eq!(model.materials.len(), 1);
+        assert_eq!(model.materials[0].name, "Brick");
+        match &model.materials[0].diffuse_texture.as_ref().unwrap().source {
+            TextureSource::Path(path) => assert_eq!(path, Path::new("res/tests/obj/brick wall.png")),
+            TextureSource::Embedded { .. } => panic!("expected a path"),
+        }
+    }
+
+    #[test]
+    fn texture_options_are_skipped() {
+        let file = |line: &str| texture_file(line.split_whitespace());
+        assert_eq!(file("brick.png").as_deref(), Some("brick.png"));
+        assert_eq!(file("-o 0.5 brick.png").as_deref(), Some("brick.png"));
+        assert_eq!(file("-s 1 2 3 -mm 0 1 -blendu off my brick.png").as_deref(), Some("my brick.png"));
+        assert_eq!(file("-clamp on"), None);
+        // Fewer numbers than an option can take
+        assert_eq!(file("-s 2 brick.png").as_deref(), Some("brick.png"));
+        assert_eq!(file("-o 0.5 -0.5 brick.png").as_deref(), Some("brick.png"));
+        assert_eq!(file("-mm 0.2 -t 1 1 bump.png").as_deref(), Some("bump.png"));
+        assert_eq!(file("-bm 2 -s 2 2 2 2.png").as_deref(), Some("2.png"));
+    }
+
+    #[test]
+    fn bad_index_names_the_line() {
+        let error = load_error("bad_index.obj");
+        assert!(error.starts_with("res/tests/obj/bad_index.obj:5: f 1 2 4"), "{}", error);
+        assert!(error.contains("position index 4 is out of range, 3 defined so far"), "{}", error);
+    }
+
+    #[test]
+    fn missing_mtllib_names_the_line() {
+        let error = load_error("missing_mtllib.obj");
+        assert!(error.starts_with("res/tests/obj/missing_mtllib.obj:2: mtllib nowhere.mtl"), "{}", error);
+        assert!(error.contains("Unable to read material library res/tests/obj/nowhere.mtl"), "{}", error);
+    }
+
+    #[test]
+    fn unknown_usemtl_names_the_line() {
+        let error = load_error("unknown_usemtl.obj");
+        assert!(error.starts_with("res/tests/obj/unknown_usemtl.obj:6: usemtl Marble"), "{}", error);
+        assert!(error.contains("Unknown material \"Marble\""), "{}", error);
+    }
+
+    #[test]
+    fn short_vertex_names_the_line() {
+        let error = load_error("short_vertex.obj");
+        assert!(error.starts_with("res/tests/obj/short_vertex.obj:2: v 1.0 0.0"), "{}", error);
+        assert!(error.contains("A vertex position needs 3 components, found 2"), "{}", error);
+    }
+}
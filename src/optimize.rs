@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use cgmath::{Vector3, InnerSpace, Zero};
+
+use crate::model::{MeshData, Vertex};
+
+// Size of the FIFO cache ACMR is measured against, on the small side of
+// what GPUs have
+const MEASURE_CACHE_SIZE: usize = 16;
+// LRU cache the triangle order is tuned for
+const CACHE_SIZE: usize = 32;
+const CACHE_DECAY_POWER: f32 = 1.5;
+const LAST_TRIANGLE_SCORE: f32 = 0.75;
+const VALENCE_BOOST_SCALE: f32 = 2.0;
+const VALENCE_BOOST_POWER: f32 = 0.5;
+// How much worse than the cache order the overdraw order may get
+const OVERDRAW_ACMR_THRESHOLD: f32 = 1.05;
+
+#[derive(Debug, Clone, Copy)]
+pub struct OptimizeStats {
+    pub vertices_before: usize,
+    pub vertices_after: usize,
+    pub acmr_before: f32,
+    pub acmr_after: f32,
+}
+
+/// Welds identical vertices, orders triangles for the post-transform vertex
+/// cache and then roughly front to back, and numbers vertices in the order
+/// they are first used. The triangles drawn, and the winding of each, stay
+/// the same.
+pub fn optimize(mesh: &mut MeshData) -> OptimizeStats {
+    let vertices_before = mesh.vertices.len();
+    let acmr_before = acmr(&mesh.indices, MEASURE_CACHE_SIZE);
+
+    weld_vertices(mesh);
+    optimize_vertex_cache(&mut mesh.indices, mesh.vertices.len());
+    optimize_overdraw(&mut mesh.indices, &mesh.vertices);
+    optimize_vertex_fetch(mesh);
+
+    OptimizeStats {
+        vertices_before,
+        vertices_after: mesh.vertices.len(),
+        acmr_before,
+        acmr_after: acmr(&mesh.indices, MEASURE_CACHE_SIZE),
+    }
+}
+
+/// Average cache miss ratio: vertices transformed per triangle with a FIFO
+/// cache of `cache_size`. 3 is the worst case, 0.5 the best a large regular
+/// grid can get.
+pub fn acmr(indices: &[u32], cache_size: usize) -> f32 {
+    if indices.len() < 3 {
+        return 0.0;
+    }
+    let mut cache: Vec<u32> = Vec::with_capacity(cache_size);
+    let mut next = 0;
+    let mut misses = 0;
+    for &index in indices {
+        if !cache.contains(&index) {
+            misses += 1;
+            if cache.len() < cache_size {
+                cache.push(index);
+            } else {
+                cache[next] = index;
+                next = (next + 1) % cache_size;
+            }
+        }
+    }
+    misses as f32 / (indices.len() / 3) as f32
+}
+
+/// Merges vertices whose every attribute, skinning and morph deltas
+/// included, is bit for bit the same.
+pub fn weld_vertices(mesh: &mut MeshData) {
+    let mut remap: HashMap<(&[u8], Vec<u8>), u32> = HashMap::new();
+    let mut vertices: Vec<Vertex> = Vec::with_capacity(mesh.vertices.len());
+    let mut sources = Vec::with_capacity(mesh.vertices.len());
+    let new_index = mesh.vertices.iter()
+        .enumerate()
+        .map(|(i, vertex)| {
+            let key = (bytemuck::bytes_of(vertex), mesh.vertex_data_bytes(i));
+            *remap.entry(key).or_insert_with(|| {
+                vertices.push(*vertex);
+                sources.push(i as u32);
+                vertices.len() as u32 - 1
+            })
+        })
+        .collect::<Vec<u32>>();
+    for index in &mut mesh.indices {
+        *index = new_index[*index as usize];
+    }
+    mesh.vertices = vertices;
+    mesh.remap_vertex_data(&sources);
+}
+
+/// Renumbers vertices in the order the indices first use them, dropping
+/// vertices nothing uses.
+pub fn optimize_vertex_fetch(mesh: &mut MeshData) {
+    let mut new_index = vec![u32::MAX; mesh.vertices.len()];
+    let mut vertices: Vec<Vertex> = Vec::with_capacity(mesh.vertices.len());
+    let mut sources = Vec::with_capacity(mesh.vertices.len());
+    for index in &mut mesh.indices {
+        let slot = &mut new_index[*index as usize];
+        if *slot == u32::MAX {
+            *slot = vertices.len() as u32;
+            vertices.push(mesh.vertices[*index as usize]);
+            sources.push(*index);
+        }
+        *index = *slot;
+    }
+    mesh.vertices = vertices;
+    mesh.remap_vertex_data(&sources);
+}
+
+/// Tom Forsyth's linear-speed vertex cache optimisation: greedily emits the
+/// triangle whose vertices score best, favouring vertices recently used and
+/// vertices with few triangles left. Indices past the last whole triangle
+/// stay where they are.
+pub fn optimize_vertex_cache(indices: &mut [u32], num_vertices: usize) {
+    let num_triangles = indices.len() / 3;
+    if num_triangles == 0 {
+        return;
+    }
+    let indices = &mut indices[..num_triangles * 3];
+
+    let mut triangles_of: Vec<Vec<usize>> = vec![Vec::new(); num_vertices];
+    for (triangle, corners) in indices.chunks_exact(3).enumerate() {
+        for &index in corners {
+            triangles_of[index as usize].push(triangle);
+        }
+    }
+    let mut vertex_score = (0..num_vertices)
+        .map(|vertex| forsyth_score(None, triangles_of[vertex].len()))
+        .collect::<Vec<f32>>();
+    let mut emitted = vec![false; num_triangles];
+    let mut output: Vec<u32> = Vec::with_capacity(indices.len());
+    let mut cache: Vec<u32> = Vec::with_capacity(CACHE_SIZE + 3);
+    // Where the scan for a fresh start picks up when nothing in the cache
+    // has triangles left
+    let mut cursor = 0;
+    let mut best = Some(0);
+
+    while output.len() < indices.len() {
+        let triangle = match best {
+            Some(triangle) => triangle,
+            None => {
+                while emitted[cursor] {
+                    cursor += 1;
+                }
+                cursor
+            }
+        };
+        let corners = triangle_corners(indices, triangle);
+        output.extend_from_slice(&corners);
+        emitted[triangle] = true;
+        for &index in &corners {
+            triangles_of[index as usize].retain(|&other| other != triangle);
+        }
+
+        // Most recently used first. Vertices pushed out lose their cache
+        // score, which only matters once they are back in the cache.
+        let mut new_cache = corners.to_vec();
+        new_cache.extend(cache.iter().filter(|index| !corners.contains(index)));
+        for (position, &index) in new_cache.iter().enumerate() {
+            let position = if position < CACHE_SIZE { Some(position) } else { None };
+            vertex_score[index as usize] = forsyth_score(position, triangles_of[index as usize].len());
+        }
+        new_cache.truncate(CACHE_SIZE);
+        cache = new_cache;
+
+        best = None;
+        let mut best_score = f32::MIN;
+        for &index in &cache {
+            for &other in &triangles_of[index as usize] {
+                let score = triangle_corners(indices, other).iter()
+                    .map(|&index| vertex_score[index as usize])
+                    .sum::<f32>();
+                if score > best_score {
+                    best_score = score;
+                    best = Some(other);
+                }
+            }
+        }
+    }
+    indices.copy_from_slice(&output);
+}
+
+fn triangle_corners(indices: &[u32], triangle: usize) -> [u32; 3] {
+    let corners = &indices[triangle * 3..triangle * 3 + 3];
+    [corners[0], corners[1], corners[2]]
+}
+
+fn forsyth_score(cache_position: Option<usize>, remaining_triangles: usize) -> f32 {
+    if remaining_triangles == 0 {
+        return -1.0;
+    }
+    let cache_score = match cache_position {
+        // The last triangle's vertices get a fixed score, so the next
+        // triangle doesn't just reuse its most recent edge
+        Some(position) if position < 3 => LAST_TRIANGLE_SCORE,
+        Some(position) => {
+            let scale = 1.0 / (CACHE_SIZE - 3) as f32;
+            (1.0 - (position - 3) as f32 * scale).powf(CACHE_DECAY_POWER)
+        }
+        None => 0.0,
+    };
+    cache_score + VALENCE_BOOST_SCALE * (remaining_triangles as f32).powf(-VALENCE_BOOST_POWER)
+}
+
+/// Splits cache ordered indices into clusters where the cache starts over
+/// and draws the clusters facing away from the mesh centre first, so
+/// outer surfaces tend to hide inner ones before they are shaded. Kept only
+/// if the cache efficiency doesn't suffer much. Indices past the last whole
+/// triangle stay where they are.
+pub fn optimize_overdraw(indices: &mut [u32], vertices: &[Vertex]) {
+    let num_triangles = indices.len() / 3;
+    if num_triangles < 2 {
+        return;
+    }
+    let indices = &mut indices[..num_triangles * 3];
+    let position = |index: u32| Vector3::from(vertices[index as usize].position);
+
+    // A triangle missing the cache on all three vertices starts a cluster
+    let mut starts = vec![0];
+    let mut cache: Vec<u32> = Vec::with_capacity(MEASURE_CACHE_SIZE);
+    let mut next = 0;
+    for (triangle, corners) in indices.chunks_exact(3).enumerate() {
+        let mut misses = 0;
+        for &index in corners {
+            if !cache.contains(&index) {
+                misses += 1;
+                if cache.len() < MEASURE_CACHE_SIZE {
+                    cache.push(index);
+                } else {
+                    cache[next] = index;
+                    next = (next + 1) % MEASURE_CACHE_SIZE;
+                }
+            }
+        }
+        if misses == 3 && triangle > 0 {
+            starts.push(triangle);
+        }
+    }
+    if starts.len() < 2 {
+        return;
+    }
+
+    let center = indices.iter().map(|&index| position(index)).sum::<Vector3<f32>>() / indices.len() as f32;
+    let mut clusters = starts.iter()
+        .zip(starts.iter().skip(1).chain(std::iter::once(&num_triangles)))
+        .map(|(&start, &end)| {
+            let mut normal = Vector3::zero();
+            let mut centroid = Vector3::zero();
+            let mut area = 0.0;
+            for corners in indices[start * 3..end * 3].chunks_exact(3) {
+                let (a, b, c) = (position(corners[0]), position(corners[1]), position(corners[2]));
+                let cross = (b - a).cross(c - a);
+                let triangle_area = cross.magnitude();
+                normal += cross;
+                centroid += (a + b + c) / 3.0 * triangle_area;
+                area += triangle_area;
+            }
+            let facing = if area > 0.0 && normal.magnitude2() > 0.0 {
+                (centroid / area - center).dot(normal.normalize())
+            } else {
+                0.0
+            };
+            (start, end, facing)
+        })
+        .collect::<Vec<_>>();
+    clusters.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+    let reordered = clusters.iter()
+        .flat_map(|&(start, end, _)| indices[start * 3..end * 3].iter().copied())
+        .collect::<Vec<u32>>();
+    if acmr(&reordered, MEASURE_CACHE_SIZE) <= acmr(indices, MEASURE_CACHE_SIZE) * OVERDRAW_ACMR_THRESHOLD {
+        indices.copy_from_slice(&reordered);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives;
+
+    // Each triangle as its corners' positions, starting from the smallest
+    // so the winding is kept, in sorted order
+    fn triangle_set(mesh: &MeshData) -> Vec<[[u32; 3]; 3]> {
+        let corner = |index: u32| {
+            let [x, y, z] = mesh.vertices[index as usize].position;
+            [x.to_bits(), y.to_bits(), z.to_bits()]
+        };
+        let mut triangles = mesh.indices.chunks_exact(3)
+            .map(|triangle| {
+                let corners = [corner(triangle[0]), corner(triangle[1]), corner(triangle[2])];
+                let first = (0..3).min_by_key(|&i| corners[i]).unwrap();
+                [corners[first], corners[(first + 1) % 3], corners[(first + 2) % 3]]
+            })
+            .collect::<Vec<_>>();
+        triangles.sort_unstable();
+        triangles
+    }
+
+    // A plane with every triangle given its own vertices, in a scattered
+    // order
+    fn scattered_grid() -> MeshData {
+        let mut grid = primitives::plane(1.0, 12);
+        let triangles = grid.indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect::<Vec<_>>();
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for k in 0..triangles.len() {
+            let triangle = triangles[k * 7 % triangles.len()];
+            for &index in &triangle {
+                indices.push(vertices.len() as u32);
+                vertices.push(grid.vertices[index as usize]);
+            }
+        }
+        grid.vertices = vertices;
+        grid.indices = indices;
+        grid
+    }
+
+    #[test]
+    fn optimize_keeps_the_triangles() {
+        let shapes = vec![
+            scattered_grid(),
+            primitives::uv_sphere(1.0, 24, 12),
+            primitives::capsule(0.5, 1.0, 16, 6),
+        ];
+        for mut mesh in shapes {
+            let before = triangle_set(&mesh);
+            let stats = optimize(&mut mesh);
+            assert_eq!(triangle_set(&mesh), before, "{}", mesh.name);
+            assert!(stats.acmr_after <= stats.acmr_before, "{}: {:?}", mesh.name, stats);
+            assert!(mesh.indices.iter().all(|&i| (i as usize) < mesh.vertices.len()));
+        }
+    }
+
+    #[test]
+    fn optimize_welds_and_reorders() {
+        let mut grid = scattered_grid();
+        let stats = optimize(&mut grid);
+        // 13 x 13 grid points, and a big drop from 3 misses a triangle
+        assert_eq!(stats.vertices_before, 12 * 12 * 6);
+        assert_eq!(stats.vertices_after, 13 * 13);
+        assert_eq!(stats.acmr_before, 3.0);
+        assert!(stats.acmr_after < 1.0, "{:?}", stats);
+        // Vertices are numbered in the order they are first used
+        let mut next = 0;
+        for &index in &grid.indices {
+            assert!(index <= next);
+            if index == next {
+                next += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn passes_leave_a_partial_triangle_alone() {
+        let sphere = primitives::uv_sphere(1.0, 8, 4);
+        let mut indices = sphere.indices.clone();
+        indices.extend_from_slice(&[1, 2]);
+        optimize_vertex_cache(&mut indices, sphere.vertices.len());
+        optimize_overdraw(&mut indices, &sphere.vertices);
+        assert_eq!(indices[indices.len() - 2..], [1, 2]);
+        let reordered = MeshData { indices: indices[..indices.len() - 2].to_vec(), ..sphere.clone() };
+        assert_eq!(triangle_set(&reordered), triangle_set(&sphere));
+    }
+}
@@ -0,0 +1,696 @@
+use std::mem::size_of;
+use std::time::Instant;
+use cgmath::Vector3;
+use serde::Deserialize;
+use wgpu::util::DeviceExt;
+use vertex_layout_derive::VertexLayout;
+use anyhow::*;
+
+use crate::texture::Texture;
+
+/// Keys each colour and size curve can have.
+pub const MAX_CURVE_KEYS: usize = 8;
+// Matches local_size_x in particles.comp
+const WORKGROUP_SIZE: u32 = 64;
+// Longer frames are simulated as if they took this long
+const MAX_FRAME_TIME: f32 = 0.25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ParticleBlend {
+    // Overlapping particles add up towards white, for sparks and fire
+    Additive,
+    // Blended over each other unsorted, for smoke
+    Alpha,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct EmitterDesc {
+
+    pub position: [f32; 3],
+    // Particles per second
+    #[serde(default = "EmitterDesc::default_spawn_rate")]
+    pub spawn_rate: f32,
+    // Seconds, each particle picks one in between
+    #[serde(default = "EmitterDesc::default_lifetime")]
+    pub lifetime: [f32; 2],
+    // Each component picked independently in between
+    #[serde(default = "EmitterDesc::default_velocity_min")]
+    pub velocity_min: [f32; 3],
+    #[serde(default = "EmitterDesc::default_velocity_max")]
+    pub velocity_max: [f32; 3],
+    #[serde(default = "EmitterDesc::default_gravity")]
+    pub gravity: [f32; 3],
+    // Keys spread evenly from birth to death, up to MAX_CURVE_KEYS
+    #[serde(default = "EmitterDesc::default_color")]
+    pub color: Vec<[f32; 4]>,
+    // World units across, keyed like `color`
+    #[serde(default = "EmitterDesc::default_size")]
+    pub size: Vec<f32>,
+    #[serde(default = "EmitterDesc::default_blend")]
+    pub blend: ParticleBlend,
+    // Once this many are alive, new particles replace the oldest
+    #[serde(default = "EmitterDesc::default_max_particles")]
+    pub max_particles: u32,
+    // Emitters with the same seed make the same particles
+    #[serde(default)]
+    pub seed: u32,
+
+} impl EmitterDesc {
+
+    fn default_spawn_rate() -> f32 { 100.0 }
+    fn default_lifetime() -> [f32; 2] { [1.0, 2.0] }
+    fn default_velocity_min() -> [f32; 3] { [-0.5, 1.0, -0.5] }
+    fn default_velocity_max() -> [f32; 3] { [0.5, 2.0, 0.5] }
+    fn default_gravity() -> [f32; 3] { [0.0, -9.81, 0.0] }
+    fn default_color() -> Vec<[f32; 4]> { vec![[1.0, 1.0, 1.0, 1.0], [1.0, 1.0, 1.0, 0.0]] }
+    fn default_size() -> Vec<f32> { vec![0.05] }
+    fn default_blend() -> ParticleBlend { ParticleBlend::Additive }
+    fn default_max_particles() -> u32 { 1024 }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.max_particles == 0 {
+            bail!("An emitter needs room for at least one particle");
+        }
+        if self.spawn_rate < 0.0 {
+            bail!("Spawn rate can't be negative, got {}", self.spawn_rate);
+        }
+        if !(self.lifetime[0] > 0.0 && self.lifetime[0] <= self.lifetime[1]) {
+            bail!("Lifetime has to be a positive range, got {:?}", self.lifetime);
+        }
+        for (name, len) in &[("colour", self.color.len()), ("size", self.size.len())] {
+            if *len == 0 || *len > MAX_CURVE_KEYS {
+                bail!("The {} curve needs 1 to {} keys, got {}", name, MAX_CURVE_KEYS, len);
+            }
+        }
+        Ok(())
+    }
+
+    /// Colour of a particle `t` of the way through its life.
+    pub fn color_at(&self, t: f32) -> [f32; 4] {
+        let (i, j, f) = curve_keys(self.color.len(), t);
+        let (a, b) = (self.color[i], self.color[j]);
+        [mix(a[0], b[0], f), mix(a[1], b[1], f), mix(a[2], b[2], f), mix(a[3], b[3], f)]
+    }
+
+    /// Size of a particle `t` of the way through its life.
+    pub fn size_at(&self, t: f32) -> f32 {
+        let (i, j, f) = curve_keys(self.size.len(), t);
+        mix(self.size[i], self.size[j], f)
+    }
+}
+
+// The two keys around `t` and how far between them it is, as particles.vert
+// samples them
+fn curve_keys(num_keys: usize, t: f32) -> (usize, usize, f32) {
+    let x = t.clamp(0.0, 1.0) * (num_keys - 1) as f32;
+    let i = (x as usize).min(num_keys - 1);
+    (i, (i + 1).min(num_keys - 1), x - i as f32)
+}
+
+// GLSL's mix
+fn mix(a: f32, b: f32, t: f32) -> f32 {
+    a * (1.0 - t) + b * t
+}
+
+/// One slot of an emitter's pool, laid out like `Particle` in
+/// particles.comp. Alive while `age` is below `lifetime`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, bytemuck::Pod, bytemuck::Zeroable, VertexLayout)]
+#[layout(step_mode = "Instance")]
+pub struct Particle {
+
+    #[layout(location = 0)]
+    pub position: [f32; 3],
+    #[layout(location = 1)]
+    pub age: f32,
+    #[layout(location = 2)]
+    pub velocity: [f32; 3],
+    #[layout(location = 3)]
+    pub lifetime: f32,
+
+} impl Particle {
+
+    pub fn is_alive(&self) -> bool {
+        self.age < self.lifetime
+    }
+}
+
+// Laid out like the Simulation uniform block in particles.comp
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct SimulationUniforms {
+    position: [f32; 4],
+    gravity: [f32; 4],
+    velocity_min: [f32; 4],
+    velocity_max: [f32; 4],
+    lifetime: [f32; 2],
+    dt: f32,
+    seed: u32,
+    // Slots spawn_start.. spawn_start + spawn_count, wrapping around the
+    // pool, get new particles, numbered from spawn_base
+    spawn_start: u32,
+    spawn_count: u32,
+    spawn_base: u32,
+    capacity: u32,
+}
+
+/// Decides which slots each step refills. Particles go into the pool in
+/// order, wrapping around, so a full pool replaces its oldest ones.
+#[derive(Debug, Clone, PartialEq)]
+struct Spawner {
+
+    // Fraction of a particle owed from earlier steps
+    accumulator: f32,
+    next_slot: u32,
+    // Particles spawned so far, wrapping
+    spawned: u32,
+
+} impl Spawner {
+
+    fn new() -> Self {
+        Self { accumulator: 0.0, next_slot: 0, spawned: 0 }
+    }
+
+    fn step(&mut self, desc: &EmitterDesc, dt: f32) -> SimulationUniforms {
+        self.accumulator += desc.spawn_rate * dt;
+        let owed = self.accumulator.floor();
+        self.accumulator -= owed;
+        let spawn_count = (owed as u32).min(desc.max_particles);
+        let uniforms = SimulationUniforms {
+            position: [desc.position[0], desc.position[1], desc.position[2], 1.0],
+            gravity: [desc.gravity[0], desc.gravity[1], desc.gravity[2], 0.0],
+            velocity_min: [desc.velocity_min[0], desc.velocity_min[1], desc.velocity_min[2], 0.0],
+            velocity_max: [desc.velocity_max[0], desc.velocity_max[1], desc.velocity_max[2], 0.0],
+            lifetime: desc.lifetime,
+            dt,
+            seed: desc.seed,
+            spawn_start: self.next_slot,
+            spawn_count,
+            spawn_base: self.spawned,
+            capacity: desc.max_particles,
+        };
+        self.next_slot = (self.next_slot + spawn_count) % desc.max_particles;
+        self.spawned = self.spawned.wrapping_add(spawn_count);
+        uniforms
+    }
+}
+
+// PCG hash, as in particles.comp
+fn hash(value: u32) -> u32 {
+    let state = value.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+// Uniform in [0, 1), the `k`th number for particle `id`
+fn random(id: u32, seed: u32, k: u32) -> f32 {
+    (hash(hash(id ^ seed).wrapping_add(k)) >> 8) as f32 / 16777216.0
+}
+
+// What particles.comp does for the slot at `index`
+fn simulate(particle: &mut Particle, index: u32, uniforms: &SimulationUniforms) {
+    let offset = (index + uniforms.capacity - uniforms.spawn_start) % uniforms.capacity;
+    if offset < uniforms.spawn_count {
+        let id = uniforms.spawn_base.wrapping_add(offset);
+        for axis in 0..3 {
+            let t = random(id, uniforms.seed, axis as u32);
+            particle.velocity[axis] = mix(uniforms.velocity_min[axis], uniforms.velocity_max[axis], t);
+            particle.position[axis] = uniforms.position[axis];
+        }
+        particle.age = 0.0;
+        particle.lifetime = mix(uniforms.lifetime[0], uniforms.lifetime[1], random(id, uniforms.seed, 3));
+    } else if particle.is_alive() {
+        for axis in 0..3 {
+            particle.velocity[axis] += uniforms.gravity[axis] * uniforms.dt;
+            particle.position[axis] += particle.velocity[axis] * uniforms.dt;
+        }
+        particle.age += uniforms.dt;
+    }
+}
+
+/// The simulation the compute shader runs, on the CPU: stepped with the same
+/// time steps from the same desc, it spawns the same particles into the
+/// same slots and moves them the same way, up to float rounding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuParticles {
+
+    desc: EmitterDesc,
+    spawner: Spawner,
+    particles: Vec<Particle>,
+
+} impl CpuParticles {
+
+    pub fn new(desc: EmitterDesc) -> Result<Self> {
+        desc.validate()?;
+        let particles = vec![Particle::default(); desc.max_particles as usize];
+        Ok(Self { desc, spawner: Spawner::new(), particles })
+    }
+
+    pub fn step(&mut self, dt: f32) {
+        let uniforms = self.spawner.step(&self.desc, dt);
+        for (index, particle) in self.particles.iter_mut().enumerate() {
+            simulate(particle, index as u32, &uniforms);
+        }
+    }
+
+    pub fn desc(&self) -> &EmitterDesc { &self.desc }
+    pub fn particles(&self) -> &[Particle] { &self.particles }
+
+    pub fn num_alive(&self) -> usize {
+        self.particles.iter().filter(|particle| particle.is_alive()).count()
+    }
+}
+
+// Laid out like the Appearance uniform block in particles.vert
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct AppearanceUniforms {
+    right: [f32; 4],
+    up: [f32; 4],
+    colors: [[f32; 4]; MAX_CURVE_KEYS],
+    // Four keys to each element
+    sizes: [[f32; 4]; MAX_CURVE_KEYS / 4],
+    num_colors: u32,
+    num_sizes: u32,
+    _padding: [u32; 2],
+}
+
+/// One emitter's pool of particles on the GPU.
+pub struct ParticleSystem {
+
+    desc: EmitterDesc,
+    spawner: Spawner,
+    particle_buffer: wgpu::Buffer,
+    simulation_buffer: wgpu::Buffer,
+    simulation_bind_group: wgpu::BindGroup,
+    appearance_buffer: wgpu::Buffer,
+    appearance_bind_group: wgpu::BindGroup,
+
+} impl ParticleSystem {
+
+    pub fn desc(&self) -> &EmitterDesc { &self.desc }
+
+    fn appearance(&self, right: Vector3<f32>, up: Vector3<f32>) -> AppearanceUniforms {
+        let mut colors = [[0.0; 4]; MAX_CURVE_KEYS];
+        colors[..self.desc.color.len()].copy_from_slice(&self.desc.color);
+        let mut sizes = [[0.0; 4]; MAX_CURVE_KEYS / 4];
+        for (i, &size) in self.desc.size.iter().enumerate() {
+            sizes[i / 4][i % 4] = size;
+        }
+        AppearanceUniforms {
+            right: [right.x, right.y, right.z, 0.0],
+            up: [up.x, up.y, up.z, 0.0],
+            colors,
+            sizes,
+            num_colors: self.desc.color.len() as u32,
+            num_sizes: self.desc.size.len() as u32,
+            _padding: [0; 2],
+        }
+    }
+}
+
+/// The emitters in the scene and the pipelines that simulate and draw them.
+/// Particles are drawn after everything opaque, tested against the depth
+/// buffer without writing to it.
+pub struct ParticleState {
+
+    simulation_layout: wgpu::BindGroupLayout,
+    appearance_layout: wgpu::BindGroupLayout,
+    compute_pipeline: wgpu::ComputePipeline,
+    additive_pipeline: wgpu::RenderPipeline,
+    alpha_pipeline: wgpu::RenderPipeline,
+    systems: Vec<ParticleSystem>,
+    last_update: Instant,
+
+} impl ParticleState {
+
+    pub fn new(
+        device: &wgpu::Device,
+        camera_layout: &wgpu::BindGroupLayout,
+        color_format: wgpu::TextureFormat,
+    ) -> Self {
+        let simulation_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<Particle>() as u64),
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<SimulationUniforms>() as u64),
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("Particle Simulation Bind Group Layout"),
+        });
+        let appearance_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<AppearanceUniforms>() as u64),
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("Particle Appearance Bind Group Layout"),
+        });
+
+        let compute_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Particle Compute Pipeline Layout"),
+            bind_group_layouts: &[&simulation_layout],
+            push_constant_ranges: &[],
+        });
+        let cs_module = device.create_shader_module(&wgpu::include_spirv!("particles.comp.spv"));
+        let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Particle Compute Pipeline"),
+            layout: Some(&compute_layout),
+            module: &cs_module,
+            entry_point: "main",
+        });
+
+        let render_layouts = [&appearance_layout, camera_layout];
+        let additive_pipeline = Self::create_render_pipeline(device, &render_layouts, color_format, ParticleBlend::Additive);
+        let alpha_pipeline = Self::create_render_pipeline(device, &render_layouts, color_format, ParticleBlend::Alpha);
+        Self {
+            simulation_layout,
+            appearance_layout,
+            compute_pipeline,
+            additive_pipeline,
+            alpha_pipeline,
+            systems: Vec::new(),
+            last_update: Instant::now(),
+        }
+    }
+
+    fn create_render_pipeline(
+        device: &wgpu::Device,
+        bind_group_layouts: &[&wgpu::BindGroupLayout],
+        color_format: wgpu::TextureFormat,
+        blend: ParticleBlend,
+    ) -> wgpu::RenderPipeline {
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Particle Pipeline Layout"),
+            bind_group_layouts,
+            push_constant_ranges: &[],
+        });
+        let vs_module = device.create_shader_module(&wgpu::include_spirv!("particles.vert.spv"));
+        let fs_module = device.create_shader_module(&wgpu::include_spirv!("particles.frag.spv"));
+        let (label, dst_factor) = match blend {
+            ParticleBlend::Additive => ("Additive Particle Pipeline", wgpu::BlendFactor::One),
+            ParticleBlend::Alpha => ("Alpha Particle Pipeline", wgpu::BlendFactor::OneMinusSrcAlpha),
+        };
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(label),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &vs_module,
+                entry_point: "main",
+                buffers: &[Particle::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &fs_module,
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format: color_format,
+                    alpha_blend: wgpu::BlendState {
+                        src_factor: wgpu::BlendFactor::One,
+                        dst_factor,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                    color_blend: wgpu::BlendState {
+                        src_factor: wgpu::BlendFactor::SrcAlpha,
+                        dst_factor,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+                clamp_depth: false,
+            }),
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+        })
+    }
+
+    /// Empty pools for `descs`, for `set_systems`.
+    pub fn create_systems(&self, device: &wgpu::Device, descs: &[EmitterDesc]) -> Result<Vec<ParticleSystem>> {
+        descs.iter()
+            .enumerate()
+            .map(|(i, desc)| {
+                desc.validate().with_context(|| format!("Emitter {}", i))?;
+                Ok(self.create_system(device, desc.clone()))
+            })
+            .collect()
+    }
+
+    fn create_system(&self, device: &wgpu::Device, desc: EmitterDesc) -> ParticleSystem {
+        let particles = vec![Particle::default(); desc.max_particles as usize];
+        let particle_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Particle Buffer"),
+                contents: bytemuck::cast_slice(&particles),
+                usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::VERTEX,
+            }
+        );
+        let simulation_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Particle Simulation Buffer"),
+            size: size_of::<SimulationUniforms>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let simulation_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.simulation_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: particle_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: simulation_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("Particle Simulation Bind Group"),
+        });
+        let appearance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Particle Appearance Buffer"),
+            size: size_of::<AppearanceUniforms>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let appearance_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.appearance_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: appearance_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("Particle Appearance Bind Group"),
+        });
+        ParticleSystem {
+            desc,
+            spawner: Spawner::new(),
+            particle_buffer,
+            simulation_buffer,
+            simulation_bind_group,
+            appearance_buffer,
+            appearance_bind_group,
+        }
+    }
+
+    pub fn set_systems(&mut self, systems: Vec<ParticleSystem>) {
+        self.systems = systems;
+    }
+
+    pub fn systems(&self) -> &[ParticleSystem] {
+        &self.systems
+    }
+
+    /// Works out this frame's step from the time since the last call and
+    /// uploads it, for `compute` to run. `right` and `up` are the camera's,
+    /// from `CameraState::billboard_axes`.
+    pub fn update(&mut self, queue: &wgpu::Queue, right: Vector3<f32>, up: Vector3<f32>) {
+        let now = Instant::now();
+        let dt = (now - self.last_update).as_secs_f32().min(MAX_FRAME_TIME);
+        self.last_update = now;
+        for system in &mut self.systems {
+            let simulation = system.spawner.step(&system.desc, dt);
+            queue.write_buffer(&system.simulation_buffer, 0, bytemuck::bytes_of(&simulation));
+            queue.write_buffer(&system.appearance_buffer, 0, bytemuck::bytes_of(&system.appearance(right, up)));
+        }
+    }
+
+    /// Steps every emitter. Has to be recorded before the render pass that
+    /// draws them.
+    pub fn compute(&self, encoder: &mut wgpu::CommandEncoder) {
+        if self.systems.is_empty() {
+            return;
+        }
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Particle Compute Pass"),
+        });
+        compute_pass.set_pipeline(&self.compute_pipeline);
+        for system in &self.systems {
+            compute_pass.set_bind_group(0, &system.simulation_bind_group, &[]);
+            compute_pass.dispatch((system.desc.max_particles - 1) / WORKGROUP_SIZE + 1, 1, 1);
+        }
+    }
+
+    /// One quad per slot; dead particles are collapsed by the vertex shader.
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, camera_bind_group: &'a wgpu::BindGroup) {
+        for system in &self.systems {
+            render_pass.set_pipeline(match system.desc.blend {
+                ParticleBlend::Additive => &self.additive_pipeline,
+                ParticleBlend::Alpha => &self.alpha_pipeline,
+            });
+            render_pass.set_bind_group(0, &system.appearance_bind_group, &[]);
+            render_pass.set_bind_group(1, camera_bind_group, &[]);
+            render_pass.set_vertex_buffer(0, system.particle_buffer.slice(..));
+            render_pass.draw(0..6, 0..system.desc.max_particles);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emitter(fields: &str) -> EmitterDesc {
+        ron::from_str(&format!("(position: (1.0, 2.0, 3.0), {})", fields)).unwrap()
+    }
+
+    fn alive_slots(particles: &CpuParticles) -> Vec<usize> {
+        (0..particles.particles().len()).filter(|&i| particles.particles()[i].is_alive()).collect()
+    }
+
+    #[test]
+    fn spawns_fill_slots_in_order_and_carry_fractions() {
+        let mut particles = CpuParticles::new(emitter("spawn_rate: 10.0, lifetime: (100.0, 100.0), max_particles: 8")).unwrap();
+        assert_eq!(particles.num_alive(), 0);
+        // 2.5 owed, 2 spawned and half a particle carried over
+        particles.step(0.25);
+        assert_eq!(alive_slots(&particles), [0, 1]);
+        particles.step(0.25);
+        assert_eq!(alive_slots(&particles), [0, 1, 2, 3, 4]);
+        // New particles start at the emitter
+        assert_eq!(particles.particles()[4].position, [1.0, 2.0, 3.0]);
+        assert_eq!(particles.particles()[4].age, 0.0);
+
+        // Past the end the pool wraps round and replaces the oldest
+        particles.step(0.5);
+        assert_eq!(particles.num_alive(), 8);
+        let ages = particles.particles().iter().map(|particle| particle.age).collect::<Vec<_>>();
+        assert_eq!(ages, [0.0, 0.0, 0.5, 0.5, 0.5, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn spawns_are_capped_at_the_pool() {
+        let mut particles = CpuParticles::new(emitter("spawn_rate: 1000.0, max_particles: 4")).unwrap();
+        particles.step(1.0);
+        assert_eq!(particles.num_alive(), 4);
+        assert!(CpuParticles::new(emitter("max_particles: 0")).is_err());
+        assert!(CpuParticles::new(emitter("lifetime: (2.0, 1.0)")).is_err());
+    }
+
+    #[test]
+    fn velocities_fall_with_gravity() {
+        let desc = "spawn_rate: 4.0, lifetime: (100.0, 100.0), max_particles: 1, \
+            velocity_min: (1.0, 2.0, 0.0), velocity_max: (1.0, 2.0, 0.0), gravity: (0.0, -10.0, 0.0)";
+        let mut particles = CpuParticles::new(emitter(desc)).unwrap();
+        let dt = 0.25;
+        // One particle from the first step, then no more
+        particles.step(dt);
+        let mut expected = particles.particles()[0];
+        assert_eq!(expected.velocity, [1.0, 2.0, 0.0]);
+        particles.desc.spawn_rate = 0.0;
+        for _ in 0..8 {
+            particles.step(dt);
+            // Velocity first, then position, as particles.comp does
+            expected.velocity[1] += -10.0 * dt;
+            for axis in 0..3 {
+                expected.position[axis] += expected.velocity[axis] * dt;
+            }
+        }
+        let particle = particles.particles()[0];
+        assert_eq!(particle.velocity, [1.0, -18.0, 0.0]);
+        for axis in 0..3 {
+            assert!((particle.position[axis] - expected.position[axis]).abs() < 1e-5, "{:?}", particle);
+        }
+        // x moved 2 units in 2 seconds; y is 2 + 2 * 2 - 10 * 0.25^2 * (8 * 9 / 2)
+        assert!((particle.position[0] - 3.0).abs() < 1e-5);
+        assert!((particle.position[1] - (2.0 + 4.0 - 22.5)).abs() < 1e-4);
+        assert!((particle.age - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn particles_die_at_their_lifetime() {
+        let mut particles = CpuParticles::new(emitter("spawn_rate: 1.0, lifetime: (2.5, 2.5), max_particles: 16")).unwrap();
+        // One a second, each living through ages 0, 1 and 2
+        for expected in &[1, 2, 3, 3, 3] {
+            particles.step(1.0);
+            assert_eq!(particles.num_alive(), *expected);
+        }
+        assert_eq!(alive_slots(&particles), [2, 3, 4]);
+        // Dead particles stay where they stopped
+        let dead = particles.particles()[0];
+        particles.step(1.0);
+        assert_eq!(particles.particles()[0], dead);
+        assert!(dead.age >= dead.lifetime);
+    }
+
+    #[test]
+    fn seeds_pick_the_particles() {
+        let run = |seed: u32| {
+            let mut particles = CpuParticles::new(emitter(&format!("seed: {}, max_particles: 64", seed))).unwrap();
+            for _ in 0..10 {
+                particles.step(0.05);
+            }
+            particles
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7).particles(), run(8).particles());
+        // Lifetimes and starting velocities stay within their ranges
+        let desc = emitter("gravity: (0.0, 0.0, 0.0)");
+        let mut spawned = CpuParticles::new(desc.clone()).unwrap();
+        spawned.step(0.5);
+        assert_eq!(spawned.num_alive(), 50);
+        for particle in spawned.particles().iter().filter(|particle| particle.is_alive()) {
+            assert!(particle.lifetime >= desc.lifetime[0] && particle.lifetime <= desc.lifetime[1]);
+            for axis in 0..3 {
+                assert!(particle.velocity[axis] >= desc.velocity_min[axis] && particle.velocity[axis] <= desc.velocity_max[axis]);
+            }
+        }
+    }
+}
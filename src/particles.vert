@@ -0,0 +1,62 @@
+// particles.vert
+#version 450
+
+layout(location=0) in vec3 a_position;
+layout(location=1) in float a_age;
+layout(location=2) in vec3 a_velocity;
+layout(location=3) in float a_lifetime;
+
+layout(location=0) out vec2 v_corner;
+layout(location=1) out vec4 v_color;
+
+layout(set=0, binding=0)
+uniform Appearance {
+    vec4 u_right;
+    vec4 u_up;
+    vec4 u_colors[8];
+    // Four keys to each element
+    vec4 u_sizes[2];
+    uint u_num_colors;
+    uint u_num_sizes;
+};
+
+layout(set=1, binding=0)
+uniform Camera {
+    mat4 u_view_proj;
+};
+
+const vec2 CORNERS[6] = vec2[6](
+    vec2(-1.0, -1.0), vec2(1.0, -1.0), vec2(-1.0, 1.0),
+    vec2(-1.0, 1.0), vec2(1.0, -1.0), vec2(1.0, 1.0)
+);
+
+// Keys spread evenly over t from 0 to 1
+vec4 sample_color(float t) {
+    float x = clamp(t, 0.0, 1.0) * float(u_num_colors - 1u);
+    uint i = min(uint(x), u_num_colors - 1u);
+    uint j = min(i + 1u, u_num_colors - 1u);
+    return mix(u_colors[i], u_colors[j], x - float(i));
+}
+
+float sample_size(float t) {
+    float x = clamp(t, 0.0, 1.0) * float(u_num_sizes - 1u);
+    uint i = min(uint(x), u_num_sizes - 1u);
+    uint j = min(i + 1u, u_num_sizes - 1u);
+    return mix(u_sizes[i / 4u][i % 4u], u_sizes[j / 4u][j % 4u], x - float(i));
+}
+
+void main() {
+    vec2 corner = CORNERS[gl_VertexIndex];
+    v_corner = corner;
+    // Dead particles
+    if (a_age >= a_lifetime) {
+        v_color = vec4(0.0);
+        gl_Position = vec4(0.0, 0.0, 2.0, 1.0);
+        return;
+    }
+    float t = a_age / a_lifetime;
+    v_color = sample_color(t);
+    float half_size = sample_size(t) * 0.5;
+    vec3 position = a_position + (u_right.xyz * corner.x + u_up.xyz * corner.y) * half_size;
+    gl_Position = u_view_proj * vec4(position, 1.0);
+}
@@ -0,0 +1,894 @@
+use std::time::Instant;
+use cgmath::{Vector3, Quaternion, InnerSpace, ElementWise, Zero};
+use serde::Deserialize;
+use anyhow::*;
+
+use crate::instance::Instance;
+
+const MAX_FRAME_TIME: f32 = 0.25;
+const SOLVER_ITERATIONS: usize = 10;
+// Penetration allowed before positions get corrected, so resting contacts
+// don't jitter
+const PENETRATION_SLOP: f32 = 0.005;
+const CORRECTION_PERCENT: f32 = 0.8;
+// Impacts slower than this don't bounce
+const RESTITUTION_THRESHOLD: f32 = 0.5;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PhysicsDesc {
+
+    #[serde(default = "PhysicsDesc::default_gravity")]
+    pub gravity: [f32; 3],
+    // Seconds per step
+    #[serde(default = "PhysicsDesc::default_timestep")]
+    pub timestep: f32,
+    #[serde(default = "PhysicsDesc::default_ground")]
+    pub ground: Option<GroundDesc>,
+
+} impl PhysicsDesc {
+    fn default_gravity() -> [f32; 3] { [0.0, -9.81, 0.0] }
+    fn default_timestep() -> f32 { 1.0 / 60.0 }
+    fn default_ground() -> Option<GroundDesc> { Some(GroundDesc::default()) }
+}
+
+impl Default for PhysicsDesc {
+    fn default() -> Self {
+        Self {
+            gravity: Self::default_gravity(),
+            timestep: Self::default_timestep(),
+            ground: Self::default_ground(),
+        }
+    }
+}
+
+/// Infinite horizontal plane at `height`, facing +y.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GroundDesc {
+
+    #[serde(default)]
+    pub height: f32,
+    #[serde(default = "default_restitution")]
+    pub restitution: f32,
+    #[serde(default = "default_friction")]
+    pub friction: f32,
+
+}
+
+impl Default for GroundDesc {
+    fn default() -> Self {
+        Self { height: 0.0, restitution: default_restitution(), friction: default_friction() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum Shape {
+    Sphere { radius: f32 },
+    Box { half_extents: [f32; 3] },
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BodyDesc {
+
+    pub shape: Shape,
+    // Zero makes the body static
+    #[serde(default = "BodyDesc::default_mass")]
+    pub mass: f32,
+    #[serde(default = "default_restitution")]
+    pub restitution: f32,
+    #[serde(default = "default_friction")]
+    pub friction: f32,
+    #[serde(default)]
+    pub velocity: [f32; 3],
+
+} impl BodyDesc {
+    fn default_mass() -> f32 { 1.0 }
+
+    /// Moving bodies need a size to have inertia.
+    pub fn validate(&self) -> Result<()> {
+        if self.mass < 0.0 {
+            bail!("Mass can't be negative, got {}", self.mass);
+        }
+        let has_size = match self.shape {
+            Shape::Sphere { radius } => radius > 0.0,
+            Shape::Box { half_extents } => half_extents.iter().all(|&half| half > 0.0),
+        };
+        if self.mass > 0.0 && !has_size {
+            bail!("A body with mass needs a positive size, got {:?}", self.shape);
+        }
+        Ok(())
+    }
+}
+
+fn default_restitution() -> f32 { 0.3 }
+fn default_friction() -> f32 { 0.5 }
+
+/// A body drives the transform of one instance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Body {
+
+    instance: usize,
+    shape: Shape,
+    position: Vector3<f32>,
+    rotation: Quaternion<f32>,
+    velocity: Vector3<f32>,
+    angular_velocity: Vector3<f32>,
+    inverse_mass: f32,
+    // Diagonal of the inverse inertia tensor in body space
+    inverse_inertia: Vector3<f32>,
+    restitution: f32,
+    friction: f32,
+
+} impl Body {
+
+    pub fn new(desc: &BodyDesc, instance_index: usize, instance: &Instance) -> Result<Self> {
+        desc.validate()?;
+        let (inverse_mass, inverse_inertia) = if desc.mass > 0.0 {
+            let inertia = match desc.shape {
+                Shape::Sphere { radius } => {
+                    let i = 0.4 * desc.mass * radius * radius;
+                    Vector3::new(i, i, i)
+                }
+                Shape::Box { half_extents: [x, y, z] } => {
+                    let (x2, y2, z2) = (4.0 * x * x, 4.0 * y * y, 4.0 * z * z);
+                    Vector3::new(y2 + z2, x2 + z2, x2 + y2) * (desc.mass / 12.0)
+                }
+            };
+            (1.0 / desc.mass, Vector3::new(1.0 / inertia.x, 1.0 / inertia.y, 1.0 / inertia.z))
+        } else {
+            (0.0, Vector3::zero())
+        };
+        Ok(Self {
+            instance: instance_index,
+            shape: desc.shape,
+            position: instance.position(),
+            rotation: instance.rotation(),
+            velocity: desc.velocity.into(),
+            angular_velocity: Vector3::zero(),
+            inverse_mass,
+            inverse_inertia,
+            restitution: desc.restitution,
+            friction: desc.friction,
+        })
+    }
+
+    fn is_dynamic(&self) -> bool { self.inverse_mass > 0.0 }
+
+    // Inverse world space inertia tensor applied to `v`
+    fn apply_inverse_inertia(&self, v: Vector3<f32>) -> Vector3<f32> {
+        let local = self.rotation.conjugate() * v;
+        self.rotation * local.mul_element_wise(self.inverse_inertia)
+    }
+
+    fn velocity_at(&self, r: Vector3<f32>) -> Vector3<f32> {
+        self.velocity + self.angular_velocity.cross(r)
+    }
+
+    fn apply_impulse(&mut self, impulse: Vector3<f32>, r: Vector3<f32>) {
+        self.velocity += impulse * self.inverse_mass;
+        self.angular_velocity += self.apply_inverse_inertia(r.cross(impulse));
+    }
+
+    // Resistance of the body to an impulse along `direction` applied at `r`
+    fn inverse_effective_mass(&self, r: Vector3<f32>, direction: Vector3<f32>) -> f32 {
+        let rn = r.cross(direction);
+        self.inverse_mass + self.apply_inverse_inertia(rn).cross(r).dot(direction)
+    }
+
+    fn corners(&self, half_extents: [f32; 3]) -> [Vector3<f32>; 8] {
+        let [x, y, z] = half_extents;
+        let mut corners = [Vector3::zero(); 8];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            let local = Vector3::new(
+                if i & 1 == 0 { -x } else { x },
+                if i & 2 == 0 { -y } else { y },
+                if i & 4 == 0 { -z } else { z },
+            );
+            *corner = self.position + self.rotation * local;
+        }
+        corners
+    }
+}
+
+struct Contact {
+
+    a: usize,
+    // None is the ground
+    b: Option<usize>,
+    // Points from b towards a
+    normal: Vector3<f32>,
+    depth: f32,
+    r_a: Vector3<f32>,
+    r_b: Vector3<f32>,
+    tangents: [Vector3<f32>; 2],
+    normal_mass: f32,
+    tangent_mass: [f32; 2],
+    // Normal velocity the solver aims for, from restitution
+    target_velocity: f32,
+    friction: f32,
+    normal_impulse: f32,
+    tangent_impulse: [f32; 2],
+
+}
+
+pub struct PhysicsState {
+
+    desc: PhysicsDesc,
+    bodies: Vec<Body>,
+    accumulator: f32,
+    last_update: Instant,
+
+} impl PhysicsState {
+
+    pub fn new(desc: PhysicsDesc, bodies: Vec<Body>) -> Self {
+        Self { desc, bodies, accumulator: 0.0, last_update: Instant::now() }
+    }
+
+    pub fn set_desc(&mut self, desc: PhysicsDesc) {
+        self.desc = desc;
+    }
+
+    pub fn set_bodies(&mut self, bodies: Vec<Body>) {
+        self.bodies = bodies;
+        self.accumulator = 0.0;
+    }
+
+    /// Advances the simulation by however many fixed steps fit in the time
+    /// since the last call and writes the bodies back into their instances.
+    pub fn update(&mut self, instances: &mut [Instance]) {
+        let now = Instant::now();
+        let elapsed = (now - self.last_update).as_secs_f32().min(MAX_FRAME_TIME);
+        self.last_update = now;
+        if self.bodies.is_empty() || self.desc.timestep <= 0.0 {
+            return;
+        }
+
+        self.accumulator += elapsed;
+        while self.accumulator >= self.desc.timestep {
+            self.step();
+            self.accumulator -= self.desc.timestep;
+        }
+        self.write_instances(instances);
+    }
+
+    pub fn write_instances(&self, instances: &mut [Instance]) {
+        for body in &self.bodies {
+            if let Some(instance) = instances.get_mut(body.instance) {
+                instance.set_position(body.position);
+                instance.set_rotation(body.rotation);
+            }
+        }
+    }
+
+    /// One fixed step: integrate velocities, solve contacts with sequential
+    /// impulses, integrate positions, then push apart what still overlaps.
+    pub fn step(&mut self) {
+        let dt = self.desc.timestep;
+        let gravity = Vector3::from(self.desc.gravity);
+        for body in self.bodies.iter_mut().filter(|body| body.is_dynamic()) {
+            body.velocity += gravity * dt;
+        }
+
+        let mut contacts = self.find_contacts();
+        for contact in &mut contacts {
+            self.prepare_contact(contact);
+        }
+        for _ in 0..SOLVER_ITERATIONS {
+            for contact in &mut contacts {
+                self.solve_contact(contact);
+            }
+        }
+
+        for body in self.bodies.iter_mut().filter(|body| body.is_dynamic()) {
+            body.position += body.velocity * dt;
+            let spin = Quaternion::from_sv(0.0, body.angular_velocity) * body.rotation;
+            body.rotation = (body.rotation + spin * (0.5 * dt)).normalize();
+        }
+
+        for contact in &contacts {
+            self.correct_position(contact);
+        }
+    }
+
+    fn find_contacts(&self) -> Vec<Contact> {
+        let mut contacts = Vec::new();
+        if let Some(ground) = &self.desc.ground {
+            for (i, body) in self.bodies.iter().enumerate().filter(|(_, body)| body.is_dynamic()) {
+                ground_contacts(i, body, ground, &mut contacts);
+            }
+        }
+        for i in 0..self.bodies.len() {
+            for j in (i + 1)..self.bodies.len() {
+                let (a, b) = (&self.bodies[i], &self.bodies[j]);
+                if a.is_dynamic() || b.is_dynamic() {
+                    body_contacts(i, a, j, b, &mut contacts);
+                }
+            }
+        }
+        contacts
+    }
+
+    fn prepare_contact(&self, contact: &mut Contact) {
+        let a = &self.bodies[contact.a];
+        let b = contact.b.map(|b| &self.bodies[b]);
+        let (restitution, friction) = match (b, &self.desc.ground) {
+            (Some(b), _) => (a.restitution.max(b.restitution), (a.friction * b.friction).sqrt()),
+            (None, Some(ground)) => (
+                a.restitution.max(ground.restitution),
+                (a.friction * ground.friction).sqrt(),
+            ),
+            (None, None) => (a.restitution, a.friction),
+        };
+
+        let (r_a, r_b) = (contact.r_a, contact.r_b);
+        let inverse_mass = |direction: Vector3<f32>| {
+            a.inverse_effective_mass(r_a, direction)
+                + b.map_or(0.0, |b| b.inverse_effective_mass(r_b, direction))
+        };
+        contact.normal_mass = 1.0 / inverse_mass(contact.normal);
+        let tangents = contact.tangents;
+        contact.tangent_mass = [1.0 / inverse_mass(tangents[0]), 1.0 / inverse_mass(tangents[1])];
+
+        // Bounce with the speed the bodies had when they first touched, not
+        // the extra speed gravity added this step. They also start the
+        // bounce sunk `depth` into each other, so the speed leaving is
+        // solved for: the flight after the step has to peak restitution
+        // squared as high as the one before it. Semi-implicit Euler keeps
+        // v^2 / 2 + g y - g dt v / 2 constant in flight rather than the
+        // plain energy, so that is what's matched. Anything simpler gains or
+        // loses height on every bounce.
+        let approach = self.relative_velocity(contact).dot(contact.normal);
+        contact.target_velocity = if approach < -RESTITUTION_THRESHOLD {
+            // Two falling bodies don't accelerate towards each other
+            let gravity = match b {
+                Some(b) if b.is_dynamic() => 0.0,
+                _ => Vector3::from(self.desc.gravity).dot(contact.normal),
+            };
+            let (dt, depth) = (self.desc.timestep, contact.depth);
+            let impact = -(approach - gravity * dt).min(0.0);
+            // Twice the height the flight before peaked at above the
+            // surface, times g, and what the flight after has to reach
+            let before = impact * impact + 2.0 * gravity * depth - gravity * dt * impact;
+            let after = restitution * restitution * before - 2.0 * gravity * depth;
+            0.5 * gravity * dt + (0.25 * gravity * gravity * dt * dt + after).max(0.0).sqrt()
+        } else {
+            0.0
+        };
+        contact.friction = friction;
+    }
+
+    fn relative_velocity(&self, contact: &Contact) -> Vector3<f32> {
+        let a = &self.bodies[contact.a];
+        let velocity_b = contact.b.map_or(Vector3::zero(), |b| self.bodies[b].velocity_at(contact.r_b));
+        a.velocity_at(contact.r_a) - velocity_b
+    }
+
+    fn apply_impulse(&mut self, contact: &Contact, impulse: Vector3<f32>) {
+        self.bodies[contact.a].apply_impulse(impulse, contact.r_a);
+        if let Some(b) = contact.b {
+            self.bodies[b].apply_impulse(-impulse, contact.r_b);
+        }
+    }
+
+    fn solve_contact(&mut self, contact: &mut Contact) {
+        // Normal impulse, accumulated and kept pushing only
+        let velocity = self.relative_velocity(contact).dot(contact.normal);
+        let delta = contact.normal_mass * (contact.target_velocity - velocity);
+        let total = (contact.normal_impulse + delta).max(0.0);
+        let delta = total - contact.normal_impulse;
+        contact.normal_impulse = total;
+        self.apply_impulse(contact, contact.normal * delta);
+
+        // Coulomb friction, bounded by the normal impulse
+        let limit = contact.friction * contact.normal_impulse;
+        for k in 0..2 {
+            let tangent = contact.tangents[k];
+            let velocity = self.relative_velocity(contact).dot(tangent);
+            let delta = -contact.tangent_mass[k] * velocity;
+            let total = (contact.tangent_impulse[k] + delta).max(-limit).min(limit);
+            let delta = total - contact.tangent_impulse[k];
+            contact.tangent_impulse[k] = total;
+            self.apply_impulse(contact, tangent * delta);
+        }
+    }
+
+    fn correct_position(&mut self, contact: &Contact) {
+        let inverse_mass_a = self.bodies[contact.a].inverse_mass;
+        let inverse_mass_b = contact.b.map_or(0.0, |b| self.bodies[b].inverse_mass);
+        let inverse_mass = inverse_mass_a + inverse_mass_b;
+        let depth = contact.depth - PENETRATION_SLOP;
+        // Bouncing bodies leave on their own; lifting them out as well would
+        // hand them potential energy they never had
+        if depth <= 0.0 || inverse_mass <= 0.0 || contact.target_velocity > 0.0 {
+            return;
+        }
+        let correction = contact.normal * (depth * CORRECTION_PERCENT / inverse_mass);
+        self.bodies[contact.a].position += correction * inverse_mass_a;
+        if let Some(b) = contact.b {
+            self.bodies[b].position -= correction * inverse_mass_b;
+        }
+    }
+}
+
+fn new_contact(
+    a: (usize, &Body),
+    b: Option<(usize, &Body)>,
+    point: Vector3<f32>,
+    normal: Vector3<f32>,
+    depth: f32,
+) -> Contact {
+    // Any axis not parallel to the normal works to build the tangent plane
+    let axis = if normal.x.abs() < 0.57 { Vector3::unit_x() } else { Vector3::unit_y() };
+    let tangent = normal.cross(axis).normalize();
+    Contact {
+        a: a.0,
+        b: b.map(|(i, _)| i),
+        normal,
+        depth,
+        r_a: point - a.1.position,
+        r_b: b.map_or(Vector3::zero(), |(_, body)| point - body.position),
+        tangents: [tangent, normal.cross(tangent)],
+        normal_mass: 0.0,
+        tangent_mass: [0.0; 2],
+        target_velocity: 0.0,
+        friction: 0.0,
+        normal_impulse: 0.0,
+        tangent_impulse: [0.0; 2],
+    }
+}
+
+fn ground_contacts(i: usize, body: &Body, ground: &GroundDesc, contacts: &mut Vec<Contact>) {
+    let normal = Vector3::unit_y();
+    match body.shape {
+        Shape::Sphere { radius } => {
+            let depth = ground.height - (body.position.y - radius);
+            if depth > 0.0 {
+                let point = body.position - normal * radius;
+                contacts.push(new_contact((i, body), None, point, normal, depth));
+            }
+        }
+        Shape::Box { half_extents } => {
+            for corner in body.corners(half_extents).iter() {
+                let depth = ground.height - corner.y;
+                if depth > 0.0 {
+                    contacts.push(new_contact((i, body), None, *corner, normal, depth));
+                }
+            }
+        }
+    }
+}
+
+fn body_contacts(i: usize, a: &Body, j: usize, b: &Body, contacts: &mut Vec<Contact>) {
+    match (a.shape, b.shape) {
+        (Shape::Sphere { radius: ra }, Shape::Sphere { radius: rb }) => {
+            let offset = a.position - b.position;
+            let distance = offset.magnitude();
+            let depth = ra + rb - distance;
+            if depth > 0.0 {
+                let normal = if distance > 0.0 { offset / distance } else { Vector3::unit_y() };
+                let point = b.position + normal * rb;
+                contacts.push(new_contact((i, a), Some((j, b)), point, normal, depth));
+            }
+        }
+        (Shape::Sphere { radius }, Shape::Box { half_extents }) => {
+            if let Some((point, normal, depth)) = sphere_box(a.position, radius, b, half_extents) {
+                contacts.push(new_contact((i, a), Some((j, b)), point, normal, depth));
+            }
+        }
+        (Shape::Box { half_extents }, Shape::Sphere { radius }) => {
+            if let Some((point, normal, depth)) = sphere_box(b.position, radius, a, half_extents) {
+                contacts.push(new_contact((j, b), Some((i, a)), point, normal, depth));
+            }
+        }
+        (Shape::Box { half_extents: ha }, Shape::Box { half_extents: hb }) => {
+            box_box((i, a, ha), (j, b, hb), contacts);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Feature {
+    FaceA(usize),
+    FaceB(usize),
+    Edges(usize, usize),
+}
+
+fn box_axes(body: &Body) -> [Vector3<f32>; 3] {
+    [
+        body.rotation * Vector3::unit_x(),
+        body.rotation * Vector3::unit_y(),
+        body.rotation * Vector3::unit_z(),
+    ]
+}
+
+// Half the length of a box's shadow on `axis`
+fn box_radius(axes: &[Vector3<f32>; 3], half_extents: [f32; 3], axis: Vector3<f32>) -> f32 {
+    (0..3).map(|k| (axes[k].dot(axis) * half_extents[k]).abs()).sum()
+}
+
+// Separating axis test over the 15 candidate axes. The axis of least overlap
+// picks the contact feature: for a face, the other box's corners behind it
+// become contacts, clamped onto the face; for two edges, a single contact
+// between their closest points. Face axes win ties so resting boxes don't
+// flicker between features.
+fn box_box(
+    (i, a, ha): (usize, &Body, [f32; 3]),
+    (j, b, hb): (usize, &Body, [f32; 3]),
+    contacts: &mut Vec<Contact>,
+) {
+    let axes_a = box_axes(a);
+    let axes_b = box_axes(b);
+    let offset = a.position - b.position;
+
+    let mut candidates = Vec::with_capacity(15);
+    for k in 0..3 {
+        candidates.push((axes_a[k], Feature::FaceA(k)));
+        candidates.push((axes_b[k], Feature::FaceB(k)));
+    }
+    for (k, axis_a) in axes_a.iter().enumerate() {
+        for (l, axis_b) in axes_b.iter().enumerate() {
+            let axis = axis_a.cross(*axis_b);
+            // Parallel edges are already covered by the face axes
+            if axis.magnitude2() > 1e-6 {
+                candidates.push((axis.normalize(), Feature::Edges(k, l)));
+            }
+        }
+    }
+
+    let mut best_face: Option<(f32, Vector3<f32>, Feature)> = None;
+    let mut best_edges: Option<(f32, Vector3<f32>, Feature)> = None;
+    for (axis, feature) in candidates {
+        let distance = offset.dot(axis);
+        let overlap = box_radius(&axes_a, ha, axis) + box_radius(&axes_b, hb, axis) - distance.abs();
+        if overlap < 0.0 {
+            return;
+        }
+        // Normals point from b towards a
+        let normal = if distance < 0.0 { -axis } else { axis };
+        let best = match feature {
+            Feature::Edges(..) => &mut best_edges,
+            _ => &mut best_face,
+        };
+        let closer = match best {
+            Some((best_overlap, _, _)) => overlap < *best_overlap,
+            None => true,
+        };
+        if closer {
+            *best = Some((overlap, normal, feature));
+        }
+    }
+
+    let (depth, normal, feature) = match (best_face, best_edges) {
+        (Some(face), Some(edges)) if edges.0 < face.0 * 0.95 - 0.01 => edges,
+        (Some(face), _) => face,
+        (None, _) => return,
+    };
+    match feature {
+        Feature::FaceB(k) => {
+            for corner in a.corners(ha).iter() {
+                if let Some((point, depth)) = clip_to_face(*corner, b, &axes_b, hb, k, normal) {
+                    contacts.push(new_contact((i, a), Some((j, b)), point, normal, depth));
+                }
+            }
+        }
+        Feature::FaceA(k) => {
+            for corner in b.corners(hb).iter() {
+                if let Some((point, depth)) = clip_to_face(*corner, a, &axes_a, ha, k, -normal) {
+                    contacts.push(new_contact((i, a), Some((j, b)), point, normal, depth));
+                }
+            }
+        }
+        Feature::Edges(k, l) => {
+            // The edges of each box that reach furthest towards the other
+            let edge_center = |body: &Body, axes: &[Vector3<f32>; 3], half: [f32; 3], skip: usize, towards: Vector3<f32>| {
+                let mut center = body.position;
+                for m in (0..3).filter(|&m| m != skip) {
+                    let sign = if axes[m].dot(towards) > 0.0 { 1.0 } else { -1.0 };
+                    center += axes[m] * (sign * half[m]);
+                }
+                center
+            };
+            let center_a = edge_center(a, &axes_a, ha, k, -normal);
+            let center_b = edge_center(b, &axes_b, hb, l, normal);
+            let (da, db) = (axes_a[k], axes_b[l]);
+
+            // Closest points between the two edge lines, kept on the edges
+            let r = center_a - center_b;
+            let (cos, c, f) = (da.dot(db), da.dot(r), db.dot(r));
+            let denominator = 1.0 - cos * cos;
+            let (s, t) = if denominator > 1e-6 {
+                ((cos * f - c) / denominator, (f - cos * c) / denominator)
+            } else {
+                (0.0, f)
+            };
+            let s = s.max(-ha[k]).min(ha[k]);
+            let t = t.max(-hb[l]).min(hb[l]);
+            let point = (center_a + da * s + center_b + db * t) * 0.5;
+            contacts.push(new_contact((i, a), Some((j, b)), point, normal, depth));
+        }
+    }
+}
+
+// Projects `corner` onto the face of `body` across axis `k` whose outward
+// normal is `face_normal`, if the corner is behind it. Returns the point on
+// the face, clamped to its extent, and how far behind the corner is.
+fn clip_to_face(
+    corner: Vector3<f32>,
+    body: &Body,
+    axes: &[Vector3<f32>; 3],
+    half_extents: [f32; 3],
+    k: usize,
+    face_normal: Vector3<f32>,
+) -> Option<(Vector3<f32>, f32)> {
+    let local = corner - body.position;
+    let depth = half_extents[k] - local.dot(face_normal);
+    if depth <= 0.0 {
+        return None;
+    }
+    let mut point = body.position + face_normal * half_extents[k];
+    for m in (0..3).filter(|&m| m != k) {
+        let t = local.dot(axes[m]).max(-half_extents[m]).min(half_extents[m]);
+        point += axes[m] * t;
+    }
+    Some((point, depth))
+}
+
+// Contact point, normal from the box towards the sphere and depth
+fn sphere_box(
+    center: Vector3<f32>,
+    radius: f32,
+    body: &Body,
+    half_extents: [f32; 3],
+) -> Option<(Vector3<f32>, Vector3<f32>, f32)> {
+    let local = body.rotation.conjugate() * (center - body.position);
+    let clamped = Vector3::new(
+        local.x.max(-half_extents[0]).min(half_extents[0]),
+        local.y.max(-half_extents[1]).min(half_extents[1]),
+        local.z.max(-half_extents[2]).min(half_extents[2]),
+    );
+    let offset = local - clamped;
+    let distance = offset.magnitude();
+    if distance > 0.0 {
+        if distance >= radius {
+            return None;
+        }
+        let normal = body.rotation * (offset / distance);
+        let point = body.position + body.rotation * clamped;
+        Some((point, normal, radius - distance))
+    } else {
+        // The center is inside the box: leave through the nearest face
+        let (normal, depth) = point_in_box(center, body, half_extents)?;
+        let normal = -normal;
+        Some((center - normal * radius, normal, depth + radius))
+    }
+}
+
+// Inward normal of the face `point` is closest to, and how deep it is. A
+// point on the surface counts as inside, at depth 0
+fn point_in_box(point: Vector3<f32>, body: &Body, half_extents: [f32; 3]) -> Option<(Vector3<f32>, f32)> {
+    let local = body.rotation.conjugate() * (point - body.position);
+    let local = [local.x, local.y, local.z];
+    let mut best: Option<(usize, f32)> = None;
+    for axis in 0..3 {
+        let depth = half_extents[axis] - local[axis].abs();
+        if depth < 0.0 {
+            return None;
+        }
+        let closer = match best {
+            Some((_, best_depth)) => depth < best_depth,
+            None => true,
+        };
+        if closer {
+            best = Some((axis, depth));
+        }
+    }
+    let (axis, depth) = best?;
+    let mut normal = Vector3::zero();
+    normal[axis] = if local[axis] > 0.0 { -1.0 } else { 1.0 };
+    Some((body.rotation * normal, depth))
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Quaternion;
+    use super::*;
+
+    fn body_desc(shape: Shape, restitution: f32) -> BodyDesc {
+        BodyDesc { shape, mass: 1.0, restitution, friction: 0.5, velocity: [0.0; 3] }
+    }
+
+    fn body(desc: &BodyDesc, position: [f32; 3]) -> Body {
+        let instance = Instance::new(position.into(), Quaternion::new(1.0, 0.0, 0.0, 0.0), 1.0);
+        Body::new(desc, 0, &instance).unwrap()
+    }
+
+    fn sphere(radius: f32, restitution: f32, position: [f32; 3]) -> Body {
+        body(&body_desc(Shape::Sphere { radius }, restitution), position)
+    }
+
+    fn physics(bodies: Vec<Body>, ground_restitution: Option<f32>, gravity: [f32; 3]) -> PhysicsState {
+        let ground = ground_restitution.map(|restitution| GroundDesc { restitution, ..Default::default() });
+        PhysicsState::new(PhysicsDesc { gravity, ground, ..Default::default() }, bodies)
+    }
+
+    // Kinetic energy plus potential energy under `gravity`
+    fn energy(body: &Body, gravity: [f32; 3]) -> f32 {
+        kinetic_energy(body) - Vector3::from(gravity).dot(body.position) / body.inverse_mass
+    }
+
+    fn kinetic_energy(body: &Body) -> f32 {
+        let inertia = body.inverse_inertia.map(|i| if i > 0.0 { 1.0 / i } else { 0.0 });
+        let local_spin = body.rotation.conjugate() * body.angular_velocity;
+        0.5 * body.velocity.magnitude2() / body.inverse_mass
+            + 0.5 * local_spin.mul_element_wise(inertia).dot(local_spin)
+    }
+
+    // Heights of the top of each flight between bounces
+    fn bounce_peaks(state: &mut PhysicsState, steps: usize) -> Vec<f32> {
+        let mut peaks = Vec::new();
+        let mut rising = false;
+        for _ in 0..steps {
+            let previous = state.bodies[0].position.y;
+            state.step();
+            let y = state.bodies[0].position.y;
+            // The speed can hit exactly 0 at the top, so a level step
+            // doesn't end the climb
+            if y < previous {
+                if rising {
+                    peaks.push(previous);
+                }
+                rising = false;
+            } else if y > previous {
+                rising = true;
+            }
+        }
+        peaks
+    }
+
+    #[test]
+    fn identical_runs_give_identical_bodies() {
+        let run = || {
+            let boxed = body_desc(Shape::Box { half_extents: [0.5, 0.25, 0.5] }, 0.2);
+            let mut tumbling = body(&boxed, [0.3, 2.0, 0.0]);
+            tumbling.angular_velocity = Vector3::new(1.0, 2.0, 0.5);
+            let bodies = vec![
+                body(&boxed, [0.0, 0.5, 0.0]),
+                tumbling,
+                sphere(0.3, 0.6, [-0.2, 3.0, 0.1]),
+                sphere(0.2, 0.6, [0.1, 4.0, -0.2]),
+            ];
+            let mut state = physics(bodies, Some(0.3), PhysicsDesc::default_gravity());
+            for _ in 0..300 {
+                state.step();
+            }
+            state.bodies
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn bounce_height_follows_restitution() {
+        // Dropped from 2 above the ground, bounces should reach 2 * e^2
+        let mut state = physics(vec![sphere(0.5, 0.5, [0.0, 2.5, 0.0])], Some(0.5), PhysicsDesc::default_gravity());
+        let peaks = bounce_peaks(&mut state, 240);
+        assert!(peaks.len() >= 2, "{:?}", peaks);
+        let first = peaks[0] - 0.5;
+        assert!((first - 0.5).abs() < 0.01, "first bounce reached {}", first);
+        assert!(peaks.windows(2).all(|pair| pair[1] < pair[0]), "{:?}", peaks);
+    }
+
+    #[test]
+    fn elastic_bounces_neither_gain_nor_lose_height() {
+        let mut state = physics(vec![sphere(0.5, 1.0, [0.0, 2.5, 0.0])], Some(1.0), PhysicsDesc::default_gravity());
+        let peaks = bounce_peaks(&mut state, 600);
+        assert!(peaks.len() >= 3, "{:?}", peaks);
+        for peak in peaks {
+            assert!((peak - 2.5).abs() < 1e-3, "reached {}", peak);
+        }
+    }
+
+    #[test]
+    fn slow_impacts_do_not_bounce() {
+        // Hits the ground at about 0.3 per second, under the threshold
+        let mut state = physics(vec![sphere(0.5, 1.0, [0.0, 0.505, 0.0])], Some(1.0), PhysicsDesc::default_gravity());
+        for _ in 0..120 {
+            state.step();
+            assert!(state.bodies[0].position.y <= 0.505);
+        }
+        assert!(state.bodies[0].velocity.magnitude() < 0.05);
+    }
+
+    #[test]
+    fn resting_sphere_stays_at_rest() {
+        let mut state = physics(vec![sphere(0.5, 0.3, [0.0, 0.5, 0.0])], Some(0.3), PhysicsDesc::default_gravity());
+        for _ in 0..600 {
+            state.step();
+        }
+        let body = &state.bodies[0];
+        assert!((body.position.y - 0.5).abs() < PENETRATION_SLOP * 2.0, "{}", body.position.y);
+        assert!(body.velocity.magnitude() < 0.05);
+    }
+
+    #[test]
+    fn friction_brings_a_sliding_box_to_rest() {
+        let desc = BodyDesc { velocity: [2.0, 0.0, 0.0], ..body_desc(Shape::Box { half_extents: [0.5; 3] }, 0.0) };
+        let mut state = physics(vec![body(&desc, [0.0, 0.5, 0.0])], Some(0.0), PhysicsDesc::default_gravity());
+        let gravity = PhysicsDesc::default_gravity();
+        let mut previous = energy(&state.bodies[0], gravity);
+        for _ in 0..120 {
+            state.step();
+            let next = energy(&state.bodies[0], gravity);
+            assert!(next <= previous + 1e-3, "energy grew from {} to {}", previous, next);
+            previous = next;
+        }
+        assert!(kinetic_energy(&state.bodies[0]) < 1e-3, "still moving");
+    }
+
+    #[test]
+    fn dropped_box_settles_on_the_ground() {
+        let desc = body_desc(Shape::Box { half_extents: [0.5, 0.25, 0.5] }, 0.5);
+        let mut dropped = body(&desc, [0.0, 2.0, 0.0]);
+        dropped.angular_velocity = Vector3::new(0.5, 0.0, 1.0);
+        let gravity = PhysicsDesc::default_gravity();
+        let mut state = physics(vec![dropped], Some(0.5), gravity);
+        let start = energy(&state.bodies[0], gravity);
+        for _ in 0..600 {
+            state.step();
+            assert!(energy(&state.bodies[0], gravity) <= start + 1e-3);
+        }
+        let body = &state.bodies[0];
+        assert!(kinetic_energy(body) < 1e-3, "still moving");
+        // Resting on one of its faces
+        let lowest = body.corners([0.5, 0.25, 0.5]).iter().map(|corner| corner.y).fold(f32::MAX, f32::min);
+        assert!(lowest.abs() < PENETRATION_SLOP * 2.0, "lowest corner at {}", lowest);
+    }
+
+    #[test]
+    fn elastic_spheres_keep_kinetic_energy() {
+        let mut a = sphere(0.5, 1.0, [-1.0, 0.0, 0.0]);
+        let mut b = sphere(0.5, 1.0, [1.0, 0.0, 0.0]);
+        a.velocity = Vector3::new(3.0, 0.0, 0.0);
+        b.velocity = Vector3::new(-1.0, 0.0, 0.0);
+        let mut state = physics(vec![a, b], None, [0.0; 3]);
+        let before = state.bodies.iter().map(kinetic_energy).sum::<f32>();
+        for _ in 0..60 {
+            state.step();
+        }
+        let after = state.bodies.iter().map(kinetic_energy).sum::<f32>();
+        assert!((after - before).abs() < before * 0.02, "{} became {}", before, after);
+        // Equal masses swap velocities
+        assert!((state.bodies[0].velocity.x + 1.0).abs() < 0.05);
+        assert!((state.bodies[1].velocity.x - 3.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn inelastic_spheres_move_together() {
+        let mut a = sphere(0.5, 0.0, [-1.0, 0.0, 0.0]);
+        a.velocity = Vector3::new(2.0, 0.0, 0.0);
+        let b = sphere(0.5, 0.0, [1.0, 0.0, 0.0]);
+        let mut state = physics(vec![a, b], None, [0.0; 3]);
+        for _ in 0..60 {
+            state.step();
+        }
+        assert!((state.bodies[0].velocity.x - 1.0).abs() < 0.05);
+        assert!((state.bodies[1].velocity.x - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn moving_bodies_need_a_size() {
+        let instance = Instance::new(Vector3::zero(), Quaternion::new(1.0, 0.0, 0.0, 0.0), 1.0);
+        let sphere = body_desc(Shape::Sphere { radius: 0.0 }, 0.3);
+        let flat_box = body_desc(Shape::Box { half_extents: [1.0, 0.0, 1.0] }, 0.3);
+        assert!(Body::new(&sphere, 0, &instance).is_err());
+        assert!(Body::new(&flat_box, 0, &instance).is_err());
+        assert!(Body::new(&BodyDesc { mass: 0.0, ..flat_box }, 0, &instance).is_ok());
+    }
+
+    #[test]
+    fn sphere_centred_on_a_box_face_touches_it() {
+        let boxed = body(&body_desc(Shape::Box { half_extents: [1.0; 3] }, 0.3), [0.0; 3]);
+        let (point, normal, depth) = sphere_box(Vector3::new(0.0, 1.0, 0.0), 0.25, &boxed, [1.0; 3]).unwrap();
+        assert!((normal - Vector3::unit_y()).magnitude() < 1e-6);
+        assert!((depth - 0.25).abs() < 1e-6);
+        assert!((point - Vector3::new(0.0, 0.75, 0.0)).magnitude() < 1e-6);
+    }
+}
@@ -0,0 +1,325 @@
+use std::path::Path;
+use anyhow::*;
+
+use crate::model::{ModelData, MeshData, Vertex};
+
+/// Loads a `.ply` file, ASCII or binary, as a single mesh. Positions,
+/// normals and texture coordinates are read, faces with more than three
+/// corners are split into fans and other elements and properties are
+/// skipped.
+pub fn load(path: &Path) -> Result<ModelData> {
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("Unable to read model {}", path.display()))?;
+    let name = path.file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+    let mesh = parse(&bytes, name)
+        .with_context(|| format!("Unable to load {}", path.display()))?;
+    Ok(ModelData { meshes: vec![mesh], ..Default::default() })
+}
+
+pub fn parse(bytes: &[u8], name: String) -> Result<MeshData> {
+    let (header, body) = split_header(bytes)?;
+    let mut reader = match header.format {
+        Format::Ascii => Reader::Ascii(std::str::from_utf8(body)
+            .context("ASCII data isn't text")?
+            .split_whitespace()),
+        Format::Binary(big_endian) => Reader::Binary { bytes: body, big_endian },
+    };
+
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    for element in &header.elements {
+        for _ in 0..element.count {
+            match element.name.as_str() {
+                "vertex" => vertices.push(read_vertex(&mut reader, element)?),
+                "face" => read_face(&mut reader, element, &mut indices)?,
+                _ => skip(&mut reader, element)?,
+            }
+        }
+    }
+    if let Some(&index) = indices.iter().find(|&&index| index as usize >= vertices.len()) {
+        bail!("Face index {} is out of range for {} vertices", index, vertices.len());
+    }
+    Ok(MeshData { name, vertices, indices, material: None, skin: None, morph: None })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    // Big endian when true
+    Binary(bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    I8, U8, I16, U16, I32, U32, F32, F64,
+}
+
+impl Type {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "char" | "int8" => Type::I8,
+            "uchar" | "uint8" => Type::U8,
+            "short" | "int16" => Type::I16,
+            "ushort" | "uint16" => Type::U16,
+            "int" | "int32" => Type::I32,
+            "uint" | "uint32" => Type::U32,
+            "float" | "float32" => Type::F32,
+            "double" | "float64" => Type::F64,
+            _ => bail!("Unknown property type {:?}", name),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Type::I8 | Type::U8 => 1,
+            Type::I16 | Type::U16 => 2,
+            Type::I32 | Type::U32 | Type::F32 => 4,
+            Type::F64 => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Property {
+    Scalar(String, Type),
+    // Name, count type and item type
+    List(String, Type, Type),
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+struct Header {
+    format: Format,
+    elements: Vec<Element>,
+}
+
+// The header is ASCII lines up to end_header, the data starts right after
+fn split_header(bytes: &[u8]) -> Result<(Header, &[u8])> {
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    let mut position = 0;
+    let mut first = true;
+    loop {
+        let end = bytes[position..].iter()
+            .position(|&byte| byte == b'\n')
+            .map(|end| position + end)
+            .context("The header has no end_header")?;
+        let line = std::str::from_utf8(&bytes[position..end]).context("The header isn't text")?.trim();
+        position = end + 1;
+
+        let mut words = line.split_whitespace();
+        let keyword = words.next();
+        if first {
+            if keyword != Some("ply") {
+                bail!("Not a PLY file");
+            }
+            first = false;
+            continue;
+        }
+        match keyword {
+            Some("format") => {
+                format = Some(match words.next() {
+                    Some("ascii") => Format::Ascii,
+                    Some("binary_little_endian") => Format::Binary(false),
+                    Some("binary_big_endian") => Format::Binary(true),
+                    other => bail!("Unknown format {:?}", other.unwrap_or("")),
+                });
+            }
+            Some("element") => {
+                let name = words.next().context("Element without a name")?;
+                let count = words.next().context("Element without a count")?;
+                let count = count.parse().with_context(|| format!("Bad element count {:?}", count))?;
+                elements.push(Element { name: name.to_string(), count, properties: Vec::new() });
+            }
+            Some("property") => {
+                let element = elements.last_mut().context("Property before any element")?;
+                let words = words.collect::<Vec<_>>();
+                let property = match words[..] {
+                    ["list", count, item, name] => Property::List(name.to_string(), Type::parse(count)?, Type::parse(item)?),
+                    [kind, name] => Property::Scalar(name.to_string(), Type::parse(kind)?),
+                    _ => bail!("Bad property {:?}", line),
+                };
+                element.properties.push(property);
+            }
+            Some("end_header") => break,
+            _ => {}
+        }
+    }
+    let format = format.context("The header has no format")?;
+    Ok((Header { format, elements }, &bytes[position..]))
+}
+
+fn read_vertex(reader: &mut Reader, element: &Element) -> Result<Vertex> {
+    let mut vertex = Vertex {
+        position: [0.0; 3],
+        tex_coords: [0.0; 2],
+        normal: [0.0; 3],
+        tangent: [0.0; 4],
+    };
+    for property in &element.properties {
+        let (name, kind) = match property {
+            Property::Scalar(name, kind) => (name, *kind),
+            Property::List(_, count, item) => {
+                reader.skip_list(*count, *item)?;
+                continue;
+            }
+        };
+        let value = reader.read(kind)? as f32;
+        match name.as_str() {
+            "x" => vertex.position[0] = value,
+            "y" => vertex.position[1] = value,
+            "z" => vertex.position[2] = value,
+            "nx" => vertex.normal[0] = value,
+            "ny" => vertex.normal[1] = value,
+            "nz" => vertex.normal[2] = value,
+            "s" | "u" | "texture_u" => vertex.tex_coords[0] = value,
+            // PLY's t grows upwards like OBJ's v, wgpu's downwards
+            "t" | "v" | "texture_v" => vertex.tex_coords[1] = 1.0 - value,
+            _ => {}
+        }
+    }
+    Ok(vertex)
+}
+
+fn read_face(reader: &mut Reader, element: &Element, indices: &mut Vec<u32>) -> Result<()> {
+    for property in &element.properties {
+        match property {
+            Property::List(name, count, item) if name == "vertex_indices" || name == "vertex_index" => {
+                let count = reader.read(*count)? as usize;
+                let corners = (0..count)
+                    .map(|_| reader.read(*item).map(|index| index as u32))
+                    .collect::<Result<Vec<_>>>()?;
+                if corners.len() < 3 {
+                    bail!("Face with {} corners", corners.len());
+                }
+                for i in 1..corners.len() - 1 {
+                    indices.extend_from_slice(&[corners[0], corners[i], corners[i + 1]]);
+                }
+            }
+            Property::List(_, count, item) => reader.skip_list(*count, *item)?,
+            Property::Scalar(_, kind) => {
+                reader.read(*kind)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn skip(reader: &mut Reader, element: &Element) -> Result<()> {
+    for property in &element.properties {
+        match property {
+            Property::List(_, count, item) => reader.skip_list(*count, *item)?,
+            Property::Scalar(_, kind) => {
+                reader.read(*kind)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+enum Reader<'a> {
+    Ascii(std::str::SplitWhitespace<'a>),
+    Binary { bytes: &'a [u8], big_endian: bool },
+}
+
+impl<'a> Reader<'a> {
+    fn read(&mut self, kind: Type) -> Result<f64> {
+        match self {
+            Reader::Ascii(words) => {
+                let word = words.next().context("The data ends early")?;
+                word.parse().with_context(|| format!("Bad number {:?}", word))
+            }
+            Reader::Binary { bytes, big_endian } => {
+                if bytes.len() < kind.size() {
+                    bail!("The data ends early");
+                }
+                let (value, rest) = bytes.split_at(kind.size());
+                *bytes = rest;
+                let mut buffer = [0; 8];
+                buffer[..value.len()].copy_from_slice(value);
+                if *big_endian {
+                    buffer[..value.len()].reverse();
+                }
+                // buffer now holds the value little endian
+                Ok(match kind {
+                    Type::I8 => buffer[0] as i8 as f64,
+                    Type::U8 => buffer[0] as f64,
+                    Type::I16 => i16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+                    Type::U16 => u16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+                    Type::I32 => i32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as f64,
+                    Type::U32 => u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as f64,
+                    Type::F32 => f32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as f64,
+                    Type::F64 => f64::from_le_bytes(buffer),
+                })
+            }
+        }
+    }
+
+    fn skip_list(&mut self, count: Type, item: Type) -> Result<()> {
+        let count = self.read(count)? as usize;
+        for _ in 0..count {
+            self.read(item)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quads_are_split_and_other_elements_skipped() {
+        let model = load(Path::new("res/tests/ply/quad.ply")).unwrap();
+        assert_eq!(model.meshes.len(), 1);
+        let quad = &model.meshes[0];
+        assert_eq!(quad.name, "quad");
+        assert_eq!(quad.indices, [0, 1, 2, 0, 2, 3]);
+        assert_eq!(quad.vertices[2].position, [1.0, 1.0, 0.0]);
+        assert_eq!(quad.vertices[2].tex_coords, [1.0, 0.0]);
+        assert_eq!(quad.vertices[0].tex_coords, [0.0, 1.0]);
+    }
+
+    #[test]
+    fn big_endian_binary() {
+        let mut bytes = b"ply\nformat binary_big_endian 1.0\nelement vertex 3\n\
+            property double x\nproperty double y\nproperty double z\nproperty short nz\n\
+            element face 1\nproperty list uchar ushort vertex_index\nend_header\n".to_vec();
+        for &(x, y) in &[(0.0f64, 0.0f64), (2.0, 0.0), (0.0, -1.5)] {
+            for value in &[x, y, 0.5] {
+                bytes.extend_from_slice(&value.to_be_bytes());
+            }
+            bytes.extend_from_slice(&1i16.to_be_bytes());
+        }
+        bytes.push(3);
+        for index in &[0u16, 1, 2] {
+            bytes.extend_from_slice(&index.to_be_bytes());
+        }
+
+        let mesh = parse(&bytes, String::from("triangle")).unwrap();
+        let positions = mesh.vertices.iter().map(|vertex| vertex.position).collect::<Vec<_>>();
+        assert_eq!(positions, [[0.0, 0.0, 0.5], [2.0, 0.0, 0.5], [0.0, -1.5, 0.5]]);
+        assert!(mesh.vertices.iter().all(|vertex| vertex.normal == [0.0, 0.0, 1.0]));
+        assert_eq!(mesh.indices, [0, 1, 2]);
+
+        let error = format!("{:#}", parse(&bytes[..bytes.len() - 1], String::new()).err().unwrap());
+        assert!(error.contains("The data ends early"), "{}", error);
+    }
+
+    #[test]
+    fn bad_files_are_rejected() {
+        let error = format!("{:#}", load(Path::new("res/tests/ply/bad_index.ply")).err().unwrap());
+        assert!(error.starts_with("Unable to load res/tests/ply/bad_index.ply"), "{}", error);
+        assert!(error.contains("Face index 3 is out of range for 3 vertices"), "{}", error);
+
+        let error = |bytes: &[u8]| format!("{:#}", parse(bytes, String::new()).err().unwrap());
+        assert!(error(b"solid cube\nend_header\n").contains("Not a PLY file"));
+        assert!(error(b"ply\nformat ascii 1.0\nelement vertex 1\n").contains("no end_header"));
+        assert!(error(b"ply\nformat ascii 1.0\nelement vertex 1\nproperty half x\nend_header\n0\n")
+            .contains("Unknown property type \"half\""));
+    }
+}
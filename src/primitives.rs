@@ -0,0 +1,499 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use cgmath::{Vector3, InnerSpace};
+use serde::Deserialize;
+
+use crate::model::{MeshData, Vertex};
+
+/// Procedural meshes, centred on the origin with +Y up. Every vertex has a
+/// unit normal and tangent; closed shapes are watertight once vertices at
+/// the same position (UV seams, poles) are welded.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub enum PrimitiveDesc {
+    // In the XZ plane, facing +Y
+    Plane { size: f32, subdivisions: u32 },
+    Cube { size: f32, subdivisions: u32 },
+    UvSphere { radius: f32, segments: u32, rings: u32 },
+    Icosphere { radius: f32, subdivisions: u32 },
+    Cylinder { radius: f32, height: f32, segments: u32, stacks: u32 },
+    Cone { radius: f32, height: f32, segments: u32, stacks: u32 },
+    // Lies in the XZ plane
+    Torus { radius: f32, tube_radius: f32, segments: u32, tube_segments: u32 },
+    // `height` is the length of the cylinder between the two hemispheres
+    Capsule { radius: f32, height: f32, segments: u32, rings: u32 },
+} impl PrimitiveDesc {
+
+    pub fn build(&self) -> MeshData {
+        match *self {
+            PrimitiveDesc::Plane { size, subdivisions } => plane(size, subdivisions),
+            PrimitiveDesc::Cube { size, subdivisions } => cube(size, subdivisions),
+            PrimitiveDesc::UvSphere { radius, segments, rings } => uv_sphere(radius, segments, rings),
+            PrimitiveDesc::Icosphere { radius, subdivisions } => icosphere(radius, subdivisions),
+            PrimitiveDesc::Cylinder { radius, height, segments, stacks } =>
+                cylinder(radius, height, segments, stacks),
+            PrimitiveDesc::Cone { radius, height, segments, stacks } =>
+                cone(radius, height, segments, stacks),
+            PrimitiveDesc::Torus { radius, tube_radius, segments, tube_segments } =>
+                torus(radius, tube_radius, segments, tube_segments),
+            PrimitiveDesc::Capsule { radius, height, segments, rings } =>
+                capsule(radius, height, segments, rings),
+        }
+    }
+}
+
+pub fn plane(size: f32, subdivisions: u32) -> MeshData {
+    let mut builder = Builder::new();
+    let center = Vector3::new(0.0, 0.0, 0.0);
+    builder.face(center, Vector3::unit_y(), Vector3::unit_x(), Vector3::unit_z(), size, subdivisions.max(1));
+    builder.finish("plane")
+}
+
+pub fn cube(size: f32, subdivisions: u32) -> MeshData {
+    let mut builder = Builder::new();
+    let (x, y, z) = (Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z());
+    // (normal, u axis, v axis), laid out so the image is upright seen from
+    // outside
+    let faces = [(x, -z, -y), (-x, z, -y), (y, x, z), (-y, x, -z), (z, x, -y), (-z, -x, -y)];
+    for &(normal, u_axis, v_axis) in faces.iter() {
+        builder.face(normal * size / 2.0, normal, u_axis, v_axis, size, subdivisions.max(1));
+    }
+    builder.finish("cube")
+}
+
+pub fn uv_sphere(radius: f32, segments: u32, rings: u32) -> MeshData {
+    let rings = rings.max(2);
+    let profile = (0..=rings)
+        .map(|i| {
+            // Half a turn from the north pole to the south pole
+            let (sin, cos) = unit_circle(i, 2 * rings);
+            ProfilePoint { radius: radius * sin, y: radius * cos, normal: [sin, cos], v: i as f32 / rings as f32 }
+        })
+        .collect::<Vec<_>>();
+    let mut builder = Builder::new();
+    builder.revolve(&profile, segments.max(3));
+    builder.finish("uv sphere")
+}
+
+pub fn icosphere(radius: f32, subdivisions: u32) -> MeshData {
+    let t = (1.0 + 5.0f32.sqrt()) / 2.0;
+    let mut positions = [
+        [-1.0, t, 0.0], [1.0, t, 0.0], [-1.0, -t, 0.0], [1.0, -t, 0.0],
+        [0.0, -1.0, t], [0.0, 1.0, t], [0.0, -1.0, -t], [0.0, 1.0, -t],
+        [t, 0.0, -1.0], [t, 0.0, 1.0], [-t, 0.0, -1.0], [-t, 0.0, 1.0],
+    ].iter().map(|&p| Vector3::from(p).normalize()).collect::<Vec<_>>();
+    let mut triangles: Vec<[usize; 3]> = vec![
+        [0, 11, 5], [0, 5, 1], [0, 1, 7], [0, 7, 10], [0, 10, 11],
+        [1, 5, 9], [5, 11, 4], [11, 10, 2], [10, 7, 6], [7, 1, 8],
+        [3, 9, 4], [3, 4, 2], [3, 2, 6], [3, 6, 8], [3, 8, 9],
+        [4, 9, 5], [2, 4, 11], [6, 2, 10], [8, 6, 7], [9, 8, 1],
+    ];
+
+    // Every pass splits each triangle in four, sharing the new midpoints
+    // between neighbours so the surface stays closed
+    for _ in 0..subdivisions {
+        let mut midpoints: HashMap<(usize, usize), usize> = HashMap::new();
+        let mut midpoint = |a: usize, b: usize, positions: &mut Vec<Vector3<f32>>| {
+            *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                positions.push(((positions[a] + positions[b]) / 2.0).normalize());
+                positions.len() - 1
+            })
+        };
+        triangles = triangles.iter()
+            .flat_map(|&[a, b, c]| {
+                let ab = midpoint(a, b, &mut positions);
+                let bc = midpoint(b, c, &mut positions);
+                let ca = midpoint(c, a, &mut positions);
+                vec![[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]
+            })
+            .collect();
+    }
+
+    // Spherical UVs need care where the texture wraps around: triangles
+    // crossing the seam get copies of their vertices shifted one turn (so u
+    // goes a little past 1 there and wants a repeating sampler), and
+    // vertices on the poles get a copy per triangle, centred between the
+    // triangle's other corners
+    let mut builder = Builder::new();
+    let mut wrapped: HashMap<(usize, bool), u32> = HashMap::new();
+    for triangle in &triangles {
+        let corners = triangle.iter()
+            .map(|&i| (i, sphere_u(positions[i])))
+            .collect::<Vec<_>>();
+        let us = corners.iter().filter_map(|&(_, u)| u).collect::<Vec<f32>>();
+        let (min, max) = us.iter().fold((1.0f32, 0.0f32), |(min, max), &u| (min.min(u), max.max(u)));
+        let crosses_seam = max - min > 0.5;
+        let shift = |u: f32| if crosses_seam && u < 0.5 { u + 1.0 } else { u };
+        let pole_u = us.iter().map(|&u| shift(u)).sum::<f32>() / us.len() as f32;
+
+        let mut indices = [0; 3];
+        for (index, &(i, u)) in indices.iter_mut().zip(&corners) {
+            let position = positions[i];
+            *index = match u {
+                Some(u) => {
+                    let shifted = shift(u) != u;
+                    *wrapped.entry((i, shifted))
+                        .or_insert_with(|| builder.push(sphere_vertex(position, radius, shift(u))))
+                }
+                None => builder.push(sphere_vertex(position, radius, pole_u)),
+            };
+        }
+        builder.triangle(indices);
+    }
+    builder.finish("icosphere")
+}
+
+pub fn cylinder(radius: f32, height: f32, segments: u32, stacks: u32) -> MeshData {
+    let (segments, stacks) = (segments.max(3), stacks.max(1));
+    let profile = (0..=stacks)
+        .map(|i| {
+            let v = i as f32 / stacks as f32;
+            ProfilePoint { radius, y: height * (0.5 - v), normal: [1.0, 0.0], v }
+        })
+        .collect::<Vec<_>>();
+    let mut builder = Builder::new();
+    builder.revolve(&profile, segments);
+    builder.disc(radius, height / 2.0, true, segments);
+    builder.disc(radius, -height / 2.0, false, segments);
+    builder.finish("cylinder")
+}
+
+pub fn cone(radius: f32, height: f32, segments: u32, stacks: u32) -> MeshData {
+    let (segments, stacks) = (segments.max(3), stacks.max(1));
+    let slant = (height * height + radius * radius).sqrt();
+    let profile = (0..=stacks)
+        .map(|i| {
+            let v = i as f32 / stacks as f32;
+            ProfilePoint {
+                radius: radius * v,
+                y: height * (0.5 - v),
+                normal: [height / slant, radius / slant],
+                v,
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut builder = Builder::new();
+    builder.revolve(&profile, segments);
+    builder.disc(radius, -height / 2.0, false, segments);
+    builder.finish("cone")
+}
+
+pub fn torus(radius: f32, tube_radius: f32, segments: u32, tube_segments: u32) -> MeshData {
+    let tube_segments = tube_segments.max(3);
+    let profile = (0..=tube_segments)
+        .map(|i| {
+            // Starts on the outer equator and heads down
+            let (sin, cos) = unit_circle(i, tube_segments);
+            ProfilePoint {
+                radius: radius + tube_radius * cos,
+                y: -tube_radius * sin,
+                normal: [cos, -sin],
+                v: i as f32 / tube_segments as f32,
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut builder = Builder::new();
+    builder.revolve(&profile, segments.max(3));
+    builder.finish("torus")
+}
+
+pub fn capsule(radius: f32, height: f32, segments: u32, rings: u32) -> MeshData {
+    let rings = rings.max(1);
+    // v follows the length of the profile, so the texture isn't stretched
+    // over the straight part
+    let length = PI * radius + height;
+    let mut profile = Vec::new();
+    for (offset, first, start) in [(height / 2.0, 0, 0.0), (-height / 2.0, rings, (PI * radius + 2.0 * height) / 2.0)].iter() {
+        for i in *first..=*first + rings {
+            let (sin, cos) = unit_circle(i, 4 * rings);
+            let arc = radius * PI / 2.0 * (i - first) as f32 / rings as f32;
+            profile.push(ProfilePoint {
+                radius: radius * sin,
+                y: offset + radius * cos,
+                normal: [sin, cos],
+                v: (start + arc) / length,
+            });
+        }
+    }
+    let mut builder = Builder::new();
+    builder.revolve(&profile, segments.max(3));
+    builder.finish("capsule")
+}
+
+// Sine and cosine of `i` steps of a turn split in `n`, exact on the axes so
+// rings meant to meet share bit-identical positions
+fn unit_circle(i: u32, n: u32) -> (f32, f32) {
+    let i = i % n;
+    let quarter = 4 * i / n;
+    if quarter * n == 4 * i {
+        return match quarter {
+            0 => (0.0, 1.0),
+            1 => (1.0, 0.0),
+            2 => (0.0, -1.0),
+            _ => (-1.0, 0.0),
+        };
+    }
+    (2.0 * PI * i as f32 / n as f32).sin_cos()
+}
+
+// Longitude of a point on the sphere as a texture coordinate, matching the
+// UV sphere. None on the poles, where it is undefined.
+fn sphere_u(position: Vector3<f32>) -> Option<f32> {
+    if position.x.abs() < 1e-6 && position.z.abs() < 1e-6 {
+        return None;
+    }
+    let u = (-position.z).atan2(position.x) / (2.0 * PI);
+    Some(if u < 0.0 { u + 1.0 } else { u })
+}
+
+fn sphere_vertex(direction: Vector3<f32>, radius: f32, u: f32) -> Vertex {
+    let (sin, cos) = (2.0 * PI * u).sin_cos();
+    Vertex {
+        position: (direction * radius).into(),
+        tex_coords: [u, direction.y.clamp(-1.0, 1.0).acos() / PI],
+        normal: direction.into(),
+        tangent: [-sin, 0.0, -cos, 1.0],
+    }
+}
+
+// A point of a profile revolved around the Y axis; `normal` is (radial, y)
+struct ProfilePoint {
+    radius: f32,
+    y: f32,
+    normal: [f32; 2],
+    v: f32,
+}
+
+struct Builder {
+    vertices: Vec<Vertex>,
+    indices: Vec<u32>,
+}
+
+impl Builder {
+    fn new() -> Self {
+        Self { vertices: Vec::new(), indices: Vec::new() }
+    }
+
+    // Welding compares positions bit for bit, so -0.0, which poles and
+    // edges on an axis produce, is made 0.0 by adding zero
+    fn push(&mut self, mut vertex: Vertex) -> u32 {
+        for x in &mut vertex.position {
+            *x += 0.0;
+        }
+        self.vertices.push(vertex);
+        self.vertices.len() as u32 - 1
+    }
+
+    // Winds the triangle to face the way its vertex normals point, and
+    // drops it if two corners coincide, as they do at poles and apexes
+    fn triangle(&mut self, [a, b, c]: [u32; 3]) {
+        let vertex = |i: u32| &self.vertices[i as usize];
+        let (pa, pb, pc) = (vertex(a).position, vertex(b).position, vertex(c).position);
+        if pa == pb || pb == pc || pc == pa {
+            return;
+        }
+        let (pa, pb, pc) = (Vector3::from(pa), Vector3::from(pb), Vector3::from(pc));
+        let normal = Vector3::from(vertex(a).normal) + Vector3::from(vertex(b).normal) + Vector3::from(vertex(c).normal);
+        if (pb - pa).cross(pc - pa).dot(normal) < 0.0 {
+            self.indices.extend_from_slice(&[a, c, b]);
+        } else {
+            self.indices.extend_from_slice(&[a, b, c]);
+        }
+    }
+
+    // Two triangles per cell of a (columns + 1) x (rows + 1) vertex grid
+    // starting at `first`, laid out row by row
+    fn grid(&mut self, first: u32, columns: u32, rows: u32) {
+        let stride = columns + 1;
+        for row in 0..rows {
+            for column in 0..columns {
+                let a = first + row * stride + column;
+                let (b, c, d) = (a + stride, a + stride + 1, a + 1);
+                self.triangle([a, b, c]);
+                self.triangle([a, c, d]);
+            }
+        }
+    }
+
+    // A square of side `size`, v_axis x u_axis has to be the normal
+    fn face(
+        &mut self, 
+        center: Vector3<f32>, 
+        normal: Vector3<f32>, 
+        u_axis: Vector3<f32>, 
+        v_axis: Vector3<f32>, 
+        size: f32, 
+        subdivisions: u32,
+    ) {
+        let first = self.vertices.len() as u32;
+        // Rows and columns the same distance either side of the centre get
+        // offsets that are exact opposites, so faces meeting at an edge put
+        // its vertices at the same position bit for bit
+        let offset = |i: u32| (2 * i as i32 - subdivisions as i32) as f32 * size / (2 * subdivisions) as f32;
+        for row in 0..=subdivisions {
+            for column in 0..=subdivisions {
+                let u = column as f32 / subdivisions as f32;
+                let v = row as f32 / subdivisions as f32;
+                let position = center + u_axis * offset(column) + v_axis * offset(row);
+                self.push(Vertex {
+                    position: position.into(),
+                    tex_coords: [u, v],
+                    normal: normal.into(),
+                    tangent: [u_axis.x, u_axis.y, u_axis.z, 1.0],
+                });
+            }
+        }
+        self.grid(first, subdivisions, subdivisions);
+    }
+
+    // u goes once around the Y axis, the first and last columns sit on the
+    // same seam with u = 0 and u = 1. v has to grow downwards on the outside
+    // for the tangent's handedness to hold.
+    fn revolve(&mut self, profile: &[ProfilePoint], segments: u32) {
+        let first = self.vertices.len() as u32;
+        for point in profile {
+            for column in 0..=segments {
+                let (sin, cos) = unit_circle(column, segments);
+                let normal = Vector3::new(point.normal[0] * cos, point.normal[1], -point.normal[0] * sin);
+                self.push(Vertex {
+                    position: [point.radius * cos, point.y, -point.radius * sin],
+                    tex_coords: [column as f32 / segments as f32, point.v],
+                    normal: normal.normalize().into(),
+                    tangent: [-sin, 0.0, -cos, 1.0],
+                });
+            }
+        }
+        self.grid(first, segments, profile.len() as u32 - 1);
+    }
+
+    // A flat cap at height `y`, sharing its rim with the revolved side
+    fn disc(&mut self, radius: f32, y: f32, up: bool, segments: u32) {
+        let (normal, flip) = if up { ([0.0, 1.0, 0.0], 1.0) } else { ([0.0, -1.0, 0.0], -1.0) };
+        let vertex = |x: f32, z: f32| Vertex {
+            position: [x, y, z],
+            tex_coords: [0.5 + x / (2.0 * radius), 0.5 + flip * z / (2.0 * radius)],
+            normal,
+            tangent: [1.0, 0.0, 0.0, 1.0],
+        };
+        let center = self.push(vertex(0.0, 0.0));
+        let first = self.vertices.len() as u32;
+        for column in 0..segments {
+            let (sin, cos) = unit_circle(column, segments);
+            self.push(vertex(radius * cos, -radius * sin));
+        }
+        for column in 0..segments {
+            self.triangle([center, first + column, first + (column + 1) % segments]);
+        }
+    }
+
+    fn finish(self, name: &str) -> MeshData {
+        MeshData { name: name.to_string(), vertices: self.vertices, indices: self.indices, material: None, skin: None, morph: None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn closed_shapes() -> Vec<PrimitiveDesc> {
+        vec![
+            PrimitiveDesc::Cube { size: 2.0, subdivisions: 3 },
+            PrimitiveDesc::UvSphere { radius: 1.0, segments: 12, rings: 7 },
+            PrimitiveDesc::Icosphere { radius: 1.5, subdivisions: 2 },
+            PrimitiveDesc::Cylinder { radius: 0.5, height: 2.0, segments: 10, stacks: 3 },
+            PrimitiveDesc::Cone { radius: 1.0, height: 1.5, segments: 9, stacks: 2 },
+            PrimitiveDesc::Torus { radius: 1.0, tube_radius: 0.25, segments: 16, tube_segments: 8 },
+            PrimitiveDesc::Capsule { radius: 0.5, height: 1.0, segments: 10, rings: 4 },
+        ]
+    }
+
+    // Vertices at the same position share an id
+    fn welded(mesh: &MeshData) -> Vec<u32> {
+        let mut ids = HashMap::new();
+        mesh.vertices.iter()
+            .map(|vertex| {
+                let [x, y, z] = vertex.position;
+                let key = [x, y, z].iter().map(|&x| x.to_bits()).collect::<Vec<_>>();
+                let next = ids.len() as u32;
+                *ids.entry(key).or_insert(next)
+            })
+            .collect()
+    }
+
+    // Directed edges of the welded triangles, with how often each occurs
+    fn edges(mesh: &MeshData) -> HashMap<(u32, u32), u32> {
+        let ids = welded(mesh);
+        let mut edges = HashMap::new();
+        for triangle in mesh.indices.chunks_exact(3) {
+            for k in 0..3 {
+                let edge = (ids[triangle[k] as usize], ids[triangle[(k + 1) % 3] as usize]);
+                *edges.entry(edge).or_insert(0) += 1;
+            }
+        }
+        edges
+    }
+
+    fn signed_volume(mesh: &MeshData) -> f32 {
+        mesh.indices.chunks_exact(3)
+            .map(|triangle| {
+                let corner = |k: usize| Vector3::from(mesh.vertices[triangle[k] as usize].position);
+                corner(0).dot(corner(1).cross(corner(2))) / 6.0
+            })
+            .sum()
+    }
+
+    #[test]
+    fn indices_are_triangles_within_range() {
+        let mut shapes = closed_shapes();
+        shapes.push(PrimitiveDesc::Plane { size: 1.0, subdivisions: 4 });
+        for desc in shapes {
+            let mesh = desc.build();
+            assert!(!mesh.indices.is_empty(), "{:?}", desc);
+            assert_eq!(mesh.indices.len() % 3, 0, "{:?}", desc);
+            assert!(mesh.indices.iter().all(|&i| (i as usize) < mesh.vertices.len()), "{:?}", desc);
+        }
+    }
+
+    #[test]
+    fn normals_and_tangents_are_unit_and_perpendicular() {
+        let mut shapes = closed_shapes();
+        shapes.push(PrimitiveDesc::Plane { size: 1.0, subdivisions: 4 });
+        for desc in shapes {
+            for vertex in &desc.build().vertices {
+                let normal = Vector3::from(vertex.normal);
+                let [x, y, z, w] = vertex.tangent;
+                let tangent = Vector3::new(x, y, z);
+                assert!((normal.magnitude() - 1.0).abs() < 1e-5, "{:?}: {:?}", desc, vertex);
+                assert!((tangent.magnitude() - 1.0).abs() < 1e-5, "{:?}: {:?}", desc, vertex);
+                assert!(normal.dot(tangent).abs() < 1e-5, "{:?}: {:?}", desc, vertex);
+                assert!(w == 1.0 || w == -1.0, "{:?}: {:?}", desc, vertex);
+            }
+        }
+    }
+
+    #[test]
+    fn closed_shapes_are_watertight_and_face_out() {
+        for desc in closed_shapes() {
+            let mesh = desc.build();
+            // Every edge is crossed once each way: no holes, no edges
+            // shared by three triangles and a consistent winding
+            let edges = edges(&mesh);
+            for (&(a, b), &count) in &edges {
+                assert_eq!(count, 1, "{:?}: edge {} -> {} is used {} times", desc, a, b, count);
+                assert!(edges.contains_key(&(b, a)), "{:?}: edge {} -> {} is open", desc, a, b);
+            }
+            assert!(signed_volume(&mesh) > 0.0, "{:?} is inside out", desc);
+        }
+    }
+
+    #[test]
+    fn cube_volume_and_plane_border() {
+        let volume = signed_volume(&cube(2.0, 3));
+        assert!((volume - 8.0).abs() < 1e-4, "{}", volume);
+
+        // A plane is open, its border is the only edge crossed once
+        let edges = edges(&plane(1.0, 4));
+        let border = edges.keys().filter(|&&(a, b)| !edges.contains_key(&(b, a))).count();
+        assert_eq!(border, 16);
+        assert!(plane(1.0, 4).vertices.iter().all(|vertex| vertex.normal == [0.0, 1.0, 0.0]));
+    }
+}
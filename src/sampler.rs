@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::num::NonZeroU8;
+use std::rc::Rc;
+use serde::Deserialize;
+
+use crate::texture::SamplerDesc;
+
+/// Common sampler settings, chosen by name in a scene.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum SamplerPreset {
+    // Trilinear, clamped to the edge
+    Linear,
+    // Nearest everything, for pixel art
+    Pixelated,
+    // UVs outside 0..1 tile
+    Repeat,
+    // UVs outside 0..1 tile, every other copy flipped
+    Mirror,
+    // UVs outside 0..1 get the colour. Falls back to clamping to the edge
+    // where the device can't do borders
+    Border(BorderColor),
+}
+
+impl Default for SamplerPreset {
+    fn default() -> Self { SamplerPreset::Linear }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum BorderColor {
+    TransparentBlack,
+    OpaqueBlack,
+    OpaqueWhite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum AddressMode {
+    ClampToEdge,
+    Repeat,
+    MirrorRepeat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum FilterMode {
+    Nearest,
+    Linear,
+}
+
+impl From<FilterMode> for wgpu::FilterMode {
+    fn from(filter: FilterMode) -> Self {
+        match filter {
+            FilterMode::Nearest => wgpu::FilterMode::Nearest,
+            FilterMode::Linear => wgpu::FilterMode::Linear,
+        }
+    }
+}
+
+/// How a texture is sampled, as a scene writes it: a preset, with any of
+/// its address mode and filters replaced.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SamplerConfig {
+
+    #[serde(default)]
+    pub preset: SamplerPreset,
+    // Along both U and V
+    #[serde(default)]
+    pub address_mode: Option<AddressMode>,
+    #[serde(default)]
+    pub mag_filter: Option<FilterMode>,
+    #[serde(default)]
+    pub min_filter: Option<FilterMode>,
+    #[serde(default)]
+    pub mipmap_filter: Option<FilterMode>,
+    // 1 is off. Higher values keep textures seen at a glancing angle sharp,
+    // up to 16
+    #[serde(default = "SamplerConfig::default_anisotropy")]
+    pub anisotropy: u8,
+    // Finest and coarsest mip level sampled
+    #[serde(default = "SamplerConfig::default_lod_clamp")]
+    pub lod_clamp: [f32; 2],
+
+} impl SamplerConfig {
+
+    fn default_anisotropy() -> u8 { 1 }
+    fn default_lod_clamp() -> [f32; 2] { [0.0, f32::MAX] }
+
+    pub fn desc(&self) -> SamplerDesc {
+        let preset = match self.preset {
+            SamplerPreset::Linear => SamplerDesc::default(),
+            SamplerPreset::Pixelated => SamplerDesc::pixelated(),
+            SamplerPreset::Repeat => SamplerDesc::repeat(wgpu::AddressMode::Repeat),
+            SamplerPreset::Mirror => SamplerDesc::repeat(wgpu::AddressMode::MirrorRepeat),
+            SamplerPreset::Border(color) => SamplerDesc::border(match color {
+                BorderColor::TransparentBlack => wgpu::SamplerBorderColor::TransparentBlack,
+                BorderColor::OpaqueBlack => wgpu::SamplerBorderColor::OpaqueBlack,
+                BorderColor::OpaqueWhite => wgpu::SamplerBorderColor::OpaqueWhite,
+            }),
+        };
+        let address_mode = self.address_mode.map(|mode| match mode {
+            AddressMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
+            AddressMode::Repeat => wgpu::AddressMode::Repeat,
+            AddressMode::MirrorRepeat => wgpu::AddressMode::MirrorRepeat,
+        });
+        let filter = |filter: Option<FilterMode>, preset| filter.map_or(preset, wgpu::FilterMode::from);
+        SamplerDesc {
+            address_mode_u: address_mode.unwrap_or(preset.address_mode_u),
+            address_mode_v: address_mode.unwrap_or(preset.address_mode_v),
+            mag_filter: filter(self.mag_filter, preset.mag_filter),
+            min_filter: filter(self.min_filter, preset.min_filter),
+            mipmap_filter: filter(self.mipmap_filter, preset.mipmap_filter),
+            anisotropy: self.anisotropy,
+            lod_min_clamp: self.lod_clamp[0],
+            lod_max_clamp: self.lod_clamp[1],
+            ..preset
+        }
+    }
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        Self {
+            preset: SamplerPreset::default(),
+            address_mode: None,
+            mag_filter: None,
+            min_filter: None,
+            mipmap_filter: None,
+            anisotropy: Self::default_anisotropy(),
+            lod_clamp: Self::default_lod_clamp(),
+        }
+    }
+}
+
+/// Samplers shared between every texture asking for the same settings.
+/// Anything can stand in for the sampler where no device is at hand.
+pub struct SamplerCache<S = wgpu::Sampler> {
+
+    samplers: HashMap<SamplerDesc, Rc<S>>,
+
+} impl<S> SamplerCache<S> {
+
+    pub fn new() -> Self {
+        Self { samplers: HashMap::new() }
+    }
+
+    /// `create` only runs for settings not asked for before.
+    pub fn get_or_create(&mut self, desc: &SamplerDesc, create: impl FnOnce(&SamplerDesc) -> S) -> Rc<S> {
+        self.samplers.entry(*desc)
+            .or_insert_with(|| Rc::new(create(desc)))
+            .clone()
+    }
+
+    /// Drops the samplers no texture uses anymore.
+    pub fn purge(&mut self) {
+        self.samplers.retain(|_, sampler| Rc::strong_count(sampler) > 1);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samplers.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.samplers.is_empty()
+    }
+}
+
+impl SamplerCache {
+    pub fn get(&mut self, device: &wgpu::Device, desc: &SamplerDesc) -> Rc<wgpu::Sampler> {
+        self.get_or_create(desc, |desc| create_sampler(device, desc))
+    }
+}
+
+impl<S> Default for SamplerCache<S> {
+    fn default() -> Self { Self::new() }
+}
+
+/// A sampler outside any cache. Settings the device can't do are brought
+/// within its reach rather than failing: borders without
+/// `ADDRESS_MODE_CLAMP_TO_BORDER` clamp to the edge, and anisotropy is
+/// rounded down to a power of two no higher than 16.
+pub fn create_sampler(device: &wgpu::Device, desc: &SamplerDesc) -> wgpu::Sampler {
+    let supports_border = device.features().contains(wgpu::Features::ADDRESS_MODE_CLAMP_TO_BORDER);
+    let address_mode = |mode| match mode {
+        wgpu::AddressMode::ClampToBorder if !supports_border => {
+            log::warn!("Sampler borders aren't supported, clamping to the edge instead");
+            wgpu::AddressMode::ClampToEdge
+        }
+        mode => mode,
+    };
+    device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some("Sampler"),
+        address_mode_u: address_mode(desc.address_mode_u),
+        address_mode_v: address_mode(desc.address_mode_v),
+        address_mode_w: wgpu::AddressMode::ClampToEdge,
+        mag_filter: desc.mag_filter,
+        min_filter: desc.min_filter,
+        mipmap_filter: desc.mipmap_filter,
+        lod_min_clamp: desc.lod_min_clamp,
+        lod_max_clamp: desc.lod_max_clamp,
+        compare: None,
+        anisotropy_clamp: anisotropy_clamp(desc.anisotropy),
+        border_color: desc.border_color,
+    })
+}
+
+// None for 0 and 1, which both mean off
+fn anisotropy_clamp(anisotropy: u8) -> Option<NonZeroU8> {
+    let clamp = anisotropy.min(16);
+    if clamp < 2 {
+        return None;
+    }
+    NonZeroU8::new(1 << (7 - clamp.leading_zeros()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_and_overrides() {
+        assert_eq!(SamplerConfig::default().desc(), SamplerDesc::default());
+
+        let config: SamplerConfig = ron::de::from_str("(preset: Pixelated, mag_filter: Some(Linear), anisotropy: 4)").unwrap();
+        let desc = config.desc();
+        assert_eq!(desc.mag_filter, wgpu::FilterMode::Linear);
+        assert_eq!((desc.min_filter, desc.mipmap_filter), (wgpu::FilterMode::Nearest, wgpu::FilterMode::Nearest));
+        assert_eq!(desc.anisotropy, 4);
+
+        let config = SamplerConfig {
+            preset: SamplerPreset::Repeat,
+            address_mode: Some(AddressMode::MirrorRepeat),
+            mipmap_filter: Some(FilterMode::Nearest),
+            lod_clamp: [1.0, 3.0],
+            ..SamplerConfig::default()
+        };
+        assert_eq!(config.desc(), SamplerDesc {
+            address_mode_u: wgpu::AddressMode::MirrorRepeat,
+            address_mode_v: wgpu::AddressMode::MirrorRepeat,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: 1.0,
+            lod_max_clamp: 3.0,
+            ..SamplerDesc::repeat(wgpu::AddressMode::Repeat)
+        });
+
+        // The border colour stays with the preset
+        let config = SamplerConfig {
+            preset: SamplerPreset::Border(BorderColor::OpaqueWhite),
+            min_filter: Some(FilterMode::Nearest),
+            ..SamplerConfig::default()
+        };
+        assert_eq!(config.desc().border_color, Some(wgpu::SamplerBorderColor::OpaqueWhite));
+        assert_eq!(config.desc().address_mode_u, wgpu::AddressMode::ClampToBorder);
+        assert_eq!(config.desc().min_filter, wgpu::FilterMode::Nearest);
+    }
+
+    #[test]
+    fn equal_settings_share_a_sampler() {
+        let mut cache = SamplerCache::<usize>::default();
+        let mut created = 0;
+        let mut get = |cache: &mut SamplerCache<usize>, desc: &SamplerDesc| cache.get_or_create(desc, |_| {
+            created += 1;
+            created
+        });
+        let linear = get(&mut cache, &SamplerDesc::default());
+        let again = get(&mut cache, &SamplerDesc::default());
+        let pixelated = get(&mut cache, &SamplerDesc::pixelated());
+        assert!(Rc::ptr_eq(&linear, &again));
+        assert!(!Rc::ptr_eq(&linear, &pixelated));
+        assert_eq!((*linear, *pixelated), (1, 2));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn purge_drops_unused_samplers() {
+        let mut cache = SamplerCache::<()>::default();
+        let kept = cache.get_or_create(&SamplerDesc::default(), |_| ());
+        cache.get_or_create(&SamplerDesc::pixelated(), |_| ());
+        assert_eq!(cache.len(), 2);
+        cache.purge();
+        assert_eq!(cache.len(), 1);
+        assert!(Rc::ptr_eq(&kept, &cache.get_or_create(&SamplerDesc::default(), |_| unreachable!())));
+        drop(kept);
+        cache.purge();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn anisotropy_rounds_down_to_a_power_of_two() {
+        let clamps = [0, 1, 2, 3, 4, 7, 8, 15, 16, 17, 255]
+            .iter()
+            .map(|&anisotropy| anisotropy_clamp(anisotropy).map(NonZeroU8::get))
+            .collect::<Vec<_>>();
+        assert_eq!(clamps, [None, None, Some(2), Some(2), Some(4), Some(4), Some(8), Some(8), Some(16), Some(16), Some(16)]);
+    }
+}
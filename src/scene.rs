@@ -0,0 +1,312 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use cgmath::{Vector3, Quaternion, Rotation3, InnerSpace, Deg};
+use serde::Deserialize;
+use anyhow::*;
+
+use crate::instance::{self, Instance};
+use crate::lod::LodDesc;
+use crate::particles::EmitterDesc;
+use crate::physics::{self, PhysicsDesc, BodyDesc};
+use crate::primitives::PrimitiveDesc;
+use crate::sampler::SamplerConfig;
+use crate::sprite::SpriteDesc;
+use crate::terrain::TerrainDesc;
+use crate::text::TextDesc;
+
+pub const SCENE_PATH: &str = "res/scene.ron";
+// Drawn when the scene names no texture
+pub const DEFAULT_TEXTURE_PATH: &str = "src/edinaldo-pereira.png";
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+pub struct SceneDesc {
+
+    #[serde(default)]
+    pub mesh: MeshDesc,
+    // Degrees. Faces meeting at a sharper angle get a hard edge when normals
+    // are generated; None smooths everything
+    #[serde(default)]
+    pub crease_angle: Option<f32>,
+    // None falls back to DEFAULT_TEXTURE_PATH
+    #[serde(default)]
+    pub texture: Option<PathBuf>,
+    // How the scene texture is sampled. Model materials bring their own
+    #[serde(default)]
+    pub sampler: SamplerConfig,
+    #[serde(default)]
+    pub instances: InstancesDesc,
+    #[serde(default)]
+    pub lod: LodDesc,
+    #[serde(default)]
+    pub physics: PhysicsDesc,
+    #[serde(default)]
+    pub text: TextDesc,
+    // Drawn in pixel space over the 3D scene
+    #[serde(default)]
+    pub sprites: Vec<SpriteDesc>,
+    #[serde(default)]
+    pub particles: Vec<EmitterDesc>,
+
+} impl SceneDesc {
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let src = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read scene {}", path.display()))?;
+        ron::de::from_str(&src)
+            .with_context(|| format!("Unable to parse scene {}", path.display()))
+    }
+
+    pub fn diff(&self, other: &Self) -> SceneDiff {
+        SceneDiff {
+            mesh: self.mesh != other.mesh
+                || self.crease_angle != other.crease_angle
+                || self.lod.simplify != other.lod.simplify
+                || self.lod.num_levels() != other.lod.num_levels(),
+            texture: self.texture != other.texture || self.sampler != other.sampler,
+            instances: self.instances != other.instances,
+            lod: self.lod != other.lod,
+            physics: self.physics != other.physics,
+            text: self.text != other.text,
+            sprites: self.sprites != other.sprites,
+            particles: self.particles != other.particles,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub enum MeshDesc {
+    Pentagon,
+    // Wavefront .obj file, with the .mtl libraries it references
+    Obj(PathBuf),
+    // .gltf or .glb, the whole default scene as one model
+    Gltf(PathBuf),
+    // .ply file, ASCII or binary, as a single mesh
+    Ply(PathBuf),
+    Primitive(PrimitiveDesc),
+    // Heightmap image, one mesh per chunk
+    Terrain(TerrainDesc),
+    // Binary .mesh written by mesh-convert. Geometry only, drawn with the
+    // scene texture
+    Cache(PathBuf),
+}
+
+impl Default for MeshDesc {
+    fn default() -> Self { MeshDesc::Pentagon }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub enum InstancesDesc {
+    Grid { per_row: u32 },
+    List(Vec<InstanceDesc>),
+} impl InstancesDesc {
+
+    pub fn build(&self) -> Vec<Instance> {
+        match self {
+            InstancesDesc::Grid { per_row } => instance::grid(*per_row),
+            InstancesDesc::List(list) => list.iter().map(InstanceDesc::build).collect(),
+        }
+    }
+
+    /// Rigid bodies for the instances that have one, given the instances
+    /// `build` returned.
+    pub fn bodies(&self, instances: &[Instance]) -> Result<Vec<physics::Body>> {
+        match self {
+            InstancesDesc::Grid { .. } => Ok(Vec::new()),
+            InstancesDesc::List(list) => list.iter()
+                .zip(instances)
+                .enumerate()
+                .filter_map(|(i, (desc, instance))| {
+                    desc.body.as_ref().map(|body| {
+                        physics::Body::new(body, i, instance)
+                            .with_context(|| format!("Invalid body on instance {}", i))
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Default for InstancesDesc {
+    fn default() -> Self { InstancesDesc::Grid { per_row: 10 } }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct InstanceDesc {
+
+    pub position: [f32; 3],
+    #[serde(default = "InstanceDesc::default_axis")]
+    pub axis: [f32; 3],
+    // Degrees
+    #[serde(default)]
+    pub angle: f32,
+    #[serde(default = "InstanceDesc::default_opacity")]
+    pub opacity: f32,
+    // Lets physics drive the instance
+    #[serde(default)]
+    pub body: Option<BodyDesc>,
+    // Added to the mesh's own morph target weights, in target order
+    #[serde(default)]
+    pub morph_weights: Vec<f32>,
+    // Text drawn above the instance
+    #[serde(default)]
+    pub label: Option<String>,
+
+} impl InstanceDesc {
+
+    fn default_axis() -> [f32; 3] { [0.0, 0.0, 1.0] }
+    fn default_opacity() -> f32 { 1.0 }
+
+    pub fn build(&self) -> Instance {
+        let axis = Vector3::from(self.axis);
+        let rotation = if axis.magnitude2() > 0.0 {
+            Quaternion::from_axis_angle(axis.normalize(), Deg(self.angle))
+        } else {
+            Quaternion::from_axis_angle(Vector3::unit_z(), Deg(0.0))
+        };
+        let mut instance = Instance::new(self.position.into(), rotation, self.opacity);
+        instance.set_morph_weights(&self.morph_weights);
+        instance.set_label(self.label.clone());
+        instance
+    }
+}
+
+/// Which parts of the running state have to be rebuilt to go from one
+/// scene to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SceneDiff {
+    pub mesh: bool,
+    pub texture: bool,
+    pub instances: bool,
+    pub lod: bool,
+    pub physics: bool,
+    pub text: bool,
+    pub sprites: bool,
+    pub particles: bool,
+}
+
+/// Polls the scene file's modification time and hands out freshly parsed
+/// scenes. The running scene only changes once the caller `accept`s one.
+pub struct SceneWatcher {
+
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    last_poll: Instant,
+    scene: SceneDesc,
+
+} impl SceneWatcher {
+
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let modified = Self::modified(&path);
+        let scene = SceneDesc::load(&path).unwrap_or_else(|e| {
+            log::error!("{:?}; using the default scene", e);
+            SceneDesc::default()
+        });
+        Self { path, modified, last_poll: Instant::now(), scene }
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+    }
+
+    pub fn path(&self) -> &Path { &self.path }
+    pub fn scene(&self) -> &SceneDesc { &self.scene }
+
+    /// Returns the new scene if the file changed since the last poll and
+    /// parses correctly. Parse errors are logged and the file is not read
+    /// again until it changes once more.
+    pub fn poll(&mut self) -> Option<SceneDesc> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return None;
+        }
+        self.last_poll = Instant::now();
+
+        let modified = Self::modified(&self.path);
+        if modified.is_none() || modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+
+        match SceneDesc::load(&self.path) {
+            Ok(scene) if scene != self.scene => Some(scene),
+            Ok(_) => None,
+            Err(e) => {
+                log::error!("{:?}; keeping the previous scene", e);
+                None
+            }
+        }
+    }
+
+    pub fn accept(&mut self, scene: SceneDesc) {
+        self.scene = scene;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scene(src: &str) -> SceneDesc {
+        ron::de::from_str(src).unwrap()
+    }
+
+    #[test]
+    fn diff_reports_only_what_changed() {
+        let base = scene("(texture: Some(\"a.png\"), instances: Grid(per_row: 4))");
+        assert_eq!(base.diff(&base.clone()), SceneDiff::default());
+
+        let instances = base.diff(&scene("(texture: Some(\"a.png\"), instances: Grid(per_row: 5))"));
+        assert_eq!(instances, SceneDiff { instances: true, ..Default::default() });
+
+        let texture = base.diff(&scene("(texture: Some(\"b.png\"), instances: Grid(per_row: 4))"));
+        assert_eq!(texture, SceneDiff { texture: true, ..Default::default() });
+        let sampler = base.diff(&scene("(texture: Some(\"a.png\"), sampler: (preset: Repeat), instances: Grid(per_row: 4))"));
+        assert_eq!(sampler, SceneDiff { texture: true, ..Default::default() });
+
+        let model = base.diff(&scene("(mesh: Obj(\"cube.obj\"), texture: Some(\"a.png\"), instances: Grid(per_row: 4))"));
+        assert_eq!(model, SceneDiff { mesh: true, ..Default::default() });
+        let crease = base.diff(&scene("(crease_angle: Some(30.0), texture: Some(\"a.png\"), instances: Grid(per_row: 4))"));
+        assert_eq!(crease, SceneDiff { mesh: true, ..Default::default() });
+
+        // Moving a switch distance only reselects levels, adding one
+        // rebuilds the model's chain too
+        let distance = base.diff(&scene("(texture: Some(\"a.png\"), instances: Grid(per_row: 4), lod: (distances: [6.0, 20.0]))"));
+        assert_eq!(distance, SceneDiff { lod: true, ..Default::default() });
+        let level = base.diff(&scene("(texture: Some(\"a.png\"), instances: Grid(per_row: 4), lod: (distances: [6.0, 12.0, 18.0]))"));
+        assert_eq!(level, SceneDiff { mesh: true, lod: true, ..Default::default() });
+    }
+
+    #[test]
+    fn broken_files_keep_the_previous_scene() {
+        let path = std::env::temp_dir().join(format!("learn_wgpu_scene_{}.ron", std::process::id()));
+        std::fs::write(&path, "(instances: Grid(per_row: 3))").unwrap();
+        let mut watcher = SceneWatcher::new(&path);
+        assert_eq!(watcher.scene().instances, InstancesDesc::Grid { per_row: 3 });
+
+        // As if the poll interval had passed and the file had changed since
+        let poll_changed = |watcher: &mut SceneWatcher, src: &str| {
+            std::fs::write(&path, src).unwrap();
+            watcher.last_poll -= POLL_INTERVAL;
+            watcher.modified = None;
+            watcher.poll()
+        };
+        assert_eq!(poll_changed(&mut watcher, "(instances: Grid(per_row: "), None);
+        assert_eq!(watcher.scene().instances, InstancesDesc::Grid { per_row: 3 });
+        // Saving the same scene again changes nothing either
+        assert_eq!(poll_changed(&mut watcher, "(instances: Grid(per_row: 3))"), None);
+
+        let fixed = poll_changed(&mut watcher, "(instances: Grid(per_row: 6))").expect("the fixed file should load");
+        assert_eq!(fixed.instances, InstancesDesc::Grid { per_row: 6 });
+        // Still the old scene until the caller takes the new one
+        assert_eq!(watcher.scene().instances, InstancesDesc::Grid { per_row: 3 });
+        watcher.accept(fixed);
+        assert_eq!(watcher.scene().instances, InstancesDesc::Grid { per_row: 6 });
+
+        // Too soon after the last poll, the file isn't looked at
+        std::fs::write(&path, "(instances: Grid(per_row: 7))").unwrap();
+        watcher.modified = None;
+        assert_eq!(watcher.poll(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
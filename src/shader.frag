@@ -2,12 +2,23 @@
 #version 450
 
 layout(location = 0) in vec2 v_tex_coords;
+layout(location = 1) in float v_opacity;
 
 layout(set = 0, binding = 0) uniform texture2D t_diffuse;
 layout(set = 0, binding = 1) uniform sampler s_diffuse;
 
+layout(set = 2, binding = 1) uniform Material {
+    vec4 u_base_color_factor;
+    vec3 u_emissive_factor;
+};
+layout(set = 2, binding = 2) uniform texture2D t_emissive;
+layout(set = 2, binding = 3) uniform sampler s_emissive;
+
 layout(location = 0) out vec4 f_color;
 
 void main() {
-    f_color = texture( sampler2D(t_diffuse, s_diffuse), v_tex_coords );
+    f_color = texture( sampler2D(t_diffuse, s_diffuse), v_tex_coords ) * u_base_color_factor;
+    // Nothing is lit, so emission adds straight to the colour
+    f_color.rgb += texture( sampler2D(t_emissive, s_emissive), v_tex_coords ).rgb * u_emissive_factor;
+    f_color.a *= v_opacity;
 }
@@ -0,0 +1,536 @@
+use std::collections::HashMap;
+use cgmath::{Point3, Vector3, InnerSpace};
+
+use crate::model::MeshData;
+use crate::bounds::Sphere;
+use crate::optimize;
+
+// Border edges count this many times more than the faces around them, so
+// the outline only moves where it is straight
+const BORDER_WEIGHT: f64 = 10.0;
+
+/// Collapses edges of `mesh` until it has at most `target_triangles`
+/// triangles, or until every collapse left would either move the surface
+/// further than `max_error` times the radius of the mesh's bounding sphere
+/// or break one of the rules below. Returns the error reached, on the same
+/// scale.
+///
+/// Every collapse moves a vertex onto one of its neighbours, so vertex data
+/// is never invented and only the indices change; vertices nothing uses
+/// any more stay until `optimize::optimize_vertex_fetch`. Vertices on a
+/// border or a UV or normal seam only slide along it, and the ends of
+/// borders and seams never move.
+pub fn simplify(mesh: &mut MeshData, target_triangles: usize, max_error: f32) -> f32 {
+    let mut simplifier = match Simplifier::new(mesh) {
+        Some(simplifier) => simplifier,
+        None => return 0.0,
+    };
+    let error_limit = max_error as f64 * simplifier.radius;
+    let mut error = 0.0_f64;
+
+    // Each pass makes the cheapest collapses that don't touch each other,
+    // then costs are measured again around the changed vertices
+    while simplifier.num_triangles > target_triangles {
+        let mut candidates = (0..simplifier.kinds.len())
+            .filter_map(|u| simplifier.cheapest_collapse(u))
+            .filter(|&(cost, _, _)| cost <= error_limit)
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            break;
+        }
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut touched = vec![false; simplifier.kinds.len()];
+        for (cost, u, v) in candidates {
+            if simplifier.num_triangles <= target_triangles {
+                break;
+            }
+            if touched[u] || touched[v] {
+                continue;
+            }
+            for neighbour in simplifier.neighbours(u) {
+                touched[neighbour] = true;
+            }
+            touched[u] = true;
+            simplifier.collapse(u, v);
+            error = error.max(cost);
+        }
+    }
+
+    mesh.indices = simplifier.triangles.iter()
+        .zip(&simplifier.alive)
+        .filter(|(_, &alive)| alive)
+        .flat_map(|(triangle, _)| triangle.iter().copied())
+        .collect();
+    if simplifier.radius > 0.0 { (error / simplifier.radius) as f32 } else { 0.0 }
+}
+
+/// Levels of detail for `mesh`, the first being the mesh itself and level
+/// `i` keeping `ratio` to the power of `i` of its triangles, each simplified
+/// from the full mesh and optimised. The chain ends early at the first
+/// level `max_error` keeps from getting smaller than the one before.
+pub fn lod_chain(mesh: &MeshData, num_levels: usize, ratio: f32, max_error: f32) -> Vec<MeshData> {
+    let num_triangles = mesh.indices.len() / 3;
+    let mut levels = vec![mesh.clone()];
+    while levels.len() < num_levels {
+        let target = (num_triangles as f32 * ratio.powi(levels.len() as i32)) as usize;
+        let mut level = mesh.clone();
+        simplify(&mut level, target, max_error);
+        if level.indices.len() >= levels[levels.len() - 1].indices.len() {
+            break;
+        }
+        optimize::optimize(&mut level);
+        levels.push(level);
+    }
+    levels
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Interior,
+    // Slides along border edges only
+    Border,
+    // Split in two by a UV or normal seam; slides along the seam only, both
+    // halves together
+    Seam,
+    // Where seams or borders meet, or on a non-manifold edge
+    Locked,
+}
+
+struct Simplifier {
+
+    positions: Vec<Vector3<f64>>,
+    // First vertex at the same position; the rest of the simplifier only
+    // deals with these
+    position_of: Vec<usize>,
+    kinds: Vec<Kind>,
+    quadrics: Vec<Quadric>,
+    triangles: Vec<[u32; 3]>,
+    alive: Vec<bool>,
+    num_triangles: usize,
+    // Triangles around each position, dead ones included until they are
+    // skipped
+    triangles_of: Vec<Vec<usize>>,
+    radius: f64,
+
+} impl Simplifier {
+
+    // None when there is nothing to simplify
+    fn new(mesh: &MeshData) -> Option<Self> {
+        let num_vertices = mesh.vertices.len();
+        let positions = mesh.vertices.iter()
+            .map(|vertex| Vector3::new(vertex.position[0] as f64, vertex.position[1] as f64, vertex.position[2] as f64))
+            .collect::<Vec<_>>();
+        let mut first_at = HashMap::new();
+        let position_of = mesh.vertices.iter()
+            .enumerate()
+            .map(|(i, vertex)| *first_at.entry(bytemuck::bytes_of(&vertex.position)).or_insert(i))
+            .collect::<Vec<_>>();
+
+        // Triangles with two corners at the same place draw nothing
+        let triangles = mesh.indices.chunks_exact(3)
+            .map(|corners| [corners[0], corners[1], corners[2]])
+            .filter(|corners| {
+                let [a, b, c] = corner_positions(corners, &position_of);
+                a != b && b != c && c != a
+            })
+            .collect::<Vec<_>>();
+        if triangles.is_empty() {
+            return None;
+        }
+
+        let mut triangles_of = vec![Vec::new(); num_vertices];
+        let mut copies = vec![Vec::new(); num_vertices];
+        // The vertices each triangle on an edge uses at its two ends, lower
+        // position first. A seam runs along edges whose two triangles use
+        // different vertices
+        let mut edges: HashMap<(usize, usize), Vec<(u32, u32)>> = HashMap::new();
+        for (t, corners) in triangles.iter().enumerate() {
+            for k in 0..3 {
+                let (a, b) = (corners[k], corners[(k + 1) % 3]);
+                let position = position_of[a as usize];
+                triangles_of[position].push(t);
+                if !copies[position].contains(&a) {
+                    copies[position].push(a);
+                }
+                let ends = if position < position_of[b as usize] { (a, b) } else { (b, a) };
+                edges.entry(edge_key(position, position_of[b as usize])).or_default().push(ends);
+            }
+        }
+
+        let mut quadrics = vec![Quadric::default(); num_vertices];
+        let mut border_edges = vec![0; num_vertices];
+        let mut seam_edges = vec![0; num_vertices];
+        let mut non_manifold = vec![false; num_vertices];
+        for (&(a, b), ends) in &edges {
+            match ends.len() {
+                1 => {
+                    border_edges[a] += 1;
+                    border_edges[b] += 1;
+                }
+                2 if ends[0] != ends[1] => {
+                    seam_edges[a] += 1;
+                    seam_edges[b] += 1;
+                }
+                2 => {}
+                _ => {
+                    non_manifold[a] = true;
+                    non_manifold[b] = true;
+                }
+            }
+        }
+        for corners in &triangles {
+            let p = corner_positions(corners, &position_of);
+            let normal = (positions[p[1]] - positions[p[0]]).cross(positions[p[2]] - positions[p[0]]);
+            let length = normal.magnitude();
+            // Zero for a sliver, which then adds nothing
+            let normal = if length > 0.0 { normal / length } else { normal };
+            let face = Quadric::plane(normal, positions[p[0]], length / 2.0);
+            for k in 0..3 {
+                let (a, b) = (p[k], p[(k + 1) % 3]);
+                quadrics[a].add(&face);
+                let ends = &edges[&edge_key(a, b)];
+                if ends.len() == 1 || (ends.len() == 2 && ends[0] != ends[1]) {
+                    // A plane standing on a border or seam edge keeps the
+                    // line it is on in place
+                    let edge = positions[b] - positions[a];
+                    let side = edge.cross(normal);
+                    if side.magnitude2() > 0.0 {
+                        let line = Quadric {
+                            weight: 0.0,
+                            ..Quadric::plane(side.normalize(), positions[a], edge.magnitude2() * BORDER_WEIGHT)
+                        };
+                        quadrics[a].add(&line);
+                        quadrics[b].add(&line);
+                    }
+                }
+            }
+        }
+        let kinds = (0..num_vertices)
+            .map(|i| match (non_manifold[i], copies[i].len(), border_edges[i], seam_edges[i]) {
+                (false, 1, 0, 0) => Kind::Interior,
+                (false, 1, 2, 0) => Kind::Border,
+                (false, 2, 0, 2) => Kind::Seam,
+                _ => Kind::Locked,
+            })
+            .collect();
+
+        let used = copies.iter()
+            .flatten()
+            .map(|&i| i as usize)
+            .map(|i| Point3::new(positions[i].x as f32, positions[i].y as f32, positions[i].z as f32))
+            .collect::<Vec<_>>();
+        let radius = Sphere::from_points(&used).map_or(0.0, |sphere| sphere.radius as f64);
+        let num_triangles = triangles.len();
+        Some(Self {
+            positions,
+            position_of,
+            kinds,
+            quadrics,
+            alive: vec![true; num_triangles],
+            triangles,
+            num_triangles,
+            triangles_of,
+            radius,
+        })
+    }
+
+    fn live_triangles(&self, position: usize) -> impl Iterator<Item = usize> + '_ {
+        self.triangles_of[position].iter().copied().filter(move |&t| self.alive[t])
+    }
+
+    fn corners(&self, t: usize) -> [usize; 3] {
+        corner_positions(&self.triangles[t], &self.position_of)
+    }
+
+    fn neighbours(&self, position: usize) -> Vec<usize> {
+        let mut neighbours = self.live_triangles(position)
+            .flat_map(|t| self.corners(t).to_vec())
+            .filter(|&corner| corner != position)
+            .collect::<Vec<_>>();
+        neighbours.sort_unstable();
+        neighbours.dedup();
+        neighbours
+    }
+
+    // For every triangle on the edge from `u` to `v`, the vertices it uses
+    // at either end
+    fn edge_ends(&self, u: usize, v: usize) -> Vec<(u32, u32)> {
+        self.live_triangles(u)
+            .filter_map(|t| {
+                let corners = &self.triangles[t];
+                let end = |position: usize| corners.iter().copied().find(|&c| self.position_of[c as usize] == position);
+                Some((end(u)?, end(v)?))
+            })
+            .collect()
+    }
+
+    // Which vertex at `v` each vertex at `u` turns into, None when one of
+    // them has nowhere to go
+    fn copy_map(&self, u: usize, v: usize) -> Option<Vec<(u32, u32)>> {
+        let mut map = self.edge_ends(u, v);
+        map.sort_unstable();
+        map.dedup();
+        let consistent = map.windows(2).all(|pair| pair[0].0 != pair[1].0);
+        let complete = self.live_triangles(u)
+            .flat_map(|t| self.triangles[t].to_vec())
+            .filter(|&corner| self.position_of[corner as usize] == u)
+            .all(|corner| map.iter().any(|&(from, _)| from == corner));
+        if consistent && complete { Some(map) } else { None }
+    }
+
+    // The error of the cheapest collapse of `u` and where it goes, None
+    // when `u` can't move
+    fn cheapest_collapse(&self, u: usize) -> Option<(f64, usize, usize)> {
+        if self.position_of[u] != u || self.kinds[u] == Kind::Locked {
+            return None;
+        }
+        let neighbours = self.neighbours(u);
+        neighbours.iter()
+            .filter(|&&v| self.can_collapse(u, v, &neighbours))
+            .map(|&v| (self.collapse_error(u, v), u, v))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+    }
+
+    fn can_collapse(&self, u: usize, v: usize, neighbours_of_u: &[usize]) -> bool {
+        let ends = self.edge_ends(u, v);
+        let shared = ends.len();
+        match self.kinds[u] {
+            Kind::Border if shared != 1 => return false,
+            Kind::Seam if shared != 2 || ends[0] == ends[1] => return false,
+            _ => {}
+        }
+        if self.copy_map(u, v).is_none() {
+            return false;
+        }
+        // Only the triangles on the edge may close up, anything else sharing
+        // both neighbours would fold the surface onto itself
+        let neighbours_of_v = self.neighbours(v);
+        let common = neighbours_of_u.iter().filter(|n| neighbours_of_v.contains(n)).count();
+        if common != shared {
+            return false;
+        }
+        // No remaining triangle may flip over or collapse to a line
+        let target = self.positions[v];
+        self.live_triangles(u).all(|t| {
+            let corners = self.corners(t);
+            if corners.contains(&v) {
+                return true;
+            }
+            let position = |i: usize| self.positions[corners[i]];
+            let moved = |i: usize| if corners[i] == u { target } else { position(i) };
+            let before = (position(1) - position(0)).cross(position(2) - position(0));
+            let after = (moved(1) - moved(0)).cross(moved(2) - moved(0));
+            before.dot(after) > 0.0
+        })
+    }
+
+    // Root mean square distance from the planes of both vertices
+    fn collapse_error(&self, u: usize, v: usize) -> f64 {
+        let mut quadric = self.quadrics[u];
+        quadric.add(&self.quadrics[v]);
+        if quadric.weight > 0.0 {
+            (quadric.error(self.positions[v]).max(0.0) / quadric.weight).sqrt()
+        } else {
+            0.0
+        }
+    }
+
+    fn collapse(&mut self, u: usize, v: usize) {
+        let triangles = self.live_triangles(u).collect::<Vec<_>>();
+        // Each side of a seam moves onto the vertex on the same side
+        let map = self.copy_map(u, v).unwrap();
+        for t in triangles {
+            if self.corners(t).contains(&v) {
+                self.alive[t] = false;
+                self.num_triangles -= 1;
+            } else {
+                for corner in &mut self.triangles[t] {
+                    if let Some(&(_, to)) = map.iter().find(|&&(from, _)| from == *corner) {
+                        *corner = to;
+                    }
+                }
+                self.triangles_of[v].push(t);
+            }
+        }
+        self.triangles_of[u].clear();
+        let quadric = self.quadrics[u];
+        self.quadrics[v].add(&quadric);
+    }
+}
+
+fn edge_key(a: usize, b: usize) -> (usize, usize) {
+    if a < b { (a, b) } else { (b, a) }
+}
+
+fn corner_positions(corners: &[u32; 3], position_of: &[usize]) -> [usize; 3] {
+    [
+        position_of[corners[0] as usize],
+        position_of[corners[1] as usize],
+        position_of[corners[2] as usize],
+    ]
+}
+
+/// Sum of squared distances to a set of planes, each scaled by a weight,
+/// as a symmetric 4x4 matrix.
+#[derive(Debug, Clone, Copy, Default)]
+struct Quadric {
+
+    // xx, xy, xz, xw, yy, yz, yw, zz, zw, ww
+    m: [f64; 10],
+    // Area the planes came from; border planes don't add to it
+    weight: f64,
+
+} impl Quadric {
+
+    // The plane through `point` facing along the unit `normal`, counted
+    // `weight` times
+    fn plane(normal: Vector3<f64>, point: Vector3<f64>, weight: f64) -> Self {
+        let (x, y, z) = (normal.x, normal.y, normal.z);
+        let w = -normal.dot(point);
+        let mut m = [x * x, x * y, x * z, x * w, y * y, y * z, y * w, z * z, z * w, w * w];
+        for value in &mut m {
+            *value *= weight;
+        }
+        Self { m, weight }
+    }
+
+    fn add(&mut self, other: &Self) {
+        for (value, other) in self.m.iter_mut().zip(&other.m) {
+            *value += other;
+        }
+        self.weight += other.weight;
+    }
+
+    fn error(&self, p: Vector3<f64>) -> f64 {
+        let m = &self.m;
+        let (x, y, z) = (p.x, p.y, p.z);
+        m[0] * x * x + m[4] * y * y + m[7] * z * z + m[9]
+            + 2.0 * (m[1] * x * y + m[2] * x * z + m[5] * y * z + m[3] * x + m[6] * y + m[8] * z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives;
+
+    fn triangles(mesh: &MeshData) -> usize {
+        mesh.indices.len() / 3
+    }
+
+    // Edges of triangles by position, counted by direction. Positions are
+    // rounded so -0.0 and 0.0 meet
+    fn edges(mesh: &MeshData) -> HashMap<([i64; 3], [i64; 3]), usize> {
+        let key = |index: u32| {
+            let [x, y, z] = mesh.vertices[index as usize].position;
+            [(x * 1e5).round() as i64, (y * 1e5).round() as i64, (z * 1e5).round() as i64]
+        };
+        let mut edges = HashMap::new();
+        for triangle in mesh.indices.chunks_exact(3) {
+            for k in 0..3 {
+                *edges.entry((key(triangle[k]), key(triangle[(k + 1) % 3]))).or_insert(0) += 1;
+            }
+        }
+        edges
+    }
+
+    fn signed_volume(mesh: &MeshData) -> f32 {
+        mesh.indices.chunks_exact(3)
+            .map(|triangle| {
+                let p = |k: usize| Vector3::from(mesh.vertices[triangle[k] as usize].position);
+                p(0).dot(p(1).cross(p(2))) / 6.0
+            })
+            .sum()
+    }
+
+    #[test]
+    fn triangle_budgets_are_met() {
+        let sphere = primitives::uv_sphere(1.0, 32, 16);
+        for &target in &[800, 400, 200] {
+            let mut level = sphere.clone();
+            let error = simplify(&mut level, target, 1.0);
+            assert!(triangles(&level) <= target, "{} > {}", triangles(&level), target);
+            assert!(error <= 1.0);
+            // Only the indices change
+            assert_eq!(level.vertices.len(), sphere.vertices.len());
+            assert!(level.indices.iter().all(|&i| (i as usize) < level.vertices.len()));
+        }
+
+        // A tight error limit stops short of the budget
+        let mut level = sphere.clone();
+        let error = simplify(&mut level, 10, 0.001);
+        assert!(triangles(&level) > 10 && error <= 0.001);
+
+        // Each level is smaller than the last, and the chain stops once
+        // the error limit holds the size
+        let chain = lod_chain(&sphere, 4, 0.5, 1.0);
+        assert_eq!(chain.len(), 4);
+        for pair in chain.windows(2) {
+            assert!(triangles(&pair[1]) < triangles(&pair[0]));
+        }
+        assert_eq!(lod_chain(&primitives::cube(1.0, 1), 4, 0.5, 1.0).len(), 1);
+    }
+
+    #[test]
+    fn flat_regions_collapse_without_error() {
+        let mut cube = primitives::cube(2.0, 6);
+        let before = triangles(&cube);
+        let error = simplify(&mut cube, 0, 0.0);
+        assert_eq!(error, 0.0);
+        assert!(triangles(&cube) * 4 < before, "{} of {}", triangles(&cube), before);
+        // Still closed, facing out and the same shape
+        let edges = edges(&cube);
+        assert!(edges.iter().all(|(&(a, b), &count)| count == 1 && edges.contains_key(&(b, a))));
+        assert!((signed_volume(&cube) - 8.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn borders_stay_in_place() {
+        // Anything but the corners can go without moving the surface
+        let mut plane = primitives::plane(2.0, 8);
+        assert_eq!(simplify(&mut plane, 0, 0.01), 0.0);
+        assert!(triangles(&plane) < 128);
+
+        // Every open edge still runs along the square's outline, all the
+        // way round
+        let edges = edges(&plane);
+        let on_side = |[x, _, z]: [i64; 3]| x.abs() == 100_000 || z.abs() == 100_000;
+        let mut length = 0;
+        for &(a, b) in edges.keys().filter(|&&(a, b)| !edges.contains_key(&(b, a))) {
+            assert!(on_side(a) && on_side(b) && (a[0] == b[0] || a[2] == b[2]), "{:?} -> {:?}", a, b);
+            length += (a[0] - b[0]).abs() + (a[2] - b[2]).abs();
+        }
+        assert_eq!(length, 800_000);
+        let area = plane.indices.chunks_exact(3)
+            .map(|t| {
+                let p = |k: usize| Vector3::from(plane.vertices[t[k] as usize].position);
+                (p(1) - p(0)).cross(p(2) - p(0)).magnitude() / 2.0
+            })
+            .sum::<f32>();
+        assert!((area - 4.0).abs() < 1e-4, "{}", area);
+    }
+
+    #[test]
+    fn uv_and_normal_seams_are_kept() {
+        // The cube's faces only share positions, every edge is a seam
+        let mut cube = primitives::cube(2.0, 6);
+        simplify(&mut cube, 0, 1.0);
+        for triangle in cube.indices.chunks_exact(3) {
+            let normal = |k: usize| cube.vertices[triangle[k] as usize].normal;
+            assert!(normal(0) == normal(1) && normal(1) == normal(2), "a triangle crosses a seam");
+        }
+
+        // The sphere's texture wraps round where u = 0 meets u = 1. A
+        // triangle straddling that would use a seam vertex from the far
+        // side and stretch back across the whole image
+        let mut sphere = primitives::uv_sphere(1.0, 32, 16);
+        simplify(&mut sphere, 200, 1.0);
+        for triangle in sphere.indices.chunks_exact(3) {
+            let u = triangle.iter().map(|&i| sphere.vertices[i as usize].tex_coords[0]).collect::<Vec<_>>();
+            let (min, max) = (u.iter().copied().fold(1.0, f32::min), u.iter().copied().fold(0.0, f32::max));
+            let crosses = (min == 0.0 && max > 0.5) || (max == 1.0 && min < 0.5);
+            assert!(!crosses, "a triangle crosses the seam: {:?}", u);
+        }
+    }
+}
@@ -0,0 +1,543 @@
+use std::ops::{Add, Mul};
+use std::time::Instant;
+use cgmath::{Matrix4, Point3, Vector3, Quaternion, InnerSpace, SquareMatrix, Zero, Transform as _};
+use wgpu::util::DeviceExt;
+use vertex_layout_derive::VertexLayout;
+use anyhow::*;
+
+use crate::model::Vertex;
+use crate::bounds::{self, Bounds};
+
+// Size of the joint uniform array in skinned.vert
+pub const MAX_JOINTS: usize = 128;
+const MAX_FRAME_TIME: f32 = 0.25;
+
+/// Per-vertex skinning data, kept in its own vertex buffer next to the
+/// mesh's `Vertex` buffer.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable, VertexLayout)]
+pub struct SkinVertex {
+
+    #[layout(location = 10)]
+    pub joints: [u16; 4],
+    // Sum to 1
+    #[layout(location = 11)]
+    pub weights: [f32; 4],
+
+}
+
+/// Translation, rotation and scale, applied scale first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+
+} impl Transform {
+
+    pub fn identity() -> Self {
+        Self {
+            translation: Vector3::new(0.0, 0.0, 0.0),
+            rotation: Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    pub fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Joint {
+
+    pub name: String,
+    pub parent: Option<usize>,
+    // Anything between the joint and its parent joint, or above a root
+    // joint, that isn't animated
+    pub parent_transform: Matrix4<f32>,
+    pub rest: Transform,
+    // Takes bind pose model space into the joint's space
+    pub inverse_bind_matrix: Matrix4<f32>,
+
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Skeleton {
+
+    joints: Vec<Joint>,
+    // Every joint after its parent
+    order: Vec<usize>,
+
+} impl Skeleton {
+
+    pub fn new(joints: Vec<Joint>) -> Result<Self> {
+        if joints.len() > MAX_JOINTS {
+            bail!("Skeleton has {} joints, at most {} are supported", joints.len(), MAX_JOINTS);
+        }
+        if let Some(joint) = joints.iter().find(|joint| joint.parent.map_or(false, |parent| parent >= joints.len())) {
+            bail!("Joint {:?} has a parent outside the skeleton", joint.name);
+        }
+        // Repeatedly takes the joints whose parent is already placed
+        let mut placed = vec![false; joints.len()];
+        let mut order = Vec::with_capacity(joints.len());
+        while order.len() < joints.len() {
+            let before = order.len();
+            for (i, joint) in joints.iter().enumerate() {
+                if !placed[i] && joint.parent.map_or(true, |parent| placed[parent]) {
+                    placed[i] = true;
+                    order.push(i);
+                }
+            }
+            if order.len() == before {
+                bail!("Skeleton joints form a cycle");
+            }
+        }
+        Ok(Self { joints, order })
+    }
+
+    pub fn joints(&self) -> &[Joint] { &self.joints }
+
+    pub fn rest_pose(&self) -> Vec<Transform> {
+        self.joints.iter().map(|joint| joint.rest).collect()
+    }
+
+    /// Matrices taking bind pose vertices to where `pose`, one local
+    /// transform per joint, puts them.
+    pub fn joint_matrices(&self, pose: &[Transform]) -> Vec<Matrix4<f32>> {
+        let mut globals = vec![Matrix4::identity(); self.joints.len()];
+        for &i in &self.order {
+            let joint = &self.joints[i];
+            let parent = joint.parent.map_or(Matrix4::identity(), |parent| globals[parent]);
+            globals[i] = parent * joint.parent_transform * pose[i].matrix();
+        }
+        globals.iter()
+            .zip(&self.joints)
+            .map(|(global, joint)| global * joint.inverse_bind_matrix)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    // Hermite spline; every keyframe has an in tangent, a value and an out
+    // tangent, in that order
+    CubicSpline,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Keyframes {
+    Translation(Vec<Vector3<f32>>),
+    Rotation(Vec<Quaternion<f32>>),
+    Scale(Vec<Vector3<f32>>),
+}
+
+/// Animates one property of one joint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Channel {
+    pub joint: usize,
+    // Seconds, increasing
+    pub times: Vec<f32>,
+    pub keyframes: Keyframes,
+    pub interpolation: Interpolation,
+}
+
+impl Channel {
+    fn is_valid(&self) -> bool {
+        let per_keyframe = if self.interpolation == Interpolation::CubicSpline { 3 } else { 1 };
+        let num_values = match &self.keyframes {
+            Keyframes::Translation(values) | Keyframes::Scale(values) => values.len(),
+            Keyframes::Rotation(values) => values.len(),
+        };
+        !self.times.is_empty() && num_values == self.times.len() * per_keyframe
+    }
+
+    fn apply(&self, time: f32, transform: &mut Transform) {
+        match &self.keyframes {
+            Keyframes::Translation(values) => {
+                transform.translation = self.sample(values, time, |a, b, s| a * (1.0 - s) + b * s);
+            }
+            Keyframes::Scale(values) => {
+                transform.scale = self.sample(values, time, |a, b, s| a * (1.0 - s) + b * s);
+            }
+            Keyframes::Rotation(values) => {
+                transform.rotation = self.sample(values, time, slerp).normalize();
+            }
+        }
+    }
+
+    fn sample<T, F>(&self, values: &[T], time: f32, lerp: F) -> T
+    where T: Copy + Add<Output = T> + Mul<f32, Output = T>, F: Fn(T, T, f32) -> T {
+        let cubic = self.interpolation == Interpolation::CubicSpline;
+        let value = |i: usize| if cubic { values[i * 3 + 1] } else { values[i] };
+        let last = self.times.len() - 1;
+        // Index of the last keyframe at or before `time`
+        let i = match self.times.iter().rposition(|&t| t <= time) {
+            Some(i) if i < last => i,
+            Some(_) => return value(last),
+            None => return value(0),
+        };
+        let duration = self.times[i + 1] - self.times[i];
+        let s = if duration > 0.0 { (time - self.times[i]) / duration } else { 0.0 };
+        match self.interpolation {
+            Interpolation::Step => value(i),
+            Interpolation::Linear => lerp(value(i), value(i + 1), s),
+            Interpolation::CubicSpline => {
+                let (s2, s3) = (s * s, s * s * s);
+                let out_tangent = values[i * 3 + 2] * duration;
+                let in_tangent = values[(i + 1) * 3] * duration;
+                value(i) * (2.0 * s3 - 3.0 * s2 + 1.0)
+                    + out_tangent * (s3 - 2.0 * s2 + s)
+                    + value(i + 1) * (-2.0 * s3 + 3.0 * s2)
+                    + in_tangent * (s3 - s2)
+            }
+        }
+    }
+}
+
+// Along the shorter arc
+fn slerp(a: Quaternion<f32>, b: Quaternion<f32>, s: f32) -> Quaternion<f32> {
+    let b = if a.dot(b) < 0.0 { -b } else { b };
+    a.slerp(b, s)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimationClip {
+
+    pub name: String,
+    channels: Vec<Channel>,
+    duration: f32,
+
+} impl AnimationClip {
+
+    /// Channels without keyframes, or with a different number of values
+    /// than keyframes, are dropped.
+    pub fn new(name: String, channels: Vec<Channel>) -> Self {
+        let channels = channels.into_iter()
+            .filter(Channel::is_valid)
+            .collect::<Vec<_>>();
+        let duration = channels.iter()
+            .filter_map(|channel| channel.times.last().copied())
+            .fold(0.0, f32::max);
+        Self { name, channels, duration }
+    }
+
+    pub fn duration(&self) -> f32 { self.duration }
+
+    // Every keyframe time and the times halfway between them, in order
+    fn sample_times(&self) -> Vec<f32> {
+        let mut times = self.channels.iter()
+            .flat_map(|channel| channel.times.iter().copied())
+            .collect::<Vec<_>>();
+        times.sort_unstable_by(f32::total_cmp);
+        times.dedup();
+        let halfway = times.windows(2).map(|pair| (pair[0] + pair[1]) / 2.0).collect::<Vec<_>>();
+        times.extend(halfway);
+        times.sort_unstable_by(f32::total_cmp);
+        times
+    }
+
+    /// Overwrites the animated properties of `pose` with their values at
+    /// `time`, holding the first and last keyframes outside the clip.
+    pub fn sample(&self, time: f32, pose: &mut [Transform]) {
+        for channel in &self.channels {
+            if let Some(transform) = pose.get_mut(channel.joint) {
+                channel.apply(time, transform);
+            }
+        }
+    }
+}
+
+/// A skeleton and the clips that animate it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkinData {
+    pub skeleton: Skeleton,
+    pub clips: Vec<AnimationClip>,
+}
+
+impl SkinData {
+    /// Joint matrices for `clip` at `time`, looping. Without clips the
+    /// skeleton stays in its rest pose.
+    pub fn joint_matrices(&self, clip: usize, time: f32) -> Vec<Matrix4<f32>> {
+        let mut pose = self.skeleton.rest_pose();
+        if let Some(clip) = self.clips.get(clip) {
+            let time = if clip.duration() > 0.0 { time % clip.duration() } else { 0.0 };
+            clip.sample(time, &mut pose);
+        }
+        self.skeleton.joint_matrices(&pose)
+    }
+
+    /// Bounds of a skinned mesh over its rest pose and every clip, sampled
+    /// at each keyframe and halfway between. Curves bulging out between
+    /// the samples can leave a little of the mesh outside.
+    pub fn bounds(&self, vertices: &[Vertex], skin: &[SkinVertex]) -> Option<Bounds> {
+        let mut poses = vec![self.skeleton.rest_pose()];
+        for clip in &self.clips {
+            for time in clip.sample_times() {
+                let mut pose = self.skeleton.rest_pose();
+                clip.sample(time, &mut pose);
+                poses.push(pose);
+            }
+        }
+        bounds::union_all(poses.iter().filter_map(|pose| {
+            let matrices = self.skeleton.joint_matrices(pose);
+            let points = vertices.iter()
+                .zip(skin)
+                .map(|(vertex, skin)| skin_position(&matrices, skin, vertex.position))
+                .collect::<Vec<_>>();
+            Bounds::from_points(&points)
+        }))
+    }
+}
+
+/// Where skinned.vert puts `position` with the given joint matrices.
+pub fn skin_position(matrices: &[Matrix4<f32>], skin: &SkinVertex, position: [f32; 3]) -> Point3<f32> {
+    let matrix = skin.joints.iter()
+        .zip(&skin.weights)
+        .fold(Matrix4::zero(), |sum, (&joint, &weight)| {
+            sum + matrices.get(joint as usize).copied().unwrap_or_else(Matrix4::identity) * weight
+        });
+    matrix.transform_point(Point3::from(position))
+}
+
+/// Plays the first clip of the model's skin and keeps the joint matrices
+/// skinned.vert reads up to date.
+pub struct SkinState {
+
+    time: f32,
+    last_update: Instant,
+    joint_buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+
+} impl SkinState {
+
+    pub fn new(device: &wgpu::Device) -> Self {
+        let joints = vec![JointRaw::from(Matrix4::identity()); MAX_JOINTS];
+        let joint_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Joint Buffer"),
+                contents: bytemuck::cast_slice(&joints),
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            }
+        );
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }
+            ],
+            label: Some("Joint Bind Group Layout"),
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: joint_buffer.as_entire_binding(),
+                }
+            ],
+            label: Some("Joint Bind Group"),
+        });
+        Self { time: 0.0, last_update: Instant::now(), joint_buffer, bind_group_layout, bind_group }
+    }
+
+    pub fn update(&mut self, queue: &wgpu::Queue, skin: Option<&SkinData>) {
+        let now = Instant::now();
+        let frame_time = (now - self.last_update).as_secs_f32().min(MAX_FRAME_TIME);
+        self.last_update = now;
+        self.time += frame_time;
+
+        if let Some(skin) = skin {
+            let joints = skin.joint_matrices(0, self.time)
+                .into_iter()
+                .map(JointRaw::from)
+                .collect::<Vec<_>>();
+            queue.write_buffer(&self.joint_buffer, 0, bytemuck::cast_slice(&joints));
+        }
+    }
+
+    pub fn bind_group_layout(&self) -> &wgpu::BindGroupLayout { &self.bind_group_layout }
+    pub fn bind_group(&self) -> &wgpu::BindGroup { &self.bind_group }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct JointRaw {
+    matrix: [[f32; 4]; 4],
+}
+
+impl From<Matrix4<f32>> for JointRaw {
+    fn from(matrix: Matrix4<f32>) -> Self {
+        Self { matrix: matrix.into() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Rotation3, MetricSpace};
+
+    fn assert_near(a: Point3<f32>, b: Point3<f32>) {
+        assert!((a - b).magnitude() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    fn channel(interpolation: Interpolation, times: Vec<f32>, values: Vec<f32>) -> Channel {
+        let values = values.into_iter().map(|x| Vector3::new(x, 0.0, 0.0)).collect();
+        Channel { joint: 0, times, keyframes: Keyframes::Translation(values), interpolation }
+    }
+
+    fn sample(channel: &Channel, time: f32) -> f32 {
+        let mut transform = Transform::identity();
+        channel.apply(time, &mut transform);
+        transform.translation.x
+    }
+
+    #[test]
+    fn step_and_linear_channels() {
+        let step = channel(Interpolation::Step, vec![1.0, 2.0, 4.0], vec![10.0, 20.0, 40.0]);
+        let linear = Channel { interpolation: Interpolation::Linear, ..step.clone() };
+        for &(time, held, blended) in &[
+            (0.0, 10.0, 10.0),
+            (1.0, 10.0, 10.0),
+            (1.5, 10.0, 15.0),
+            (2.0, 20.0, 20.0),
+            (3.5, 20.0, 35.0),
+            (4.0, 40.0, 40.0),
+            (9.0, 40.0, 40.0),
+        ] {
+            assert_eq!(sample(&step, time), held, "step at {}", time);
+            assert!((sample(&linear, time) - blended).abs() < 1e-5, "linear at {}", time);
+        }
+    }
+
+    #[test]
+    fn cubic_spline_channel() {
+        // In tangent, value, out tangent per keyframe; tangents are per
+        // second, so the two second gap doubles them
+        let cubic = channel(Interpolation::CubicSpline, vec![0.0, 2.0], vec![0.0, 0.0, 1.0, 0.0, 1.0, 0.0]);
+        assert_eq!(sample(&cubic, 0.0), 0.0);
+        assert_eq!(sample(&cubic, 2.0), 1.0);
+        // 3s^2 - 2s^3 for the values, plus (s^3 - 2s^2 + s) * 2 for the out
+        // tangent
+        assert!((sample(&cubic, 1.0) - (0.5 + 0.25)).abs() < 1e-5);
+        assert!((sample(&cubic, 0.5) - (0.15625 + 0.28125)).abs() < 1e-5);
+
+        let flat = channel(Interpolation::CubicSpline, vec![0.0, 1.0], vec![0.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+        assert!((sample(&flat, 0.5) - 0.5).abs() < 1e-5);
+
+        // Drops channels whose values don't come in threes
+        let short = channel(Interpolation::CubicSpline, vec![0.0, 1.0], vec![0.0, 1.0]);
+        assert!(!short.is_valid());
+        assert!(AnimationClip::new(String::from("Short"), vec![short]).channels.is_empty());
+    }
+
+    #[test]
+    fn rotations_take_the_shorter_arc() {
+        let quarter = Quaternion::from_angle_z(Deg(90.0));
+        let channel = Channel {
+            joint: 0,
+            times: vec![0.0, 1.0],
+            // The same rotation as +90 degrees, the long way round from
+            // the identity
+            keyframes: Keyframes::Rotation(vec![Quaternion::new(1.0, 0.0, 0.0, 0.0), -quarter]),
+            interpolation: Interpolation::Linear,
+        };
+        let mut transform = Transform::identity();
+        channel.apply(0.5, &mut transform);
+        let point = transform.matrix().transform_point(Point3::new(1.0, 0.0, 0.0));
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        assert_near(point, Point3::new(half, half, 0.0));
+    }
+
+    // A root at the origin and a child one up, in a bind pose matching
+    // the rest pose. The clip turns the root a quarter turn about z
+    fn two_joint_rig() -> SkinData {
+        let up = Vector3::new(0.0, 1.0, 0.0);
+        let rest = |translation| Transform { translation, ..Transform::identity() };
+        let joints = vec![
+            // Listed before its parent
+            Joint {
+                name: String::from("Child"),
+                parent: Some(1),
+                parent_transform: Matrix4::identity(),
+                rest: rest(up),
+                inverse_bind_matrix: Matrix4::from_translation(-up),
+            },
+            Joint {
+                name: String::from("Root"),
+                parent: None,
+                parent_transform: Matrix4::identity(),
+                rest: rest(Vector3::new(0.0, 0.0, 0.0)),
+                inverse_bind_matrix: Matrix4::identity(),
+            },
+        ];
+        let turn = Channel {
+            joint: 1,
+            times: vec![0.0, 1.0],
+            keyframes: Keyframes::Rotation(vec![Quaternion::new(1.0, 0.0, 0.0, 0.0), Quaternion::from_angle_z(Deg(90.0))]),
+            interpolation: Interpolation::Linear,
+        };
+        SkinData {
+            skeleton: Skeleton::new(joints).unwrap(),
+            clips: vec![AnimationClip::new(String::from("Turn"), vec![turn])],
+        }
+    }
+
+    #[test]
+    fn joint_matrices_of_a_two_joint_rig() {
+        let rig = two_joint_rig();
+        let tip = [0.0, 2.0, 0.0];
+        let on_child = SkinVertex { joints: [0, 0, 0, 0], weights: [1.0, 0.0, 0.0, 0.0] };
+        let shared = SkinVertex { joints: [0, 1, 0, 0], weights: [0.5, 0.5, 0.0, 0.0] };
+
+        // The rest pose is the bind pose
+        for matrix in rig.skeleton.joint_matrices(&rig.skeleton.rest_pose()) {
+            let columns: [[f32; 4]; 4] = (matrix - Matrix4::identity()).into();
+            assert!(columns.iter().flatten().all(|x| x.abs() < 1e-6), "{:?}", matrix);
+        }
+
+        // Turning the root carries the child with it
+        let turned = rig.joint_matrices(0, 1.0 - 1e-6);
+        assert_near(skin_position(&turned, &on_child, tip), Point3::new(-2.0, 0.0, 0.0));
+
+        // Turning only the child pivots about its own origin
+        let mut pose = rig.skeleton.rest_pose();
+        pose[0].rotation = Quaternion::from_angle_z(Deg(90.0));
+        let bent = rig.skeleton.joint_matrices(&pose);
+        assert_near(skin_position(&bent, &on_child, tip), Point3::new(-1.0, 1.0, 0.0));
+        assert_near(skin_position(&bent, &shared, tip), Point3::new(-0.5, 1.5, 0.0));
+
+        let cycle = rig.skeleton.joints().iter()
+            .map(|joint| Joint { parent: Some(0), ..joint.clone() })
+            .collect();
+        assert!(Skeleton::new(cycle).is_err());
+    }
+
+    #[test]
+    fn bounds_cover_the_clip() {
+        let rig = two_joint_rig();
+        let vertex = Vertex { position: [0.0, 2.0, 0.0], tex_coords: [0.0; 2], normal: [0.0; 3], tangent: [0.0; 4] };
+        let skin = SkinVertex { joints: [0, 0, 0, 0], weights: [1.0, 0.0, 0.0, 0.0] };
+        let bounds = rig.bounds(&[vertex], &[skin]).unwrap();
+        // From straight up to pointing along -x, through the halfway sample
+        let half = 2.0 * std::f32::consts::FRAC_1_SQRT_2;
+        assert_near(bounds.aabb.min, Point3::new(-2.0, 0.0, 0.0));
+        assert_near(bounds.aabb.max, Point3::new(0.0, 2.0, 0.0));
+        for point in &[Point3::new(0.0, 2.0, 0.0), Point3::new(-half, half, 0.0), Point3::new(-2.0, 0.0, 0.0)] {
+            assert!(bounds.sphere.center.distance(*point) <= bounds.sphere.radius + 1e-4);
+        }
+    }
+}
@@ -0,0 +1,47 @@
+// skinned.vert
+#version 450
+
+// Must match skin::MAX_JOINTS
+const int MAX_JOINTS = 128;
+
+layout(location=0) in vec3 a_position;
+layout(location=1) in vec2 a_tex_coords;
+
+layout(location=5) in vec4 model_matrix_0;
+layout(location=6) in vec4 model_matrix_1;
+layout(location=7) in vec4 model_matrix_2;
+layout(location=8) in vec4 model_matrix_3;
+layout(location=9) in float a_opacity;
+
+layout(location=10) in uvec4 a_joints;
+layout(location=11) in vec4 a_weights;
+
+layout(location=0) out vec2 v_tex_coords;
+layout(location=1) out float v_opacity;
+
+layout(set=1, binding=0)
+uniform Camera {
+    mat4 u_view_proj;
+};
+
+layout(set=2, binding=0)
+uniform Node {
+    mat4 u_node;
+};
+
+layout(set=3, binding=0)
+uniform Joints {
+    mat4 u_joints[MAX_JOINTS];
+};
+
+void main() {
+    mat4 model_matrix = mat4(model_matrix_0, model_matrix_1, model_matrix_2, model_matrix_3);
+    mat4 skin_matrix =
+        a_weights.x * u_joints[a_joints.x] +
+        a_weights.y * u_joints[a_joints.y] +
+        a_weights.z * u_joints[a_joints.z] +
+        a_weights.w * u_joints[a_joints.w];
+    v_tex_coords = a_tex_coords;
+    v_opacity = a_opacity;
+    gl_Position = u_view_proj * model_matrix * u_node * skin_matrix * vec4(a_position, 1.0);
+}
@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::rc::Rc;
+use serde::Deserialize;
+use wgpu::util::DeviceExt;
+use vertex_layout_derive::VertexLayout;
+use anyhow::*;
+
+use crate::assets::TextureCache;
+use crate::camera::OPENGL_TO_WGPU_MATRIX;
+use crate::sampler::SamplerConfig;
+use crate::texture::{Texture, TextureState, SamplerDesc};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SpriteDesc {
+
+    pub texture: PathBuf,
+    // Centre, in pixels from the top left of the window
+    pub position: [f32; 2],
+    pub size: [f32; 2],
+    // Degrees, clockwise
+    #[serde(default)]
+    pub angle: f32,
+    // Part of the texture shown, as (min u, min v, max u, max v)
+    #[serde(default = "SpriteDesc::default_uv_rect")]
+    pub uv_rect: [f32; 4],
+    #[serde(default = "SpriteDesc::default_tint")]
+    pub tint: [f32; 4],
+    // Higher layers are drawn over lower ones
+    #[serde(default)]
+    pub layer: i32,
+    #[serde(default)]
+    pub sampler: SamplerConfig,
+
+} impl SpriteDesc {
+
+    fn default_uv_rect() -> [f32; 4] { [0.0, 0.0, 1.0, 1.0] }
+    fn default_tint() -> [f32; 4] { [1.0, 1.0, 1.0, 1.0] }
+
+    pub fn build(&self, texture: SpriteTexture) -> Sprite {
+        let [u0, v0, u1, v1] = self.uv_rect;
+        Sprite {
+            texture,
+            position: self.position,
+            size: self.size,
+            rotation: self.angle.to_radians(),
+            uv_min: [u0, v0],
+            uv_max: [u1, v1],
+            tint: self.tint,
+            layer: self.layer,
+        }
+    }
+}
+
+/// Loads the textures of `descs` through `cache`, and builds their sprites
+/// against them, numbering the distinct textures in the order they first
+/// appear. The same image sampled two ways counts as two textures.
+pub fn load_sprites(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    cache: &mut TextureCache,
+    descs: &[SpriteDesc],
+) -> Result<(Vec<Rc<Texture>>, Vec<Sprite>)> {
+    let mut textures = Vec::new();
+    let mut loaded: HashMap<(&PathBuf, SamplerDesc), SpriteTexture> = HashMap::new();
+    let mut sprites = Vec::with_capacity(descs.len());
+    for desc in descs {
+        let key = (&desc.texture, desc.sampler.desc());
+        let texture = match loaded.get(&key) {
+            Some(&texture) => texture,
+            None => {
+                textures.push(cache.load_with(device, queue, &desc.texture, true, &key.1)?);
+                let texture = SpriteTexture(textures.len() - 1);
+                loaded.insert(key, texture);
+                texture
+            }
+        };
+        sprites.push(desc.build(texture));
+    }
+    Ok((textures, sprites))
+}
+
+/// Index of a texture in the `SpriteState` it was added to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SpriteTexture(pub usize);
+
+/// A textured rectangle in pixel space, y down.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sprite {
+
+    pub texture: SpriteTexture,
+    // Centre, in pixels from the top left of the window
+    pub position: [f32; 2],
+    pub size: [f32; 2],
+    // Radians around the centre, clockwise on screen
+    pub rotation: f32,
+    pub uv_min: [f32; 2],
+    pub uv_max: [f32; 2],
+    // Multiplies the texture
+    pub tint: [f32; 4],
+    // Higher layers are drawn over lower ones
+    pub layer: i32,
+
+} impl Sprite {
+
+    /// The whole texture, untinted and unrotated, on layer 0.
+    pub fn new(texture: SpriteTexture, position: [f32; 2], size: [f32; 2]) -> Self {
+        Self {
+            texture,
+            position,
+            size,
+            rotation: 0.0,
+            uv_min: [0.0, 0.0],
+            uv_max: [1.0, 1.0],
+            tint: [1.0, 1.0, 1.0, 1.0],
+            layer: 0,
+        }
+    }
+
+    fn to_raw(self) -> SpriteRaw {
+        SpriteRaw {
+            position: self.position,
+            size: self.size,
+            rotation: self.rotation,
+            uv_min: self.uv_min,
+            uv_max: self.uv_max,
+            tint: self.tint,
+        }
+    }
+}
+
+/// Orthographic projection putting (0, 0) at the top left of a `width` by
+/// `height` pixel target and (`width`, `height`) at the bottom right.
+pub fn pixel_projection(width: u32, height: u32) -> cgmath::Matrix4<f32> {
+    let (width, height) = (width.max(1) as f32, height.max(1) as f32);
+    OPENGL_TO_WGPU_MATRIX * cgmath::ortho(0.0, width, height, 0.0, -1.0, 1.0)
+}
+
+/// Sprites queued during a frame, drawn blended over whatever is in the
+/// target with one instanced draw per run of sprites sharing a texture.
+/// Sprites are sorted by layer, then by texture, so within a layer the
+/// draw order follows the textures, not the order they were queued in.
+pub struct SpriteState {
+
+    textures: Vec<(Rc<Texture>, wgpu::BindGroup)>,
+    camera_buffer: wgpu::Buffer,
+    camera_bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+    // Queued since the last `prepare`
+    sprites: Vec<Sprite>,
+    instance_buffer: wgpu::Buffer,
+    instance_capacity: usize,
+    // Ranges of the instance buffer, each drawn with one texture
+    batches: Vec<(SpriteTexture, Range<u32>)>,
+
+} impl SpriteState {
+
+    /// `texture_layout` is the layout of `TextureState`, which sprite
+    /// textures are bound with.
+    pub fn new(
+        device: &wgpu::Device,
+        texture_layout: &wgpu::BindGroupLayout,
+        color_format: wgpu::TextureFormat,
+    ) -> Self {
+        let projection: [[f32; 4]; 4] = pixel_projection(1, 1).into();
+        let camera_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Sprite Camera Buffer"),
+                contents: bytemuck::cast_slice(&[projection]),
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            }
+        );
+        let camera_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }
+            ],
+            label: Some("Sprite Camera Bind Group Layout"),
+        });
+        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &camera_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: camera_buffer.as_entire_binding(),
+                }
+            ],
+            label: Some("Sprite Camera Bind Group"),
+        });
+        let pipeline = Self::create_pipeline(device, &[texture_layout, &camera_layout], color_format);
+        let instance_capacity = 256;
+        let instance_buffer = Self::create_instance_buffer(device, instance_capacity);
+        Self {
+            textures: Vec::new(),
+            camera_buffer,
+            camera_bind_group,
+            pipeline,
+            sprites: Vec::new(),
+            instance_buffer,
+            instance_capacity,
+            batches: Vec::new(),
+        }
+    }
+
+    fn create_instance_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Sprite Instance Buffer"),
+            size: (capacity * size_of::<SpriteRaw>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    // Blended like the transparent 3D pipeline, ignoring the depth buffer
+    fn create_pipeline(
+        device: &wgpu::Device,
+        bind_group_layouts: &[&wgpu::BindGroupLayout],
+        color_format: wgpu::TextureFormat,
+    ) -> wgpu::RenderPipeline {
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Sprite Pipeline Layout"),
+            bind_group_layouts,
+            push_constant_ranges: &[],
+        });
+        let vs_module = device.create_shader_module(&wgpu::include_spirv!("sprite.vert.spv"));
+        let fs_module = device.create_shader_module(&wgpu::include_spirv!("sprite.frag.spv"));
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Sprite Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &vs_module,
+                entry_point: "main",
+                buffers: &[SpriteRaw::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &fs_module,
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format: color_format,
+                    alpha_blend: wgpu::BlendState {
+                        src_factor: wgpu::BlendFactor::One,
+                        dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                    color_blend: wgpu::BlendState {
+                        src_factor: wgpu::BlendFactor::SrcAlpha,
+                        dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Always,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+                clamp_depth: false,
+            }),
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+        })
+    }
+
+    pub fn add_texture(&mut self, device: &wgpu::Device, texture_layout: &wgpu::BindGroupLayout, texture: Rc<Texture>) -> SpriteTexture {
+        let bind_group = TextureState::create_bind_group(device, texture_layout, &texture);
+        self.textures.push((texture, bind_group));
+        SpriteTexture(self.textures.len() - 1)
+    }
+
+    /// Replaces every texture, numbering the new ones from 0.
+    pub fn set_textures(&mut self, device: &wgpu::Device, texture_layout: &wgpu::BindGroupLayout, textures: Vec<Rc<Texture>>) {
+        self.textures.clear();
+        for texture in textures {
+            self.add_texture(device, texture_layout, texture);
+        }
+    }
+
+    pub fn num_textures(&self) -> usize {
+        self.textures.len()
+    }
+
+    /// Sprites with a texture this state doesn't have are dropped.
+    pub fn queue(&mut self, sprite: Sprite) {
+        if sprite.texture.0 < self.textures.len() {
+            self.sprites.push(sprite);
+        } else {
+            log::warn!("Sprite texture {} doesn't exist, skipping the sprite", sprite.texture.0);
+        }
+    }
+
+    /// Sorts and uploads what was queued since the last call for the next
+    /// `draw`, on a `width` by `height` pixel target.
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, width: u32, height: u32) {
+        let projection: [[f32; 4]; 4] = pixel_projection(width, height).into();
+        queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[projection]));
+
+        self.batches = sort_into_batches(&mut self.sprites);
+        let instance_data = self.sprites.iter().map(|sprite| sprite.to_raw()).collect::<Vec<SpriteRaw>>();
+        if instance_data.len() > self.instance_capacity {
+            self.instance_capacity = instance_data.len().next_power_of_two();
+            self.instance_buffer = Self::create_instance_buffer(device, self.instance_capacity);
+        }
+        queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instance_data));
+        self.sprites.clear();
+    }
+
+    /// Draws as many times as there are batches.
+    pub fn num_batches(&self) -> usize {
+        self.batches.len()
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        if self.batches.is_empty() {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.instance_buffer.slice(..));
+        for (texture, instances) in &self.batches {
+            render_pass.set_bind_group(0, &self.textures[texture.0].1, &[]);
+            render_pass.draw(0..6, instances.clone());
+        }
+    }
+}
+
+/// Orders `sprites` by layer, then texture within a layer, keeping the
+/// queued order otherwise, and returns the batches they are drawn in.
+pub fn sort_into_batches(sprites: &mut [Sprite]) -> Vec<(SpriteTexture, Range<u32>)> {
+    sprites.sort_by_key(|sprite| (sprite.layer, sprite.texture));
+    batches(sprites)
+}
+
+/// Runs of consecutive sprites with the same texture.
+pub fn batches(sprites: &[Sprite]) -> Vec<(SpriteTexture, Range<u32>)> {
+    let mut batches: Vec<(SpriteTexture, Range<u32>)> = Vec::new();
+    for (i, sprite) in sprites.iter().enumerate() {
+        match batches.last_mut() {
+            Some((texture, range)) if *texture == sprite.texture => range.end = i as u32 + 1,
+            _ => batches.push((sprite.texture, i as u32..i as u32 + 1)),
+        }
+    }
+    batches
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, VertexLayout)]
+#[layout(step_mode = "Instance")]
+struct SpriteRaw {
+
+    #[layout(location = 0)]
+    position: [f32; 2],
+    #[layout(location = 1)]
+    size: [f32; 2],
+    #[layout(location = 2)]
+    rotation: f32,
+    #[layout(location = 3)]
+    uv_min: [f32; 2],
+    #[layout(location = 4)]
+    uv_max: [f32; 2],
+    #[layout(location = 5)]
+    tint: [f32; 4],
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sprite(texture: usize, layer: i32, x: f32) -> Sprite {
+        Sprite { layer, ..Sprite::new(SpriteTexture(texture), [x, 0.0], [1.0, 1.0]) }
+    }
+
+    fn positions(sprites: &[Sprite]) -> Vec<f32> {
+        sprites.iter().map(|sprite| sprite.position[0]).collect()
+    }
+
+    #[test]
+    fn interleaved_textures_merge() {
+        let mut sprites = vec![sprite(1, 0, 0.0), sprite(0, 0, 1.0), sprite(1, 0, 2.0), sprite(0, 0, 3.0), sprite(1, 0, 4.0)];
+        assert_eq!(batches(&sprites).len(), 5);
+        let batches = sort_into_batches(&mut sprites);
+        assert_eq!(batches, [(SpriteTexture(0), 0..2), (SpriteTexture(1), 2..5)]);
+        // Sprites sharing a texture keep the order they were queued in
+        assert_eq!(positions(&sprites), [1.0, 3.0, 0.0, 2.0, 4.0]);
+    }
+
+    #[test]
+    fn lower_layers_come_first() {
+        let mut sprites = vec![sprite(0, 2, 0.0), sprite(1, -1, 1.0), sprite(0, 0, 2.0), sprite(0, -1, 3.0), sprite(1, 2, 4.0)];
+        let batches = sort_into_batches(&mut sprites);
+        assert_eq!(positions(&sprites), [3.0, 1.0, 2.0, 0.0, 4.0]);
+        // Neighbours on different layers still share a batch, the order
+        // within it is kept
+        assert_eq!(batches, [
+            (SpriteTexture(0), 0..1),
+            (SpriteTexture(1), 1..2),
+            (SpriteTexture(0), 2..4),
+            (SpriteTexture(1), 4..5),
+        ]);
+    }
+
+    #[test]
+    fn no_sprites_no_batches() {
+        assert!(sort_into_batches(&mut []).is_empty());
+        assert!(batches(&[]).is_empty());
+    }
+}
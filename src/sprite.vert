@@ -0,0 +1,35 @@
+// sprite.vert
+#version 450
+
+layout(location=0) in vec2 a_position;
+layout(location=1) in vec2 a_size;
+layout(location=2) in float a_rotation;
+layout(location=3) in vec2 a_uv_min;
+layout(location=4) in vec2 a_uv_max;
+layout(location=5) in vec4 a_tint;
+
+layout(location=0) out vec2 v_tex_coords;
+layout(location=1) out vec4 v_tint;
+
+layout(set=1, binding=0)
+uniform Camera {
+    mat4 u_view_proj;
+};
+
+// Two triangles from the top left corner, in units of the sprite's size
+const vec2 CORNERS[6] = vec2[6](
+    vec2(0.0, 0.0), vec2(0.0, 1.0), vec2(1.0, 0.0),
+    vec2(1.0, 0.0), vec2(0.0, 1.0), vec2(1.0, 1.0)
+);
+
+void main() {
+    vec2 corner = CORNERS[gl_VertexIndex];
+    vec2 local = (corner - 0.5) * a_size;
+    float c = cos(a_rotation);
+    float s = sin(a_rotation);
+    // With y down this turns clockwise on screen
+    vec2 rotated = vec2(c * local.x - s * local.y, s * local.x + c * local.y);
+    v_tex_coords = mix(a_uv_min, a_uv_max, corner);
+    v_tint = a_tint;
+    gl_Position = u_view_proj * vec4(a_position + rotated, 0.0, 1.0);
+}
@@ -0,0 +1,292 @@
+use std::path::{Path, PathBuf};
+use cgmath::{Vector3, InnerSpace};
+use serde::Deserialize;
+use anyhow::*;
+
+use crate::instance::Instance;
+use crate::model::{MeshData, Vertex};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TerrainDesc {
+
+    // Grayscale image, black is height 0 and white `height_scale`
+    pub heightmap: PathBuf,
+    // Distance between neighbouring pixels in X and Z
+    #[serde(default = "TerrainDesc::default_spacing")]
+    pub spacing: f32,
+    #[serde(default = "TerrainDesc::default_height_scale")]
+    pub height_scale: f32,
+    // Quads along each side of a chunk
+    #[serde(default = "TerrainDesc::default_chunk_size")]
+    pub chunk_size: u32,
+
+} impl TerrainDesc {
+
+    fn default_spacing() -> f32 { 0.1 }
+    fn default_height_scale() -> f32 { 1.0 }
+    fn default_chunk_size() -> u32 { 32 }
+}
+
+/// A height field sampled on a regular grid in the XZ plane, centred on the
+/// origin. Pixel (x, y) of the image sits at grid column x and row y, rows
+/// running towards +Z, so the image reads upright seen from above with -Z
+/// up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Terrain {
+
+    columns: u32,
+    rows: u32,
+    spacing: f32,
+    // Row after row
+    heights: Vec<f32>,
+
+} impl Terrain {
+
+    pub fn load(desc: &TerrainDesc) -> Result<Self> {
+        Self::from_path(&desc.heightmap, desc.spacing, desc.height_scale)
+    }
+
+    pub fn from_path(path: &Path, spacing: f32, height_scale: f32) -> Result<Self> {
+        let img = image::open(path)
+            .with_context(|| format!("Unable to load heightmap {}", path.display()))?;
+        Self::from_image(&img, spacing, height_scale)
+            .with_context(|| format!("Unable to use {} as a heightmap", path.display()))
+    }
+
+    /// Colour images are converted to luma first. 16 bit images keep their
+    /// full precision.
+    pub fn from_image(img: &image::DynamicImage, spacing: f32, height_scale: f32) -> Result<Self> {
+        let luma = img.to_luma16();
+        let (columns, rows) = luma.dimensions();
+        if columns < 2 || rows < 2 {
+            bail!("A heightmap needs at least 2x2 pixels, got {}x{}", columns, rows);
+        }
+        if spacing <= 0.0 {
+            bail!("Terrain spacing has to be positive, got {}", spacing);
+        }
+        let heights = luma.pixels()
+            .map(|pixel| pixel[0] as f32 / u16::MAX as f32 * height_scale)
+            .collect();
+        Ok(Self { columns, rows, spacing, heights })
+    }
+
+    pub fn columns(&self) -> u32 { self.columns }
+    pub fn rows(&self) -> u32 { self.rows }
+    pub fn spacing(&self) -> f32 { self.spacing }
+
+    /// Height of the grid point at `column`, `row`.
+    pub fn sample(&self, column: u32, row: u32) -> f32 {
+        self.heights[(row * self.columns + column) as usize]
+    }
+
+    /// World X and Z of the grid point at `column`, `row`.
+    pub fn grid_position(&self, column: u32, row: u32) -> (f32, f32) {
+        (
+            (column as f32 - (self.columns - 1) as f32 / 2.0) * self.spacing,
+            (row as f32 - (self.rows - 1) as f32 / 2.0) * self.spacing,
+        )
+    }
+
+    /// Height of the ground at `x`, `z`, bilinear between the four grid
+    /// points around it. Outside the terrain the height at the nearest edge
+    /// is returned. Inside a cell it can differ slightly from the two
+    /// triangles drawn there, never on the grid lines.
+    pub fn height_at(&self, x: f32, z: f32) -> f32 {
+        let (cell, fx, fz) = self.cell(x, z);
+        let [a, b, c, d] = self.cell_heights(cell);
+        let near = a + (b - a) * fx;
+        let far = c + (d - c) * fx;
+        near + (far - near) * fz
+    }
+
+    /// Normal of the surface `height_at` describes, for lining things up
+    /// with the slope.
+    pub fn normal_at(&self, x: f32, z: f32) -> Vector3<f32> {
+        let (cell, fx, fz) = self.cell(x, z);
+        let [a, b, c, d] = self.cell_heights(cell);
+        let dx = (b - a) * (1.0 - fz) + (d - c) * fz;
+        let dz = (c - a) * (1.0 - fx) + (d - b) * fx;
+        Vector3::new(-dx, self.spacing, -dz).normalize()
+    }
+
+    /// Raises each instance by the height of the ground under it, so one at
+    /// height 0 stands on the surface.
+    pub fn place(&self, instances: &mut [Instance]) {
+        for instance in instances {
+            let mut position = instance.position();
+            position.y += self.height_at(position.x, position.z);
+            instance.set_position(position);
+        }
+    }
+
+    /// The terrain as meshes of at most `chunk_size` quads a side, each
+    /// with its own buffers and bounds once uploaded. Normals and tangents
+    /// come from the whole grid, so lighting matches across chunk borders;
+    /// UVs stretch one texture over the whole terrain.
+    pub fn chunks(&self, chunk_size: u32) -> Vec<MeshData> {
+        let chunk_size = chunk_size.max(1);
+        let quads = (self.columns - 1, self.rows - 1);
+        let starts = |quads: u32| (0..quads).step_by(chunk_size as usize);
+        starts(quads.1)
+            .flat_map(|row| starts(quads.0).map(move |column| (column, row)))
+            .map(|first| {
+                let last = ((first.0 + chunk_size).min(quads.0), (first.1 + chunk_size).min(quads.1));
+                let name = format!("terrain chunk {} {}", first.0 / chunk_size, first.1 / chunk_size);
+                self.chunk(first, last, name)
+            })
+            .collect()
+    }
+
+    // Grid points `first` to `last` inclusive
+    fn chunk(&self, first: (u32, u32), last: (u32, u32), name: String) -> MeshData {
+        let width = last.0 - first.0 + 1;
+        let vertices = (first.1..=last.1)
+            .flat_map(|row| (first.0..=last.0).map(move |column| (column, row)))
+            .map(|(column, row)| self.vertex(column, row))
+            .collect::<Vec<_>>();
+        let mut indices = Vec::with_capacity(((width - 1) * (last.1 - first.1) * 6) as usize);
+        for row in 0..last.1 - first.1 {
+            for column in 0..width - 1 {
+                let a = row * width + column;
+                let (b, c, d) = (a + 1, a + width, a + width + 1);
+                indices.extend_from_slice(&[a, c, b, b, c, d]);
+            }
+        }
+        MeshData { name, vertices, indices, material: None, skin: None, morph: None }
+    }
+
+    fn vertex(&self, column: u32, row: u32) -> Vertex {
+        let (x, z) = self.grid_position(column, row);
+        // Central differences, one sided on the edges
+        let slope = |before: f32, after: f32, steps: u32| (after - before) / (steps as f32 * self.spacing);
+        let (left, right) = (column.saturating_sub(1), (column + 1).min(self.columns - 1));
+        let (back, front) = (row.saturating_sub(1), (row + 1).min(self.rows - 1));
+        let dx = slope(self.sample(left, row), self.sample(right, row), right - left);
+        let dz = slope(self.sample(column, back), self.sample(column, front), front - back);
+        let normal = Vector3::new(-dx, 1.0, -dz).normalize();
+        // u grows along +X, v along +Z, so the bitangent already points
+        // towards decreasing v
+        let along_x = Vector3::new(1.0, dx, 0.0);
+        let tangent = (along_x - normal * normal.dot(along_x)).normalize();
+        Vertex {
+            position: [x, self.sample(column, row), z],
+            tex_coords: [column as f32 / (self.columns - 1) as f32, row as f32 / (self.rows - 1) as f32],
+            normal: normal.into(),
+            tangent: [tangent.x, tangent.y, tangent.z, 1.0],
+        }
+    }
+
+    // The grid cell holding `x`, `z` and how far across it the point is,
+    // clamped to the terrain
+    fn cell(&self, x: f32, z: f32) -> ((u32, u32), f32, f32) {
+        let (max_column, max_row) = ((self.columns - 1) as f32, (self.rows - 1) as f32);
+        let column = (x / self.spacing + max_column / 2.0).max(0.0).min(max_column);
+        let row = (z / self.spacing + max_row / 2.0).max(0.0).min(max_row);
+        // The far edges belong to the last cell
+        let cell_column = column.floor().min(max_column - 1.0);
+        let cell_row = row.floor().min(max_row - 1.0);
+        ((cell_column as u32, cell_row as u32), column - cell_column, row - cell_row)
+    }
+
+    // Heights at the cell's corners: its own point, the next along X, the
+    // next along Z and the one diagonally across
+    fn cell_heights(&self, (column, row): (u32, u32)) -> [f32; 4] {
+        [
+            self.sample(column, row),
+            self.sample(column + 1, row),
+            self.sample(column, row + 1),
+            self.sample(column + 1, row + 1),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Quaternion;
+    use super::*;
+
+    // Pixel values become heights as they are, one unit apart
+    fn terrain(columns: u32, rows: u32, pixel: impl Fn(u32, u32) -> u16) -> Terrain {
+        let img = image::ImageBuffer::from_fn(columns, rows, |x, y| image::Luma([pixel(x, y)]));
+        Terrain::from_image(&image::DynamicImage::ImageLuma16(img), 1.0, u16::MAX as f32).unwrap()
+    }
+
+    fn assert_height(terrain: &Terrain, x: f32, z: f32, height: f32) {
+        let actual = terrain.height_at(x, z);
+        assert!((actual - height).abs() < 1e-3, "height at {} {} is {}, not {}", x, z, actual, height);
+    }
+
+    fn assert_close(a: Vector3<f32>, b: Vector3<f32>) {
+        assert!((a - b).magnitude() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn heights_are_bilinear_and_clamped() {
+        let terrain = terrain(3, 3, |x, y| (y * 30 + x * 10) as u16);
+        // Grid points sit at -1, 0 and 1
+        assert_height(&terrain, 0.0, 0.0, 40.0);
+        assert_height(&terrain, 1.0, 1.0, 80.0);
+        assert_height(&terrain, -0.5, -0.5, 20.0);
+        assert_height(&terrain, 0.5, -0.25, 40.0 + 5.0 - 7.5);
+        assert_height(&terrain, -5.0, -5.0, 0.0);
+        assert_height(&terrain, 5.0, 0.0, 50.0);
+        assert_height(&terrain, 0.5, 9.0, 75.0);
+    }
+
+    #[test]
+    fn normals_follow_the_slope() {
+        let flat = terrain(3, 3, |_, _| 7);
+        assert_close(flat.normal_at(0.3, -0.6), Vector3::unit_y());
+        assert_close(flat.normal_at(10.0, 10.0), Vector3::unit_y());
+
+        let ramp = terrain(3, 3, |x, _| x as u16 * 2);
+        let expected = Vector3::new(-2.0, 1.0, 0.0).normalize();
+        assert_close(ramp.normal_at(-0.5, 0.5), expected);
+        assert_close(ramp.normal_at(0.7, -0.2), expected);
+
+        let tilted = terrain(3, 3, |_, y| y as u16 * 3);
+        assert_close(tilted.normal_at(0.2, 0.2), Vector3::new(0.0, 1.0, -3.0).normalize());
+    }
+
+    #[test]
+    fn instances_stand_on_the_ground() {
+        let terrain = terrain(3, 3, |x, _| x as u16 * 10);
+        let rotation = Quaternion::new(1.0, 0.0, 0.0, 0.0);
+        let mut instances = vec![
+            Instance::new(Vector3::new(-1.0, 0.0, 0.0), rotation, 1.0),
+            Instance::new(Vector3::new(0.5, 2.0, 1.0), rotation, 1.0),
+        ];
+        terrain.place(&mut instances);
+        assert_close(instances[0].position(), Vector3::new(-1.0, 0.0, 0.0));
+        assert_close(instances[1].position(), Vector3::new(0.5, 17.0, 1.0));
+    }
+
+    #[test]
+    fn chunks_share_their_borders() {
+        let terrain = terrain(5, 4, |x, y| (x * 7 + y * y * 5) as u16);
+        let chunks = terrain.chunks(2);
+        // Four quads across and three down make 2x2 chunks, the last row
+        // only one quad deep
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks.iter().map(|chunk| chunk.vertices.len()).collect::<Vec<_>>(), [9, 9, 6, 6]);
+        assert_eq!(chunks.iter().map(|chunk| chunk.indices.len()).sum::<usize>(), 4 * 3 * 6);
+
+        let vertices = chunks.iter().flat_map(|chunk| chunk.vertices.iter()).collect::<Vec<_>>();
+        for a in &vertices {
+            for b in vertices.iter().filter(|b| b.position == a.position) {
+                assert_eq!(a.normal, b.normal);
+                assert_eq!(a.tangent, b.tangent);
+                assert_eq!(a.tex_coords, b.tex_coords);
+            }
+        }
+        // The corner where all four chunks meet
+        let (x, z) = terrain.grid_position(2, 2);
+        assert_eq!(vertices.iter().filter(|v| v.position[0] == x && v.position[2] == z).count(), 4);
+
+        let (u, v): (Vec<f32>, Vec<f32>) = vertices.iter().map(|v| (v.tex_coords[0], v.tex_coords[1])).unzip();
+        let range = |values: &[f32]| values.iter().fold((f32::MAX, f32::MIN), |(lo, hi), &x| (lo.min(x), hi.max(x)));
+        assert_eq!(range(&u), (0.0, 1.0));
+        assert_eq!(range(&v), (0.0, 1.0));
+        assert!(vertices.iter().all(|v| (v.position[1] - terrain.height_at(v.position[0], v.position[2])).abs() < 1e-4));
+    }
+}
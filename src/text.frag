@@ -0,0 +1,26 @@
+// text.frag
+#version 450
+
+layout(location = 0) in vec2 v_tex_coords;
+layout(location = 1) in vec4 v_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_atlas;
+layout(set = 0, binding = 1) uniform sampler s_atlas;
+layout(set = 0, binding = 2)
+uniform Text {
+    vec2 u_screen_size;
+    uint u_sdf;
+};
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    float value = texture( sampler2D(t_atlas, s_atlas), v_tex_coords ).r;
+    float alpha = value;
+    if (u_sdf != 0) {
+        // The outline is at 0.5; blend over about a pixel whatever the scale
+        float width = fwidth(value) * 0.5;
+        alpha = smoothstep(0.5 - width, 0.5 + width, value);
+    }
+    f_color = vec4(v_color.rgb, v_color.a * alpha);
+}
@@ -0,0 +1,706 @@
+use std::collections::{HashMap, HashSet};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use cgmath::Point3;
+use rusttype::{Font, Scale, point};
+use serde::Deserialize;
+use wgpu::util::DeviceExt;
+use vertex_layout_derive::VertexLayout;
+use anyhow::*;
+
+use crate::texture::Texture;
+
+const ATLAS_SIZE: u32 = 1024;
+// Empty pixels kept between glyphs so filtering doesn't bleed
+const ATLAS_PADDING: u32 = 1;
+// How far from the outline, in atlas pixels, the distance field reaches
+const SDF_SPREAD: u32 = 4;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TextDesc {
+
+    // TrueType or OpenType font
+    #[serde(default = "TextDesc::default_font")]
+    pub font: PathBuf,
+    // Pixel size glyphs are rasterized at. Distance fields stay sharp well
+    // above it, plain coverage blurs
+    #[serde(default = "TextDesc::default_size")]
+    pub size: f32,
+    #[serde(default = "TextDesc::default_sdf")]
+    pub sdf: bool,
+    // Instance counts in the top left corner
+    #[serde(default)]
+    pub hud: bool,
+
+} impl TextDesc {
+
+    fn default_font() -> PathBuf { PathBuf::from("res/fonts/DejaVuSans.ttf") }
+    fn default_size() -> f32 { 32.0 }
+    fn default_sdf() -> bool { true }
+}
+
+impl Default for TextDesc {
+    fn default() -> Self {
+        Self {
+            font: Self::default_font(),
+            size: Self::default_size(),
+            sdf: Self::default_sdf(),
+            hud: false,
+        }
+    }
+}
+
+pub fn load_font(path: &Path) -> Result<Font<'static>> {
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("Unable to read font {}", path.display()))?;
+    Font::from_bytes(bytes)
+        .with_context(|| format!("Unable to parse font {}", path.display()))
+}
+
+/// Where a glyph's bitmap is in the atlas and how it sits on the baseline,
+/// in atlas pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AtlasGlyph {
+
+    // Texture coordinates of the top left and bottom right corners, None
+    // for glyphs with nothing to draw, like spaces
+    pub uv: Option<([f32; 2], [f32; 2])>,
+    // From the pen position on the baseline to the bitmap's top left
+    // corner, y down
+    pub offset: [f32; 2],
+    pub size: [f32; 2],
+    pub advance: f32,
+
+}
+
+/// Glyphs of one font rasterized on demand into a single channel image,
+/// packed in rows. With `sdf` the pixels hold the signed distance to the
+/// outline, 0.5 on it, instead of coverage.
+pub struct GlyphAtlas {
+
+    size: f32,
+    sdf: bool,
+    image: image::GrayImage,
+    glyphs: HashMap<char, AtlasGlyph>,
+    // Glyphs that didn't fit, so they are only warned about once
+    rejected: HashSet<char>,
+    // Top left of the next free spot and the height of the current row
+    cursor: (u32, u32),
+    row_height: u32,
+    // Glyphs were added since the image was last uploaded
+    dirty: bool,
+
+} impl GlyphAtlas {
+
+    pub fn new(size: f32, sdf: bool) -> Self {
+        Self {
+            size,
+            sdf,
+            image: image::GrayImage::new(ATLAS_SIZE, ATLAS_SIZE),
+            glyphs: HashMap::new(),
+            rejected: HashSet::new(),
+            cursor: (0, 0),
+            row_height: 0,
+            dirty: true,
+        }
+    }
+
+    pub fn size(&self) -> f32 { self.size }
+    pub fn sdf(&self) -> bool { self.sdf }
+    pub fn image(&self) -> &image::GrayImage { &self.image }
+
+    /// Rasterizes `c` the first time it is asked for. None once the atlas
+    /// is full.
+    pub fn glyph(&mut self, font: &Font, c: char) -> Option<AtlasGlyph> {
+        if let Some(glyph) = self.glyphs.get(&c) {
+            return Some(*glyph);
+        }
+        if self.rejected.contains(&c) {
+            return None;
+        }
+        let scaled = font.glyph(c).scaled(Scale::uniform(self.size));
+        let advance = scaled.h_metrics().advance_width;
+        let positioned = scaled.positioned(point(0.0, 0.0));
+        let bounds = match positioned.pixel_bounding_box() {
+            Some(bounds) => bounds,
+            None => {
+                let glyph = AtlasGlyph { uv: None, offset: [0.0, 0.0], size: [0.0, 0.0], advance };
+                self.glyphs.insert(c, glyph);
+                return Some(glyph);
+            }
+        };
+
+        let pad = if self.sdf { SDF_SPREAD } else { 0 };
+        let (width, height) = (bounds.width() as u32 + 2 * pad, bounds.height() as u32 + 2 * pad);
+        let mut coverage = vec![0.0; (width * height) as usize];
+        positioned.draw(|x, y, value| coverage[((y + pad) * width + x + pad) as usize] = value);
+        let values = if self.sdf { signed_distance(&coverage, width, height) } else { coverage };
+
+        let (x, y) = match self.allocate(width, height) {
+            Some(spot) => spot,
+            None => {
+                log::warn!("The glyph atlas is full, {:?} is left out", c);
+                self.rejected.insert(c);
+                return None;
+            }
+        };
+        for (i, value) in values.iter().enumerate() {
+            let (dx, dy) = (i as u32 % width, i as u32 / width);
+            self.image.put_pixel(x + dx, y + dy, image::Luma([(value.clamp(0.0, 1.0) * 255.0).round() as u8]));
+        }
+        self.dirty = true;
+
+        let uv = |x: u32, y: u32| [x as f32 / ATLAS_SIZE as f32, y as f32 / ATLAS_SIZE as f32];
+        let glyph = AtlasGlyph {
+            uv: Some((uv(x, y), uv(x + width, y + height))),
+            offset: [(bounds.min.x - pad as i32) as f32, (bounds.min.y - pad as i32) as f32],
+            size: [width as f32, height as f32],
+            advance,
+        };
+        self.glyphs.insert(c, glyph);
+        Some(glyph)
+    }
+
+    fn allocate(&mut self, width: u32, height: u32) -> Option<(u32, u32)> {
+        if self.cursor.0 + width > ATLAS_SIZE {
+            self.cursor = (0, self.cursor.1 + self.row_height + ATLAS_PADDING);
+            self.row_height = 0;
+        }
+        if width > ATLAS_SIZE || self.cursor.1 + height > ATLAS_SIZE {
+            return None;
+        }
+        let spot = self.cursor;
+        self.cursor.0 += width + ATLAS_PADDING;
+        self.row_height = self.row_height.max(height);
+        Some(spot)
+    }
+
+    /// Whether glyphs were added since the last call.
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::replace(&mut self.dirty, false)
+    }
+}
+
+// Distance from each pixel centre to the nearest pixel on the other side of
+// the outline, brute force within SDF_SPREAD, mapped so 0.5 is the outline
+// and 0 and 1 are SDF_SPREAD pixels outside and inside
+fn signed_distance(coverage: &[f32], width: u32, height: u32) -> Vec<f32> {
+    let inside = |x: i32, y: i32| {
+        x >= 0 && y >= 0 && x < width as i32 && y < height as i32
+            && coverage[(y as u32 * width + x as u32) as usize] >= 0.5
+    };
+    let spread = SDF_SPREAD as i32;
+    (0..height as i32)
+        .flat_map(|y| (0..width as i32).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let here = inside(x, y);
+            let mut nearest = spread as f32;
+            for dy in -spread..=spread {
+                for dx in -spread..=spread {
+                    if inside(x + dx, y + dy) != here {
+                        // The outline runs half way between the two pixels
+                        let distance = ((dx * dx + dy * dy) as f32).sqrt() - 0.5;
+                        nearest = nearest.min(distance);
+                    }
+                }
+            }
+            let signed = if here { nearest } else { -nearest };
+            0.5 + signed / (2.0 * spread as f32)
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextStyle {
+
+    // Line height is about 1.2 times this, in pixels
+    pub size: f32,
+    pub color: [f32; 4],
+    // Lines wrap at spaces before getting wider, in pixels
+    pub max_width: Option<f32>,
+
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        Self { size: 16.0, color: [1.0, 1.0, 1.0, 1.0], max_width: None }
+    }
+}
+
+/// One glyph's quad, in pixels from the top left of the text, y down.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GlyphQuad {
+    pub min: [f32; 2],
+    pub max: [f32; 2],
+    pub uv_min: [f32; 2],
+    pub uv_max: [f32; 2],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextLayout {
+    pub quads: Vec<GlyphQuad>,
+    // Widest line and the height of all lines
+    pub size: [f32; 2],
+    pub num_lines: usize,
+}
+
+/// Places the glyphs of `text` line by line, kerning each pair. Newlines
+/// always break; with a `max_width`, a line also breaks after the last
+/// space that keeps it narrow enough, or before the glyph that overflows if
+/// it has no space. Glyphs the atlas has no room for are skipped.
+pub fn layout(font: &Font, atlas: &mut GlyphAtlas, text: &str, style: &TextStyle) -> TextLayout {
+    let scale = style.size / atlas.size();
+    let v_metrics = font.v_metrics(Scale::uniform(style.size));
+    let line_height = v_metrics.ascent - v_metrics.descent + v_metrics.line_gap;
+    let kerning_scale = Scale::uniform(style.size);
+
+    // Pen x of every char and the line it is on, before the glyphs are
+    // turned into quads
+    let mut placed: Vec<(AtlasGlyph, f32, usize)> = Vec::new();
+    let mut line = 0;
+    let mut pen = 0.0;
+    let mut previous: Option<char> = None;
+    // First char after the last space of the current line
+    let mut line_start = 0;
+    let mut break_at: Option<usize> = None;
+    for c in text.chars() {
+        if c == '\n' {
+            line += 1;
+            pen = 0.0;
+            previous = None;
+            line_start = placed.len();
+            break_at = None;
+            continue;
+        }
+        let glyph = match atlas.glyph(font, c) {
+            Some(glyph) => glyph,
+            None => continue,
+        };
+        if let Some(previous) = previous {
+            pen += font.pair_kerning(kerning_scale, previous, c);
+        }
+        let advance = glyph.advance * scale;
+        let overflows = style.max_width.map_or(false, |max_width| pen + advance > max_width);
+        if overflows && !c.is_whitespace() && placed.len() > line_start {
+            // Everything after the break point moves down, starting at the
+            // left edge
+            let start = break_at.unwrap_or(placed.len());
+            let shift = placed.get(start).map_or(pen, |&(_, x, _)| x);
+            line += 1;
+            for (_, x, glyph_line) in &mut placed[start..] {
+                *x -= shift;
+                *glyph_line = line;
+            }
+            pen -= shift;
+            line_start = start;
+            break_at = None;
+        }
+        placed.push((glyph, pen, line));
+        pen += advance;
+        previous = Some(c);
+        if c.is_whitespace() {
+            break_at = Some(placed.len());
+        }
+    }
+
+    // Trailing spaces don't count towards the width
+    let mut width: f32 = 0.0;
+    let quads = placed.iter()
+        .filter_map(|&(glyph, x, line)| {
+            let (uv_min, uv_max) = glyph.uv?;
+            width = width.max(x + glyph.advance * scale);
+            let baseline = v_metrics.ascent + line as f32 * line_height;
+            let min = [x + glyph.offset[0] * scale, baseline + glyph.offset[1] * scale];
+            Some(GlyphQuad {
+                min,
+                max: [min[0] + glyph.size[0] * scale, min[1] + glyph.size[1] * scale],
+                uv_min,
+                uv_max,
+            })
+        })
+        .collect();
+    TextLayout { quads, size: [width, (line + 1) as f32 * line_height], num_lines: line + 1 }
+}
+
+/// Text queued during a frame, drawn on top of everything as one batch of
+/// quads: screen space text positioned in pixels and labels hanging over
+/// points in the world, keeping their pixel size and facing the camera.
+pub struct TextState {
+
+    font: Font<'static>,
+    atlas: GlyphAtlas,
+    atlas_texture: Texture,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+    // Queued since the last `prepare`
+    vertices: Vec<TextVertex>,
+    vertex_buffer: wgpu::Buffer,
+    vertex_capacity: usize,
+    num_vertices: u32,
+
+} impl TextState {
+
+    pub fn new(
+        device: &wgpu::Device,
+        camera_layout: &wgpu::BindGroupLayout,
+        color_format: wgpu::TextureFormat,
+        desc: &TextDesc,
+    ) -> Result<Self> {
+        let font = load_font(&desc.font)?;
+        let atlas = GlyphAtlas::new(desc.size, desc.sdf);
+        let atlas_texture = Texture::create_atlas_texture(device, ATLAS_SIZE, "Glyph Atlas");
+        let uniforms = TextUniforms { screen_size: [1.0, 1.0], sdf: desc.sdf as u32, _padding: 0 };
+        let uniform_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Text Uniform Buffer"),
+                contents: bytemuck::bytes_of(&uniforms),
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            }
+        );
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: false,
+                        filtering: true,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(size_of::<TextUniforms>() as u64),
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("Text Bind Group Layout"),
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(atlas_texture.view()),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(atlas_texture.sampler()),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("Text Bind Group"),
+        });
+        let pipeline = Self::create_pipeline(device, &[&bind_group_layout, camera_layout], color_format);
+        let vertex_capacity = 6 * 256;
+        let vertex_buffer = Self::create_vertex_buffer(device, vertex_capacity);
+        Ok(Self {
+            font,
+            atlas,
+            atlas_texture,
+            uniform_buffer,
+            bind_group,
+            pipeline,
+            vertices: Vec::new(),
+            vertex_buffer,
+            vertex_capacity,
+            num_vertices: 0,
+        })
+    }
+
+    fn create_vertex_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Text Vertex Buffer"),
+            size: (capacity * size_of::<TextVertex>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    // Blended over the frame and drawn whatever the depth buffer holds
+    fn create_pipeline(
+        device: &wgpu::Device,
+        bind_group_layouts: &[&wgpu::BindGroupLayout],
+        color_format: wgpu::TextureFormat,
+    ) -> wgpu::RenderPipeline {
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Text Pipeline Layout"),
+            bind_group_layouts,
+            push_constant_ranges: &[],
+        });
+        let vs_module = device.create_shader_module(&wgpu::include_spirv!("text.vert.spv"));
+        let fs_module = device.create_shader_module(&wgpu::include_spirv!("text.frag.spv"));
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Text Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &vs_module,
+                entry_point: "main",
+                buffers: &[TextVertex::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &fs_module,
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format: color_format,
+                    alpha_blend: wgpu::BlendState {
+                        src_factor: wgpu::BlendFactor::One,
+                        dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                    color_blend: wgpu::BlendState {
+                        src_factor: wgpu::BlendFactor::SrcAlpha,
+                        dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: wgpu::CullMode::None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Always,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+                clamp_depth: false,
+            }),
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+        })
+    }
+
+    /// Lays `text` out without queuing it, e.g. to measure it.
+    pub fn layout(&mut self, text: &str, style: &TextStyle) -> TextLayout {
+        layout(&self.font, &mut self.atlas, text, style)
+    }
+
+    /// Queues `text` with its top left corner `position` pixels from the
+    /// top left of the window.
+    pub fn queue_screen(&mut self, text: &str, position: [f32; 2], style: &TextStyle) {
+        let layout = self.layout(text, style);
+        self.queue_quads(&layout, [0.0; 3], position, style.color, true);
+    }
+
+    /// Queues `text` centred over `anchor`, its bottom edge on it.
+    pub fn queue_label(&mut self, text: &str, anchor: Point3<f32>, style: &TextStyle) {
+        let layout = self.layout(text, style);
+        let offset = [-layout.size[0] / 2.0, -layout.size[1]];
+        self.queue_quads(&layout, anchor.into(), offset, style.color, false);
+    }
+
+    fn queue_quads(&mut self, layout: &TextLayout, anchor: [f32; 3], offset: [f32; 2], color: [f32; 4], screen_space: bool) {
+        let screen_space = if screen_space { 1.0 } else { 0.0 };
+        for quad in &layout.quads {
+            let corner = |x: usize, y: usize| TextVertex {
+                anchor,
+                offset: [offset[0] + [quad.min[0], quad.max[0]][x], offset[1] + [quad.min[1], quad.max[1]][y]],
+                tex_coords: [[quad.uv_min[0], quad.uv_max[0]][x], [quad.uv_min[1], quad.uv_max[1]][y]],
+                color,
+                screen_space,
+            };
+            self.vertices.extend_from_slice(&[
+                corner(0, 0), corner(0, 1), corner(1, 0),
+                corner(1, 0), corner(0, 1), corner(1, 1),
+            ]);
+        }
+    }
+
+    /// Uploads what was queued since the last call, and any new glyphs,
+    /// for the next `draw`.
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, width: u32, height: u32) {
+        if self.atlas.take_dirty() {
+            self.atlas_texture.write_atlas(queue, self.atlas.image());
+        }
+        let uniforms = TextUniforms {
+            screen_size: [width.max(1) as f32, height.max(1) as f32],
+            sdf: self.atlas.sdf() as u32,
+            _padding: 0,
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniforms));
+        if self.vertices.len() > self.vertex_capacity {
+            self.vertex_capacity = self.vertices.len().next_power_of_two();
+            self.vertex_buffer = Self::create_vertex_buffer(device, self.vertex_capacity);
+        }
+        queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(&self.vertices));
+        self.num_vertices = self.vertices.len() as u32;
+        self.vertices.clear();
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, camera_bind_group: &'a wgpu::BindGroup) {
+        if self.num_vertices == 0 {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.set_bind_group(1, camera_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.draw(0..self.num_vertices, 0..1);
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, VertexLayout)]
+struct TextVertex {
+
+    // World position labels hang from, unused in screen space
+    #[layout(location = 0)]
+    anchor: [f32; 3],
+    // Pixels from the anchor, or from the window's top left corner, y down
+    #[layout(location = 1)]
+    offset: [f32; 2],
+    #[layout(location = 2)]
+    tex_coords: [f32; 2],
+    #[layout(location = 3)]
+    color: [f32; 4],
+    // 1 for screen space text, 0 for labels
+    #[layout(location = 4)]
+    screen_space: f32,
+
+}
+
+// Laid out like the Text uniform block in text.vert and text.frag
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct TextUniforms {
+    screen_size: [f32; 2],
+    sdf: u32,
+    _padding: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glyphs_that_do_not_fit_are_remembered() {
+        let font = load_font(&TextDesc::default().font).unwrap();
+        // Big enough that only a few glyphs fit
+        let mut atlas = GlyphAtlas::new(400.0, false);
+        let glyphs = ('A'..='Z').map(|c| atlas.glyph(&font, c)).collect::<Vec<_>>();
+        let first_missing = glyphs.iter().position(Option::is_none).expect("the atlas should fill up");
+        assert!(first_missing > 0);
+        let missing = (b'A' + first_missing as u8) as char;
+        assert!(atlas.rejected.contains(&missing));
+
+        // Asking again neither draws nor moves anything
+        let (cursor, row_height) = (atlas.cursor, atlas.row_height);
+        atlas.dirty = false;
+        assert_eq!(atlas.glyph(&font, missing), None);
+        assert_eq!((atlas.cursor, atlas.row_height, atlas.dirty), (cursor, row_height, false));
+        // Glyphs already in stay available
+        assert_eq!(atlas.glyph(&font, 'A'), glyphs[0]);
+        // Spaces take no room, so they always fit
+        assert!(atlas.glyph(&font, ' ').unwrap().uv.is_none());
+    }
+
+    fn fixture() -> (Font<'static>, GlyphAtlas) {
+        (load_font(&TextDesc::default().font).unwrap(), GlyphAtlas::new(32.0, false))
+    }
+
+    fn wrapped(max_width: f32) -> TextStyle {
+        TextStyle { max_width: Some(max_width), ..TextStyle::default() }
+    }
+
+    fn line_height(font: &Font, style: &TextStyle) -> f32 {
+        let v_metrics = font.v_metrics(Scale::uniform(style.size));
+        v_metrics.ascent - v_metrics.descent + v_metrics.line_gap
+    }
+
+    #[test]
+    fn lines_wrap_at_the_last_space_that_fits() {
+        let (font, mut atlas) = fixture();
+        let style = TextStyle::default();
+        let width = layout(&font, &mut atlas, "aa bb", &style).size[0];
+        let text = layout(&font, &mut atlas, "aa bb cc", &wrapped(width + 1.0));
+        assert_eq!(text.num_lines, 2);
+        assert_eq!(text, layout(&font, &mut atlas, "aa bb\ncc", &style));
+        // Any narrower and "bb" moves down too
+        let text = layout(&font, &mut atlas, "aa bb cc", &wrapped(width - 1.0));
+        assert_eq!(text, layout(&font, &mut atlas, "aa\nbb cc", &style));
+    }
+
+    #[test]
+    fn words_wider_than_a_line_break_between_glyphs() {
+        let (font, mut atlas) = fixture();
+        let style = TextStyle::default();
+        let width = layout(&font, &mut atlas, "abc", &style).size[0];
+        let text = layout(&font, &mut atlas, "abcdefgh", &wrapped(width + 1.0));
+        assert_eq!(text.num_lines, 3);
+        assert_eq!(text, layout(&font, &mut atlas, "abc\ndef\ngh", &style));
+        // A glyph wider than the line still gets one to itself
+        assert_eq!(layout(&font, &mut atlas, "ab", &wrapped(1.0)), layout(&font, &mut atlas, "a\nb", &style));
+    }
+
+    #[test]
+    fn newlines_always_break() {
+        let (font, mut atlas) = fixture();
+        let style = TextStyle::default();
+        let line_height = line_height(&font, &style);
+        let text = layout(&font, &mut atlas, "a\n\nb", &style);
+        assert_eq!((text.num_lines, text.quads.len()), (3, 2));
+        assert_eq!(text.size[1], 3.0 * line_height);
+        let b = layout(&font, &mut atlas, "b", &style).quads[0];
+        assert_eq!(text.quads[1].min, [b.min[0], b.min[1] + 2.0 * line_height]);
+        assert_eq!(text.quads[1].max, [b.max[0], b.max[1] + 2.0 * line_height]);
+    }
+
+    #[test]
+    fn kerning_moves_the_second_glyph() {
+        let (font, mut atlas) = fixture();
+        let style = TextStyle::default();
+        let kerning = font.pair_kerning(Scale::uniform(style.size), 'A', 'V');
+        assert!(kerning < 0.0, "the font should kern AV");
+        let advance = atlas.glyph(&font, 'A').unwrap().advance * style.size / atlas.size();
+        let text = layout(&font, &mut atlas, "AV", &style);
+        let v = layout(&font, &mut atlas, "V", &style).quads[0];
+        assert!((text.quads[1].min[0] - (v.min[0] + advance + kerning)).abs() < 1e-4);
+        assert!((text.quads[1].min[0] - (v.min[0] + advance)).abs() > 0.1);
+    }
+
+    #[test]
+    fn size_covers_the_widest_line() {
+        let (font, mut atlas) = fixture();
+        let style = TextStyle::default();
+        let scale = style.size / atlas.size();
+        let advance = |atlas: &mut GlyphAtlas, c| atlas.glyph(&font, c).unwrap().advance * scale;
+        let kerning = font.pair_kerning(Scale::uniform(style.size), 'a', 'b');
+        let width = advance(&mut atlas, 'a') + kerning + advance(&mut atlas, 'b');
+
+        let text = layout(&font, &mut atlas, "ab", &style);
+        assert_eq!(text.size, [width, line_height(&font, &style)]);
+        // Trailing spaces and shorter lines leave it as it is
+        assert_eq!(layout(&font, &mut atlas, "ab  ", &style).size, text.size);
+        let text = layout(&font, &mut atlas, "a\nab\nb", &style);
+        assert_eq!(text.size, [width, 3.0 * line_height(&font, &style)]);
+        assert_eq!(layout(&font, &mut atlas, "", &style).size, [0.0, line_height(&font, &style)]);
+    }
+}
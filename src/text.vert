@@ -0,0 +1,38 @@
+// text.vert
+#version 450
+
+layout(location=0) in vec3 a_anchor;
+layout(location=1) in vec2 a_offset;
+layout(location=2) in vec2 a_tex_coords;
+layout(location=3) in vec4 a_color;
+layout(location=4) in float a_screen_space;
+
+layout(location=0) out vec2 v_tex_coords;
+layout(location=1) out vec4 v_color;
+
+layout(set=0, binding=2)
+uniform Text {
+    vec2 u_screen_size;
+    uint u_sdf;
+};
+
+layout(set=1, binding=0)
+uniform Camera {
+    mat4 u_view_proj;
+};
+
+void main() {
+    v_tex_coords = a_tex_coords;
+    v_color = a_color;
+    // Screen space text hangs from the top left corner of the window
+    vec4 anchor = a_screen_space > 0.5
+        ? vec4(-1.0, 1.0, 0.0, 1.0)
+        : u_view_proj * vec4(a_anchor, 1.0);
+    // Scaled by w so the offset stays in pixels after the divide
+    vec2 offset = a_offset / u_screen_size * vec2(2.0, -2.0) * anchor.w;
+    gl_Position = vec4(anchor.xy + offset, anchor.zw);
+    // Labels behind the camera
+    if (anchor.w <= 0.0) {
+        gl_Position = vec4(0.0, 0.0, 2.0, 1.0);
+    }
+}
@@ -1,23 +1,20 @@
 use image::GenericImageView;
 use anyhow::*;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use crate::mipmap::{self, MipmapGenerator, Mipmaps};
+use crate::sampler;
 
 pub struct TextureState {
 
-    texture: Texture, 
+    texture: Rc<Texture>, 
     bind_group: wgpu::BindGroup,
     bind_group_layout: wgpu::BindGroupLayout,
 
 } impl TextureState {
 
-    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
-
-        let bytes = include_bytes!("edinaldo-pereira.png");
-        let texture = Texture::from_bytes(
-            device, 
-            queue, 
-            bytes, 
-            Some("edinaldo-pereira.png")
-        ).unwrap();
+    pub fn new(device: &wgpu::Device, texture: Rc<Texture>) -> Self {
 
         let bind_group_layout = device.create_bind_group_layout(
             &wgpu::BindGroupLayoutDescriptor {
@@ -28,7 +25,7 @@ pub struct TextureState {
                         ty: wgpu::BindingType::Texture {
                             multisampled: false,
                             view_dimension: wgpu::TextureViewDimension::D2,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
                         },
                         count: None,
                     },
@@ -46,9 +43,22 @@ pub struct TextureState {
             }
         );
 
-        let bind_group = device.create_bind_group(
+        let bind_group = Self::create_bind_group(device, &bind_group_layout, &texture);
+        Self { 
+            texture, 
+            bind_group, 
+            bind_group_layout, 
+        }
+    }
+
+    pub fn create_bind_group(
+        device: &wgpu::Device, 
+        layout: &wgpu::BindGroupLayout, 
+        texture: &Texture,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(
             &wgpu::BindGroupDescriptor {
-                layout: &bind_group_layout,
+                layout,
                 entries: &[
                     wgpu::BindGroupEntry {
                         binding: 0,
@@ -61,12 +71,14 @@ pub struct TextureState {
                 ],
                 label: Some("Bind Group"),
             }
-        );
-        Self { 
-            texture, 
-            bind_group, 
-            bind_group_layout, 
-        }
+        )
+    }
+
+    /// Swaps the bound texture. The layout is kept, so pipelines built
+    /// against it stay valid.
+    pub fn set_texture(&mut self, device: &wgpu::Device, texture: Rc<Texture>) {
+        self.bind_group = Self::create_bind_group(device, &self.bind_group_layout, &texture);
+        self.texture = texture;
     }
 
     pub fn bind_group(&self) -> &wgpu::BindGroup {
@@ -77,33 +89,161 @@ pub struct TextureState {
     }
 }
 
-struct Texture {
+pub struct Texture {
 
     texture: wgpu::Texture,
     view: wgpu::TextureView,
-    sampler: wgpu::Sampler,
+    // Shared with every texture sampled the same way
+    sampler: Rc<wgpu::Sampler>,
 
 } impl Texture {
 
-    pub fn from_bytes(
+    pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+    pub fn create_depth_texture(
+        device: &wgpu::Device, 
+        sc_desc: &wgpu::SwapChainDescriptor, 
+        label: &str,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width: sc_desc.width,
+            height: sc_desc.height,
+            depth: 1,
+        };
+        let texture = device.create_texture(
+            &wgpu::TextureDescriptor {
+                label: Some(label),
+                size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: Self::DEPTH_FORMAT,
+                usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+            }
+        );
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                compare: Some(wgpu::CompareFunction::LessEqual),
+                lod_min_clamp: -100.0,
+                lod_max_clamp: 100.0,
+                ..Default::default()
+            }
+        );
+
+        Self { texture, view, sampler: Rc::new(sampler) }
+    }
+
+    /// A square single channel texture, blank until `write_atlas` fills it,
+    /// for data drawn on the CPU at runtime like glyphs.
+    pub fn create_atlas_texture(device: &wgpu::Device, size: u32, label: &str) -> Self {
+        let texture = device.create_texture(
+            &wgpu::TextureDescriptor {
+                label: Some(label),
+                size: wgpu::Extent3d { width: size, height: size, depth: 1 },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::R8Unorm,
+                usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+            }
+        );
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                ..Default::default()
+            }
+        );
+
+        Self { texture, view, sampler: Rc::new(sampler) }
+    }
+
+    /// Replaces the contents of a texture from `create_atlas_texture`.
+    pub fn write_atlas(&self, queue: &wgpu::Queue, img: &image::GrayImage) {
+        let (width, height) = img.dimensions();
+        queue.write_texture(
+            wgpu::TextureCopyView {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+
+            img.as_raw(),
+
+            wgpu::TextureDataLayout {
+                offset: 0,
+                bytes_per_row: width,
+                rows_per_image: height,
+            },
+            wgpu::Extent3d { width, height, depth: 1 },
+        );
+    }
+
+    /// Magenta and black checks, to draw with when a texture fails to load.
+    pub fn placeholder(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let img = image::RgbaImage::from_fn(2, 2, |x, y| {
+            if (x + y) % 2 == 0 { image::Rgba([255, 0, 255, 255]) } else { image::Rgba([0, 0, 0, 255]) }
+        });
+        let sampler = sampler::create_sampler(device, &SamplerDesc::pixelated());
+        Self::from_image_with_mipmaps(
+            device, 
+            queue, 
+            &image::DynamicImage::ImageRgba8(img), 
+            Some("Placeholder"), 
+            true, 
+            Rc::new(sampler), 
+            Mipmaps::None,
+        ).expect("An RGBA image always converts")
+    }
+
+    /// `srgb` is for colour data; normal maps and other data textures have
+    /// to be sampled linearly. The mip chain is generated on the GPU.
+    /// `sampler` usually comes from a `SamplerCache`.
+    pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        bytes: &[u8], 
+        img: &image::DynamicImage,
         label: Option<&str>,
+        srgb: bool,
+        sampler: Rc<wgpu::Sampler>,
+        mipmaps: &mut MipmapGenerator,
     ) -> Result<Self> {
-        let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, label)
+        Self::from_image_with_mipmaps(device, queue, img, label, srgb, sampler, Mipmaps::Gpu(mipmaps))
     }
 
-    pub fn from_image(
+    pub fn from_image_with_mipmaps(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
-        label: Option<&str>
+        label: Option<&str>,
+        srgb: bool,
+        sampler: Rc<wgpu::Sampler>,
+        mipmaps: Mipmaps,
     ) -> Result<Self> {
         let rgba_img = img.to_rgba8();//.expect(format!("{:?}", img));
         let rgba_raw = rgba_img.as_raw();
         let dimensions = img.dimensions();
+        let mip_level_count = match mipmaps {
+            Mipmaps::None => 1,
+            Mipmaps::Gpu(_) | Mipmaps::Cpu => mipmap::mip_level_count(dimensions.0, dimensions.1),
+        };
+        let format = if srgb { 
+            wgpu::TextureFormat::Rgba8UnormSrgb 
+        } else { 
+            wgpu::TextureFormat::Rgba8Unorm 
+        };
 
         let size = wgpu::Extent3d {
             width: dimensions.0,
@@ -114,11 +254,14 @@ struct Texture {
             &wgpu::TextureDescriptor {
                 label,
                 size,
-                mip_level_count: 1,
+                mip_level_count,
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
-                format: wgpu::TextureFormat::Rgba8UnormSrgb,
-                usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+                format,
+                // Rendered to when the GPU fills the mip chain
+                usage: wgpu::TextureUsage::SAMPLED 
+                    | wgpu::TextureUsage::COPY_DST 
+                    | wgpu::TextureUsage::RENDER_ATTACHMENT,
             }
         );
         queue.write_texture(
@@ -137,18 +280,12 @@ struct Texture {
             },
             size,
         );
+        match mipmaps {
+            Mipmaps::None => {}
+            Mipmaps::Gpu(generator) => generator.generate(device, queue, &texture, format, mip_level_count),
+            Mipmaps::Cpu => mipmap::write_mipmaps(queue, &texture, &mipmap::generate_mipmaps_cpu(&rgba_img, srgb)),
+        }
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(
-            &wgpu::SamplerDescriptor {
-                address_mode_u: wgpu::AddressMode::ClampToEdge,
-                address_mode_v: wgpu::AddressMode::ClampToEdge,
-                address_mode_w: wgpu::AddressMode::ClampToEdge,
-                mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Nearest,
-                mipmap_filter: wgpu::FilterMode::Nearest,
-                ..Default::default()
-            }
-        );
         
         Ok(Self { texture, view, sampler })
     }
@@ -160,3 +297,91 @@ struct Texture {
         &self.sampler
     }
 }
+
+/// Sampler settings a texture is created with.
+#[derive(Debug, Clone, Copy)]
+pub struct SamplerDesc {
+
+    pub address_mode_u: wgpu::AddressMode,
+    pub address_mode_v: wgpu::AddressMode,
+    pub mag_filter: wgpu::FilterMode,
+    pub min_filter: wgpu::FilterMode,
+    pub mipmap_filter: wgpu::FilterMode,
+    // Finest and coarsest mip level sampled
+    pub lod_min_clamp: f32,
+    pub lod_max_clamp: f32,
+    // 1 is off, otherwise 2, 4, 8 or 16
+    pub anisotropy: u8,
+    // Only used with ClampToBorder
+    pub border_color: Option<wgpu::SamplerBorderColor>,
+
+} impl SamplerDesc {
+
+    /// Nearest filtering all the way, for pixel art.
+    pub fn pixelated() -> Self {
+        Self {
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        }
+    }
+
+    /// Tiles with `mode`, Repeat or MirrorRepeat, in both directions.
+    pub fn repeat(mode: wgpu::AddressMode) -> Self {
+        Self { address_mode_u: mode, address_mode_v: mode, ..Default::default() }
+    }
+
+    /// Samples `color` outside the texture.
+    pub fn border(color: wgpu::SamplerBorderColor) -> Self {
+        Self {
+            border_color: Some(color),
+            ..Self::repeat(wgpu::AddressMode::ClampToBorder)
+        }
+    }
+
+    // The LOD clamps compared by their bits, so the descs can key a HashMap
+    fn key(&self) -> impl Eq + Hash {
+        (
+            self.address_mode_u,
+            self.address_mode_v,
+            self.mag_filter,
+            self.min_filter,
+            self.mipmap_filter,
+            self.lod_min_clamp.to_bits(),
+            self.lod_max_clamp.to_bits(),
+            self.anisotropy,
+            self.border_color,
+        )
+    }
+}
+
+impl Default for SamplerDesc {
+    fn default() -> Self {
+        Self {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            lod_min_clamp: 0.0,
+            lod_max_clamp: f32::MAX,
+            anisotropy: 1,
+            border_color: None,
+        }
+    }
+}
+
+impl PartialEq for SamplerDesc {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for SamplerDesc {}
+
+impl Hash for SamplerDesc {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
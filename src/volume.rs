@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+use cgmath::{Point3, Vector3, InnerSpace, Zero};
+use anyhow::*;
+
+use crate::model::{MeshData, Vertex};
+
+// Corner i of a cell is offset by bit 0 along X, bit 1 along Y and bit 2
+// along Z
+const CORNERS: [[u32; 3]; 8] = [
+    [0, 0, 0], [1, 0, 0], [0, 1, 0], [1, 1, 0],
+    [0, 0, 1], [1, 0, 1], [0, 1, 1], [1, 1, 1],
+];
+// Lower corner first
+const EDGES: [(usize, usize); 12] = [
+    (0, 1), (2, 3), (4, 5), (6, 7),
+    (0, 2), (1, 3), (4, 6), (5, 7),
+    (0, 4), (1, 5), (2, 6), (3, 7),
+];
+// Counter-clockwise seen from outside the cell
+const FACES: [[usize; 4]; 6] = [
+    [1, 3, 7, 5], [0, 4, 6, 2],
+    [2, 6, 7, 3], [0, 1, 5, 4],
+    [4, 5, 7, 6], [0, 2, 3, 1],
+];
+
+/// Scalar values sampled on a regular 3D grid, X varying fastest, then Y,
+/// then Z.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScalarGrid {
+
+    // Points along X, Y and Z
+    size: [u32; 3],
+    // Position of the first point
+    origin: Point3<f32>,
+    // Distance between neighbouring points along each axis
+    spacing: Vector3<f32>,
+    values: Vec<f32>,
+
+} impl ScalarGrid {
+
+    pub fn new(size: [u32; 3], origin: Point3<f32>, spacing: Vector3<f32>, values: Vec<f32>) -> Result<Self> {
+        let num_points = size.iter().map(|&n| n as usize).product::<usize>();
+        if values.len() != num_points {
+            bail!("A {:?} grid has {} points but got {} values", size, num_points, values.len());
+        }
+        if spacing.x <= 0.0 || spacing.y <= 0.0 || spacing.z <= 0.0 {
+            bail!("Grid spacing has to be positive, got {:?}", spacing);
+        }
+        Ok(Self { size, origin, spacing, values })
+    }
+
+    /// Samples `field` at every grid point.
+    pub fn from_fn<F>(size: [u32; 3], origin: Point3<f32>, spacing: Vector3<f32>, field: F) -> Result<Self>
+    where F: Fn(Point3<f32>) -> f32 {
+        let values = (0..size[2])
+            .flat_map(|z| (0..size[1]).flat_map(move |y| (0..size[0]).map(move |x| [x, y, z])))
+            .map(|point| field(Self::point_position(origin, spacing, point)))
+            .collect();
+        Self::new(size, origin, spacing, values)
+    }
+
+    pub fn size(&self) -> [u32; 3] { self.size }
+    pub fn origin(&self) -> Point3<f32> { self.origin }
+    pub fn spacing(&self) -> Vector3<f32> { self.spacing }
+    pub fn values(&self) -> &[f32] { &self.values }
+
+    fn index(&self, [x, y, z]: [u32; 3]) -> usize {
+        ((z * self.size[1] + y) * self.size[0] + x) as usize
+    }
+
+    pub fn value(&self, point: [u32; 3]) -> f32 {
+        self.values[self.index(point)]
+    }
+
+    fn point_position(origin: Point3<f32>, spacing: Vector3<f32>, [x, y, z]: [u32; 3]) -> Point3<f32> {
+        origin + Vector3::new(x as f32 * spacing.x, y as f32 * spacing.y, z as f32 * spacing.z)
+    }
+
+    pub fn position(&self, point: [u32; 3]) -> Point3<f32> {
+        Self::point_position(self.origin, self.spacing, point)
+    }
+
+    /// Central differences, one sided on the grid's faces.
+    pub fn gradient(&self, point: [u32; 3]) -> Vector3<f32> {
+        let mut gradient = Vector3::zero();
+        for axis in 0..3 {
+            let (mut before, mut after) = (point, point);
+            before[axis] = point[axis].saturating_sub(1);
+            after[axis] = (point[axis] + 1).min(self.size[axis] - 1);
+            let steps = after[axis] - before[axis];
+            if steps > 0 {
+                gradient[axis] = (self.value(after) - self.value(before)) / (steps as f32 * self.spacing[axis]);
+            }
+        }
+        gradient
+    }
+
+    /// Marching cubes surface where the field crosses `iso`. Values below
+    /// `iso` are inside, so a signed distance field comes out with its
+    /// zero set at `iso` 0. Normals follow the field's gradient, pointing
+    /// out; UVs project the grid's XZ extent from above.
+    pub fn isosurface(&self, iso: f32) -> MeshData {
+        let cells = self.size[2].saturating_sub(1);
+        self.extract(iso, 0..cells, &triangle_table(), String::from("isosurface"))
+    }
+
+    // Cells from `layers` along Z
+    fn extract(&self, iso: f32, layers: Range<u32>, table: &[Vec<[usize; 3]>], name: String) -> MeshData {
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        // Keyed by the edge's lower grid point and axis, so cells sharing
+        // an edge share its vertex. Axis 3 is the grid point itself.
+        let mut edge_vertices: HashMap<(usize, usize), u32> = HashMap::new();
+        if self.size[0] < 2 || self.size[1] < 2 {
+            return MeshData { name, vertices, indices, material: None, skin: None, morph: None };
+        }
+
+        for z in layers {
+            for y in 0..self.size[1] - 1 {
+                for x in 0..self.size[0] - 1 {
+                    let corner = |i: usize| [x + CORNERS[i][0], y + CORNERS[i][1], z + CORNERS[i][2]];
+                    let case = (0..8)
+                        .filter(|&i| self.value(corner(i)) < iso)
+                        .fold(0, |case, i| case | 1 << i);
+                    for triangle in &table[case] {
+                        let mut corners = [0; 3];
+                        for (index, &edge) in corners.iter_mut().zip(triangle) {
+                            let (a, b) = (corner(EDGES[edge].0), corner(EDGES[edge].1));
+                            // A crossing right on a grid point is shared by
+                            // every edge meeting there
+                            let key = if self.value(a) == iso {
+                                (self.index(a), 3)
+                            } else if self.value(b) == iso {
+                                (self.index(b), 3)
+                            } else {
+                                (self.index(a), edge / 4)
+                            };
+                            *index = *edge_vertices.entry(key).or_insert_with(|| {
+                                vertices.push(self.edge_vertex(iso, a, b));
+                                vertices.len() as u32 - 1
+                            });
+                        }
+                        // Grid points exactly at `iso` collapse triangles
+                        if corners[0] != corners[1] && corners[1] != corners[2] && corners[2] != corners[0] {
+                            indices.extend_from_slice(&corners);
+                        }
+                    }
+                }
+            }
+        }
+        MeshData { name, vertices, indices, material: None, skin: None, morph: None }
+    }
+
+    fn edge_vertex(&self, iso: f32, a: [u32; 3], b: [u32; 3]) -> Vertex {
+        let (value_a, value_b) = (self.value(a), self.value(b));
+        let t = (iso - value_a) / (value_b - value_a);
+        let position = self.position(a) + (self.position(b) - self.position(a)) * t;
+        let gradient = self.gradient(a) * (1.0 - t) + self.gradient(b) * t;
+        let normal = if gradient.magnitude2() > 0.0 { gradient.normalize() } else { Vector3::unit_y() };
+
+        let extent = self.position([self.size[0] - 1, 0, self.size[2] - 1]) - self.origin;
+        let tex_coords = [
+            (position.x - self.origin.x) / extent.x.max(f32::EPSILON),
+            (position.z - self.origin.z) / extent.z.max(f32::EPSILON),
+        ];
+        // u grows along X; the bitangent has to point towards decreasing v,
+        // which is -Z
+        let along = if normal.x.abs() < 0.999 { Vector3::unit_x() } else { Vector3::unit_z() };
+        let tangent = (along - normal * normal.dot(along)).normalize();
+        let w = if normal.cross(tangent).z > 0.0 { -1.0 } else { 1.0 };
+        Vertex {
+            position: position.into(),
+            tex_coords,
+            normal: normal.into(),
+            tangent: [tangent.x, tangent.y, tangent.z, w],
+        }
+    }
+}
+
+/// The surface `ScalarGrid::isosurface` extracts, as up to `num_chunks`
+/// meshes of consecutive layers along Z, each extracted on its own thread.
+/// Vertices on the layer between two chunks are in both.
+pub fn isosurface_chunks(grid: &Arc<ScalarGrid>, iso: f32, num_chunks: u32) -> Vec<MeshData> {
+    let cells = grid.size[2].saturating_sub(1);
+    let num_chunks = num_chunks.max(1).min(cells.max(1));
+    let table = Arc::new(triangle_table());
+    let threads = (0..num_chunks)
+        .map(|chunk| {
+            let (grid, table) = (Arc::clone(grid), Arc::clone(&table));
+            let layers = cells * chunk / num_chunks..cells * (chunk + 1) / num_chunks;
+            let name = format!("isosurface {}..{}", layers.start, layers.end);
+            std::thread::spawn(move || grid.extract(iso, layers, &table, name))
+        })
+        .collect::<Vec<_>>();
+    threads.into_iter()
+        .map(|thread| thread.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+        .collect()
+}
+
+/// Triangles for each of the 256 cases of inside corners, as indices into
+/// `EDGES`. Built by following where the surface crosses each face of the
+/// cell: every loop it makes around the cell becomes a fan. Faces with two
+/// diagonally opposite inside corners always keep those corners apart, a
+/// choice that only depends on the face, so neighbouring cells agree on it
+/// and the surface has no holes.
+fn triangle_table() -> Vec<Vec<[usize; 3]>> {
+    let edge = |a: usize, b: usize| {
+        EDGES.iter().position(|&edge| edge == (a.min(b), a.max(b))).unwrap()
+    };
+    let same_face = |a: usize, b: usize| FACES.iter().any(|face| {
+        let on_face = |edge: usize| face.contains(&EDGES[edge].0) && face.contains(&EDGES[edge].1);
+        on_face(a) && on_face(b)
+    });
+    (0..256)
+        .map(|case: usize| {
+            let inside = |corner: usize| case & 1 << corner != 0;
+            // Going round a face, the surface leaves an inside corner's
+            // region where an edge goes from inside to outside and comes
+            // back in on the edge before the corner. The loop goes from the
+            // first to the second.
+            let mut next = [None; 12];
+            for face in FACES.iter() {
+                let crossings = (0..4)
+                    .filter(|&i| inside(face[i]) != inside(face[(i + 1) % 4]))
+                    .map(|i| (edge(face[i], face[(i + 1) % 4]), inside(face[i])))
+                    .collect::<Vec<_>>();
+                for (i, &(exit, leaving)) in crossings.iter().enumerate() {
+                    if leaving {
+                        let (entry, _) = crossings[(i + crossings.len() - 1) % crossings.len()];
+                        next[exit] = Some(entry);
+                    }
+                }
+            }
+
+            let mut triangles = Vec::new();
+            let mut visited = [false; 12];
+            for start in 0..12 {
+                if visited[start] || next[start].is_none() {
+                    continue;
+                }
+                let mut polygon = Vec::new();
+                let mut current = start;
+                while !visited[current] {
+                    visited[current] = true;
+                    polygon.push(current);
+                    current = next[current].unwrap();
+                }
+                // A fan diagonal lying in a face of the cell could be the
+                // neighbouring cell's diagonal too, pinching the surface
+                // there, so the fan starts where none does if it can
+                let start = (0..polygon.len())
+                    .find(|&start| {
+                        (2..polygon.len() - 1).all(|i| !same_face(polygon[start], polygon[(start + i) % polygon.len()]))
+                    })
+                    .unwrap_or(0);
+                polygon.rotate_left(start);
+                // The loop runs clockwise seen from outside
+                for i in 1..polygon.len() - 1 {
+                    triangles.push([polygon[0], polygon[i + 1], polygon[i]]);
+                }
+            }
+            triangles
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::EuclideanSpace;
+
+    // A cube of `n` points a side spanning -1.2 to 1.2
+    fn grid<F>(n: u32, field: F) -> ScalarGrid
+    where F: Fn(Point3<f32>) -> f32 {
+        let spacing = 2.4 / (n - 1) as f32;
+        ScalarGrid::from_fn([n, n, n], Point3::new(-1.2, -1.2, -1.2), Vector3::new(spacing, spacing, spacing), field).unwrap()
+    }
+
+    fn position_key(vertex: &Vertex) -> [u32; 3] {
+        let [x, y, z] = vertex.position;
+        [x.to_bits(), y.to_bits(), z.to_bits()]
+    }
+
+    // Each triangle by its corners' positions, starting from the smallest
+    // so the winding is kept
+    fn triangle_set(meshes: &[MeshData]) -> Vec<[[u32; 3]; 3]> {
+        let mut triangles = meshes.iter()
+            .flat_map(|mesh| mesh.indices.chunks_exact(3).map(move |t| {
+                let corners = [0, 1, 2].iter().map(|&k| position_key(&mesh.vertices[t[k] as usize])).collect::<Vec<_>>();
+                let first = (0..3).min_by_key(|&i| corners[i]).unwrap();
+                [corners[first], corners[(first + 1) % 3], corners[(first + 2) % 3]]
+            }))
+            .collect::<Vec<_>>();
+        triangles.sort_unstable();
+        triangles
+    }
+
+    fn assert_watertight(mesh: &MeshData) {
+        let mut edges = HashMap::new();
+        for triangle in triangle_set(std::slice::from_ref(mesh)) {
+            for k in 0..3 {
+                *edges.entry((triangle[k], triangle[(k + 1) % 3])).or_insert(0) += 1;
+            }
+        }
+        for (&(a, b), &count) in &edges {
+            assert_eq!(count, 1, "edge {:?} -> {:?} is used {} times", a, b, count);
+            assert!(edges.contains_key(&(b, a)), "edge {:?} -> {:?} is open", a, b);
+        }
+    }
+
+    fn signed_volume(mesh: &MeshData) -> f32 {
+        mesh.indices.chunks_exact(3)
+            .map(|t| {
+                let p = |k: usize| Vector3::from(mesh.vertices[t[k] as usize].position);
+                p(0).dot(p(1).cross(p(2))) / 6.0
+            })
+            .sum()
+    }
+
+    #[test]
+    fn sphere_vertices_lie_on_the_sphere() {
+        let radius = 0.93;
+        let sphere = grid(25, |p| p.to_vec().magnitude() - radius).isosurface(0.0);
+        assert!(sphere.indices.len() > 1000);
+        for vertex in &sphere.vertices {
+            let position = Vector3::from(vertex.position);
+            // Linear interpolation cuts slightly inside the curve
+            assert!((position.magnitude() - radius).abs() < 0.01, "{:?}", vertex.position);
+            assert!(Vector3::from(vertex.normal).dot(position.normalize()) > 0.99, "{:?}", vertex);
+        }
+        assert_watertight(&sphere);
+        let volume = 4.0 / 3.0 * std::f32::consts::PI * radius * radius * radius;
+        assert!((signed_volume(&sphere) - volume).abs() < volume * 0.02, "{} vs {}", signed_volume(&sphere), volume);
+    }
+
+    #[test]
+    fn grid_points_on_the_surface_keep_it_closed() {
+        // Squared distance from the middle point minus 25, exact in floats,
+        // so the surface passes right through the grid points 5 steps out
+        // along each axis and through the 3-4-5 points
+        let values = (0..13i32)
+            .flat_map(|z| (0..13i32).flat_map(move |y| (0..13i32).map(move |x| [x - 6, y - 6, z - 6])))
+            .map(|[x, y, z]| (x * x + y * y + z * z - 25) as f32)
+            .collect();
+        let grid = ScalarGrid::new([13, 13, 13], Point3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0), values).unwrap();
+        assert_eq!(grid.value([11, 6, 6]), 0.0);
+        let sphere = grid.isosurface(0.0);
+        for &point in &[[11.0, 6.0, 6.0], [6.0, 1.0, 6.0], [9.0, 10.0, 6.0]] {
+            assert_eq!(sphere.vertices.iter().filter(|vertex| vertex.position == point).count(), 1, "{:?}", point);
+        }
+        assert_watertight(&sphere);
+        assert!(signed_volume(&sphere) > 0.0);
+    }
+
+    #[test]
+    fn plane_vertices_lie_on_the_plane() {
+        // A field linear in the position is interpolated exactly
+        let normal = Vector3::new(0.3, 1.0, -0.2).normalize();
+        let plane = grid(13, |p| p.to_vec().dot(normal) - 0.15).isosurface(0.0);
+        assert!(!plane.indices.is_empty());
+        for vertex in &plane.vertices {
+            let distance = Vector3::from(vertex.position).dot(normal) - 0.15;
+            assert!(distance.abs() < 1e-5, "{:?} is {} off the plane", vertex.position, distance);
+            assert!((Vector3::from(vertex.normal) - normal).magnitude() < 1e-4, "{:?}", vertex.normal);
+        }
+        // Triangles face the way the field grows
+        for t in plane.indices.chunks_exact(3) {
+            let p = |k: usize| Vector3::from(plane.vertices[t[k] as usize].position);
+            assert!((p(1) - p(0)).cross(p(2) - p(0)).dot(normal) > 0.0);
+        }
+    }
+
+    #[test]
+    fn chunks_match_the_whole_surface() {
+        let grid = Arc::new(grid(21, |p| {
+            // Two overlapping blobs, so the surface crosses chunk borders
+            // at different heights
+            let a = (p - Point3::new(0.3, 0.2, -0.4)).magnitude() - 0.6;
+            let b = (p - Point3::new(-0.3, -0.2, 0.4)).magnitude() - 0.5;
+            a.min(b)
+        }));
+        let whole = grid.isosurface(0.0);
+        for &num_chunks in &[1, 3, 7, 100] {
+            let chunks = isosurface_chunks(&grid, 0.0, num_chunks);
+            assert_eq!(chunks.len(), num_chunks.min(20) as usize);
+            assert_eq!(triangle_set(&chunks), triangle_set(std::slice::from_ref(&whole)), "{} chunks", num_chunks);
+        }
+    }
+}
@@ -0,0 +1,350 @@
+//! `#[derive(VertexLayout)]` for the `#[repr(C)]` structs learn_wgpu puts
+//! in vertex buffers.
+
+use proc_macro2::{Span, TokenStream};
+use quote::{quote, format_ident};
+use syn::{parse_macro_input, Data, DeriveInput, Error, Expr, Fields, Lit, Meta, NestedMeta, Type};
+
+/// Gives the struct an `ATTRIBUTES` array, one `wgpu::VertexAttribute` per
+/// shader location, and `desc()`, the `wgpu::VertexBufferLayout` of a
+/// buffer of it.
+///
+/// Fields marked `#[layout(location = N)]` become attributes; the others
+/// still take up their space. The format follows from the field's type:
+/// `f32`, `u32` or `i32` and arrays of up to four of them, or of two or four
+/// `u16`, `i16`, `u8` or `i8`, take one location. Arrays of those, like
+/// matrices, and longer arrays of 4 byte scalars that split into fours take
+/// one location each, counting up from N. `format = "Uchar4Norm"` picks the
+/// format by name, and `count = 2` spreads a field over that many locations
+/// where the length is a constant the macro can't read.
+///
+/// `#[layout(step_mode = "Instance")]` on the struct steps it per instance.
+/// The struct has to be `#[repr(C)]`, since the offsets add up the fields in
+/// the order they are written. Fields that don't fill their attributes
+/// exactly, or padding between fields, fail to compile.
+#[proc_macro_derive(VertexLayout, attributes(layout))]
+pub fn derive_vertex_layout(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input).unwrap_or_else(|error| error.to_compile_error()).into()
+}
+
+struct Attribute {
+    format: syn::Ident,
+    location: u32,
+    // Index of the field and of the attribute within it
+    field: usize,
+    element: u32,
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(&input.generics, "VertexLayout can't be derived for generic structs"));
+    }
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => fields.named.iter().collect::<Vec<_>>(),
+            Fields::Unnamed(fields) => fields.unnamed.iter().collect::<Vec<_>>(),
+            Fields::Unit => Vec::new(),
+        },
+        _ => return Err(Error::new_spanned(input, "VertexLayout can only be derived for structs")),
+    };
+    if !is_repr_c(&input.attrs) {
+        return Err(Error::new_spanned(name, "VertexLayout needs #[repr(C)], otherwise the compiler may reorder the fields"));
+    }
+
+    let mut step_mode = format_ident!("Vertex");
+    for (key, lit) in layout_args(&input.attrs)? {
+        match (key.as_str(), &lit) {
+            ("step_mode", Lit::Str(value)) if value.value() == "Vertex" || value.value() == "Instance" => {
+                step_mode = syn::Ident::new(&value.value(), value.span());
+            }
+            ("step_mode", _) => return Err(Error::new_spanned(lit, "step_mode is \"Vertex\" or \"Instance\"")),
+            _ => return Err(Error::new_spanned(lit, format!("Unknown struct option {}", key))),
+        }
+    }
+
+    let mut attributes = Vec::new();
+    for (index, field) in fields.iter().enumerate() {
+        let args = layout_args(&field.attrs)?;
+        if args.is_empty() {
+            continue;
+        }
+        let (mut location, mut format, mut count) = (None, None, None);
+        for (key, lit) in args {
+            match (key.as_str(), &lit) {
+                ("location", Lit::Int(value)) => location = Some(value.base10_parse::<u32>()?),
+                ("format", Lit::Str(value)) => format = Some(syn::Ident::new(&value.value(), value.span())),
+                ("count", Lit::Int(value)) => count = Some(value.base10_parse::<u32>()?),
+                _ => return Err(Error::new_spanned(lit, format!("Unexpected field option {}", key))),
+            }
+        }
+        let location = location.ok_or_else(|| Error::new_spanned(field, "Attribute fields need a location"))?;
+        let (format, count) = match (format, infer_format(&field.ty)) {
+            (Some(format), inferred) => (format, count.or_else(|| inferred.map(|(_, count)| count)).unwrap_or(1)),
+            (None, Some((format, inferred))) => (syn::Ident::new(format, Span::call_site()), count.unwrap_or(inferred)),
+            (None, None) => return Err(Error::new_spanned(&field.ty, "Can't tell the vertex format of this type, give one with format = \"...\"")),
+        };
+        for element in 0..count {
+            let location = location + element;
+            if let Some(other) = attributes.iter().find(|other: &&Attribute| other.location == location) {
+                let message = match &fields[other.field].ident {
+                    Some(ident) => format!("Location {} is already used by {}", location, ident),
+                    None => format!("Location {} is already used by field {}", location, other.field),
+                };
+                return Err(Error::new_spanned(field, message));
+            }
+            attributes.push(Attribute { format: format.clone(), location, field: index, element });
+        }
+    }
+
+    let types = fields.iter().map(|field| &field.ty).collect::<Vec<_>>();
+    let offsets = (0..fields.len())
+        .map(|index| {
+            let before = &types[..index];
+            quote!(0 #(+ ::std::mem::size_of::<#before>())*)
+        })
+        .collect::<Vec<_>>();
+    let num_attributes = attributes.len();
+    let entries = attributes.iter().map(|attribute| {
+        let Attribute { format, location, field, element } = attribute;
+        let offset = &offsets[*field];
+        quote! {
+            wgpu::VertexAttribute {
+                format: wgpu::VertexFormat::#format,
+                offset: (#offset + #element as usize * wgpu::VertexFormat::#format.size() as usize) as wgpu::BufferAddress,
+                shader_location: #location,
+            }
+        }
+    });
+
+    // Checked by the compiler: a false condition makes an array of length 1
+    // where one of length 0 is expected
+    let mut checks = Vec::new();
+    for (index, ty) in types.iter().enumerate() {
+        let field_attributes = attributes.iter().filter(|attribute| attribute.field == index).collect::<Vec<_>>();
+        if let Some(first) = field_attributes.first() {
+            let format = &first.format;
+            let count = field_attributes.len();
+            checks.push(quote! {
+                let _: [(); 0] = [(); !(#count * wgpu::VertexFormat::#format.size() as usize == ::std::mem::size_of::<#ty>()) as usize];
+            });
+        }
+    }
+    checks.push(quote! {
+        let _: [(); 0] = [(); !(0 #(+ ::std::mem::size_of::<#types>())* == ::std::mem::size_of::<#name>()) as usize];
+    });
+
+    Ok(quote! {
+        impl #name {
+            pub const ATTRIBUTES: [wgpu::VertexAttribute; #num_attributes] = [#(#entries),*];
+
+            pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+                wgpu::VertexBufferLayout {
+                    array_stride: ::std::mem::size_of::<Self>() as wgpu::BufferAddress,
+                    step_mode: wgpu::InputStepMode::#step_mode,
+                    attributes: &Self::ATTRIBUTES,
+                }
+            }
+        }
+
+        // Each attribute fills its field exactly and the fields are packed
+        const _: () = {
+            #(#checks)*
+        };
+    })
+}
+
+// Whether one of the #[repr(...)] lists C, alongside e.g. align(16)
+fn is_repr_c(attrs: &[syn::Attribute]) -> bool {
+    attrs.iter()
+        .filter(|attr| attr.path.is_ident("repr"))
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(Meta::List(list)) => Some(list),
+            _ => None,
+        })
+        .any(|list| list.nested.iter().any(|nested| match nested {
+            NestedMeta::Meta(Meta::Path(path)) => path.is_ident("C"),
+            _ => false,
+        }))
+}
+
+// The `key = value` pairs of every #[layout(...)]
+fn layout_args(attrs: &[syn::Attribute]) -> syn::Result<Vec<(String, Lit)>> {
+    let mut args = Vec::new();
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("layout")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(Error::new_spanned(meta, "Expected #[layout(key = value, ...)]")),
+        };
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(pair)) => {
+                    let key = pair.path.get_ident()
+                        .map(|ident| ident.to_string())
+                        .ok_or_else(|| Error::new_spanned(&pair.path, "Expected a name"))?;
+                    args.push((key, pair.lit));
+                }
+                other => return Err(Error::new_spanned(other, "Expected key = value")),
+            }
+        }
+    }
+    Ok(args)
+}
+
+// The format and number of locations of a field's type, when the type says
+fn infer_format(ty: &Type) -> Option<(&'static str, u32)> {
+    match ty {
+        Type::Path(_) => scalar_format(scalar(ty)?, 1).map(|format| (format, 1)),
+        Type::Array(array) => {
+            let length = array_length(&array.len)?;
+            if let Some(name) = scalar(&array.elem) {
+                if let Some(format) = scalar_format(name, length) {
+                    return Some((format, 1));
+                }
+                // Longer arrays of 4 byte scalars go four at a time
+                return match scalar_format(name, 4) {
+                    Some(format) if length % 4 == 0 && matches!(name, "f32" | "u32" | "i32") => Some((format, length / 4)),
+                    _ => None,
+                };
+            }
+            match infer_format(&array.elem)? {
+                (format, 1) => Some((format, length)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn scalar(ty: &Type) -> Option<&'static str> {
+    let path = match ty {
+        Type::Path(path) if path.qself.is_none() => path,
+        _ => return None,
+    };
+    let ident = path.path.get_ident()?.to_string();
+    ["f32", "u32", "i32", "u16", "i16", "u8", "i8"].iter()
+        .copied()
+        .find(|&name| name == ident)
+}
+
+fn scalar_format(name: &str, length: u32) -> Option<&'static str> {
+    Some(match (name, length) {
+        ("f32", 1) => "Float",
+        ("f32", 2) => "Float2",
+        ("f32", 3) => "Float3",
+        ("f32", 4) => "Float4",
+        ("u32", 1) => "Uint",
+        ("u32", 2) => "Uint2",
+        ("u32", 3) => "Uint3",
+        ("u32", 4) => "Uint4",
+        ("i32", 1) => "Int",
+        ("i32", 2) => "Int2",
+        ("i32", 3) => "Int3",
+        ("i32", 4) => "Int4",
+        ("u16", 2) => "Ushort2",
+        ("u16", 4) => "Ushort4",
+        ("i16", 2) => "Short2",
+        ("i16", 4) => "Short4",
+        ("u8", 2) => "Uchar2",
+        ("u8", 4) => "Uchar4",
+        ("i8", 2) => "Char2",
+        ("i8", 4) => "Char4",
+        _ => return None,
+    })
+}
+
+fn array_length(length: &Expr) -> Option<u32> {
+    match length {
+        Expr::Lit(expr) => match &expr.lit {
+            Lit::Int(value) => value.base10_parse().ok(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    fn message(input: DeriveInput) -> String {
+        expand(&input).expect_err("the derive should fail").to_string()
+    }
+
+    #[test]
+    fn repr_c_is_required() {
+        let error = message(parse_quote! {
+            struct Vertex {
+                #[layout(location = 0)]
+                position: [f32; 3],
+            }
+        });
+        assert!(error.contains("needs #[repr(C)]"), "{}", error);
+        assert!(expand(&parse_quote! {
+            #[repr(packed)]
+            struct Vertex { #[layout(location = 0)] position: [f32; 3] }
+        }).is_err());
+        assert!(expand(&parse_quote! {
+            #[repr(C, align(16))]
+            struct Vertex { #[layout(location = 0)] position: [f32; 3] }
+        }).is_ok());
+    }
+
+    #[test]
+    fn locations_and_formats() {
+        let tokens = expand(&parse_quote! {
+            #[repr(C)]
+            #[layout(step_mode = "Instance")]
+            struct Instance {
+                #[layout(location = 5)]
+                model: [[f32; 4]; 4],
+                #[layout(location = 9, format = "Uchar4Norm")]
+                color: u32,
+                padding: f32,
+                #[layout(location = 12, format = "Float4", count = 2)]
+                weights: [f32; WEIGHTS],
+            }
+        }).unwrap().to_string();
+        assert!(tokens.contains("ATTRIBUTES : [wgpu :: VertexAttribute ; 7usize]"), "{}", tokens);
+        assert!(tokens.contains("wgpu :: InputStepMode :: Instance"), "{}", tokens);
+        for &(format, location) in &[("Float4", 5u32), ("Float4", 8), ("Uchar4Norm", 9), ("Float4", 13)] {
+            let entry = format!("format : wgpu :: VertexFormat :: {} ,", format);
+            let location = format!("shader_location : {}u32", location);
+            assert!(tokens.contains(&entry) && tokens.contains(&location), "{}", tokens);
+        }
+    }
+
+    #[test]
+    fn bad_fields_are_rejected() {
+        let error = message(parse_quote! {
+            #[repr(C)]
+            struct Vertex {
+                #[layout(location = 0)]
+                position: [f32; 3],
+                #[layout(location = 0)]
+                normal: [f32; 3],
+            }
+        });
+        assert_eq!(error, "Location 0 is already used by position");
+
+        let error = message(parse_quote! {
+            #[repr(C)]
+            struct Vertex { #[layout(location = 0)] position: [f64; 3] }
+        });
+        assert!(error.starts_with("Can't tell the vertex format"), "{}", error);
+
+        let error = message(parse_quote! {
+            #[repr(C)]
+            struct Vertex { #[layout(location = 0, stride = 4)] position: [f32; 3] }
+        });
+        assert_eq!(error, "Unexpected field option stride");
+
+        let error = message(parse_quote! {
+            #[repr(C)]
+            struct Vertex<T> { #[layout(location = 0)] position: T }
+        });
+        assert!(error.contains("generic"), "{}", error);
+    }
+}